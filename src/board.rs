@@ -5,10 +5,12 @@ use std::rc::Rc;
 use std::thread::{JoinHandle, self};
 use kanal;
 
-use crate::component::{Component, ComponentId, Message, ThreadlessComponent, ExecuteStepResult};
+use crate::component::{Component, ComponentId, ComponentEvent, Message, ThreadlessComponent, ExecuteStepResult};
 use crate::pins::{PinId, PinState};
 use crate::vcd::{VcdTree, VcdWriter, VcdConfig, VcdTreeHandle};
 
+pub mod description;
+
 /// Index of a pin. Unlike [PinId], this is unique for the whole board, not only for one component.
 type PinIndex = usize;
 
@@ -47,7 +49,6 @@ impl ThreadedComponentData {
 /// When a handle is dropped, the corresponding component thread is stopped automatically.
 impl Drop for ThreadedComponentData {
     fn drop(&mut self) {
-        println!("Dropping {}", self.id.0);
         self.input_tx.send(Message::Die).expect("Error sending update");
         self.thread.take().unwrap().join().unwrap();
     }
@@ -81,7 +82,9 @@ impl ThreadlessComponentData {
 struct CommonComponentData {
     index: usize,
     is_threaded: bool,
-    pins: Vec<PinIndex>
+    pins: Vec<PinIndex>,
+    name: String,
+    pin_names: Vec<String>,
 }
 
 /// A representation for a state of a single wire.
@@ -152,6 +155,14 @@ impl Wire {
     }
 }
 
+/// A snapshot of a wire state, for inspecting the board.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WireInfo {
+    pub state: PinState,
+    /// Connected pins as `component.PIN`, with the states they are outputting.
+    pub pins: Vec<(String, PinState)>,
+}
+
 /// A representation for a single pin of a component.
 struct Pin {
     /// [PinId] is unique only up to a component.
@@ -193,6 +204,25 @@ impl PartialOrd for PingEvent {
     }
 }
 
+/// The reason [Board::simulate] has returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// All the requested cycles have been simulated.
+    Finished,
+    /// A component has reported an event at a given time in nanoseconds, the simulation has stopped early.
+    Event(ComponentId, ComponentEvent, f64),
+}
+
+impl StopReason {
+    /// Returns the exit code requested by firmware, if the simulation has stopped because of it.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            StopReason::Event(_, ComponentEvent::Exit(code), _) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
 /// Top-level element of a simulation. A board containing multiple components.
 pub struct Board {
     threaded_components: Vec<ThreadedComponentData>,
@@ -219,20 +249,31 @@ pub struct Board {
 
     /// Nanoseconds per step.
     clock_period: f64,
+    /// Current simulation time, preserved between [Board::simulate] calls.
+    time_ns: f64,
+    /// First event reported by a component during the current step.
+    stop_event: Option<(ComponentId, ComponentEvent)>,
+    header_written: bool,
 }
 pub struct ComponentHandle {
     id: ComponentId,
+    name: String,
     pin_name_lookup: HashMap<String, PinId>,
 }
 
 impl ComponentHandle {
+    pub fn id(&self) -> ComponentId {
+        self.id
+    }
+
+    /// Finds a pin of the component by its name.
+    ///
+    /// Panics if the component has no such pin.
     pub fn pin(&self, name: &str) -> (ComponentId, PinId) {
-        (
-            self.id,
-            *self.pin_name_lookup
-                 .get(name)
-                 .expect("Such pin doesn't exist!")
-        )
+        match self.pin_name_lookup.get(name) {
+            Some(&pin) => (self.id, pin),
+            None => panic!("Component `{}` has no pin `{}`", self.name, name),
+        }
     }
 }
 
@@ -242,6 +283,17 @@ impl Board {
     /// `vcd_path` is an output path for .vcd file.
     /// `freq` is the clock frequency in Hz.
     pub fn new(vcd_path: &str, freq: f64) -> Board {
+        Board::with_vcd_writer(VcdWriter::new(vcd_path), freq)
+    }
+
+    /// Creates a new board without VCD output.
+    ///
+    /// `freq` is the clock frequency in Hz.
+    pub fn without_vcd(freq: f64) -> Board {
+        Board::with_vcd_writer(VcdWriter::disabled(), freq)
+    }
+
+    fn with_vcd_writer(vcd_writer: VcdWriter, freq: f64) -> Board {
         let (output_tx, output_rx) = kanal::unbounded();
         Board {
             output_rx: Some(output_rx),
@@ -254,9 +306,12 @@ impl Board {
             common_component_data: Vec::new(),
             clock_pin: false,
             
-            vcd_writer: VcdWriter::new(vcd_path),
+            vcd_writer,
             clock_period: 5e8 / freq,
             events: BinaryHeap::new(),
+            time_ns: 0.0,
+            stop_event: None,
+            header_written: false,
         }
     }
 
@@ -294,6 +349,8 @@ impl Board {
             index: self.threaded_components.len(),
             is_threaded: true,
             pins: Vec::with_capacity(pins_count as usize),
+            name: name.to_string(),
+            pin_names: (0..pins_count).map(T::pin_name).collect(),
         });
 
         let thread = thread::Builder::new().name(name.to_string()).spawn(move || {
@@ -312,10 +369,13 @@ impl Board {
         for id in 0..pins_count {
             c.notify_on_pin_change(id, PinState::Z);
         }
+        // First step lets the component drive its initial outputs
+        self.threaded_components_changed.push(self.threaded_components.len());
         self.threaded_components.push(c);
         
         ComponentHandle {
             id: component_id,
+            name: name.to_string(),
             pin_name_lookup,
         }
     }
@@ -336,6 +396,8 @@ impl Board {
             index: self.threadless_components.len(),
             is_threaded: false,
             pins: Vec::with_capacity(pins_count as usize),
+            name: name.to_string(),
+            pin_names: (0..pins_count).map(T::pin_name).collect(),
         });
 
         self.add_pins(pins_count, component_id);
@@ -353,6 +415,7 @@ impl Board {
 
         ComponentHandle {
             id: component_id,
+            name: name.to_string(),
             pin_name_lookup,
         }
     }
@@ -474,6 +537,9 @@ impl Board {
                     Message::PingMeAt(id, time) => {
                         self.events.push(PingEvent(id, time));
                     },
+                    Message::Event(id, event) => {
+                        self.stop_event.get_or_insert((id, event));
+                    },
                 }
             }
         }
@@ -487,8 +553,8 @@ impl Board {
             if time_ns <= current_time {
                 self.events.pop();
                 let data = &self.common_component_data[id.0];
-                if data.is_threaded {
-                    self.threaded_components[data.index].is_changed = true;
+                if data.is_threaded && !self.threaded_components_changed.contains(&data.index) {
+                    self.threaded_components_changed.push(data.index);
                 }
             } else {
                 break;
@@ -522,6 +588,9 @@ impl Board {
             if let Some(time_ns) = result.time_ns {
                 self.events.push(PingEvent(ComponentId(id), time_ns));
             }
+            if let Some(event) = result.event {
+                self.stop_event.get_or_insert((ComponentId(id), event));
+            }
 
             if result.changed {
                 self.vcd_writer.set_change(id);
@@ -539,11 +608,52 @@ impl Board {
         self.handle_messages(done_counter);
     }
 
+    /// Current simulation time in nanoseconds.
+    pub fn time_ns(&self) -> f64 {
+        self.time_ns
+    }
+
+    /// Gets current states of all wires.
+    pub fn wires(&self) -> Vec<WireInfo> {
+        self.wires.iter().map(|wire| WireInfo {
+            state: wire.read(),
+            pins: wire.pins.iter().map(|&index| {
+                let pin = &self.pins[index];
+                let name = match pin.component {
+                    Some(ComponentId(id)) => {
+                        let data = &self.common_component_data[id];
+                        format!("{}.{}", data.name, data.pin_names[pin.id as usize])
+                    }
+                    None => format!("pin{}", index),
+                };
+                (name, pin.out_state)
+            }).collect(),
+        }).collect()
+    }
+
+    /// Gets a clocked component by its id, if it has type `T`.
+    ///
+    /// Threaded components run in their own threads and can't be accessed.
+    pub fn component_mut<T: Component + 'static>(&mut self, id: ComponentId) -> Option<&mut T> {
+        let data = self.common_component_data.get(id.0)?;
+        if data.is_threaded {
+            return None;
+        }
+        self.threadless_components[data.index].component.as_any_mut().downcast_mut()
+    }
+
     /// Run the simulation for specified number of cycles.
-    pub fn simulate(&mut self, cycles: u64) {
+    /// 
+    /// Stops early if any component reports an event (for example, a CPU fault or a breakpoint),
+    /// after the clock edge it has happened at.
+    /// Calling it again continues the simulation from the same point in time.
+    pub fn simulate(&mut self, cycles: u64) -> StopReason {
         use indicatif::ProgressBar;
 
-        self.vcd_writer.write_header();
+        if !self.header_written {
+            self.vcd_writer.write_header();
+            self.header_written = true;
+        }
         let progress = if cycles < 1000 {
                 ProgressBar::hidden()
             } else {
@@ -552,15 +662,161 @@ impl Board {
         
         let mut global_output_changes = Vec::new();
 
-        let mut time_ns = 0.0;
         for i in 0..cycles*2 {
+            let time_ns = self.time_ns;
             self.toggle_clock(&mut global_output_changes, time_ns);
-            self.vcd_writer.write_step(time_ns + self.clock_period);
-            time_ns += self.clock_period;
+            self.vcd_writer.write_step(self.time_ns + self.clock_period);
+            self.time_ns += self.clock_period;
             if (i+1) % 2_000_000 == 0 {
                 progress.inc(1);
             }
+            if let Some((id, event)) = self.stop_event.take() {
+                progress.abandon();
+                return StopReason::Event(id, event, time_ns);
+            }
         }
         progress.finish();
+        StopReason::Finished
+    }
+}
+
+/// A helper macro for `board`
+#[macro_export]
+macro_rules! board_item_list {
+    (@config) => {
+        $crate::vcd::VcdConfig::Disable
+    };
+    (@config $config:expr) => {
+        $config
+    };
+    ($board:ident; ) => {};
+    ($board:ident; $name:ident : clocked $component:expr $(, $config:expr)? ; $($tail:tt)*) => {
+        let $name = $board.add_component_clocked($component, stringify!($name), &$crate::board_item_list!(@config $($config)?));
+        $crate::board_item_list!($board; $($tail)*);
+    };
+    ($board:ident; $name:ident : threaded $component:expr $(, $config:expr)? ; $($tail:tt)*) => {
+        let $name = $board.add_component_threaded($component, stringify!($name), &$crate::board_item_list!(@config $($config)?));
+        $crate::board_item_list!($board; $($tail)*);
+    };
+    ($board:ident; $component:ident . $pin:ident $(-- $components:ident . $pins:ident)+ ; $($tail:tt)*) => {
+        $board.add_wire(&[
+            $component.pin(stringify!($pin)),
+            $($components.pin(stringify!($pins))),+
+        ]);
+        $crate::board_item_list!($board; $($tail)*);
+    };
+}
+
+/// A declarative macro to add components and wires to a [Board].
+///
+/// Every component is declared as `name: clocked|threaded component[, vcd_config];`
+/// and bound to a [ComponentHandle] variable with the same name.
+/// A net connects pins, given by component and pin names, with `--`.
+/// The VCD config is disabled if it's omitted.
+///
+/// Panics with the component and pin names if a pin doesn't exist.
+///
+/// ```
+/// # use amber::{board, vcd_config, board::Board, components::{led::Led, uart::Uart}, vcd::VcdConfig};
+/// let mut board = Board::without_vcd(16e6);
+/// board! { board;
+///     uart: threaded Uart::<8>::new(9600.0, None), vcd_config!{rx_data};
+///     tx_led: threaded Led::new(), VcdConfig::Enable;
+///     rx_led: threaded Led::new();
+///
+///     uart.TX -- tx_led.LED;
+///     uart.RX -- rx_led.LED;
+/// }
+/// board.simulate(100);
+/// ```
+#[macro_export]
+macro_rules! board {
+    ($board:expr; $($tail:tt)*) => {
+        let board = &mut $board;
+        $crate::board_item_list!(board; $($tail)*);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{component::ComponentEvent, components::{avr::{mcu_model::Atmega328P, mcu_ticker::McuDefault}, led::Led, uart::Uart}, pins::PinState};
+
+    use super::*;
+
+    #[test]
+    fn board_macro() {
+        let mut board = Board::without_vcd(16e6);
+        board! { board;
+            mcu: clocked McuDefault::<Atmega328P>::new();
+            uart: threaded Uart::<8>::new(9600.0, None);
+            tx_led: threaded Led::new();
+
+            mcu.PD0 -- uart.TX -- tx_led.LED;
+            mcu.PD1 -- uart.RX;
+        }
+        assert_eq!(mcu.id(), ComponentId(0));
+        assert_eq!(uart.pin("RX"), (ComponentId(1), 1));
+        assert_eq!(tx_led.pin("LED"), (ComponentId(2), 0));
+
+        // The UART drives its TX line high, which reaches the other pins of the net
+        board.simulate(2);
+        let wires = board.wires();
+        assert_eq!(wires.len(), 2);
+        assert_eq!(wires[0].state, PinState::High);
+        let pins: Vec<_> = wires[0].pins.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(pins, ["mcu.PD0", "uart.TX", "tx_led.LED"]);
+        assert_eq!(wires[0].pins[1].1, PinState::High);
+        let pins: Vec<_> = wires[1].pins.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(pins, ["mcu.PD1", "uart.RX"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn breakpoints() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[
+            0xE005, // ldi r16, 5
+            0x9598, // break
+            0xE011, // ldi r17, 1
+            0xCFFF, // rjmp .-2
+        ]);
+        mcu.add_breakpoint(0);
+        mcu.add_breakpoint(2);
+        let mut board = Board::without_vcd(16e6);
+        board! { board;
+            _led: threaded Led::new();
+            mcu: clocked mcu;
+        }
+        let id = mcu.id();
+        assert_eq!(id, ComponentId(1));
+
+        // A breakpoint at the reset vector stops the board before the first instruction
+        assert_eq!(board.simulate(10), StopReason::Event(id, ComponentEvent::Breakpoint(0), 0.0));
+        assert_eq!(board.component_mut::<McuDefault<Atmega328P>>(id).unwrap().read_register(16), 0);
+
+        // BREAK halts the CPU and stops the board, the instruction after it is checked when the CPU is resumed
+        assert_eq!(board.simulate(10), StopReason::Event(id, ComponentEvent::Break(1), 125.0));
+        assert_eq!(board.simulate(10), StopReason::Finished);
+        let mcu = board.component_mut::<McuDefault<Atmega328P>>(id).unwrap();
+        assert_eq!((mcu.read_register(16), mcu.pc()), (5, 2));
+        mcu.resume();
+        assert_eq!(board.simulate(10), StopReason::Event(id, ComponentEvent::Breakpoint(2), 812.5));
+        let mcu = board.component_mut::<McuDefault<Atmega328P>>(id).unwrap();
+        assert_eq!(mcu.read_register(17), 0);
+
+        // Resuming executes the instruction at the breakpoint
+        assert_eq!(board.simulate(2), StopReason::Finished);
+        assert_eq!(board.component_mut::<McuDefault<Atmega328P>>(id).unwrap().read_register(17), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Component `uart` has no pin `RXX`")]
+    fn board_macro_unknown_pin() {
+        let mut board = Board::without_vcd(16e6);
+        board! { board;
+            uart: threaded Uart::<8>::new(9600.0, None);
+            rx_led: threaded Led::new();
+
+            uart.RXX -- rx_led.LED;
+        }
+    }
+}
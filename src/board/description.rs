@@ -0,0 +1,554 @@
+//! Plain-text board descriptions.
+//!
+//! A description has one statement per line, `#` starts a comment:
+//! ```none
+//! board freq=16e6 vcd=out.vcd
+//!
+//! component mcu atmega2560 clocked firmware=uart_test.hex vcd={pc regs}
+//! component uart uart threaded baud=9600 parity=even vcd=all
+//! component tx_led led
+//!
+//! wire mcu.PE1 uart.RX tx_led.LED
+//! ```
+//!
+//! - `board` sets the clock frequency `freq` in Hz (16 MHz by default) and the VCD output path `vcd`.
+//! - `component <name> <type> [clocked|threaded]` adds a component. MCUs are clocked by default, others are threaded.
+//! - `wire` connects two or more pins, given as `component.PIN`.
+//!
+//! Component types and their parameters:
+//! - `atmega2560`, `atmega328p`, `attiny85`: `firmware` (.hex, .elf, .srec or .bin file),
+//!   `semihost` (data address of semihosting registers, printing into the standard output).
+//! - `uart`: `baud` (9600), `parity` (`none`, `even` or `odd`), `bits` (5 to 9, 8 by default),
+//!   `console` (`stdio` connects it to the standard input and output).
+//! - `led`.
+//! - `sram`: `size` in bytes (32768).
+//!
+//! Every component also takes `vcd`: `all`, `none` (the default),
+//! or a list of signals in the same format as [vcd_config](crate::vcd_config), like `vcd={pc io:{timer0}}`.
+//! Relative paths are relative to the description file.
+
+use std::{collections::{HashMap, HashSet}, fmt, fs, io, path::{Path, PathBuf}, str::FromStr};
+
+use crate::{
+    component::Component,
+    components::{avr::{mcu_model::{self, McuModel}, mcu_ticker::{McuDefault, FirmwareError}, Semihosting}, led::Led, sram::Sram, uart::{self, Uart}},
+    pins::PinId,
+    vcd::VcdConfig,
+};
+
+use super::{Board, ComponentHandle};
+
+/// An error while loading a board description.
+#[derive(Debug)]
+pub enum DescriptionError {
+    Io(io::Error),
+    /// Malformed statement or parameter, with a line number.
+    Syntax(usize, String),
+    /// Unknown component type, with a line number.
+    UnknownComponent(usize, String),
+    /// Wire connecting a pin which doesn't exist, with a line number.
+    UnknownPin(usize, String),
+    /// Firmware of a named component couldn't be loaded.
+    Firmware(String, FirmwareError),
+}
+
+impl fmt::Display for DescriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptionError::Io(e) => write!(f, "{}", e),
+            DescriptionError::Syntax(line, msg) => write!(f, "line {}: {}", line, msg),
+            DescriptionError::UnknownComponent(line, name) => write!(f, "line {}: unknown component type `{}`", line, name),
+            DescriptionError::UnknownPin(line, msg) => write!(f, "line {}: {}", line, msg),
+            DescriptionError::Firmware(name, e) => write!(f, "couldn't load firmware of `{}`: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for DescriptionError {}
+
+impl From<io::Error> for DescriptionError {
+    fn from(e: io::Error) -> Self {
+        DescriptionError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Model {
+    Atmega2560,
+    Atmega328P,
+    Attiny85,
+}
+
+enum Kind {
+    Mcu {
+        model: Model,
+        firmware: Option<String>,
+        semihost: Option<u16>,
+    },
+    Uart {
+        bits: u8,
+        baud: f64,
+        parity: Option<bool>,
+        console: bool,
+    },
+    Led,
+    Sram {
+        size: usize,
+    },
+}
+
+impl Kind {
+    fn pin_names(&self) -> HashMap<String, PinId> {
+        match self {
+            Kind::Mcu { model: Model::Atmega2560, .. } => McuDefault::<mcu_model::Atmega2560>::get_pin_name_lookup(),
+            Kind::Mcu { model: Model::Atmega328P, .. } => McuDefault::<mcu_model::Atmega328P>::get_pin_name_lookup(),
+            Kind::Mcu { model: Model::Attiny85, .. } => McuDefault::<mcu_model::Attiny85>::get_pin_name_lookup(),
+            Kind::Uart { .. } => Uart::<8>::get_pin_name_lookup(),
+            Kind::Led => Led::get_pin_name_lookup(),
+            Kind::Sram { .. } => Sram::get_pin_name_lookup(),
+        }
+    }
+}
+
+struct ComponentDesc {
+    name: String,
+    kind: Kind,
+    clocked: bool,
+    vcd: VcdConfig,
+}
+
+/// A parsed board description, which can build any number of identical boards.
+pub struct Description {
+    freq: f64,
+    vcd: Option<String>,
+    /// Directory relative paths start from.
+    base_dir: PathBuf,
+    components: Vec<ComponentDesc>,
+    /// Wires as lists of component indices and pin names.
+    wires: Vec<Vec<(usize, String)>>,
+}
+
+/// Splits a line into words and braces.
+fn tokenize(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() || c == '{' || c == '}' {
+            if let Some(s) = start.take() {
+                tokens.push(&line[s..i]);
+            }
+            if !c.is_whitespace() {
+                tokens.push(&line[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(&line[s..]);
+    }
+    tokens
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal integer.
+fn parse_int<T: FromStr + TryFrom<u64>>(value: &str) -> Option<T> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().and_then(|x| T::try_from(x).ok()),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parses a braced signal list after the opening brace, up to the closing one.
+fn parse_vcd_list<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<VcdConfig, String> {
+    let mut hash_map = HashMap::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            "}" => return Ok(VcdConfig::new_module(hash_map)),
+            "{" => return Err("unexpected `{` in a VCD list".to_string()),
+            _ => {
+                let config = match token.strip_suffix(':') {
+                    Some(_) if tokens.next() != Some("{") => {
+                        return Err(format!("expected `{{` after `{}`", token));
+                    }
+                    Some(_) => parse_vcd_list(tokens)?,
+                    None => VcdConfig::Enable,
+                };
+                hash_map.insert(token.trim_end_matches(':').to_string(), config);
+            }
+        }
+    }
+    Err("unclosed `{` in a VCD list".to_string())
+}
+
+enum Value<'a> {
+    Word(&'a str),
+    List(VcdConfig),
+}
+
+/// `key=value` parameters of a statement.
+struct Params<'a>(Vec<(&'a str, Value<'a>)>);
+
+impl<'a> Params<'a> {
+    fn parse(tokens: &[&'a str]) -> Result<Params<'a>, String> {
+        let mut params: Vec<(&str, Value)> = Vec::new();
+        let mut tokens = tokens.iter().copied();
+        while let Some(token) = tokens.next() {
+            let Some((key, value)) = token.split_once('=') else {
+                return Err(format!("expected a `key=value` parameter, found `{}`", token));
+            };
+            if params.iter().any(|(k, _)| *k == key) {
+                return Err(format!("parameter `{}` is given twice", key));
+            }
+            let value = if value.is_empty() {
+                match tokens.next() {
+                    Some("{") => Value::List(parse_vcd_list(&mut tokens)?),
+                    _ => return Err(format!("missing value of `{}`", key)),
+                }
+            } else {
+                Value::Word(value)
+            };
+            params.push((key, value));
+        }
+        Ok(Params(params))
+    }
+
+    fn take(&mut self, key: &str) -> Option<Value<'a>> {
+        let i = self.0.iter().position(|(k, _)| *k == key)?;
+        Some(self.0.remove(i).1)
+    }
+
+    fn word(&mut self, key: &str) -> Result<Option<&'a str>, String> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Word(value)) => Ok(Some(value)),
+            Some(Value::List(_)) => Err(format!("`{}` can't be a list", key)),
+        }
+    }
+
+    fn number<T: FromStr>(&mut self, key: &str, default: T) -> Result<T, String> {
+        match self.word(key)? {
+            None => Ok(default),
+            Some(value) => value.parse().map_err(|_| format!("invalid `{}` value `{}`", key, value)),
+        }
+    }
+
+    fn int<T: FromStr + TryFrom<u64>>(&mut self, key: &str) -> Result<Option<T>, String> {
+        match self.word(key)? {
+            None => Ok(None),
+            Some(value) => parse_int(value).map(Some).ok_or_else(|| format!("invalid `{}` value `{}`", key, value)),
+        }
+    }
+
+    fn vcd(&mut self) -> Result<VcdConfig, String> {
+        match self.take("vcd") {
+            None | Some(Value::Word("none")) => Ok(VcdConfig::Disable),
+            Some(Value::Word("all")) => Ok(VcdConfig::Enable),
+            Some(Value::Word(value)) => Err(format!("invalid `vcd` value `{}`, expected `all`, `none` or a list", value)),
+            Some(Value::List(config)) => Ok(config),
+        }
+    }
+
+    /// Checks that all the parameters have been used.
+    fn finish(self, what: &str) -> Result<(), String> {
+        match self.0.first() {
+            Some((key, _)) => Err(format!("unknown parameter `{}` for {}", key, what)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Description {
+    /// Reads and parses a description file.
+    pub fn load(path: &str) -> Result<Description, DescriptionError> {
+        let text = fs::read_to_string(path)?;
+        let mut description = Description::parse(&text)?;
+        description.base_dir = Path::new(path).parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(description)
+    }
+
+    /// Parses a description, with paths relative to the current directory.
+    pub fn parse(text: &str) -> Result<Description, DescriptionError> {
+        let mut description = Description {
+            freq: 16e6,
+            vcd: None,
+            base_dir: PathBuf::new(),
+            components: Vec::new(),
+            wires: Vec::new(),
+        };
+        let mut board_line = None;
+        let mut pin_names = Vec::new();
+        let mut wired = HashSet::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let syntax = |msg: String| DescriptionError::Syntax(line_number, msg);
+            let line = line.split('#').next().unwrap_or_default();
+            let tokens = tokenize(line);
+            let Some((&statement, tokens)) = tokens.split_first() else {
+                continue;
+            };
+            match statement {
+                "board" => {
+                    if let Some(line) = board_line.replace(line_number) {
+                        return Err(syntax(format!("board is already described at line {}", line)));
+                    }
+                    let mut params = Params::parse(tokens).map_err(syntax)?;
+                    description.freq = params.number("freq", 16e6).map_err(syntax)?;
+                    if description.freq.is_nan() || description.freq <= 0.0 {
+                        return Err(syntax("`freq` must be positive".to_string()));
+                    }
+                    description.vcd = params.word("vcd").map_err(syntax)?.map(str::to_string);
+                    params.finish("board").map_err(syntax)?;
+                }
+                "component" => {
+                    let [name, kind, tokens @ ..] = tokens else {
+                        return Err(syntax("expected `component <name> <type>`".to_string()));
+                    };
+                    if name.contains('.') || name.contains('=') {
+                        return Err(syntax(format!("invalid component name `{}`", name)));
+                    }
+                    if description.components.iter().any(|c| c.name == *name) {
+                        return Err(syntax(format!("component `{}` is already declared", name)));
+                    }
+                    let (mode, tokens) = match tokens.split_first() {
+                        Some((&mode @ ("clocked" | "threaded"), tokens)) => (Some(mode == "clocked"), tokens),
+                        _ => (None, tokens),
+                    };
+                    let mut params = Params::parse(tokens).map_err(syntax)?;
+                    let component = Self::parse_component(name, kind, mode, &mut params)
+                        .map_err(syntax)?
+                        .ok_or_else(|| DescriptionError::UnknownComponent(line_number, kind.to_string()))?;
+                    params.finish(kind).map_err(syntax)?;
+                    pin_names.push(component.kind.pin_names());
+                    description.components.push(component);
+                }
+                "wire" => {
+                    if tokens.len() < 2 {
+                        return Err(syntax("a wire has to connect at least two pins".to_string()));
+                    }
+                    let mut wire = Vec::with_capacity(tokens.len());
+                    for &token in tokens {
+                        let Some((name, pin)) = token.split_once('.') else {
+                            return Err(syntax(format!("expected `component.PIN`, found `{}`", token)));
+                        };
+                        let Some(index) = description.components.iter().position(|c| c.name == name) else {
+                            return Err(DescriptionError::UnknownPin(line_number, format!("unknown component `{}` in `{}`", name, token)));
+                        };
+                        if !pin_names[index].contains_key(pin) {
+                            return Err(DescriptionError::UnknownPin(line_number, format!("component `{}` has no pin `{}`", name, pin)));
+                        }
+                        if !wired.insert((index, pin)) {
+                            return Err(syntax(format!("pin `{}` is already wired", token)));
+                        }
+                        wire.push((index, pin.to_string()));
+                    }
+                    description.wires.push(wire);
+                }
+                _ => return Err(syntax(format!("unknown statement `{}`", statement))),
+            }
+        }
+        Ok(description)
+    }
+
+    /// Parses component parameters, returns `Ok(None)` if the type is unknown.
+    fn parse_component(name: &str, kind: &str, mode: Option<bool>, params: &mut Params) -> Result<Option<ComponentDesc>, String> {
+        let model = match kind {
+            "atmega2560" => Some(Model::Atmega2560),
+            "atmega328p" => Some(Model::Atmega328P),
+            "attiny85" => Some(Model::Attiny85),
+            _ => None,
+        };
+        let (kind_desc, clocked) = if let Some(model) = model {
+            let firmware = params.word("firmware")?.map(str::to_string);
+            let semihost = params.int("semihost")?;
+            if mode == Some(false) {
+                return Err(format!("{} can only be clocked", kind));
+            }
+            (Kind::Mcu { model, firmware, semihost }, true)
+        } else {
+            let kind_desc = match kind {
+                "uart" => {
+                    let bits = params.int("bits")?.unwrap_or(8);
+                    if !(5..=9).contains(&bits) {
+                        return Err(format!("invalid `bits` value `{}`, expected 5 to 9", bits));
+                    }
+                    let baud: f64 = params.number("baud", 9600.0)?;
+                    if baud.is_nan() || baud <= 0.0 {
+                        return Err("`baud` must be positive".to_string());
+                    }
+                    let parity = match params.word("parity")? {
+                        None | Some("none") => None,
+                        Some("even") => Some(false),
+                        Some("odd") => Some(true),
+                        Some(value) => return Err(format!("invalid `parity` value `{}`, expected `none`, `even` or `odd`", value)),
+                    };
+                    let console = match params.word("console")? {
+                        None | Some("none") => false,
+                        Some("stdio") => true,
+                        Some(value) => return Err(format!("invalid `console` value `{}`, expected `stdio` or `none`", value)),
+                    };
+                    Kind::Uart { bits, baud, parity, console }
+                }
+                "led" => Kind::Led,
+                "sram" => {
+                    let size = params.int("size")?.unwrap_or(0x8000);
+                    if size == 0 || size > 0x10000 {
+                        return Err("`size` must be from 1 byte to 64 KiB".to_string());
+                    }
+                    Kind::Sram { size }
+                }
+                _ => return Ok(None),
+            };
+            if mode == Some(true) && !matches!(kind_desc, Kind::Sram { .. }) {
+                return Err(format!("{} can only be threaded", kind));
+            }
+            (kind_desc, mode.unwrap_or(false))
+        };
+        let vcd = params.vcd()?;
+        Ok(Some(ComponentDesc {
+            name: name.to_string(),
+            kind: kind_desc,
+            clocked,
+            vcd,
+        }))
+    }
+
+    /// Board clock frequency in Hz.
+    pub fn frequency(&self) -> f64 {
+        self.freq
+    }
+
+    fn path(&self, path: &str) -> String {
+        self.base_dir.join(path).to_string_lossy().into_owned()
+    }
+
+    /// Builds a board, returning it with handles of all the components by their names.
+    pub fn build(&self) -> Result<(Board, HashMap<String, ComponentHandle>), DescriptionError> {
+        let mut board = match &self.vcd {
+            Some(path) => Board::new(&self.path(path), self.freq),
+            None => Board::without_vcd(self.freq),
+        };
+        let mut handles = Vec::with_capacity(self.components.len());
+        for component in &self.components {
+            handles.push(self.add_component(&mut board, component)?);
+        }
+        for wire in &self.wires {
+            let pins: Vec<_> = wire.iter().map(|(index, pin)| handles[*index].pin(pin)).collect();
+            board.add_wire(&pins);
+        }
+        let handles = self.components.iter().map(|c| c.name.clone()).zip(handles).collect();
+        Ok((board, handles))
+    }
+
+    fn add_component(&self, board: &mut Board, c: &ComponentDesc) -> Result<ComponentHandle, DescriptionError> {
+        Ok(match &c.kind {
+            Kind::Mcu { model: Model::Atmega2560, .. } => self.add_mcu::<mcu_model::Atmega2560>(board, c)?,
+            Kind::Mcu { model: Model::Atmega328P, .. } => self.add_mcu::<mcu_model::Atmega328P>(board, c)?,
+            Kind::Mcu { model: Model::Attiny85, .. } => self.add_mcu::<mcu_model::Attiny85>(board, c)?,
+            &Kind::Uart { bits, baud, parity, console } => match bits {
+                5 => add(board, c, uart_component::<5>(baud, parity, console)),
+                6 => add(board, c, uart_component::<6>(baud, parity, console)),
+                7 => add(board, c, uart_component::<7>(baud, parity, console)),
+                8 => add(board, c, uart_component::<8>(baud, parity, console)),
+                _ => add(board, c, uart_component::<9>(baud, parity, console)),
+            },
+            Kind::Led => add(board, c, Led::new()),
+            &Kind::Sram { size } => add(board, c, Sram::new(size)),
+        })
+    }
+
+    fn add_mcu<M: McuModel + 'static>(&self, board: &mut Board, c: &ComponentDesc) -> Result<ComponentHandle, DescriptionError> {
+        let Kind::Mcu { firmware, semihost, .. } = &c.kind else {
+            unreachable!()
+        };
+        let mut mcu = McuDefault::<M>::new();
+        mcu.set_board_frequency(self.freq);
+        if let Some(firmware) = firmware {
+            mcu.load_firmware(&self.path(firmware))
+                .map_err(|e| DescriptionError::Firmware(c.name.clone(), e))?;
+        }
+        if let Some(addr) = *semihost {
+            mcu.enable_semihosting(Semihosting::stdout(addr));
+        }
+        Ok(add(board, c, mcu))
+    }
+}
+
+fn uart_component<const CHAR_SIZE: u8>(baud: f64, parity: Option<bool>, console: bool) -> Uart<CHAR_SIZE> {
+    let mut component = Uart::new(baud, parity);
+    if console {
+        component.set_output(Box::new(io::stdout()));
+        component.set_input(uart::stdin_channel());
+    }
+    component
+}
+
+fn add<T: Component + 'static>(board: &mut Board, c: &ComponentDesc, component: T) -> ComponentHandle {
+    if c.clocked {
+        board.add_component_clocked(component, &c.name, &c.vcd)
+    } else {
+        board.add_component_threaded(component, &c.name, &c.vcd)
+    }
+}
+
+impl Board {
+    /// Builds a board from a description file, see [description](crate::board::description) for the format.
+    ///
+    /// Returns the board with handles of all the components by their names.
+    pub fn from_description(path: &str) -> Result<(Board, HashMap<String, ComponentHandle>), DescriptionError> {
+        Description::load(path)?.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match Description::parse(text) {
+            Ok(_) => panic!("Description has to be invalid"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let description = Description::parse("\
+            # Comment
+            board freq=8e6
+
+            component mcu atmega328p semihost=0xF00 vcd={pc io:{timer0 }}
+            component uart uart baud=115200 parity=odd bits=7
+            component ram sram clocked size=0x800 vcd=all
+            wire mcu.PD1 uart.RX # TXD
+            wire mcu.PD0 uart.TX
+        ").unwrap();
+        assert_eq!(description.frequency(), 8e6);
+        assert_eq!(description.components.len(), 3);
+        assert!(matches!(description.components[0].kind, Kind::Mcu { model: Model::Atmega328P, firmware: None, semihost: Some(0xF00) }));
+        assert!(description.components[0].clocked);
+        assert!(matches!(description.components[0].vcd.get("io").get("timer0"), VcdConfig::Enable));
+        assert!(matches!(description.components[0].vcd.get("regs"), VcdConfig::Disable));
+        assert!(matches!(description.components[1].kind, Kind::Uart { bits: 7, parity: Some(true), console: false, .. }));
+        assert!(!description.components[1].clocked);
+        assert!(matches!(description.components[2].kind, Kind::Sram { size: 0x800 }));
+        assert!(description.components[2].clocked);
+        assert_eq!(description.wires, [
+            vec![(0, "PD1".to_string()), (1, "RX".to_string())],
+            vec![(0, "PD0".to_string()), (1, "TX".to_string())],
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("component x lamp"), "line 1: unknown component type `lamp`");
+        assert_eq!(error("component x led\nwire x.LED y.RX"), "line 2: unknown component `y` in `y.RX`");
+        assert_eq!(error("component x led\ncomponent y led\nwire x.LED y.PIN"), "line 3: component `y` has no pin `PIN`");
+        assert_eq!(error("component x led color=red"), "line 1: unknown parameter `color` for led");
+        assert_eq!(error("component x uart clocked"), "line 1: uart can only be threaded");
+        assert_eq!(error("component x led vcd={a b:{c}"), "line 1: unclosed `{` in a VCD list");
+        assert_eq!(error("component x led\ncomponent y led\ncomponent z led\nwire x.LED y.LED\nwire x.LED z.LED"),
+            "line 5: pin `x.LED` is already wired");
+        assert_eq!(error("connect a b"), "line 1: unknown statement `connect`");
+    }
+}
@@ -1,16 +1,54 @@
+use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
+use std::fmt;
+
 use crate::vcd::{VcdFiller, VcdTreeHandle, VcdTree};
 use crate::pins::{PinId, PinState};
+use crate::components::avr::{CpuFault, WatchKind};
 use kanal;
 
 /// A unique identifier for a component.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ComponentId(pub usize);
 
+/// An event reported by a component, which stops the simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComponentEvent {
+    /// CPU of the component has stopped because of a fault.
+    CpuFault(CpuFault),
+    /// Firmware has requested to end the simulation with an exit code.
+    Exit(i32),
+    /// CPU has reached a breakpoint at a word address, the instruction there hasn't been executed yet.
+    Breakpoint(u32),
+    /// An instruction has accessed a watched data address.
+    Watchpoint(u16, WatchKind),
+    /// CPU has been halted by a BREAK instruction at a word address.
+    Break(u32),
+}
+
+impl fmt::Display for ComponentEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComponentEvent::CpuFault(fault) => write!(f, "CPU fault: {}", fault),
+            ComponentEvent::Exit(code) => write!(f, "exit with code {}", code),
+            ComponentEvent::Breakpoint(pc) => write!(f, "breakpoint at 0x{:X}", pc << 1),
+            ComponentEvent::Break(pc) => write!(f, "BREAK at 0x{:X}", pc << 1),
+            ComponentEvent::Watchpoint(addr, kind) => {
+                let kind = match kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+                write!(f, "{} watchpoint at 0x{:04X}", kind, addr)
+            }
+        }
+    }
+}
+
 /// Messages used to communicate with components, working in other threads.
 /// 
 /// The main communication protocol is the following:
@@ -22,6 +60,7 @@ pub struct ComponentId(pub usize);
 ///     Board -> PinChange(component, pin_n, pin_state_n) -> Component
 ///     Board -> Step -> Component
 ///     [Component advances a step using all the new pin states]
+///     Component -> Event(component, event) [only if something stops the simulation]
 ///     Component -> Done(component)
 /// }
 /// Board -> Die -> Component
@@ -35,6 +74,8 @@ pub enum Message {
     ClockFalling,
     
     PingMeAt(ComponentId, f64),
+    /// Component to Board: sent before Done if the component has an event to report.
+    Event(ComponentId, ComponentEvent),
     /// Component to Board: sent after Step is done. Contains whether VCD has changed
     Done(ComponentId, bool),
     /// Board to Component: stop component thread.
@@ -79,6 +120,11 @@ pub trait Component: Send + VcdFiller {
     /// After this step all the pin value changes must be accounted for.
     fn advance(&mut self, time_ns: f64) -> Option<f64>;
 
+    /// Take an event which should stop the simulation, if there is one.
+    fn take_event(&mut self) -> Option<ComponentEvent> {
+        None
+    }
+
     /// Execute a single step and output all changes
     /// 
     /// Returns whether VCD have changed
@@ -112,7 +158,7 @@ pub trait Component: Send + VcdFiller {
             match m {
                 Message::Die => break,
                 Message::PinChange(_, pin, state) => self.set_pin(pin, state),
-                Message::Done(_, _) | Message::PingMeAt(_, _) => {},
+                Message::Done(_, _) | Message::PingMeAt(_, _) | Message::Event(_, _) => {},
                 Message::Step(_) | Message::ClockRising | Message::ClockFalling => {
                     let ping = match m {
                         Message::Step(x) => self.advance(x),
@@ -129,6 +175,10 @@ pub trait Component: Send + VcdFiller {
                         output_tx.send(Message::PingMeAt(id, time))
                                  .expect("Cannot send update");
                     }
+                    if let Some(event) = self.take_event() {
+                        output_tx.send(Message::Event(id, event))
+                                 .expect("Cannot send update");
+                    }
                     output_tx.send(Message::Done(id, changed))
                              .expect("Cannot send update");
                 },
@@ -140,48 +190,61 @@ pub trait Component: Send + VcdFiller {
 pub struct ExecuteStepResult<'a> {
     pub changed: bool,
     pub output_changes: &'a [(PinId, PinState)],
-    pub time_ns: Option<f64>
+    pub time_ns: Option<f64>,
+    pub event: Option<ComponentEvent>,
 }
 
 pub trait ThreadlessComponent {
     fn set_pin(&mut self, pin: PinId, state: PinState);
+    /// Gets the component for downcasting to its concrete type.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
     fn execute_step_threadless(&mut self, vcd: &Rc<RefCell<VcdTreeHandle>>, time_ns: f64) -> ExecuteStepResult;
     fn clock_rising_edge_threadless(&mut self, vcd: &Rc<RefCell<VcdTreeHandle>>) -> ExecuteStepResult;
     fn clock_falling_edge_threadless(&mut self, vcd: &Rc<RefCell<VcdTreeHandle>>) -> ExecuteStepResult;
 }
 
-impl<T: Component> ThreadlessComponent for T {
+impl<T: Component + 'static> ThreadlessComponent for T {
     fn set_pin(&mut self, pin: PinId, state: PinState) {
         self.set_pin(pin, state);
     }
 
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
     fn execute_step_threadless(&mut self, vcd: &Rc<RefCell<VcdTreeHandle>>, time_ns: f64) -> ExecuteStepResult {
         let ping = self.advance(time_ns);
+        let event = self.take_event();
         let (changed, output_changes) = self.fill_everything_threadless(vcd);
         ExecuteStepResult {
             changed,
             output_changes,
             time_ns: ping,
+            event,
         }
     }
 
     fn clock_rising_edge_threadless(&mut self, vcd: &Rc<RefCell<VcdTreeHandle>>) -> ExecuteStepResult {
         self.clock_rising_edge();
+        let event = self.take_event();
         let (changed, output_changes) = self.fill_everything_threadless(vcd);
         ExecuteStepResult {
             changed,
             output_changes,
             time_ns: None,
+            event,
         }
     }
 
     fn clock_falling_edge_threadless(&mut self, vcd: &Rc<RefCell<VcdTreeHandle>>) -> ExecuteStepResult {
         self.clock_falling_edge();
+        let event = self.take_event();
         let (changed, output_changes) = self.fill_everything_threadless(vcd);
         ExecuteStepResult {
             changed,
             output_changes,
             time_ns: None,
+            event,
         }
     }
 }
\ No newline at end of file
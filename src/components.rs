@@ -1,3 +1,31 @@
 pub mod avr;
 pub mod led;
-pub mod uart;
\ No newline at end of file
+pub mod sram;
+pub mod uart;
+
+#[cfg(test)]
+pub mod test_helper {
+    use std::{io::{self, Write}, sync::{Arc, Mutex}};
+
+    /// Writer capturing everything written through any of its clones for checks.
+    #[derive(Clone, Default)]
+    pub struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl CaptureWriter {
+        /// Returns a copy of everything written so far.
+        pub fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for CaptureWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
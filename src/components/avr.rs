@@ -8,6 +8,21 @@ mod mcu;
 mod io_controller;
 pub mod mcu_model;
 mod sreg;
+mod spm_controller;
+mod fault;
+mod fuses;
 mod bit_helpers;
+mod instruction;
+pub mod disasm;
+pub mod atdf;
+pub mod elf;
+pub mod dwarf;
+pub mod gdb;
+pub mod debugger;
+pub mod snapshot;
 
-pub type Atmega2560 = McuDefault<mcu_model::Atmega2560>;
\ No newline at end of file
+pub use self::{mcu::{CpuState, trace::{Tracer, TraceFilter}, semihost::{self, Semihosting}, coverage::{Coverage, CoverageError}, debug::{Watchpoint, WatchKind}, hex::{HexError, HexImage, parse_intel_hex, parse_srec}}, snapshot::SnapshotError, io_controller::{SleepMode, InterruptSource, ResetSource}, fault::{CpuFault, CpuFaultKind}, fuses::Fuses, mcu_ticker::FirmwareError};
+
+pub type Atmega2560 = McuDefault<mcu_model::Atmega2560>;
+pub type Atmega328P = McuDefault<mcu_model::Atmega328P>;
+pub type Attiny85 = McuDefault<mcu_model::Attiny85>;
\ No newline at end of file
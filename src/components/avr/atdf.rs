@@ -0,0 +1,879 @@
+//! Import of Microchip ATDF device description files.
+//!
+//! ATDF is the XML format of Microchip device packs. Only memory segments, IO registers,
+//! interrupt vectors and pin signals are read, everything else is ignored.
+
+use std::{fmt::{self, Write}, fs, io, path::Path};
+
+use super::{io_controller::InterruptSource, mcu_model::InstructionSet};
+
+/// An error while loading an ATDF file.
+#[derive(Debug)]
+pub enum AtdfError {
+    Io(io::Error),
+    /// Malformed XML, with a line number.
+    Xml(usize, &'static str),
+    /// Well-formed XML which is not a supported device description.
+    Format(String),
+}
+
+impl fmt::Display for AtdfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AtdfError::Io(e) => write!(f, "{}", e),
+            AtdfError::Xml(line, msg) => write!(f, "invalid XML at line {}: {}", line, msg),
+            AtdfError::Format(msg) => write!(f, "invalid ATDF file: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AtdfError {}
+
+impl From<io::Error> for AtdfError {
+    fn from(e: io::Error) -> Self {
+        AtdfError::Io(e)
+    }
+}
+
+/// An XML element, text content is ignored.
+#[derive(Debug, Clone, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, AtdfError> {
+        self.attr(name).ok_or_else(|| AtdfError::Format(format!("<{}> without {} attribute", self.name, name)))
+    }
+
+    /// Reads a decimal or `0x` prefixed hexadecimal attribute.
+    fn number(&self, name: &str) -> Result<u32, AtdfError> {
+        let s = self.required(name)?;
+        let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        parsed.map_err(|_| AtdfError::Format(format!("invalid number {:?} in <{}>", s, self.name)))
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |e| e.name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|e| e.name == name)
+    }
+}
+
+/// A minimal XML parser, enough for machine generated files like ATDF.
+struct XmlParser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> XmlParser<'a> {
+    fn error(&self, msg: &'static str) -> AtdfError {
+        AtdfError::Xml(self.src[..self.pos].matches('\n').count() + 1, msg)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str, msg: &'static str) -> Result<(), AtdfError> {
+        if self.eat(s) {Ok(())} else {Err(self.error(msg))}
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), AtdfError> {
+        match self.rest().find(end) {
+            Some(i) => {
+                self.pos += i + end.len();
+                Ok(())
+            }
+            None => Err(self.error("unterminated markup")),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, AtdfError> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || "-_:.".contains(c))).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.pos += len;
+        Ok(rest[..len].to_string())
+    }
+
+    fn attr_value(&mut self) -> Result<String, AtdfError> {
+        let quote = match self.rest().chars().next() {
+            Some(c @ ('"' | '\'')) => c,
+            _ => return Err(self.error("expected a quoted value")),
+        };
+        self.pos += 1;
+        let len = self.rest().find(quote).ok_or_else(|| self.error("unterminated value"))?;
+        let value = unescape(&self.rest()[..len]).ok_or_else(|| self.error("invalid entity"))?;
+        self.pos += len + 1;
+        Ok(value)
+    }
+
+    /// Parses a whole document and returns its root element.
+    fn parse(mut self) -> Result<Element, AtdfError> {
+        // The bottom element collects the root
+        let mut stack = vec![Element::default()];
+        while let Some(i) = self.rest().find('<') {
+            self.pos += i;
+            if self.eat("<?") {
+                self.skip_past("?>")?;
+            } else if self.eat("<!--") {
+                self.skip_past("-->")?;
+            } else if self.eat("<![CDATA[") {
+                self.skip_past("]]>")?;
+            } else if self.eat("<!") {
+                self.skip_past(">")?;
+            } else if self.eat("</") {
+                let name = self.name()?;
+                self.skip_whitespace();
+                self.expect(">", "expected '>'")?;
+                if stack.len() == 1 {
+                    return Err(self.error("unexpected closing tag"));
+                }
+                let element = stack.pop().unwrap();
+                if element.name != name {
+                    return Err(self.error("mismatched closing tag"));
+                }
+                stack.last_mut().unwrap().children.push(element);
+            } else {
+                self.pos += 1;
+                let mut element = Element {name: self.name()?, ..Element::default()};
+                loop {
+                    self.skip_whitespace();
+                    if self.eat("/>") {
+                        stack.last_mut().unwrap().children.push(element);
+                        break;
+                    }
+                    if self.eat(">") {
+                        stack.push(element);
+                        break;
+                    }
+                    let name = self.name()?;
+                    self.skip_whitespace();
+                    self.expect("=", "expected '='")?;
+                    self.skip_whitespace();
+                    element.attrs.push((name, self.attr_value()?));
+                }
+            }
+        }
+        if stack.len() != 1 {
+            return Err(self.error("unclosed element"));
+        }
+        let mut document = stack.pop().unwrap();
+        if document.children.len() != 1 {
+            return Err(self.error("expected a single root element"));
+        }
+        Ok(document.children.pop().unwrap())
+    }
+}
+
+/// Replaces XML entities and character references.
+fn unescape(s: &str) -> Option<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        result.push_str(&rest[..i]);
+        let end = rest[i..].find(';')? + i;
+        let entity = &rest[i + 1..end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => entity.strip_prefix('#')?.parse().ok()?,
+                };
+                char::from_u32(code)?
+            }
+        };
+        result.push(c);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Some(result)
+}
+
+/// A memory segment of an address space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemorySegment {
+    pub name: String,
+    /// Address space id, like `prog`, `data`, `eeprom` or `fuses`.
+    pub address_space: String,
+    /// Segment type, like `flash`, `regs`, `io`, `ram` or `eeprom`.
+    pub kind: String,
+    /// Start address in bytes.
+    pub start: u32,
+    /// Size in bytes.
+    pub size: u32,
+    /// Page size in bytes, for flash and EEPROM.
+    pub page_size: Option<u32>,
+}
+
+/// A named group of bits of a register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    pub name: String,
+    pub caption: String,
+    pub mask: u32,
+}
+
+/// An IO register in the data address space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub name: String,
+    pub caption: String,
+    /// Peripheral instance of the register, like `TC1`.
+    pub instance: String,
+    /// Data address of the first byte.
+    pub addr: u16,
+    /// Size in bytes.
+    pub size: u8,
+    pub bitfields: Vec<Bitfield>,
+}
+
+/// An interrupt vector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interrupt {
+    pub index: u8,
+    pub name: String,
+    pub caption: String,
+}
+
+/// A peripheral signal routed to a pin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinSignal {
+    /// Pin name, like `PB5`.
+    pub pad: String,
+    /// Peripheral instance, like `TC1`.
+    pub instance: String,
+    /// Signal group, like `OC1A`, or `P` for GPIO.
+    pub group: String,
+    pub index: Option<String>,
+}
+
+/// Description of an AVR part, imported from an ATDF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescription {
+    pub name: String,
+    /// Core architecture, like `AVR8`.
+    pub architecture: String,
+    /// Device family, like `megaAVR` or `tinyAVR`.
+    pub family: String,
+    pub segments: Vec<MemorySegment>,
+    /// IO registers, sorted by address.
+    pub registers: Vec<Register>,
+    /// Interrupt vectors, sorted by index.
+    pub interrupts: Vec<Interrupt>,
+    pub signals: Vec<PinSignal>,
+}
+
+/// Parses an ATDF file contents.
+pub fn parse_atdf(src: &str) -> Result<DeviceDescription, AtdfError> {
+    let root = XmlParser {src, pos: 0}.parse()?;
+    if root.name != "avr-tools-device-file" {
+        return Err(AtdfError::Format("not a device file".to_string()));
+    }
+    let device = root.child("devices")
+        .and_then(|devices| devices.child("device"))
+        .ok_or_else(|| AtdfError::Format("no device".to_string()))?;
+
+    let mut segments = Vec::new();
+    for space in device.child("address-spaces").iter().flat_map(|e| e.children("address-space")) {
+        let address_space = space.required("id")?;
+        for segment in space.children("memory-segment") {
+            segments.push(MemorySegment {
+                name: segment.required("name")?.to_string(),
+                address_space: address_space.to_string(),
+                kind: segment.required("type")?.to_string(),
+                start: segment.number("start")?,
+                size: segment.number("size")?,
+                page_size: segment.attr("pagesize").map(|_| segment.number("pagesize")).transpose()?,
+            });
+        }
+    }
+
+    let modules: Vec<&Element> = root.child("modules").iter().flat_map(|e| e.children("module")).collect();
+    let mut registers = Vec::new();
+    let mut signals = Vec::new();
+    for module in device.child("peripherals").iter().flat_map(|e| e.children("module")) {
+        let module_name = module.required("name")?;
+        let definition = modules.iter().find(|m| m.attr("name") == Some(module_name));
+        for instance in module.children("instance") {
+            let instance_name = instance.required("name")?;
+            for group_ref in instance.children("register-group") {
+                if group_ref.attr("address-space").is_some_and(|space| space != "data") {
+                    continue;
+                }
+                let group_name = group_ref.attr("name-in-module").map_or_else(|| group_ref.required("name"), Ok)?;
+                let offset = group_ref.number("offset")?;
+                let group = definition
+                    .and_then(|m| m.children("register-group").find(|g| g.attr("name") == Some(group_name)))
+                    .ok_or_else(|| AtdfError::Format(format!("register group {} is not defined", group_name)))?;
+                for register in group.children("register") {
+                    let bitfields = register.children("bitfield")
+                        .map(|bitfield| Ok(Bitfield {
+                            name: bitfield.required("name")?.to_string(),
+                            caption: bitfield.attr("caption").unwrap_or_default().to_string(),
+                            mask: bitfield.number("mask")?,
+                        }))
+                        .collect::<Result<_, AtdfError>>()?;
+                    let name = register.required("name")?;
+                    let addr = offset.checked_add(register.number("offset")?)
+                        .ok_or_else(|| AtdfError::Format(format!("register {} address is too big", name)))?;
+                    let size = register.attr("size").map_or(Ok(1), |_| register.number("size"))?;
+                    // The whole register has to fit into the data address space
+                    if size == 0 || size > 8 || addr > 0x10000 - size {
+                        return Err(AtdfError::Format(format!("register {} at 0x{:X} has invalid size {}", name, addr, size)));
+                    }
+                    registers.push(Register {
+                        name: name.to_string(),
+                        caption: register.attr("caption").unwrap_or_default().to_string(),
+                        instance: instance_name.to_string(),
+                        addr: addr as u16,
+                        size: size as u8,
+                        bitfields,
+                    });
+                }
+            }
+            for signal in instance.child("signals").iter().flat_map(|e| e.children("signal")) {
+                signals.push(PinSignal {
+                    pad: signal.required("pad")?.to_string(),
+                    instance: instance_name.to_string(),
+                    group: signal.required("group")?.to_string(),
+                    index: signal.attr("index").map(str::to_string),
+                });
+            }
+        }
+    }
+    registers.sort_by_key(|r| r.addr);
+
+    let mut interrupts = device.child("interrupts").iter().flat_map(|e| e.children("interrupt"))
+        .map(|interrupt| Ok(Interrupt {
+            index: u8::try_from(interrupt.number("index")?).map_err(|_| AtdfError::Format("interrupt index is too big".to_string()))?,
+            name: interrupt.required("name")?.to_string(),
+            caption: interrupt.attr("caption").unwrap_or_default().to_string(),
+        }))
+        .collect::<Result<Vec<_>, AtdfError>>()?;
+    interrupts.sort_by_key(|i| i.index);
+
+    if !segments.iter().any(|s| s.kind == "flash" && s.size >= 2) {
+        return Err(AtdfError::Format("no flash memory segment".to_string()));
+    }
+
+    Ok(DeviceDescription {
+        name: device.required("name")?.to_string(),
+        architecture: device.attr("architecture").unwrap_or_default().to_string(),
+        family: device.attr("family").unwrap_or_default().to_string(),
+        segments,
+        registers,
+        interrupts,
+        signals,
+    })
+}
+
+/// Loads an ATDF file.
+pub fn load_atdf(path: impl AsRef<Path>) -> Result<DeviceDescription, AtdfError> {
+    parse_atdf(&fs::read_to_string(path)?)
+}
+
+/// Interrupt source of a vector name, if it is modelled by Amber.
+fn interrupt_source(name: &str) -> Option<InterruptSource> {
+    match name {
+        "WDT" => return Some(InterruptSource::Watchdog),
+        "SPM_READY" | "SPM_RDY" => return Some(InterruptSource::SpmReady),
+        "USI_START" | "USI_STR" => return Some(InterruptSource::UsiStart),
+        "USI_OVF" | "USI_OVERFLOW" => return Some(InterruptSource::UsiOverflow),
+        _ => {}
+    }
+    if let Some(usart) = name.strip_prefix("USART") {
+        // USART_RX on parts with a single USART, USART0_RX otherwise
+        let (index, event) = usart.split_once('_')?;
+        let index = if index.is_empty() {0} else {index.parse().ok()?};
+        return match event {
+            "RX" => Some(InterruptSource::UsartRx(index)),
+            "UDRE" => Some(InterruptSource::UsartUdre(index)),
+            "TX" => Some(InterruptSource::UsartTx(index)),
+            _ => None,
+        };
+    }
+    let (timer, event) = name.strip_prefix("TIMER")?.split_once('_')?;
+    let timer = timer.parse().ok()?;
+    match event {
+        "CAPT" => Some(InterruptSource::TimerCapture(timer)),
+        "OVF" => Some(InterruptSource::TimerOverflow(timer)),
+        "COMPA" => Some(InterruptSource::TimerCompare(timer, 0)),
+        "COMPB" => Some(InterruptSource::TimerCompare(timer, 1)),
+        "COMPC" => Some(InterruptSource::TimerCompare(timer, 2)),
+        _ => None,
+    }
+}
+
+impl DeviceDescription {
+    /// Finds the first memory segment of a type.
+    pub fn segment(&self, kind: &str) -> Option<&MemorySegment> {
+        self.segments.iter().find(|s| s.kind == kind)
+    }
+
+    /// Flash size in words.
+    pub fn flash_size(&self) -> usize {
+        self.segment("flash").map_or(0, |s| s.size as usize / 2)
+    }
+
+    /// Flash page size in words.
+    pub fn flash_page_size(&self) -> usize {
+        self.segment("flash").and_then(|s| s.page_size).map_or(0, |size| size as usize / 2)
+    }
+
+    /// EEPROM size in bytes.
+    pub fn eeprom_size(&self) -> usize {
+        self.segment("eeprom").map_or(0, |s| s.size as usize)
+    }
+
+    /// Data address of the first SRAM byte.
+    pub fn sram_start(&self) -> u16 {
+        self.segment("ram").map_or(0, |s| s.start as u16)
+    }
+
+    /// SRAM size in bytes.
+    pub fn sram_size(&self) -> usize {
+        self.segment("ram").map_or(0, |s| s.size as usize)
+    }
+
+    /// Size of PC in bytes, 3 for parts with more than 128 KiB of flash.
+    pub fn pc_bytes(&self) -> u8 {
+        if self.flash_size() > 0x10000 {3} else {2}
+    }
+
+    /// Size of a single interrupt vector in words, parts with more than 8 KiB of flash use JMP.
+    pub fn vector_size(&self) -> u32 {
+        if self.flash_size() > 0x1000 {2} else {1}
+    }
+
+    /// Instruction set of the core, guessed from the family, flash size and RAMPZ presence.
+    pub fn instruction_set(&self) -> InstructionSet {
+        if self.pc_bytes() == 3 {
+            InstructionSet::Avr6
+        } else if self.register("RAMPZ").is_some() {
+            InstructionSet::Avr51
+        } else if self.family == "tinyAVR" {
+            InstructionSet::Avr25
+        } else {
+            InstructionSet::Avr5
+        }
+    }
+
+    /// Finds a register by name.
+    pub fn register(&self, name: &str) -> Option<&Register> {
+        self.registers.iter().find(|r| r.name == name)
+    }
+
+    /// Finds a register containing a data address.
+    pub fn register_at(&self, addr: u16) -> Option<&Register> {
+        self.registers.iter().find(|r| (r.addr as u32..r.addr as u32 + r.size as u32).contains(&(addr as u32)))
+    }
+
+    /// Name of a register byte at a data address, like `OCR1AL` for a byte of a 16-bit register.
+    pub fn register_name(&self, addr: u16) -> Option<String> {
+        let register = self.register_at(addr)?;
+        let offset = addr - register.addr;
+        Some(match register.size {
+            1 => register.name.clone(),
+            2 => format!("{}{}", register.name, if offset == 0 {'L'} else {'H'}),
+            _ => format!("{}{}", register.name, offset),
+        })
+    }
+
+    /// Name of a register at an IO address (0x00 to 0x3F).
+    pub fn io_register_name(&self, addr: u8) -> Option<String> {
+        if addr >= 0x40 {
+            return None;
+        }
+        self.register_name(addr as u16 + 0x20)
+    }
+
+    /// Register names in the extended IO space (data addresses from 0x60 to SRAM), sorted by address.
+    pub fn extended_io_registers(&self) -> Vec<(u16, String)> {
+        (0x60..self.sram_start())
+            .filter_map(|addr| self.register_name(addr).map(|name| (addr, name)))
+            .collect()
+    }
+
+    /// Vector number of an interrupt by name, like `TIMER1_OVF`.
+    pub fn vector(&self, name: &str) -> Option<u8> {
+        self.interrupts.iter().find(|i| i.name == name).map(|i| i.index)
+    }
+
+    /// Vector number of an interrupt source, see [McuModel::interrupt_vector](super::mcu_model::McuModel::interrupt_vector).
+    pub fn interrupt_vector(&self, source: InterruptSource) -> Option<u8> {
+        self.interrupts.iter()
+            .find(|i| interrupt_source(&i.name) == Some(source))
+            .map(|i| i.index)
+    }
+
+    /// Signals routed to a pin.
+    pub fn pin_signals<'a>(&'a self, pad: &'a str) -> impl Iterator<Item = &'a PinSignal> {
+        self.signals.iter().filter(move |s| s.pad == pad)
+    }
+
+    /// Pin of a peripheral signal, like `PB5` for `OC1A` of `TC1`.
+    pub fn signal_pin(&self, instance: &str, group: &str) -> Option<&str> {
+        self.signals.iter()
+            .find(|s| s.instance == instance && s.group == group)
+            .map(|s| s.pad.as_str())
+    }
+
+    /// GPIO ports as a letter, PINx data address and the number of pins.
+    pub fn gpio_ports(&self) -> Vec<(char, u16, u8)> {
+        let mut ports: Vec<(char, u16, u8)> = Vec::new();
+        for signal in self.signals.iter().filter(|s| s.group == "P") {
+            let Some(name) = signal.pad.strip_prefix('P').and_then(|s| s.chars().next()) else {
+                continue;
+            };
+            match ports.iter_mut().find(|p| p.0 == name) {
+                Some(port) => port.2 += 1,
+                None => {
+                    if let Some(pin) = self.register(&format!("PIN{}", name)) {
+                        ports.push((name, pin.addr, 1));
+                    }
+                }
+            }
+        }
+        ports
+    }
+
+    /// Generates Rust source of an [McuModel](super::mcu_model::McuModel) implementation,
+    /// to be placed in `mcu_model.rs`.
+    ///
+    /// Only GPIO ports, the watchdog and the sleep controller are described, other peripherals
+    /// have to be added to the generated [Peripherals](super::mcu_model::Peripherals) by hand.
+    /// The generated [FuseDesc](super::mcu_model::FuseDesc) assumes an ATmega fuse layout without a boot section.
+    pub fn to_rust(&self, type_name: &str) -> String {
+        let upper = type_name.to_uppercase();
+        let mut s = String::new();
+        writeln!(s, "pub struct {};", type_name).unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "static {}_PERIPHERALS: Peripherals = Peripherals {{", upper).unwrap();
+        writeln!(s, "    gpio: &[").unwrap();
+        for (name, pin_addr, pin_count) in self.gpio_ports() {
+            writeln!(s, "        GpioPortDesc {{name: '{}', pin_addr: 0x{:02X}, pin_count: {}}},", name, pin_addr, pin_count).unwrap();
+        }
+        writeln!(s, "    ],").unwrap();
+        writeln!(s, "    timers8: &[],").unwrap();
+        writeln!(s, "    timers16: &[],").unwrap();
+        writeln!(s, "    pll_timer: None,").unwrap();
+        writeln!(s, "    usarts: &[],").unwrap();
+        writeln!(s, "    usi: None,").unwrap();
+        writeln!(s, "    xmem: None,").unwrap();
+        let wdtcsr = self.register("WDTCSR").or_else(|| self.register("WDTCR")).map_or(0, |r| r.addr);
+        writeln!(s, "    wdtcsr: 0x{:02X},", wdtcsr).unwrap();
+        let clkpr = self.register("CLKPR").map_or(0x61, |r| r.addr);
+        writeln!(s, "    clkpr: 0x{:02X},", clkpr).unwrap();
+        let mcusr = self.register("MCUSR");
+        writeln!(s, "    mcusr: 0x{:02X},", mcusr.map_or(0x54, |r| r.addr)).unwrap();
+        let reset_flags = mcusr.map_or(0x0F, |r| r.bitfields.iter().fold(0, |flags, b| flags | b.mask) & 0x1F);
+        writeln!(s, "    reset_flags: 0x{:02X},", reset_flags).unwrap();
+        self.write_sleep_desc(&mut s);
+        writeln!(s, "}};").unwrap();
+        writeln!(s).unwrap();
+
+        writeln!(s, "static {}_FUSES: FuseDesc = FuseDesc {{", upper).unwrap();
+        writeln!(s, "    defaults: [0xFF, 0xD9, 0xFF],").unwrap();
+        writeln!(s, "    clock_sources: ATMEGA_CLOCK_SOURCES,").unwrap();
+        writeln!(s, "    boot_sizes: None,").unwrap();
+        writeln!(s, "    bodlevel_byte: 2,").unwrap();
+        writeln!(s, "    self_programming: None,").unwrap();
+        writeln!(s, "}};").unwrap();
+        writeln!(s).unwrap();
+
+        writeln!(s, "/// {} IO register names, empty for reserved addresses.", self.name).unwrap();
+        writeln!(s, "const {}_IO_REGISTERS: [&str; 64] = [", upper).unwrap();
+        for row in 0..8 {
+            let names: Vec<String> = (0..8)
+                .map(|i| format!("{:?}", self.io_register_name(row * 8 + i).unwrap_or_default()))
+                .collect();
+            writeln!(s, "    {},", names.join(", ")).unwrap();
+        }
+        writeln!(s, "];").unwrap();
+        writeln!(s).unwrap();
+        let extended_io = self.extended_io_registers();
+        if !extended_io.is_empty() {
+            writeln!(s, "/// {} extended IO register names, sorted by data address.", self.name).unwrap();
+            writeln!(s, "const {}_EXT_IO_REGISTERS: &[(u16, &str)] = &[", upper).unwrap();
+            for row in extended_io.chunks(4) {
+                let names: Vec<String> = row.iter()
+                    .map(|(addr, name)| format!("(0x{:02X}, {:?}),", addr, name))
+                    .collect();
+                writeln!(s, "    {}", names.join(" ")).unwrap();
+            }
+            writeln!(s, "];").unwrap();
+            writeln!(s).unwrap();
+        }
+
+        writeln!(s, "impl McuModel for {} {{", type_name).unwrap();
+        let rampz_mask = if self.register("RAMPZ").is_some() {((self.flash_size() * 2).saturating_sub(1) >> 16).min(0xFF)} else {0};
+        let eind_mask = if self.register("EIND").is_some() {(self.flash_size().saturating_sub(1) >> 16).min(0xFF)} else {0};
+        let nrww_start = self.segments.iter()
+            .find(|s| s.kind == "flash" && s.name.contains("NRWW"))
+            .map_or(0, |s| s.start / 2);
+        let functions = [
+            ("flash_size", "usize", self.flash_size().to_string()),
+            ("rampz_mask", "u8", format!("0x{:02X}", rampz_mask)),
+            ("eind_mask", "u8", format!("0x{:02X}", eind_mask)),
+            ("flash_page_size", "usize", self.flash_page_size().to_string()),
+            ("nrww_start", "u32", format!("0x{:X}", nrww_start)),
+            ("eeprom_size", "usize", self.eeprom_size().to_string()),
+            ("sram_start", "u16", format!("0x{:X}", self.sram_start())),
+            ("sram_size", "usize", self.sram_size().to_string()),
+            ("pc_bytes", "u8", self.pc_bytes().to_string()),
+            ("instruction_set", "InstructionSet", format!("InstructionSet::{:?}", self.instruction_set())),
+            ("peripherals", "&'static Peripherals", format!("&{}_PERIPHERALS", upper)),
+            ("fuse_layout", "&'static FuseDesc", format!("&{}_FUSES", upper)),
+        ];
+        for (name, ty, value) in functions {
+            writeln!(s, "    fn {}() -> {} {{", name, ty).unwrap();
+            writeln!(s, "        {}", value).unwrap();
+            writeln!(s, "    }}").unwrap();
+            writeln!(s).unwrap();
+        }
+        writeln!(s, "    fn interrupt_vector(source: InterruptSource) -> Option<u8> {{").unwrap();
+        writeln!(s, "        match source {{").unwrap();
+        for interrupt in self.interrupts.iter() {
+            if let Some(source) = interrupt_source(&interrupt.name) {
+                writeln!(s, "            InterruptSource::{:?} => Some({}),", source, interrupt.index).unwrap();
+            }
+        }
+        writeln!(s, "            _ => None,").unwrap();
+        writeln!(s, "        }}").unwrap();
+        writeln!(s, "    }}").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "    fn vector_size() -> u32 {{").unwrap();
+        writeln!(s, "        {}", self.vector_size()).unwrap();
+        writeln!(s, "    }}").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "    fn io_register_name(addr: u8) -> Option<&'static str> {{").unwrap();
+        writeln!(s, "        {}_IO_REGISTERS.get(addr as usize)", upper).unwrap();
+        writeln!(s, "            .copied()").unwrap();
+        writeln!(s, "            .filter(|name| !name.is_empty())").unwrap();
+        writeln!(s, "    }}").unwrap();
+        if !extended_io.is_empty() {
+            writeln!(s).unwrap();
+            writeln!(s, "    fn extended_io_registers() -> &'static [(u16, &'static str)] {{").unwrap();
+            writeln!(s, "        {}_EXT_IO_REGISTERS", upper).unwrap();
+            writeln!(s, "    }}").unwrap();
+        }
+        writeln!(s, "}}").unwrap();
+        s
+    }
+
+    /// Writes the `sleep` field: SMCR of ATmega parts, or SE and SM bits of MCUCR.
+    fn write_sleep_desc(&self, s: &mut String) {
+        if self.register("SMCR").is_some() {
+            writeln!(s, "    sleep: ATMEGA_SLEEP,").unwrap();
+            return;
+        }
+        let mcucr = self.register("MCUCR");
+        let bitfield = |name: &str| mcucr
+            .and_then(|r| r.bitfields.iter().find(|b| b.name == name))
+            .map_or(0, |b| b.mask);
+        let sm = bitfield("SM");
+        writeln!(s, "    sleep: SleepDesc {{").unwrap();
+        writeln!(s, "        addr: 0x{:02X},", mcucr.map_or(0, |r| r.addr)).unwrap();
+        writeln!(s, "        mask: 0xFF,").unwrap();
+        writeln!(s, "        se_bit: {},", bitfield("SE").trailing_zeros().min(7)).unwrap();
+        writeln!(s, "        sm_shift: {},", sm.trailing_zeros().min(7)).unwrap();
+        if sm.count_ones() == 3 {
+            writeln!(s, "        modes: ATMEGA_SLEEP.modes,").unwrap();
+        } else {
+            writeln!(s, "        modes: &[Some(SleepMode::Idle), Some(SleepMode::AdcNoiseReduction), Some(SleepMode::PowerDown), None],").unwrap();
+        }
+        writeln!(s, "    }},").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTINY85: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Trimmed ATtiny85 device file -->
+<avr-tools-device-file schema-version="4.0">
+  <devices>
+    <device name="ATtiny85" architecture="AVR8" family="tinyAVR">
+      <address-spaces>
+        <address-space endianness="little" name="prog" id="prog" start="0x0000" size="0x2000">
+          <memory-segment start="0x0000" size="0x2000" type="flash" rw="RW" exec="1" name="FLASH" pagesize="0x40"/>
+        </address-space>
+        <address-space endianness="little" name="data" id="data" start="0x0000" size="0x0260">
+          <memory-segment external="false" type="regs" size="0x0020" start="0x0000" name="REGISTERS"/>
+          <memory-segment external="false" type="io" size="0x0040" start="0x0020" name="MAPPED_IO"/>
+          <memory-segment external="false" type="ram" size="0x0200" start="0x0060" name="IRAM"/>
+        </address-space>
+        <address-space endianness="little" name="eeprom" id="eeprom" start="0x0000" size="0x0200">
+          <memory-segment start="0x0000" size="0x0200" type="eeprom" rw="RW" exec="0" name="EEPROM" pagesize="0x04"/>
+        </address-space>
+      </address-spaces>
+      <peripherals>
+        <module name="PORT">
+          <instance name="PORTB" caption="I/O Port">
+            <register-group name="PORTB" name-in-module="PORTB" offset="0x00" address-space="data" caption="I/O Port"/>
+            <signals>
+              <signal group="P" function="default" pad="PB0" index="0"/>
+              <signal group="P" function="default" pad="PB1" index="1"/>
+              <signal group="P" function="default" pad="PB2" index="2"/>
+            </signals>
+          </instance>
+        </module>
+        <module name="TC8_ASYNC">
+          <instance name="TC1" caption="Timer/Counter1">
+            <register-group name="TC1" name-in-module="TC1" offset="0x00" address-space="data"/>
+            <signals>
+              <signal group="OC1A" function="default" pad="PB1"/>
+            </signals>
+          </instance>
+        </module>
+        <module name="CPU">
+          <instance name="CPU">
+            <register-group name="CPU" name-in-module="CPU" offset="0x00" address-space="data"/>
+          </instance>
+        </module>
+      </peripherals>
+      <interrupts>
+        <interrupt index="0" name="RESET" caption="External Reset, Power-on Reset &amp; Watchdog Reset"/>
+        <interrupt index="4" name="TIMER1_OVF" caption="Timer/Counter1 Overflow"/>
+        <interrupt index="3" name="TIMER1_COMPA" caption="Timer/Counter1 Compare Match A"/>
+        <interrupt index="12" name="WDT" caption="Watchdog Time-out"/>
+      </interrupts>
+    </device>
+  </devices>
+  <modules>
+    <module caption="I/O Port" name="PORT">
+      <register-group caption="I/O Port" name="PORTB">
+        <register caption="Input Pins, Port B" name="PINB" offset="0x36" size="1" mask="0x3F"/>
+        <register caption="Data Direction Register, Port B" name="DDRB" offset="0x37" size="1" mask="0x3F"/>
+        <register caption="Data Register, Port B" name="PORTB" offset="0x38" size="1" mask="0x3F"/>
+      </register-group>
+    </module>
+    <module caption="Timer/Counter, 8-bit" name="TC8_ASYNC">
+      <register-group caption="Timer/Counter, 8-bit" name="TC1">
+        <register caption="Timer/Counter1 Output Compare Register A" name="OCR1A" offset="0x4E" size="1" mask="0xFF"/>
+        <register caption="Timer/Counter Register" name="TCNT1" offset="0x4F" size="1" mask="0xFF"/>
+      </register-group>
+    </module>
+    <module caption="CPU Registers" name="CPU">
+      <register-group caption="CPU Registers" name="CPU">
+        <register caption="MCU Control Register" name="MCUCR" offset="0x55" size="1">
+          <bitfield caption="Sleep Enable" mask="0x20" name="SE"/>
+          <bitfield caption="Sleep Mode Select Bits" mask="0x18" name="SM"/>
+        </register>
+        <register caption="Stack Pointer" name="SP" offset="0x5D" size="2" mask="0x03FF"/>
+        <register caption="Status Register" name="SREG" offset="0x5F" size="1"/>
+      </register-group>
+    </module>
+  </modules>
+</avr-tools-device-file>
+"#;
+
+    #[test]
+    fn device() {
+        let device = parse_atdf(ATTINY85).unwrap();
+        assert_eq!(device.name, "ATtiny85");
+        assert_eq!(device.flash_size(), 4096);
+        assert_eq!(device.flash_page_size(), 32);
+        assert_eq!(device.eeprom_size(), 512);
+        assert_eq!(device.sram_start(), 0x60);
+        assert_eq!(device.sram_size(), 512);
+        assert_eq!(device.pc_bytes(), 2);
+        assert_eq!(device.vector_size(), 1);
+        assert_eq!(device.instruction_set(), InstructionSet::Avr25);
+
+        assert_eq!(device.registers.len(), 8);
+        let mcucr = device.register("MCUCR").unwrap();
+        assert_eq!(mcucr.addr, 0x55);
+        assert_eq!(mcucr.bitfields[1], Bitfield {name: "SM".to_string(), caption: "Sleep Mode Select Bits".to_string(), mask: 0x18});
+        assert_eq!(device.register_at(0x4E).unwrap().instance, "TC1");
+        assert_eq!(device.io_register_name(0x16).as_deref(), Some("PINB"));
+        assert_eq!(device.io_register_name(0x3E).as_deref(), Some("SPH"));
+        assert_eq!(device.io_register_name(0x00), None);
+
+        assert_eq!(device.interrupts[0].caption, "External Reset, Power-on Reset & Watchdog Reset");
+        assert_eq!(device.vector("TIMER1_COMPA"), Some(3));
+        assert_eq!(device.interrupt_vector(InterruptSource::TimerOverflow(1)), Some(4));
+        assert_eq!(device.interrupt_vector(InterruptSource::Watchdog), Some(12));
+        assert_eq!(device.interrupt_vector(InterruptSource::SpmReady), None);
+
+        assert_eq!(device.signal_pin("TC1", "OC1A"), Some("PB1"));
+        assert_eq!(device.pin_signals("PB1").count(), 2);
+        assert_eq!(device.gpio_ports(), [('B', 0x36, 3)]);
+    }
+
+    #[test]
+    fn generated_model() {
+        let source = parse_atdf(ATTINY85).unwrap().to_rust("Attiny85");
+        assert!(source.contains("GpioPortDesc {name: 'B', pin_addr: 0x36, pin_count: 3},"));
+        assert!(source.contains("        se_bit: 5,\n        sm_shift: 3,\n"));
+        assert!(source.contains("    \"\", \"\", \"\", \"\", \"\", \"MCUCR\", \"\", \"\",\n"));
+        assert!(source.contains("    fn flash_size() -> usize {\n        4096\n    }\n"));
+        assert!(source.contains("InterruptSource::TimerCompare(1, 0) => Some(3),"));
+        assert!(source.contains("InstructionSet::Avr25"));
+        assert!(!source.contains("EXT_IO_REGISTERS"));
+
+        // Registers between IO space and SRAM get their own table
+        let text = ATTINY85
+            .replace(r#"start="0x0060" name="IRAM""#, r#"start="0x0100" name="IRAM""#)
+            .replace(r#"name="TCNT1" offset="0x4F""#, r#"name="TCNT1" offset="0x8F""#);
+        let device = parse_atdf(&text).unwrap();
+        assert_eq!(device.extended_io_registers(), [(0x8F, "TCNT1".to_string())]);
+        let source = device.to_rust("Attiny85");
+        assert!(source.contains("const ATTINY85_EXT_IO_REGISTERS: &[(u16, &str)] = &[\n    (0x8F, \"TCNT1\"),\n];\n"));
+        assert!(source.contains("    fn extended_io_registers() -> &'static [(u16, &'static str)] {\n        ATTINY85_EXT_IO_REGISTERS\n"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(parse_atdf("<a><b></a>"), Err(AtdfError::Xml(1, "mismatched closing tag"))));
+        assert!(matches!(parse_atdf("<a>\n<b x=1/></a>"), Err(AtdfError::Xml(2, "expected a quoted value"))));
+        assert!(matches!(parse_atdf("<a>"), Err(AtdfError::Xml(_, "unclosed element"))));
+        assert!(matches!(parse_atdf("<a x='&bad;'/>"), Err(AtdfError::Xml(_, "invalid entity"))));
+        assert!(matches!(parse_atdf("<a/>"), Err(AtdfError::Format(_))));
+        assert!(matches!(parse_atdf("<avr-tools-device-file/>"), Err(AtdfError::Format(_))));
+
+        let no_flash = ATTINY85.replace(r#"type="flash""#, r#"type="boot""#);
+        assert!(matches!(parse_atdf(&no_flash), Err(AtdfError::Format(msg)) if msg == "no flash memory segment"));
+        let huge_register = ATTINY85.replace(r#"name="SP" offset="0x5D" size="2""#, r#"name="SP" offset="0x5D" size="0x100""#);
+        assert!(matches!(parse_atdf(&huge_register), Err(AtdfError::Format(msg)) if msg == "register SP at 0x5D has invalid size 256"));
+        let last_register = ATTINY85.replace(r#"name="SP" offset="0x5D""#, r#"name="SP" offset="0xFFFF""#);
+        assert!(matches!(parse_atdf(&last_register), Err(AtdfError::Format(_))));
+        let overflow = ATTINY85.replace(r#"name="SP" offset="0x5D""#, r#"name="SP" offset="0xFFFFFFFF""#);
+        assert!(matches!(parse_atdf(&overflow), Err(AtdfError::Format(_))));
+    }
+}
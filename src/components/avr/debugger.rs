@@ -0,0 +1,796 @@
+//! Interactive console debugger, for debugging firmware without setting up `avr-gdb`.
+//!
+//! Runs a [Board] with an AVR MCU on it, stopping the whole board on breakpoints, watchpoints
+//! and other component events. Type `help` at the prompt for the list of commands.
+
+use std::{fmt, io::{self, BufRead, Write}, marker::PhantomData, sync::{Arc, Mutex, mpsc::{self, Sender}}};
+
+use crate::{board::{Board, StopReason}, component::{ComponentEvent, ComponentId}, components::uart::Uart};
+
+use super::{
+    mcu::{CpuState, debug::{Watchpoint, WatchKind}},
+    mcu_model::McuModel,
+    mcu_ticker::McuDefault,
+    disasm::{disassemble_at, DisassembledInstruction},
+    instruction::Instruction,
+    elf::{SymbolKind, DATA_OFFSET, EEPROM_OFFSET},
+};
+
+const HELP: &str = "\
+Commands:
+  step, s [N]                      Execute N instructions [default: 1]
+  next, n [N]                      Like step, but runs called functions to the end
+  continue, c [CYCLES]             Run until a breakpoint, a watchpoint or another event
+  break, b <ADDR|SYMBOL>           Set a breakpoint at a flash byte address
+  watch, w [read|write|access] <ADDR|IOREG|SYMBOL> [VALUE]
+                                   Set a watchpoint on data memory [default: write]
+  delete, d [N]                    Delete breakpoint or watchpoint N, or all of them
+  info, i                          List breakpoints and watchpoints
+  regs, r                          Show registers
+  sreg                             Show SREG flags
+  stack [N]                        Show N bytes on top of the stack [default: 16]
+  x <ADDR|IOREG|SYMBOL> [LEN]      Show data memory
+  io [IOREG]                       Show an IO register, or all of them (except UDR and unmodeled ones)
+  list, l [ADDR|SYMBOL] [N]        Disassemble N instructions, around PC by default
+  wires                            Show states of wires and connected pins
+  uart                             Show UART traffic
+  send <TEXT>                      Send text to the MCU through UART, with \\n, \\r, \\t, \\\\ and \\xHH escapes
+  help, h                          Print this message
+  quit, q                          Exit the debugger
+An empty line repeats the last command.";
+
+/// SREG flag names, from bit 7 to bit 0.
+const SREG_FLAGS: &[u8; 8] = b"ITHSVNZC";
+/// Instructions shown before PC by `list`.
+const LIST_BEFORE: u32 = 4;
+
+/// Output of a UART terminal, collected for the debugger.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A UART terminal connected to the MCU: the debugger shows what it receives and sends input through it.
+pub struct UartMonitor {
+    received: SharedBuffer,
+    /// Number of received bytes already shown.
+    shown: usize,
+    sent: Vec<u8>,
+    input: Sender<u8>,
+}
+
+impl UartMonitor {
+    /// Connects a terminal to the monitor, replacing its input and output.
+    ///
+    /// The terminal has to be added to the board and wired to the MCU separately.
+    pub fn connect<const CHAR_SIZE: u8>(uart: &mut Uart<CHAR_SIZE>) -> UartMonitor {
+        let received = SharedBuffer::default();
+        let (input, rx) = mpsc::channel();
+        uart.set_output(Box::new(received.clone()));
+        uart.set_input(rx);
+        UartMonitor {
+            received,
+            shown: 0,
+            sent: Vec::new(),
+            input,
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        for &b in data {
+            // The terminal only stops listening when the board is dropped
+            let _ = self.input.send(b);
+        }
+        self.sent.extend_from_slice(data);
+    }
+
+    fn received(&self) -> Vec<u8> {
+        self.received.0.lock().unwrap().clone()
+    }
+
+    /// Takes received bytes which were not shown yet.
+    fn take_new(&mut self) -> Vec<u8> {
+        let received = self.received.0.lock().unwrap();
+        let data = received[self.shown..].to_vec();
+        self.shown = received.len();
+        data
+    }
+}
+
+/// A breakpoint or a watchpoint set by the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DebugPoint {
+    /// Word address.
+    Breakpoint(u32),
+    Watchpoint(Watchpoint),
+}
+
+/// An error of a single command, the debugger keeps running after it.
+#[derive(Debug)]
+enum CommandError {
+    Io(io::Error),
+    Usage(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Io(e) => write!(f, "{}", e),
+            CommandError::Usage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::Io(e)
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(e: String) -> Self {
+        CommandError::Usage(e)
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_count(arg: Option<&&str>, default: u32) -> Result<u32, CommandError> {
+    match arg {
+        Some(s) => parse_number(s).ok_or_else(|| format!("Invalid number: {}", s).into()),
+        None => Ok(default),
+    }
+}
+
+/// Escapes non-printable characters, like `\n` or `\x00`.
+fn escape(data: &[u8]) -> String {
+    data.iter().flat_map(|&b| std::ascii::escape_default(b)).map(char::from).collect()
+}
+
+/// Reverses [escape].
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut result = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => result.push(b'\n'),
+            Some(b'r') => result.push(b'\r'),
+            Some(b't') => result.push(b'\t'),
+            Some(b'\\') => result.push(b'\\'),
+            Some(b'x') => {
+                let hex = [bytes.next(), bytes.next()];
+                let value = match hex {
+                    [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo]).ok()
+                        .and_then(|s| u8::from_str_radix(s, 16).ok()),
+                    _ => None,
+                };
+                result.push(value.ok_or_else(|| format!("Invalid escape in: {}", text))?);
+            }
+            _ => return Err(format!("Invalid escape in: {}", text)),
+        }
+    }
+    Ok(result)
+}
+
+/// Formats SREG with a letter for every set flag and `-` for every cleared one.
+fn format_sreg(sreg: u8) -> String {
+    SREG_FLAGS.iter().enumerate()
+        .map(|(i, &flag)| if sreg & (0x80 >> i) != 0 {flag as char} else {'-'})
+        .collect()
+}
+
+/// Interactive debugger controlling a board with an MCU of model `M`.
+pub struct Debugger<'a, M: McuModel + 'static> {
+    board: &'a mut Board,
+    mcu: ComponentId,
+    points: Vec<DebugPoint>,
+    uart: Option<UartMonitor>,
+    /// Board cycles a single command runs for at most.
+    cycle_limit: u64,
+    last_command: String,
+    model: PhantomData<M>,
+}
+
+impl<'a, M: McuModel + 'static> Debugger<'a, M> {
+    /// Creates a debugger for a clocked [McuDefault] component on the board.
+    ///
+    /// Panics if the component is not an MCU of model `M`.
+    pub fn new(board: &'a mut Board, mcu: ComponentId) -> Debugger<'a, M> {
+        assert!(board.component_mut::<McuDefault<M>>(mcu).is_some(), "Component is not a clocked MCU of this model");
+        Debugger {
+            board,
+            mcu,
+            points: Vec::new(),
+            uart: None,
+            cycle_limit: 1 << 32,
+            last_command: String::new(),
+            model: PhantomData,
+        }
+    }
+
+    /// Shows the traffic of a UART terminal, and lets the user send data to the MCU.
+    pub fn set_uart_monitor(&mut self, uart: UartMonitor) {
+        self.uart = Some(uart);
+    }
+
+    /// Sets the maximum number of board cycles a single command runs for.
+    pub fn set_cycle_limit(&mut self, cycles: u64) {
+        self.cycle_limit = cycles;
+    }
+
+    fn mcu(&mut self) -> &mut McuDefault<M> {
+        self.board.component_mut(self.mcu).expect("MCU component has disappeared")
+    }
+
+    /// Runs the debugger prompt until `quit` or the end of input.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        self.show_location(&mut output)?;
+        loop {
+            write!(output, "(amber) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            if !self.execute(line.trim(), &mut output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes a single command line, returns `false` if the debugger should exit.
+    ///
+    /// An empty line repeats the last command.
+    pub fn execute(&mut self, line: &str, output: &mut impl Write) -> io::Result<bool> {
+        let line = if line.is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.to_string();
+            line.to_string()
+        };
+        match self.command(&line, output) {
+            Ok(proceed) => Ok(proceed),
+            Err(CommandError::Io(e)) => Err(e),
+            Err(CommandError::Usage(e)) => {
+                writeln!(output, "{}", e)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, out: &mut impl Write) -> Result<bool, CommandError> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = args.split_first() else {
+            return Ok(true);
+        };
+        match command {
+            "step" | "s" => {
+                let count = parse_count(args.first(), 1)?;
+                let mut stop = StopReason::Finished;
+                for _ in 0..count {
+                    stop = self.step_instruction();
+                    if stop != StopReason::Finished {
+                        break;
+                    }
+                }
+                self.report(stop, out)?;
+            }
+            "next" | "n" => {
+                let count = parse_count(args.first(), 1)?;
+                let mut stop = StopReason::Finished;
+                for _ in 0..count {
+                    stop = self.next_instruction();
+                    if stop != StopReason::Finished {
+                        break;
+                    }
+                }
+                self.report(stop, out)?;
+            }
+            "continue" | "c" => {
+                let cycles = match args.first() {
+                    Some(s) => parse_number(s).ok_or_else(|| format!("Invalid number: {}", s))? as u64,
+                    None => self.cycle_limit,
+                };
+                self.mcu().resume();
+                let stop = self.board.simulate(cycles);
+                if stop == StopReason::Finished {
+                    writeln!(out, "Ran for {} cycles without stopping", cycles)?;
+                }
+                self.report(stop, out)?;
+            }
+            "break" | "b" => {
+                let [arg] = args else {
+                    return Err("Usage: break <ADDR|SYMBOL>".to_string().into());
+                };
+                let addr = self.parse_code_address(arg)?;
+                self.add_point(DebugPoint::Breakpoint(addr), out)?;
+            }
+            "watch" | "w" => {
+                let (kind, args) = match args.split_first() {
+                    Some((&"read", args)) => (WatchKind::Read, args),
+                    Some((&"write", args)) => (WatchKind::Write, args),
+                    Some((&"access", args)) => (WatchKind::Access, args),
+                    _ => (WatchKind::Write, args),
+                };
+                let (addr, len, value) = match args {
+                    [addr] => (addr, None, None),
+                    [addr, value] => (addr, Some(1), Some(value)),
+                    _ => return Err("Usage: watch [read|write|access] <ADDR|IOREG|SYMBOL> [VALUE]".to_string().into()),
+                };
+                let (addr, symbol_len) = self.parse_data_address(addr)?;
+                let mut watchpoint = Watchpoint {addr, len: len.unwrap_or(symbol_len), kind, value: None};
+                if let Some(value) = value {
+                    let value = parse_number(value).filter(|&v| v <= 0xFF)
+                        .ok_or_else(|| format!("Invalid byte value: {}", value))?;
+                    watchpoint = watchpoint.with_value(value as u8);
+                }
+                self.add_point(DebugPoint::Watchpoint(watchpoint), out)?;
+            }
+            "delete" | "d" => match args {
+                [] => {
+                    self.points.clear();
+                    self.mcu().clear_debug_points();
+                    writeln!(out, "Deleted all breakpoints and watchpoints")?;
+                }
+                [n] => {
+                    let index = parse_number(n)
+                        .filter(|&n| n >= 1 && n as usize <= self.points.len())
+                        .ok_or_else(|| format!("No breakpoint or watchpoint {}", n))?;
+                    match self.points.remove(index as usize - 1) {
+                        DebugPoint::Breakpoint(addr) => self.mcu().remove_breakpoint(addr),
+                        DebugPoint::Watchpoint(w) => self.mcu().remove_watchpoint(w),
+                    }
+                }
+                _ => return Err("Usage: delete [N]".to_string().into()),
+            },
+            "info" | "i" => {
+                if self.points.is_empty() {
+                    writeln!(out, "No breakpoints or watchpoints")?;
+                }
+                for i in 0..self.points.len() {
+                    let text = self.describe_point(self.points[i]);
+                    writeln!(out, "{}: {}", i + 1, text)?;
+                }
+            }
+            "regs" | "r" => self.show_registers(out)?,
+            "sreg" => {
+                let sreg = self.mcu().sreg();
+                let flags: Vec<String> = SREG_FLAGS.iter().enumerate()
+                    .map(|(i, &flag)| format!("{}={}", flag as char, sreg >> (7 - i) & 1))
+                    .collect();
+                writeln!(out, "SREG = 0x{:02X}  {}", sreg, flags.join(" "))?;
+            }
+            "stack" => {
+                let count = parse_count(args.first(), 16)?;
+                let sp = self.mcu().sp() as u32;
+                let end = (sp + count).min(M::sram_end() as u32);
+                if sp >= end {
+                    writeln!(out, "Stack is empty (SP = 0x{:04X})", sp)?;
+                } else {
+                    self.dump(sp + 1, end - sp, out)?;
+                }
+            }
+            "x" => {
+                let (addr, symbol_len) = match args.first() {
+                    Some(arg) => self.parse_data_address(arg)?,
+                    None => return Err("Usage: x <ADDR|IOREG|SYMBOL> [LEN]".to_string().into()),
+                };
+                let len = match symbol_len {
+                    1 => parse_count(args.get(1), 16)?,
+                    len => parse_count(args.get(1), len as u32)?,
+                };
+                let len = len.min(0x10000 - addr as u32);
+                self.dump(addr as u32, len, out)?;
+            }
+            "io" => match args {
+                [] => {
+                    // Registers like UDR can't be read without changing them, so they are left out
+                    for addr in 0x20..M::sram_start() {
+                        if let Some(name) = M::register_name(addr) {
+                            if self.mcu().peek_has_side_effects(addr) {
+                                continue;
+                            }
+                            let value = self.mcu().peek(addr);
+                            writeln!(out, "{:<8} 0x{:02X} = 0x{:02X}  0b{:08b}", name, addr, value, value)?;
+                        }
+                    }
+                }
+                [name] => {
+                    let (addr, _) = self.parse_data_address(name)?;
+                    if self.mcu().peek_has_side_effects(addr) {
+                        return Err(format!("{} isn't read, reading it has side effects", name).into());
+                    }
+                    let value = self.mcu().peek(addr);
+                    writeln!(out, "{} = 0x{:02X}  0b{:08b}", name, value, value)?;
+                }
+                _ => return Err("Usage: io [IOREG]".to_string().into()),
+            },
+            "list" | "l" => {
+                let (start, count) = match args {
+                    [] => (self.list_start(), LIST_BEFORE + 6),
+                    [addr] => (self.parse_code_address(addr)?, 10),
+                    [addr, count] => (self.parse_code_address(addr)?, parse_count(Some(count), 10)?),
+                    _ => return Err("Usage: list [ADDR|SYMBOL] [N]".to_string().into()),
+                };
+                self.list(start, count, out)?;
+            }
+            "wires" => {
+                for wire in self.board.wires() {
+                    let pins: Vec<String> = wire.pins.iter()
+                        .map(|(name, state)| format!("{}={:?}", name, state))
+                        .collect();
+                    writeln!(out, "{:<9} {}", format!("{:?}", wire.state), pins.join(" "))?;
+                }
+            }
+            "uart" => {
+                let uart = self.uart.as_mut().ok_or("No UART terminal is connected".to_string())?;
+                writeln!(out, "Received: \"{}\"", escape(&uart.received()))?;
+                writeln!(out, "Sent:     \"{}\"", escape(&uart.sent))?;
+                uart.take_new();
+            }
+            "send" => {
+                let text = line.trim_start()[command.len()..].trim_start();
+                let data = unescape(text)?;
+                let uart = self.uart.as_mut().ok_or("No UART terminal is connected".to_string())?;
+                uart.send(&data);
+            }
+            "help" | "h" => writeln!(out, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => return Err(format!("Unknown command: {}, try help", command).into()),
+        }
+        Ok(true)
+    }
+
+    /// Runs the board until the instruction at PC has finished.
+    ///
+    /// PC already points to the next instruction while the previous one takes its cycles
+    /// (like after a watchpoint), so it waits for a new instruction to start.
+    fn step_instruction(&mut self) -> StopReason {
+        let mcu = self.mcu();
+        mcu.resume();
+        let start = mcu.cycles();
+        for _ in 0..self.cycle_limit {
+            let stop = self.board.simulate(1);
+            if stop != StopReason::Finished {
+                return stop;
+            }
+            let mcu = self.mcu();
+            if mcu.instruction_finished() && mcu.cycles() != start {
+                break;
+            }
+        }
+        StopReason::Finished
+    }
+
+    /// Like [Debugger::step_instruction], but runs a called function until it returns.
+    fn next_instruction(&mut self) -> StopReason {
+        let mcu = self.mcu();
+        let pc = mcu.pc();
+        let instr = disassemble_at::<M>(mcu.flash(), pc);
+        let operand = instr.operand.unwrap_or(0);
+        if !matches!(Instruction::decode(instr.opcode, operand),
+                Instruction::Call {..} | Instruction::Rcall {..} | Instruction::Icall | Instruction::Eicall) {
+            return self.step_instruction();
+        }
+
+        let ret = (pc + instr.size()) % M::flash_size() as u32;
+        let sp = mcu.sp();
+        let temporary = !self.points.contains(&DebugPoint::Breakpoint(ret));
+        if temporary {
+            self.mcu().add_breakpoint(ret);
+        }
+        let stop = loop {
+            self.mcu().resume();
+            let stop = self.board.simulate(self.cycle_limit);
+            // A recursive call returning to the same address has a deeper stack
+            match stop {
+                StopReason::Event(id, ComponentEvent::Breakpoint(addr), _)
+                    if id == self.mcu && addr == ret => {
+                    if self.mcu().sp() >= sp {
+                        break StopReason::Finished;
+                    }
+                }
+                stop => break stop,
+            }
+        };
+        if temporary {
+            self.mcu().remove_breakpoint(ret);
+        }
+        stop
+    }
+
+    /// Prints why the board has stopped, where the CPU is and new UART output.
+    fn report(&mut self, stop: StopReason, out: &mut impl Write) -> io::Result<()> {
+        if let StopReason::Event(id, event, time_ns) = stop {
+            let source = if id == self.mcu {String::new()} else {format!(" (component {})", id.0)};
+            writeln!(out, "Stopped at {} ns{}: {}", time_ns.round(), source, event)?;
+        }
+        if let Some(uart) = &mut self.uart {
+            let data = uart.take_new();
+            if !data.is_empty() {
+                writeln!(out, "UART: \"{}\"", escape(&data))?;
+            }
+        }
+        self.show_location(out)
+    }
+
+    fn show_location(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let mcu = self.mcu();
+        let state = mcu.state();
+        let pc = mcu.pc();
+        let instr = disassemble_at::<M>(mcu.flash(), pc);
+        match self.function_name(pc) {
+            Some(name) => writeln!(out, "=> {}    <{}>", instr, name)?,
+            None => writeln!(out, "=> {}", instr)?,
+        }
+        match state {
+            CpuState::Running => Ok(()),
+            CpuState::Sleeping(mode) => writeln!(out, "CPU is sleeping ({:?})", mode),
+            CpuState::Halted => writeln!(out, "CPU is halted by BREAK"),
+            CpuState::Faulted(fault) => writeln!(out, "CPU is stopped by a fault: {}", fault),
+        }
+    }
+
+    fn show_registers(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let mcu = self.mcu();
+        for row in 0..4 {
+            let regs: Vec<String> = (row * 8..row * 8 + 8)
+                .map(|i| format!("{:02X}", mcu.read_register(i)))
+                .collect();
+            writeln!(out, "{:<8} {}", format!("r{}-r{}", row * 8, row * 8 + 7), regs.join(" "))?;
+        }
+        let (x, y, z) = (mcu.read_register(26) as u16 | (mcu.read_register(27) as u16) << 8,
+            mcu.read_register(28) as u16 | (mcu.read_register(29) as u16) << 8,
+            mcu.read_register(30) as u16 | (mcu.read_register(31) as u16) << 8);
+        writeln!(out, "X = 0x{:04X}  Y = 0x{:04X}  Z = 0x{:04X}", x, y, z)?;
+        let (pc, sp, sreg, cycles) = (mcu.pc(), mcu.sp(), mcu.sreg(), mcu.cycles());
+        writeln!(out, "PC = 0x{:X}  SP = 0x{:04X}  SREG = {}", pc << 1, sp, format_sreg(sreg))?;
+        writeln!(out, "Cycles = {}  Time = {} ns", cycles, self.board.time_ns().round())
+    }
+
+    /// Prints a hex dump of data memory, 16 bytes per line.
+    fn dump(&mut self, addr: u32, len: u32, out: &mut impl Write) -> io::Result<()> {
+        let mcu = self.mcu();
+        for line in (addr..addr + len).step_by(16) {
+            let bytes: Vec<String> = (line..(line + 16).min(addr + len))
+                .map(|a| format!("{:02X}", mcu.peek(a as u16)))
+                .collect();
+            writeln!(out, "0x{:04X}: {}", line, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// Finds an instruction [LIST_BEFORE] instructions before PC.
+    ///
+    /// Two-word instructions make disassembling backwards ambiguous,
+    /// so the furthest start address ending exactly at PC is taken.
+    fn list_start(&mut self) -> u32 {
+        let mcu = self.mcu();
+        let pc = mcu.pc();
+        let flash = mcu.flash();
+        for start in pc.saturating_sub(LIST_BEFORE * 2)..pc {
+            let mut addr = start;
+            let mut count = 0;
+            while addr < pc {
+                addr += disassemble_at::<M>(flash, addr).size();
+                count += 1;
+            }
+            if addr == pc && count <= LIST_BEFORE {
+                return start;
+            }
+        }
+        pc
+    }
+
+    fn list(&mut self, start: u32, count: u32, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.mcu().pc();
+        let mut addr = start;
+        for _ in 0..count {
+            if addr >= M::flash_size() as u32 {
+                break;
+            }
+            let instr: DisassembledInstruction = disassemble_at::<M>(self.mcu().flash(), addr);
+            if let Some(name) = self.function_name(addr).filter(|name| !name.contains('+')) {
+                writeln!(out, "<{}>:", name)?;
+            }
+            let marker = if addr == pc {"=>"} else {"  "};
+            writeln!(out, "{} {}", marker, instr)?;
+            addr += instr.size();
+        }
+        Ok(())
+    }
+
+    /// Formats a word address as `function+offset`, if the firmware has symbols.
+    fn function_name(&mut self, addr: u32) -> Option<String> {
+        self.mcu().firmware()?.symbols.format_function(addr << 1)
+    }
+
+    /// Parses a flash byte address or a function name into a word address.
+    fn parse_code_address(&mut self, s: &str) -> Result<u32, CommandError> {
+        if let Some(addr) = parse_number(s) {
+            if addr & 1 != 0 || addr >= (M::flash_size() as u32) << 1 {
+                return Err(format!("Invalid flash byte address: {}", s).into());
+            }
+            return Ok(addr >> 1);
+        }
+        self.mcu().firmware()
+            .and_then(|f| f.symbols.get(s))
+            .filter(|symbol| symbol.kind == SymbolKind::Function)
+            .map(|symbol| symbol.addr >> 1)
+            .ok_or_else(|| format!("Unknown address or function: {}", s).into())
+    }
+
+    /// Parses a data address, an IO register or a variable name into a data address and a size.
+    fn parse_data_address(&mut self, s: &str) -> Result<(u16, u16), CommandError> {
+        if let Some(addr) = parse_number(s) {
+            let addr = u16::try_from(addr).map_err(|_| format!("Invalid data address: {}", s))?;
+            return Ok((addr, 1));
+        }
+        if let Some(addr) = (0x20..M::sram_start()).find(|&a| M::register_name(a).is_some_and(|name| name.eq_ignore_ascii_case(s))) {
+            return Ok((addr, 1));
+        }
+        let Some(symbol) = self.mcu().firmware()
+            .and_then(|f| f.symbols.get(s))
+            .filter(|symbol| symbol.kind == SymbolKind::Object) else {
+            return Err(format!("Unknown address, IO register or variable: {}", s).into());
+        };
+        match symbol.addr.checked_sub(DATA_OFFSET) {
+            Some(addr) if symbol.addr < EEPROM_OFFSET => Ok((addr as u16, symbol.size.clamp(1, 0xFFFF) as u16)),
+            None => Err(format!("{} is in flash, not in data memory", s).into()),
+            Some(_) if symbol.addr < EEPROM_OFFSET + 0x10000 => Err(format!("{} is in EEPROM, not in data memory", s).into()),
+            Some(_) => Err(format!("{} is in fuse, lock or signature memory, not in data memory", s).into()),
+        }
+    }
+
+    fn add_point(&mut self, point: DebugPoint, out: &mut impl Write) -> io::Result<()> {
+        if !self.points.contains(&point) {
+            self.points.push(point);
+            match point {
+                DebugPoint::Breakpoint(addr) => self.mcu().add_breakpoint(addr),
+                DebugPoint::Watchpoint(w) => self.mcu().add_watchpoint(w),
+            }
+        }
+        let index = self.points.iter().position(|&p| p == point).unwrap() + 1;
+        let text = self.describe_point(point);
+        writeln!(out, "{}: {}", index, text)
+    }
+
+    fn describe_point(&mut self, point: DebugPoint) -> String {
+        match point {
+            DebugPoint::Breakpoint(addr) => match self.function_name(addr) {
+                Some(name) => format!("breakpoint at 0x{:X} <{}>", addr << 1, name),
+                None => format!("breakpoint at 0x{:X}", addr << 1),
+            },
+            DebugPoint::Watchpoint(w) => {
+                let kind = match w.kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+                let mut text = format!("{} watchpoint at 0x{:04X}", kind, w.addr);
+                if w.len > 1 {
+                    text += &format!("..0x{:04X}", w.addr as u32 + w.len as u32 - 1);
+                }
+                if let Some(name) = M::register_name(w.addr) {
+                    text += &format!(" <{}>", name);
+                } else if let Some((symbol, offset)) = self.mcu().firmware().and_then(|f| f.symbols.object_at(w.addr)) {
+                    text += &match offset {
+                        0 => format!(" <{}>", symbol.name),
+                        _ => format!(" <{}+0x{:X}>", symbol.name, offset),
+                    };
+                }
+                if let Some(value) = w.value {
+                    text += &format!(" == 0x{:02X}", value);
+                }
+                text
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{components::avr::{mcu_model::Atmega328P, elf::{ElfImage, test_helper::build_elf}}, vcd::VcdConfig};
+
+    use super::*;
+
+    /// Runs commands, returning their output.
+    fn run(debugger: &mut Debugger<Atmega328P>, commands: &[&str]) -> String {
+        let mut output = Vec::new();
+        for command in commands {
+            assert!(debugger.execute(command, &mut output).unwrap());
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[
+            0xE005, // ldi r16, 5
+            0xD004, // rcall .+8
+            0x9300, 0x0100, // sts 0x0100, r16
+            0xCFFF, // rjmp .-2
+            0x0000, // nop
+            0x9503, // inc r16
+            0x9508, // ret
+        ]);
+        let mut board = Board::without_vcd(16e6);
+        let mcu = board.add_component_clocked(mcu, "mcu", &VcdConfig::Disable);
+        let mut debugger = Debugger::<Atmega328P>::new(&mut board, mcu.id());
+        debugger.set_cycle_limit(1000);
+
+        assert_eq!(run(&mut debugger, &["step"]), "=>     2:  04 D0          rcall 0xC\n");
+        assert_eq!(run(&mut debugger, &["next"]), "=>     4:  00 93 00 01    sts 0x0100, r16\n");
+        assert_eq!(run(&mut debugger, &["r"]).lines().nth(2), Some("r16-r23  06 00 00 00 00 00 00 00"));
+
+        assert_eq!(run(&mut debugger, &["watch 0x100 6", "b 0xC", "info"]),
+            "1: write watchpoint at 0x0100 == 0x06\n2: breakpoint at 0xC\n\
+             1: write watchpoint at 0x0100 == 0x06\n2: breakpoint at 0xC\n");
+        let output = run(&mut debugger, &["c"]);
+        assert!(output.starts_with("Stopped at 625 ns: write watchpoint at 0x0100\n"), "{}", output);
+        assert_eq!(run(&mut debugger, &["x 0x100 2"]), "0x0100: 06 00\n");
+
+        assert_eq!(run(&mut debugger, &["d 1", "c 100"]),
+            "Ran for 100 cycles without stopping\n=>     8:  FF CF          rjmp 0x8\n");
+        assert_eq!(run(&mut debugger, &["sreg"]), "SREG = 0x00  I=0 T=0 H=0 S=0 V=0 N=0 Z=0 C=0\n");
+        assert_eq!(run(&mut debugger, &["stack"]), "Stack is empty (SP = 0x08FF)\n");
+        assert_eq!(run(&mut debugger, &["io SPL"]), "SPL = 0xFF  0b11111111\n");
+        // Extended IO registers are named too, UDR isn't read as that would clear RXC
+        assert_eq!(run(&mut debugger, &["io ucsr0a", "io UDR0"]),
+            "ucsr0a = 0x20  0b00100000\nUDR0 isn't read, reading it has side effects\n");
+        let output = run(&mut debugger, &["io"]);
+        assert!(output.contains("\nSPL      0x5D = 0xFF  0b11111111\n"), "{}", output);
+        assert!(output.contains("\nUCSR0A   0xC0 = 0x20  0b00100000\n"), "{}", output);
+        assert!(!output.contains("UDR0") && !output.contains("ADCL"), "{}", output);
+        assert_eq!(run(&mut debugger, &["w read UCSR0A"]), "2: read watchpoint at 0x00C0 <UCSR0A>\n");
+        assert_eq!(run(&mut debugger, &["d 2"]), "");
+        assert_eq!(debugger.mcu().take_unmodeled_accesses(), []);
+        assert_eq!(run(&mut debugger, &["foo", "b 0x3", "uart"]),
+            "Unknown command: foo, try help\nInvalid flash byte address: 0x3\nNo UART terminal is connected\n");
+        assert!(!debugger.execute("quit", &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn symbols() {
+        let elf = build_elf(
+            &[0xFF, 0xCF],
+            &[(".text", &[])],
+            &[("counter", 0x800100, 2, 1), ("table", 0x0002, 4, 1), ("settings", 0x810000, 8, 1)]);
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_elf_image(ElfImage::parse(&elf).unwrap()).unwrap();
+        let mut board = Board::without_vcd(16e6);
+        let mcu = board.add_component_clocked(mcu, "mcu", &VcdConfig::Disable);
+        let mut debugger = Debugger::<Atmega328P>::new(&mut board, mcu.id());
+
+        assert_eq!(run(&mut debugger, &["x counter", "x table", "watch settings"]),
+            "0x0100: 00 00\ntable is in flash, not in data memory\nsettings is in EEPROM, not in data memory\n");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape("a\\n\\x00\\\\").unwrap(), b"a\n\x00\\");
+        assert!(unescape("\\x0").is_err());
+        assert_eq!(escape(b"a\n\x00"), "a\\n\\x00");
+    }
+}
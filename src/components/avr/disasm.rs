@@ -0,0 +1,303 @@
+//! AVR disassembler, working on raw flash images.
+
+use std::fmt;
+
+use super::{mcu_model::McuModel, atdf::DeviceDescription, bit_helpers::is_two_word, instruction::{Instruction, Pointer, PointerMode}};
+
+/// A single disassembled instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassembledInstruction {
+    /// Word address of the instruction.
+    pub addr: u32,
+    pub opcode: u16,
+    /// Second word of a two-word instruction.
+    pub operand: Option<u16>,
+    /// Mnemonic with operands.
+    pub text: String,
+}
+
+impl DisassembledInstruction {
+    /// Returns the instruction size in words.
+    #[inline]
+    pub fn size(&self) -> u32 {
+        if self.operand.is_some() {2} else {1}
+    }
+}
+
+/// Formats as an `avr-objdump` like line, with byte addresses.
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:5X}:  {:02X} {:02X} ", self.addr << 1, self.opcode as u8, self.opcode >> 8)?;
+        match self.operand {
+            Some(x) => write!(f, "{:02X} {:02X}", x as u8, x >> 8)?,
+            None => write!(f, "     ")?,
+        }
+        write!(f, "    {}", self.text)
+    }
+}
+
+/// Device properties used for formatting operands.
+struct Names<'a> {
+    /// Flash size in words, relative jumps wrap around it.
+    flash_size: u32,
+    io_register_name: &'a dyn Fn(u8) -> Option<String>,
+}
+
+/// Disassembles a single instruction at a word address.
+///
+/// Words outside of the flash image are read as erased (0xFFFF).
+pub fn disassemble_at<M: McuModel>(flash: &[u16], addr: u32) -> DisassembledInstruction {
+    let names = Names {
+        flash_size: M::flash_size() as u32,
+        io_register_name: &|a| M::io_register_name(a).map(str::to_string),
+    };
+    disassemble_with(flash, addr, &names)
+}
+
+/// Disassembles a single instruction at a word address, naming IO registers from an ATDF description.
+pub fn disassemble_at_device(flash: &[u16], addr: u32, device: &DeviceDescription) -> DisassembledInstruction {
+    let names = Names {
+        flash_size: device.flash_size() as u32,
+        io_register_name: &|a| device.io_register_name(a),
+    };
+    disassemble_with(flash, addr, &names)
+}
+
+fn disassemble_with(flash: &[u16], addr: u32, names: &Names) -> DisassembledInstruction {
+    let word = |addr: u32| flash.get(addr as usize).copied().unwrap_or(0xFFFF);
+    let opcode = word(addr);
+    let operand = if is_two_word(opcode) {Some(word(addr + 1))} else {None};
+    let instr = Instruction::decode(opcode, operand.unwrap_or(0));
+    DisassembledInstruction {
+        addr,
+        opcode,
+        operand,
+        text: format_instruction(instr, opcode, addr, names),
+    }
+}
+
+/// Disassembles a whole flash image, one instruction after another.
+pub fn disassemble<M: McuModel>(flash: &[u16]) -> Vec<DisassembledInstruction> {
+    let mut result = Vec::new();
+    let mut addr = 0;
+    while (addr as usize) < flash.len() {
+        let instr = disassemble_at::<M>(flash, addr);
+        addr += instr.size();
+        result.push(instr);
+    }
+    result
+}
+
+const SET_FLAG: [&str; 8] = ["sec", "sez", "sen", "sev", "ses", "seh", "set", "sei"];
+const CLEAR_FLAG: [&str; 8] = ["clc", "clz", "cln", "clv", "cls", "clh", "clt", "cli"];
+const BRANCH_SET: [&str; 8] = ["brcs", "breq", "brmi", "brvs", "brlt", "brhs", "brts", "brie"];
+const BRANCH_CLEAR: [&str; 8] = ["brcc", "brne", "brpl", "brvc", "brge", "brhc", "brtc", "brid"];
+
+fn io(names: &Names, a: u8) -> String {
+    match (names.io_register_name)(a) {
+        Some(name) => name,
+        None => format!("0x{:02X}", a),
+    }
+}
+
+/// Formats a byte address of a relative jump target.
+fn target(names: &Names, addr: u32, k: i32) -> String {
+    let target = addr.wrapping_add_signed(k + 1) % names.flash_size;
+    format!("0x{:X}", target << 1)
+}
+
+fn pointer(ptr: Pointer, mode: PointerMode) -> String {
+    let name = match ptr {
+        Pointer::X => "X",
+        Pointer::Y => "Y",
+        Pointer::Z => "Z",
+    };
+    match mode {
+        PointerMode::Unchanged => name.to_string(),
+        PointerMode::PostIncrement => format!("{}+", name),
+        PointerMode::PreDecrement => format!("-{}", name),
+    }
+}
+
+fn displacement(ptr: Pointer, q: u8) -> String {
+    let name = pointer(ptr, PointerMode::Unchanged);
+    if q == 0 {name} else {format!("{}+{}", name, q)}
+}
+
+fn format_instruction(instr: Instruction, opcode: u16, addr: u32, names: &Names) -> String {
+    use Instruction::*;
+
+    match instr {
+        Nop => "nop".to_string(),
+        Movw { d, r } => format!("movw r{}, r{}", d, r),
+        Muls { d, r } => format!("muls r{}, r{}", d, r),
+        Mulsu { d, r } => format!("mulsu r{}, r{}", d, r),
+        Fmul { d, r } => format!("fmul r{}, r{}", d, r),
+        Fmuls { d, r } => format!("fmuls r{}, r{}", d, r),
+        Fmulsu { d, r } => format!("fmulsu r{}, r{}", d, r),
+        Cpc { d, r } => format!("cpc r{}, r{}", d, r),
+        Sbc { d, r } => format!("sbc r{}, r{}", d, r),
+        Add { d, r } => format!("add r{}, r{}", d, r),
+        Cpse { d, r } => format!("cpse r{}, r{}", d, r),
+        Cp { d, r } => format!("cp r{}, r{}", d, r),
+        Sub { d, r } => format!("sub r{}, r{}", d, r),
+        Adc { d, r } => format!("adc r{}, r{}", d, r),
+        And { d, r } => format!("and r{}, r{}", d, r),
+        Eor { d, r } => format!("eor r{}, r{}", d, r),
+        Or { d, r } => format!("or r{}, r{}", d, r),
+        Mov { d, r } => format!("mov r{}, r{}", d, r),
+        Mul { d, r } => format!("mul r{}, r{}", d, r),
+
+        Cpi { d, k } => format!("cpi r{}, 0x{:02X}", d, k),
+        Sbci { d, k } => format!("sbci r{}, 0x{:02X}", d, k),
+        Subi { d, k } => format!("subi r{}, 0x{:02X}", d, k),
+        Ori { d, k } => format!("ori r{}, 0x{:02X}", d, k),
+        Andi { d, k } => format!("andi r{}, 0x{:02X}", d, k),
+        Ldi { d, k } => format!("ldi r{}, 0x{:02X}", d, k),
+        Adiw { d, k } => format!("adiw r{}, 0x{:02X}", d, k),
+        Sbiw { d, k } => format!("sbiw r{}, 0x{:02X}", d, k),
+
+        Ldd { d, ptr, q: 0 } => format!("ld r{}, {}", d, displacement(ptr, 0)),
+        Ldd { d, ptr, q } => format!("ldd r{}, {}", d, displacement(ptr, q)),
+        Std { ptr, q: 0, r } => format!("st {}, r{}", displacement(ptr, 0), r),
+        Std { ptr, q, r } => format!("std {}, r{}", displacement(ptr, q), r),
+        Ld { d, ptr, mode } => format!("ld r{}, {}", d, pointer(ptr, mode)),
+        St { ptr, mode, r } => format!("st {}, r{}", pointer(ptr, mode), r),
+        Lds { d, k } => format!("lds r{}, 0x{:04X}", d, k),
+        Sts { k, r } => format!("sts 0x{:04X}, r{}", k, r),
+        Lpm { post_increment: None, .. } => "lpm".to_string(),
+        Lpm { d, post_increment: Some(inc) } => format!("lpm r{}, Z{}", d, if inc {"+"} else {""}),
+        Elpm { post_increment: None, .. } => "elpm".to_string(),
+        Elpm { d, post_increment: Some(inc) } => format!("elpm r{}, Z{}", d, if inc {"+"} else {""}),
+        Spm => "spm".to_string(),
+        Pop { d } => format!("pop r{}", d),
+        Push { r } => format!("push r{}", r),
+        In { d, a } => format!("in r{}, {}", d, io(names, a)),
+        Out { a, r } => format!("out {}, r{}", io(names, a), r),
+
+        Com { d } => format!("com r{}", d),
+        Neg { d } => format!("neg r{}", d),
+        Swap { d } => format!("swap r{}", d),
+        Inc { d } => format!("inc r{}", d),
+        Dec { d } => format!("dec r{}", d),
+        Asr { d } => format!("asr r{}", d),
+        Lsr { d } => format!("lsr r{}", d),
+        Ror { d } => format!("ror r{}", d),
+        Bset { s } => SET_FLAG[s as usize].to_string(),
+        Bclr { s } => CLEAR_FLAG[s as usize].to_string(),
+        Bld { d, b } => format!("bld r{}, {}", d, b),
+        Bst { d, b } => format!("bst r{}, {}", d, b),
+        Sbi { a, b } => format!("sbi {}, {}", io(names, a), b),
+        Cbi { a, b } => format!("cbi {}, {}", io(names, a), b),
+
+        Rjmp { k } => format!("rjmp {}", target(names, addr, k as i32)),
+        Rcall { k } => format!("rcall {}", target(names, addr, k as i32)),
+        Jmp { k } => format!("jmp 0x{:X}", k << 1),
+        Call { k } => format!("call 0x{:X}", k << 1),
+        Ijmp => "ijmp".to_string(),
+        Eijmp => "eijmp".to_string(),
+        Icall => "icall".to_string(),
+        Eicall => "eicall".to_string(),
+        Ret => "ret".to_string(),
+        Reti => "reti".to_string(),
+        Sbrc { r, b } => format!("sbrc r{}, {}", r, b),
+        Sbrs { r, b } => format!("sbrs r{}, {}", r, b),
+        Sbic { a, b } => format!("sbic {}, {}", io(names, a), b),
+        Sbis { a, b } => format!("sbis {}, {}", io(names, a), b),
+        Brbs { s, k } => format!("{} {}", BRANCH_SET[s as usize], target(names, addr, k as i32)),
+        Brbc { s, k } => format!("{} {}", BRANCH_CLEAR[s as usize], target(names, addr, k as i32)),
+
+        Sleep => "sleep".to_string(),
+        Break => "break".to_string(),
+        Wdr => "wdr".to_string(),
+
+        Reserved => format!(".word 0x{:04X}", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::{mcu_model::Atmega2560, atdf::Register};
+
+    use super::*;
+
+    fn text(flash: &[u16], addr: u32) -> String {
+        disassemble_at::<Atmega2560>(flash, addr).text
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(text(&[0x0C01], 0), "add r0, r1");
+        assert_eq!(text(&[0xEF0F], 0), "ldi r16, 0xFF");
+        assert_eq!(text(&[0x01FC], 0), "movw r30, r24");
+        assert_eq!(text(&[0x921D], 0), "st X+, r1");
+        assert_eq!(text(&[0x910A], 0), "ld r16, -Y");
+        assert_eq!(text(&[0x8100], 0), "ld r16, Z");
+        assert_eq!(text(&[0xA9EA], 0), "ldd r30, Y+50");
+        assert_eq!(text(&[0x9300, 0x0200], 0), "sts 0x0200, r16");
+        assert_eq!(text(&[0x95C8], 0), "lpm");
+        assert_eq!(text(&[0x9005], 0), "lpm r0, Z+");
+        assert_eq!(text(&[0x94F8], 0), "cli");
+        assert_eq!(text(&[0x0001], 0), ".word 0x0001");
+    }
+
+    #[test]
+    fn io_registers() {
+        assert_eq!(text(&[0xB901], 0), "out DDRA, r16");
+        assert_eq!(text(&[0xB61F], 0), "in r1, SREG");
+        assert_eq!(text(&[0x9A2D], 0), "sbi PORTB, 5");
+        assert_eq!(text(&[0x9BB7], 0), "sbis TIFR1, 7");
+        assert_eq!(text(&[0xB429], 0), "in r2, 0x29");
+    }
+
+    #[test]
+    fn device_registers() {
+        let device = DeviceDescription {
+            name: "Test".to_string(),
+            architecture: "AVR8".to_string(),
+            family: "megaAVR".to_string(),
+            segments: vec![],
+            registers: vec![Register {
+                name: "TWBR".to_string(),
+                caption: String::new(),
+                instance: "TWI".to_string(),
+                addr: 0x30,
+                size: 1,
+                bitfields: vec![],
+            }],
+            interrupts: vec![],
+            signals: vec![],
+        };
+        assert_eq!(disassemble_at_device(&[0xBB00], 0, &device).text, "out TWBR, r16");
+        assert_eq!(disassemble_at_device(&[0xBB01], 0, &device).text, "out 0x11, r16");
+    }
+
+    #[test]
+    fn branch_targets() {
+        let mut flash = vec![0x0000; 0x20];
+        flash[0x10] = 0xCFFF; // rjmp .-2
+        flash[0x11] = 0xF7E9; // brne .-6
+        flash[0x12] = 0x940E; // call 0x1234
+        flash[0x13] = 0x091A;
+        flash[0x14] = 0xD00A; // rcall .+20
+
+        assert_eq!(text(&flash, 0x10), "rjmp 0x20");
+        assert_eq!(text(&flash, 0x11), "brne 0x1E");
+        assert_eq!(text(&flash, 0x12), "call 0x1234");
+        assert_eq!(text(&flash, 0x14), "rcall 0x3E");
+        assert_eq!(text(&[0xCFFE], 0), "rjmp 0x3FFFE");
+    }
+
+    #[test]
+    fn whole_image() {
+        let flash = [0x940C, 0x0004, 0x0000, 0x0000, 0xE50A, 0xCFFF];
+        let lines: Vec<_> = disassemble::<Atmega2560>(&flash).iter().map(|i| i.to_string()).collect();
+        assert_eq!(lines, [
+            "    0:  0C 94 04 00    jmp 0x8",
+            "    4:  00 00          nop",
+            "    6:  00 00          nop",
+            "    8:  0A E5          ldi r16, 0x5A",
+            "    A:  FF CF          rjmp 0xA",
+        ]);
+    }
+}
@@ -0,0 +1,450 @@
+//! DWARF `.debug_line` parser, mapping flash addresses to source lines.
+//!
+//! Supports DWARF versions 2 to 5, as produced by avr-gcc.
+
+use super::elf::ElfError;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0B;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1E;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0E;
+const DW_FORM_LINE_STRP: u64 = 0x1F;
+const DW_FORM_UDATA: u64 = 0x0F;
+
+/// A range of flash byte addresses generated from a single source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: u32,
+    /// First address after the range.
+    pub end: u32,
+    /// Index into [LineTable::files].
+    pub file: usize,
+    pub line: u32,
+}
+
+/// Source lines of a firmware image, from the DWARF line number program.
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    /// Source file paths.
+    pub files: Vec<String>,
+    /// Address ranges, sorted by start address.
+    pub ranges: Vec<LineRange>,
+}
+
+impl LineTable {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Finds the source file and line of a flash byte address.
+    pub fn find(&self, addr: u32) -> Option<(&str, u32)> {
+        let end = self.ranges.partition_point(|r| r.start <= addr);
+        self.ranges[..end].iter().rev()
+            .find(|r| addr < r.end)
+            .map(|r| (self.files[r.file].as_str(), r.line))
+    }
+
+    /// Adds a file path, returning its index.
+    fn add_file(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+/// Section contents with a read position.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ElfError> {
+        let end = self.pos.checked_add(len).ok_or(ElfError::Format("bad .debug_line length"))?;
+        let bytes = self.data.get(self.pos..end).ok_or(ElfError::Format("unexpected end of .debug_line"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ElfError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ElfError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    /// Reads a little-endian unsigned number of 1 to 8 bytes.
+    fn uint(&mut self, size: usize) -> Result<u64, ElfError> {
+        let b = self.bytes(size)?;
+        Ok(b.iter().rev().fold(0, |acc, &x| acc << 8 | x as u64))
+    }
+
+    fn uleb(&mut self) -> Result<u64, ElfError> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                result |= ((b & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, ElfError> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                result |= ((b & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Reads a zero-terminated string.
+    fn str(&mut self) -> Result<String, ElfError> {
+        let tail = self.data.get(self.pos..).ok_or(ElfError::Format("unexpected end of .debug_line"))?;
+        let len = tail.iter().position(|&b| b == 0).ok_or(ElfError::Format("unterminated string"))?;
+        self.pos += len + 1;
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+}
+
+/// Reads a zero-terminated string at an offset of a string section.
+fn section_str(section: Option<&[u8]>, offset: u64) -> Result<String, ElfError> {
+    let section = section.ok_or(ElfError::Format("missing DWARF string section"))?;
+    let mut cursor = Cursor {data: section, pos: offset as usize};
+    cursor.str()
+}
+
+/// Sections referenced by DWARF 5 file name tables.
+#[derive(Clone, Copy, Default)]
+pub struct StringSections<'a> {
+    /// `.debug_str` contents.
+    pub str: Option<&'a [u8]>,
+    /// `.debug_line_str` contents.
+    pub line_str: Option<&'a [u8]>,
+}
+
+/// Line number program header fields needed to run it.
+struct Header {
+    min_instruction_length: u8,
+    line_base: i8,
+    line_range: u8,
+    opcode_base: u8,
+    standard_opcode_lengths: Vec<u8>,
+    /// File paths, by their index in the program.
+    files: Vec<Option<usize>>,
+}
+
+/// Joins a directory and a file name, unless the name is absolute.
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() || name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
+}
+
+/// Reads a DWARF 5 directory or file name table, returning paths and directory indices.
+fn read_entry_table(c: &mut Cursor, offset_size: usize, strings: StringSections) -> Result<Vec<(String, usize)>, ElfError> {
+    let format_count = c.u8()?;
+    let mut format = Vec::new();
+    for _ in 0..format_count {
+        format.push((c.uleb()?, c.uleb()?));
+    }
+    let count = c.uleb()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut dir = 0;
+        for &(content, form) in &format {
+            let mut text = None;
+            let mut number = 0;
+            match form {
+                DW_FORM_STRING => text = Some(c.str()?),
+                DW_FORM_LINE_STRP => text = Some(section_str(strings.line_str, c.uint(offset_size)?)?),
+                DW_FORM_STRP => text = Some(section_str(strings.str, c.uint(offset_size)?)?),
+                DW_FORM_UDATA => number = c.uleb()?,
+                DW_FORM_DATA1 => number = c.uint(1)?,
+                DW_FORM_DATA2 => number = c.uint(2)?,
+                DW_FORM_DATA4 => number = c.uint(4)?,
+                DW_FORM_DATA8 => number = c.uint(8)?,
+                DW_FORM_DATA16 => {
+                    c.bytes(16)?;
+                }
+                DW_FORM_BLOCK => {
+                    let len = c.uleb()? as usize;
+                    c.bytes(len)?;
+                }
+                _ => return Err(ElfError::Format("unsupported DWARF form in .debug_line")),
+            }
+            match content {
+                DW_LNCT_PATH => path = text.ok_or(ElfError::Format("bad DWARF file path"))?,
+                DW_LNCT_DIRECTORY_INDEX => dir = number as usize,
+                _ => {}
+            }
+        }
+        entries.push((path, dir));
+    }
+    Ok(entries)
+}
+
+fn read_header(c: &mut Cursor, version: u16, offset_size: usize, strings: StringSections, table: &mut LineTable) -> Result<Header, ElfError> {
+    if version >= 5 {
+        // Address and segment selector sizes
+        c.bytes(2)?;
+    }
+    let header_length = c.uint(offset_size)? as usize;
+    let program_start = c.pos.checked_add(header_length).ok_or(ElfError::Format("bad .debug_line header length"))?;
+    let min_instruction_length = c.u8()?;
+    if version >= 4 {
+        // Maximum operations per instruction, only used by VLIW targets
+        c.u8()?;
+    }
+    // Default `is_stmt`, all rows are used
+    c.u8()?;
+    let line_base = c.u8()? as i8;
+    let line_range = c.u8()?;
+    if line_range == 0 {
+        return Err(ElfError::Format("bad .debug_line header"));
+    }
+    let opcode_base = c.u8()?;
+    let standard_opcode_lengths = c.bytes(opcode_base.saturating_sub(1) as usize)?.to_vec();
+
+    let mut files = Vec::new();
+    if version >= 5 {
+        let dirs = read_entry_table(c, offset_size, strings)?;
+        for (name, dir) in read_entry_table(c, offset_size, strings)? {
+            let dir = dirs.get(dir).map_or("", |(d, _)| d.as_str());
+            files.push(Some(table.add_file(join_path(dir, &name))));
+        }
+    } else {
+        let mut dirs = vec![String::new()];
+        loop {
+            let dir = c.str()?;
+            if dir.is_empty() {
+                break;
+            }
+            dirs.push(dir);
+        }
+        // File indices start from 1
+        files.push(None);
+        loop {
+            let name = c.str()?;
+            if name.is_empty() {
+                break;
+            }
+            let dir = c.uleb()? as usize;
+            c.uleb()?;
+            c.uleb()?;
+            let dir = dirs.get(dir).map_or("", String::as_str);
+            files.push(Some(table.add_file(join_path(dir, &name))));
+        }
+    }
+    c.pos = program_start;
+    Ok(Header {
+        min_instruction_length,
+        line_base,
+        line_range,
+        opcode_base,
+        standard_opcode_lengths,
+        files,
+    })
+}
+
+/// A row of the line number matrix.
+#[derive(Clone, Copy)]
+struct Row {
+    addr: u32,
+    file: Option<usize>,
+    line: u32,
+}
+
+/// Runs a line number program until `end`, adding its rows as address ranges.
+fn run_program(c: &mut Cursor, end: usize, header: &mut Header, version: u16, table: &mut LineTable) -> Result<(), ElfError> {
+    let initial_file = if version >= 5 {0} else {1};
+    let mut addr = 0u32;
+    let mut file = initial_file;
+    let mut line = 1i64;
+    let mut prev: Option<Row> = None;
+
+    // A row ends the range of the previous one
+    let mut emit = |addr: u32, file: u64, line: i64, end_sequence: bool, table: &mut LineTable, files: &[Option<usize>]| {
+        if let Some(p) = prev.take() {
+            if let Some(file) = p.file {
+                if p.line != 0 && addr > p.addr {
+                    table.ranges.push(LineRange {start: p.addr, end: addr, file, line: p.line});
+                }
+            }
+        }
+        if !end_sequence {
+            prev = Some(Row {
+                addr,
+                file: files.get(file as usize).copied().flatten(),
+                line: line.clamp(0, u32::MAX as i64) as u32,
+            });
+        }
+    };
+
+    let min_length = header.min_instruction_length as u32;
+    while c.pos < end {
+        let opcode = c.u8()?;
+        if opcode >= header.opcode_base {
+            let adjusted = opcode - header.opcode_base;
+            addr = addr.wrapping_add((adjusted / header.line_range) as u32 * min_length);
+            line += header.line_base as i64 + (adjusted % header.line_range) as i64;
+            emit(addr, file, line, false, table, &header.files);
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = c.uleb()? as usize;
+                let start = c.pos;
+                if len == 0 {
+                    continue;
+                }
+                match c.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        emit(addr, file, line, true, table, &header.files);
+                        addr = 0;
+                        file = initial_file;
+                        line = 1;
+                    }
+                    DW_LNE_SET_ADDRESS => addr = c.uint((len - 1).min(8))? as u32,
+                    DW_LNE_DEFINE_FILE => {
+                        let name = c.str()?;
+                        let index = table.add_file(name);
+                        header.files.push(Some(index));
+                    }
+                    _ => {}
+                }
+                c.pos = start.checked_add(len).ok_or(ElfError::Format("bad .debug_line opcode length"))?;
+            }
+            DW_LNS_COPY => emit(addr, file, line, false, table, &header.files),
+            DW_LNS_ADVANCE_PC => addr = addr.wrapping_add(c.uleb()? as u32 * min_length),
+            DW_LNS_ADVANCE_LINE => line += c.sleb()?,
+            DW_LNS_SET_FILE => file = c.uleb()?,
+            DW_LNS_CONST_ADD_PC => {
+                addr = addr.wrapping_add(((255 - header.opcode_base) / header.line_range) as u32 * min_length);
+            }
+            DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(c.u16()? as u32),
+            _ => {
+                // Other standard opcodes only change registers which aren't used
+                for _ in 0..header.standard_opcode_lengths[opcode as usize - 1] {
+                    c.uleb()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses `.debug_line` section contents, with all its compilation units.
+pub fn parse_debug_line(data: &[u8], strings: StringSections) -> Result<LineTable, ElfError> {
+    let mut table = LineTable::default();
+    let mut c = Cursor {data, pos: 0};
+    while c.pos < data.len() {
+        let (unit_length, offset_size) = match c.uint(4)? {
+            0xFFFFFFFF => (c.uint(8)?, 8),
+            length => (length, 4),
+        };
+        let end = c.pos.checked_add(unit_length as usize)
+            .filter(|&end| end <= data.len())
+            .ok_or(ElfError::Format("bad .debug_line unit length"))?;
+        let version = c.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(ElfError::Format("unsupported DWARF version"));
+        }
+        let mut header = read_header(&mut c, version, offset_size, strings, &mut table)?;
+        run_program(&mut c, end, &mut header, version, &mut table)?;
+        c.pos = end;
+    }
+    table.ranges.sort_by_key(|r| r.start);
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Line programs of the same x86-64 program built by gcc, to be checked against `readelf --debug-dump=decodedline`.
+    const PROGRAM: &str = "050e000902291100000000000013050875051267050e7505016705102f050e83050574050e000204032f050b000204039e\
+        051e00020403d50517000204014a050c680501670202000101";
+    const DWARF3_HEADER: &str = "6a00000003001a0000000101fb0e0d00010101010000000100000100612e630000000000";
+    const DWARF5_HEADER: &str = "7c000000050008002a000000010101fb0e0d00010101010000000100000101011f010000000002011f020f02080000000008\
+        00000000";
+
+    fn check(table: &LineTable, file: &str) {
+        assert_eq!(table.files, [file]);
+        assert_eq!(table.ranges.len(), 14);
+        assert_eq!(table.ranges[0], LineRange {start: 0x1129, end: 0x1130, file: 0, line: 2});
+        assert_eq!(table.ranges[13], LineRange {start: 0x117E, end: 0x1180, file: 0, line: 11});
+        assert_eq!(table.find(0x1158), Some((file, 9)));
+        assert_eq!(table.find(0x1172), Some((file, 8)));
+        assert_eq!(table.find(0x1180), None);
+    }
+
+    #[test]
+    fn gcc_line_tables() {
+        let dwarf3 = hex(&(DWARF3_HEADER.to_string() + PROGRAM));
+        check(&parse_debug_line(&dwarf3, StringSections::default()).unwrap(), "a.c");
+
+        let dwarf5 = hex(&(DWARF5_HEADER.to_string() + PROGRAM));
+        let line_str = b"/tmp/dw\0a.c\0";
+        let strings = StringSections {str: None, line_str: Some(line_str)};
+        check(&parse_debug_line(&dwarf5, strings).unwrap(), "/tmp/dw/a.c");
+        assert!(parse_debug_line(&dwarf5, StringSections::default()).is_err());
+        assert!(parse_debug_line(&dwarf5[..40], strings).is_err());
+    }
+
+    #[test]
+    fn huge_lengths() {
+        // 64-bit unit with a header length reaching past the address space
+        let unit = hex("ffffffff0a000000000000000300ffffffffffffffff");
+        assert!(matches!(parse_debug_line(&unit, StringSections::default()), Err(ElfError::Format(_))));
+
+        // Extended opcode with a length of 2^64 - 1
+        let mut dwarf3 = hex(&(DWARF3_HEADER.to_string() + "00ffffffffffffffffff0180"));
+        dwarf3[0] = dwarf3.len() as u8 - 4;
+        assert!(matches!(parse_debug_line(&dwarf3, StringSections::default()), Err(ElfError::Format(_))));
+    }
+}
@@ -0,0 +1,438 @@
+//! ELF32 AVR firmware loader.
+
+use std::{fmt, fs, io};
+
+use super::dwarf::{self, LineTable, StringSections};
+
+/// AVR `e_machine` value.
+const EM_AVR: u16 = 83;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// Start of the data memory in AVR ELF address space, everything below is flash.
+pub const DATA_OFFSET: u32 = 0x800000;
+/// Start of the EEPROM in AVR ELF address space, following the data memory.
+pub const EEPROM_OFFSET: u32 = 0x810000;
+
+/// An error while loading an ELF file.
+#[derive(Debug)]
+pub enum ElfError {
+    Io(io::Error),
+    /// File is not a valid ELF32 little-endian AVR file.
+    Format(&'static str),
+    /// Flash image doesn't fit into the flash memory, with the last byte address of the image.
+    OutOfFlash(u32),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "{}", e),
+            ElfError::Format(s) => write!(f, "invalid ELF file: {}", s),
+            ElfError::OutOfFlash(addr) => write!(f, "byte address 0x{:X} is outside of flash memory", addr),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<io::Error> for ElfError {
+    fn from(e: io::Error) -> Self {
+        ElfError::Io(e)
+    }
+}
+
+/// A kind of an ELF symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+/// A named ELF symbol.
+///
+/// Function addresses are flash byte addresses, variable addresses are offset by [DATA_OFFSET].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+/// Symbols of a firmware image, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolTable {
+        symbols.sort_by_key(|s| s.addr);
+        SymbolTable { symbols }
+    }
+
+    /// Finds a symbol by its name.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// Finds a symbol of a given kind containing an address, returning it with the offset inside.
+    fn lookup(&self, addr: u32, kind: SymbolKind) -> Option<(&Symbol, u32)> {
+        let end = self.symbols.partition_point(|s| s.addr <= addr);
+        self.symbols[..end].iter().rev()
+            .filter(|s| s.kind == kind)
+            .find(|s| addr < s.addr.saturating_add(s.size.max(1)))
+            .map(|s| (s, addr - s.addr))
+    }
+
+    /// Finds a function containing a flash byte address.
+    pub fn function_at(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        self.lookup(addr, SymbolKind::Function)
+    }
+
+    /// Finds a variable containing a data memory address.
+    pub fn object_at(&self, addr: u16) -> Option<(&Symbol, u32)> {
+        self.lookup(DATA_OFFSET + addr as u32, SymbolKind::Object)
+    }
+
+    /// Formats a flash byte address as `function+offset`, if it is inside a function.
+    pub fn format_function(&self, addr: u32) -> Option<String> {
+        self.function_at(addr).map(|(s, offset)| match offset {
+            0 => s.name.clone(),
+            _ => format!("{}+0x{:X}", s.name, offset),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+}
+
+/// A firmware image loaded from an ELF file.
+#[derive(Debug, Clone, Default)]
+pub struct ElfImage {
+    /// Flash memory contents (`.text` and `.data` initializers), in words.
+    pub flash: Vec<u16>,
+    /// `.eeprom` section contents.
+    pub eeprom: Option<Vec<u8>>,
+    /// `.fuse` section contents, low fuse byte first.
+    pub fuses: Option<Vec<u8>>,
+    /// `.lock` section contents.
+    pub lock: Option<Vec<u8>>,
+    pub symbols: SymbolTable,
+    /// Source lines from `.debug_line`, empty if the file has no debug info.
+    pub lines: LineTable,
+    /// Why `.debug_line` couldn't be parsed, leaving `lines` empty.
+    pub line_error: Option<&'static str>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: u32, size: u32) -> Result<&'a [u8], ElfError> {
+        let start = offset as usize;
+        let end = start.checked_add(size as usize).ok_or(ElfError::Format("offset overflow"))?;
+        self.0.get(start..end).ok_or(ElfError::Format("unexpected end of file"))
+    }
+
+    fn u8(&self, offset: u32) -> Result<u8, ElfError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u32) -> Result<u16, ElfError> {
+        let b = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&self, offset: u32) -> Result<u32, ElfError> {
+        let b = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Gets a reader of a table entry, at least `size` bytes long.
+    fn entry(&self, table: u32, index: u32, entry_size: u32, size: u32) -> Result<Reader<'a>, ElfError> {
+        let offset = index.checked_mul(entry_size)
+            .and_then(|offset| offset.checked_add(table))
+            .ok_or(ElfError::Format("table entry out of file"))?;
+        Ok(Reader(self.bytes(offset, size)?))
+    }
+
+    /// Reads a zero-terminated string.
+    fn str(&self, offset: u32) -> Result<String, ElfError> {
+        let tail = self.0.get(offset as usize..).ok_or(ElfError::Format("string out of file"))?;
+        let len = tail.iter().position(|&b| b == 0).ok_or(ElfError::Format("unterminated string"))?;
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+}
+
+struct Section {
+    name: String,
+    kind: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+}
+
+impl ElfImage {
+    /// Reads an ELF file.
+    pub fn read(filename: &str) -> Result<ElfImage, ElfError> {
+        ElfImage::parse(&fs::read(filename)?)
+    }
+
+    /// Parses ELF file contents.
+    pub fn parse(data: &[u8]) -> Result<ElfImage, ElfError> {
+        let r = Reader(data);
+        if r.bytes(0, 4)? != b"\x7FELF" {
+            return Err(ElfError::Format("bad magic"));
+        }
+        if r.u8(4)? != 1 || r.u8(5)? != 1 {
+            return Err(ElfError::Format("not a 32-bit little-endian file"));
+        }
+        if r.u16(18)? != EM_AVR {
+            return Err(ElfError::Format("not an AVR file"));
+        }
+
+        let mut image = ElfImage::default();
+        let mut flash = Vec::new();
+
+        let phoff = r.u32(28)?;
+        let phentsize = r.u16(42)? as u32;
+        for i in 0..r.u16(44)? as u32 {
+            let ph = r.entry(phoff, i, phentsize, 32)?;
+            let paddr = ph.u32(12)?;
+            let filesz = ph.u32(16)?;
+            if ph.u32(0)? != PT_LOAD || filesz == 0 || paddr >= DATA_OFFSET {
+                continue;
+            }
+            let bytes = r.bytes(ph.u32(4)?, filesz)?;
+            let end = paddr.checked_add(filesz).ok_or(ElfError::Format("segment address overflow"))? as usize;
+            if flash.len() < end {
+                flash.resize(end, 0xFF);
+            }
+            flash[paddr as usize..end].copy_from_slice(bytes);
+        }
+        image.flash = flash.chunks(2)
+            .map(|c| c[0] as u16 | (*c.get(1).unwrap_or(&0xFF) as u16) << 8)
+            .collect();
+
+        let sections = read_sections(&r)?;
+        for section in sections.iter() {
+            let contents = || r.bytes(section.offset, section.size).map(|b| b.to_vec());
+            match section.name.as_str() {
+                ".eeprom" => image.eeprom = Some(contents()?),
+                ".fuse" => image.fuses = Some(contents()?),
+                ".lock" => image.lock = Some(contents()?),
+                _ => {}
+            }
+            if section.kind == SHT_SYMTAB {
+                let strtab = sections.get(section.link as usize)
+                    .ok_or(ElfError::Format("bad symbol string table"))?;
+                image.symbols = read_symbols(&r, section, strtab)?;
+            }
+        }
+
+        let section_data = |name: &str| sections.iter()
+            .find(|s| s.name == name)
+            .map(|s| r.bytes(s.offset, s.size))
+            .transpose();
+        if let Some(debug_line) = section_data(".debug_line")? {
+            let strings = StringSections {
+                str: section_data(".debug_str")?,
+                line_str: section_data(".debug_line_str")?,
+            };
+            // Line info is only used by lcov reports, so the firmware can run without it
+            match dwarf::parse_debug_line(debug_line, strings) {
+                Ok(lines) => image.lines = lines,
+                Err(ElfError::Format(e)) => image.line_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+fn read_sections(r: &Reader) -> Result<Vec<Section>, ElfError> {
+    let shoff = r.u32(32)?;
+    let shentsize = r.u16(46)? as u32;
+    let shnum = r.u16(48)? as u32;
+    let shstrndx = r.u16(50)? as u32;
+    if shoff == 0 {
+        return Ok(Vec::new());
+    }
+
+    let names = r.entry(shoff, shstrndx, shentsize, 40)?.u32(16)?;
+    (0..shnum).map(|i| {
+        let sh = r.entry(shoff, i, shentsize, 40)?;
+        let name = names.checked_add(sh.u32(0)?).ok_or(ElfError::Format("section name out of file"))?;
+        Ok(Section {
+            name: r.str(name)?,
+            kind: sh.u32(4)?,
+            offset: sh.u32(16)?,
+            size: sh.u32(20)?,
+            link: sh.u32(24)?,
+        })
+    }).collect()
+}
+
+fn read_symbols(r: &Reader, symtab: &Section, strtab: &Section) -> Result<SymbolTable, ElfError> {
+    let mut symbols = Vec::new();
+    for i in 0..symtab.size / 16 {
+        let sym = r.entry(symtab.offset, i, 16, 16)?;
+        let name = strtab.offset.checked_add(sym.u32(0)?).ok_or(ElfError::Format("symbol name out of file"))?;
+        let name = r.str(name)?;
+        if name.is_empty() {
+            continue;
+        }
+        let kind = match sym.u8(12)? & 0xF {
+            STT_FUNC => SymbolKind::Function,
+            STT_OBJECT => SymbolKind::Object,
+            0 => SymbolKind::Other,
+            _ => continue,
+        };
+        symbols.push(Symbol {
+            name,
+            addr: sym.u32(4)?,
+            size: sym.u32(8)?,
+            kind,
+        });
+    }
+    Ok(SymbolTable::new(symbols))
+}
+
+#[cfg(test)]
+pub mod test_helper {
+    /// Builds a minimal AVR ELF file with a flash segment, sections and symbols.
+    pub fn build_elf(flash: &[u8], sections: &[(&str, &[u8])], symbols: &[(&str, u32, u32, u8)]) -> Vec<u8> {
+        let mut shstrtab = vec![0u8];
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 16];
+        for &(name, addr, size, kind) in symbols {
+            let mut sym = Vec::new();
+            sym.extend((strtab.len() as u32).to_le_bytes());
+            sym.extend(addr.to_le_bytes());
+            sym.extend(size.to_le_bytes());
+            sym.extend([kind, 0, 1, 0]);
+            symtab.extend(sym);
+            strtab.extend(name.bytes().chain([0]));
+        }
+
+        let mut all_sections: Vec<(&str, u32, &[u8], u32)> = vec![("", 0, &[], 0)];
+        all_sections.extend(sections.iter().map(|&(name, data)| (name, 1, data, 0)));
+        let symtab_index = all_sections.len();
+        all_sections.push((".symtab", 2, &symtab, symtab_index as u32 + 1));
+        all_sections.push((".strtab", 3, &strtab, 0));
+        all_sections.push((".shstrtab", 3, &[], 0));
+
+        let mut data = vec![0u8; 52 + 32];
+        data.extend(flash);
+        let mut headers = Vec::new();
+        for (i, &(name, kind, contents, link)) in all_sections.iter().enumerate() {
+            let name_offset = shstrtab.len() as u32;
+            shstrtab.extend(name.bytes().chain([0]));
+            let contents = if i == all_sections.len() - 1 {&shstrtab[..]} else {contents};
+            let offset = data.len() as u32;
+            data.extend(contents);
+            let mut sh = Vec::new();
+            for x in [name_offset, kind, 0, 0, offset, contents.len() as u32, link, 0, 1, 0] {
+                sh.extend(x.to_le_bytes());
+            }
+            headers.push(sh);
+        }
+        let shoff = data.len() as u32;
+        data.extend(headers.concat());
+
+        data[0..7].copy_from_slice(b"\x7FELF\x01\x01\x01");
+        data[16..20].copy_from_slice(&[2, 0, 83, 0]);
+        data[28..32].copy_from_slice(&52u32.to_le_bytes());
+        data[32..36].copy_from_slice(&shoff.to_le_bytes());
+        data[42..44].copy_from_slice(&32u16.to_le_bytes());
+        data[44..46].copy_from_slice(&1u16.to_le_bytes());
+        data[46..48].copy_from_slice(&40u16.to_le_bytes());
+        data[48..50].copy_from_slice(&(all_sections.len() as u16).to_le_bytes());
+        data[50..52].copy_from_slice(&(all_sections.len() as u16 - 1).to_le_bytes());
+        for (i, x) in [1, 84, 0, 0, flash.len() as u32, flash.len() as u32, 5, 1].into_iter().enumerate() {
+            data[52 + i * 4..56 + i * 4].copy_from_slice(&x.to_le_bytes());
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_helper::build_elf;
+
+    #[test]
+    fn load_image() {
+        let elf = build_elf(
+            &[0x0C, 0x94, 0x04, 0x00, 0xFF],
+            &[(".text", &[]), (".eeprom", &[1, 2, 3]), (".fuse", &[0xFF, 0xD8, 0xFD])],
+            &[
+                ("main", 0x0008, 0x10, STT_FUNC),
+                ("__vectors", 0x0000, 0x08, STT_FUNC),
+                ("counter", 0x800200, 2, STT_OBJECT),
+                ("main.c", 0, 0, 4),
+            ]);
+        let image = ElfImage::parse(&elf).unwrap();
+        assert_eq!(image.flash, [0x940C, 0x0004, 0xFFFF]);
+        assert_eq!(image.eeprom, Some(vec![1, 2, 3]));
+        assert_eq!(image.fuses, Some(vec![0xFF, 0xD8, 0xFD]));
+        assert_eq!(image.lock, None);
+
+        let symbols = &image.symbols;
+        assert_eq!(symbols.iter().count(), 3);
+        assert_eq!(symbols.get("main").unwrap().addr, 0x0008);
+        assert_eq!(symbols.format_function(0x0000).as_deref(), Some("__vectors"));
+        assert_eq!(symbols.format_function(0x000C).as_deref(), Some("main+0x4"));
+        assert_eq!(symbols.format_function(0x0018), None);
+        assert_eq!(symbols.object_at(0x0201).map(|(s, offset)| (s.name.as_str(), offset)), Some(("counter", 1)));
+        assert_eq!(symbols.object_at(0x0202), None);
+    }
+
+    #[test]
+    fn symbol_at_end_of_address_space() {
+        let symbols = SymbolTable::new(vec![Symbol {
+            name: "last".to_string(),
+            addr: 0xFFFF_FFF0,
+            size: 0x20,
+            kind: SymbolKind::Function,
+        }]);
+        assert_eq!(symbols.format_function(0xFFFF_FFFE).as_deref(), Some("last+0xE"));
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(matches!(ElfImage::parse(b"\x7FELF"), Err(ElfError::Format(_))));
+        assert!(matches!(ElfImage::parse(b"not an elf file at all"), Err(ElfError::Format("bad magic"))));
+
+        let mut elf = build_elf(&[], &[], &[]);
+        elf[18] = 3;
+        assert!(matches!(ElfImage::parse(&elf), Err(ElfError::Format("not an AVR file"))));
+
+        // Header offsets near the end of the address space
+        let mut elf = build_elf(&[0xFF, 0xCF], &[(".text", &[])], &[]);
+        elf[28..32].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        elf[44..46].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(ElfImage::parse(&elf), Err(ElfError::Format(_))));
+
+        let mut elf = build_elf(&[0xFF, 0xCF], &[(".text", &[])], &[]);
+        let shoff = u32::from_le_bytes(elf[32..36].try_into().unwrap()) as usize;
+        let shstrtab = shoff + u16::from_le_bytes([elf[50], elf[51]]) as usize * 40;
+        elf[shstrtab + 16..shstrtab + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(ElfImage::parse(&elf), Err(ElfError::Format("section name out of file"))));
+    }
+
+    #[test]
+    fn bad_debug_line() {
+        let elf = build_elf(&[0xFF, 0xCF], &[(".text", &[]), (".debug_line", &[0x10, 0, 0, 0, 6, 0])], &[]);
+        let image = ElfImage::parse(&elf).unwrap();
+        assert_eq!(image.flash, [0xCFFF]);
+        assert!(image.lines.is_empty());
+        assert_eq!(image.line_error, Some("bad .debug_line unit length"));
+    }
+}
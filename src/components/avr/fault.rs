@@ -0,0 +1,49 @@
+use std::fmt;
+
+/// A kind of an unrecoverable CPU error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFaultKind {
+    /// Opcode is reserved or not supported.
+    IllegalOpcode,
+    /// PC points outside of the flash memory.
+    PcOutOfFlash,
+    /// Stack has grown below the start of SRAM, into IO space or the register file.
+    StackOverflow,
+    /// Access to a nonexistent internal IO register.
+    InvalidIoRegister(u8),
+    /// LPM or SPM access to a flash section protected by lock bits.
+    LockBitViolation,
+}
+
+/// An unrecoverable CPU error, stopping the MCU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFault {
+    pub kind: CpuFaultKind,
+    /// Word address of the faulting instruction.
+    pub pc: u32,
+    /// Opcode of the faulting instruction.
+    pub opcode: u16,
+    /// Number of CPU cycles executed before the fault.
+    pub cycle: u64,
+}
+
+impl fmt::Display for CpuFaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuFaultKind::IllegalOpcode => write!(f, "illegal opcode"),
+            CpuFaultKind::PcOutOfFlash => write!(f, "PC out of flash memory"),
+            CpuFaultKind::StackOverflow => write!(f, "stack overflow"),
+            CpuFaultKind::InvalidIoRegister(i) => write!(f, "invalid IO register 0x{:02X}", i),
+            CpuFaultKind::LockBitViolation => write!(f, "flash access protected by lock bits"),
+        }
+    }
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte address 0x{:05X} (opcode 0x{:04X}, cycle {})",
+            self.kind, self.pc << 1, self.opcode, self.cycle)
+    }
+}
+
+impl std::error::Error for CpuFault {}
@@ -0,0 +1,226 @@
+//! Fuse and lock bytes of AVR MCUs.
+
+use super::mcu_model::{McuModel, FuseDesc, ClockSource};
+
+/// Fuse and lock bytes of an MCU. Like on a real part, a programmed bit is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    pub extended: u8,
+    pub lock: u8,
+
+    desc: &'static FuseDesc,
+    /// Flash size in words.
+    flash_size: u32,
+}
+
+/// Returns `true` if `bit` of `byte` is programmed.
+#[inline]
+fn programmed(byte: u8, bit: u8) -> bool {
+    byte & (1 << bit) == 0
+}
+
+impl Fuses {
+    /// Creates default fuses of a model with unprogrammed lock bits.
+    pub fn new<M: McuModel>() -> Fuses {
+        let desc = M::fuse_layout();
+        Fuses {
+            low: desc.defaults[0],
+            high: desc.defaults[1],
+            extended: desc.defaults[2],
+            lock: 0xFF,
+            desc,
+            flash_size: M::flash_size() as u32,
+        }
+    }
+
+    /// Creates fuses from `.fuse` and `.lock` ELF section contents.
+    ///
+    /// Fuse bytes are low, high and extended, missing bytes keep their defaults.
+    pub fn from_sections<M: McuModel>(fuses: &[u8], lock: Option<u8>) -> Fuses {
+        let mut result = Fuses::new::<M>();
+        for (i, &val) in fuses.iter().take(3).enumerate() {
+            *result.byte_mut(i) = val;
+        }
+        if let Some(lock) = lock {
+            result.lock = lock;
+        }
+        result
+    }
+
+    /// Gets a fuse byte by index (0 is low, 1 is high, 2 is extended).
+    pub fn byte(&self, i: usize) -> u8 {
+        match i {
+            0 => self.low,
+            1 => self.high,
+            _ => self.extended,
+        }
+    }
+
+    fn byte_mut(&mut self, i: usize) -> &mut u8 {
+        match i {
+            0 => &mut self.low,
+            1 => &mut self.high,
+            _ => &mut self.extended,
+        }
+    }
+
+    /// CKDIV8 fuse, dividing the system clock by 8 after reset.
+    #[inline]
+    pub fn ckdiv8(&self) -> bool {
+        programmed(self.low, 7)
+    }
+
+    /// Clock source selected by CKSEL3:0 fuses.
+    #[inline]
+    pub fn clock_source(&self) -> ClockSource {
+        self.desc.clock_sources[(self.low & 0x0F) as usize]
+    }
+
+    /// Frequency of the selected clock source, given a frequency of the external clock.
+    ///
+    /// Returns `None` for reserved CKSEL values, in which case the MCU doesn't run.
+    pub fn clock_frequency(&self, external: f64) -> Option<f64> {
+        match self.clock_source() {
+            ClockSource::External => Some(external),
+            ClockSource::Internal(freq) => Some(freq as f64),
+            ClockSource::Reserved => None,
+        }
+    }
+
+    /// WDTON fuse, keeping the watchdog always on in system reset mode.
+    #[inline]
+    pub fn wdton(&self) -> bool {
+        programmed(self.high, 4)
+    }
+
+    /// Brown-out detection level in volts selected by BODLEVEL2:0 fuses, `None` if disabled.
+    pub fn bod_level(&self) -> Option<f64> {
+        match self.byte(self.desc.bodlevel_byte) & 0x07 {
+            0b110 => Some(1.8),
+            0b101 => Some(2.7),
+            0b100 => Some(4.3),
+            _ => None,
+        }
+    }
+
+    /// Boot loader section size in words selected by BOOTSZ1:0 fuses, `None` if there is no boot section.
+    pub fn boot_size(&self) -> Option<u32> {
+        self.desc.boot_sizes.map(|sizes| sizes[((self.high >> 1) & 0x03) as usize])
+    }
+
+    /// Word address of the boot loader section start, `None` if there is no boot section.
+    pub fn boot_start(&self) -> Option<u32> {
+        self.boot_size().map(|size| self.flash_size - size)
+    }
+
+    /// BOOTRST fuse, moving the reset vector to the boot loader section.
+    #[inline]
+    pub fn boot_reset(&self) -> bool {
+        self.desc.boot_sizes.is_some() && programmed(self.high, 0)
+    }
+
+    /// Word address the CPU starts from after reset.
+    pub fn reset_vector(&self) -> u32 {
+        if self.boot_reset() {self.boot_start().unwrap_or(0)} else {0}
+    }
+
+    /// Returns `true` if word address `addr` is in the boot loader section.
+    pub fn in_boot_section(&self, addr: u32) -> bool {
+        self.boot_start().is_some_and(|start| addr >= start)
+    }
+
+    /// Returns `true` if SPM instruction executed at word address `pc` is able to work.
+    ///
+    /// SPM only works from the boot loader section, or when SELFPRGEN fuse is programmed
+    /// on parts without one.
+    pub fn spm_executable(&self, pc: u32) -> bool {
+        if self.desc.boot_sizes.is_some() {
+            return self.in_boot_section(pc);
+        }
+        match self.desc.self_programming {
+            Some((byte, bit)) => programmed(self.byte(byte), bit),
+            None => true,
+        }
+    }
+
+    /// Returns `true` if lock bits allow SPM to write the page at word address `addr`.
+    ///
+    /// BLB11 protects the boot loader section, BLB01 protects the application section.
+    pub fn spm_allowed(&self, addr: u32) -> bool {
+        if self.desc.boot_sizes.is_none() {
+            return true;
+        }
+        let bit = if self.in_boot_section(addr) {4} else {2};
+        !programmed(self.lock, bit)
+    }
+
+    /// Returns `true` if lock bits allow LPM executed at word address `pc` to read byte address `addr`.
+    ///
+    /// BLB12 protects the boot loader section from the application, BLB02 protects
+    /// the application section from the boot loader.
+    pub fn lpm_allowed(&self, pc: u32, addr: u32) -> bool {
+        match (self.in_boot_section(pc), self.in_boot_section(addr >> 1)) {
+            (false, true) => !programmed(self.lock, 5),
+            (true, false) => !programmed(self.lock, 3),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::mcu_model::{Atmega2560, Atmega328P, Attiny85};
+
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let fuses = Fuses::new::<Atmega2560>();
+        assert!(!fuses.ckdiv8());
+        assert_eq!(fuses.clock_source(), ClockSource::External);
+        assert!(!fuses.wdton());
+        assert_eq!(fuses.bod_level(), None);
+        assert_eq!(fuses.reset_vector(), 0);
+        assert_eq!(fuses.boot_start(), Some(0x1F000));
+
+        let fuses = Fuses::new::<Attiny85>();
+        assert_eq!(fuses.boot_start(), None);
+        assert!(!fuses.spm_executable(0));
+    }
+
+    #[test]
+    fn boot_section() {
+        // BOOTSZ = 01, BOOTRST programmed
+        let fuses = Fuses::from_sections::<Atmega328P>(&[0x62, 0xDA], None);
+        assert!(fuses.ckdiv8());
+        assert_eq!(fuses.clock_source(), ClockSource::Internal(8_000_000));
+        assert_eq!(fuses.extended, 0xFF);
+        assert_eq!(fuses.boot_size(), Some(1024));
+        assert_eq!(fuses.reset_vector(), 0x3C00);
+        assert!(fuses.spm_executable(0x3C00));
+        assert!(!fuses.spm_executable(0x3BFF));
+    }
+
+    #[test]
+    fn lock_bits() {
+        // BLB02, BLB01 and BLB12 programmed
+        let fuses = Fuses::from_sections::<Atmega328P>(&[], Some(0xD3));
+        assert!(!fuses.spm_allowed(0));
+        assert!(fuses.spm_allowed(0x3F00));
+        assert!(!fuses.lpm_allowed(0, 0x7F00));
+        assert!(fuses.lpm_allowed(0, 0x100));
+        assert!(!fuses.lpm_allowed(0x3F00, 0x100));
+        assert!(fuses.lpm_allowed(0x3F00, 0x7F00));
+    }
+
+    #[test]
+    fn bod_and_tiny_fuses() {
+        let fuses = Fuses::from_sections::<Attiny85>(&[0xE1, 0xDD, 0xFE], None);
+        assert_eq!(fuses.clock_source(), ClockSource::Internal(16_000_000));
+        assert_eq!(fuses.bod_level(), Some(2.7));
+        assert!(fuses.spm_executable(0));
+        assert!(fuses.spm_allowed(0));
+    }
+}
@@ -0,0 +1,633 @@
+//! GDB remote serial protocol server for debugging firmware with `avr-gdb`.
+//!
+//! Connect with `target remote localhost:<port>`. While the CPU is stopped, the server blocks
+//! inside the [McuTicker](super::mcu_ticker::McuTicker) clock, so the whole board stops with it.
+
+use std::{io::{self, BufRead, BufReader, Write}, mem, net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr}};
+
+use super::{mcu::{Mcu, CpuState, debug::{Watchpoint, WatchKind}}, mcu_model::McuModel, io_controller::IoControllerTrait, elf::{DATA_OFFSET, EEPROM_OFFSET}};
+
+/// Number of steps between checks for a Ctrl-C or a new connection.
+const POLL_INTERVAL: u16 = 1024;
+
+/// What to do after handling a packet.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+}
+
+/// A GDB connection, buffered for reading.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// GDB server attached to a single MCU.
+pub struct GdbStub {
+    listener: TcpListener,
+    stream: Option<Connection>,
+    /// No connection was accepted yet, the first one is waited for.
+    waiting: bool,
+    /// Stop reply to send before the next step.
+    stop: Option<String>,
+    last_stop: String,
+    step: bool,
+    prev_state: CpuState,
+    poll_counter: u16,
+    /// New connections are accepted, cleared if the listener has failed.
+    listening: bool,
+    error: Option<io::Error>,
+}
+
+impl GdbStub {
+    /// Starts listening on an address. The CPU is stopped until GDB connects.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<GdbStub> {
+        Ok(GdbStub {
+            listener: TcpListener::bind(addr)?,
+            stream: None,
+            waiting: true,
+            stop: None,
+            last_stop: "S05".to_string(),
+            step: false,
+            prev_state: CpuState::Running,
+            poll_counter: 0,
+            listening: true,
+            error: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns `true` if GDB is connected.
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Gets the last socket error, which has detached GDB or stopped accepting connections.
+    ///
+    /// Socket errors never stop the simulation, the MCU runs on without GDB.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    fn connect(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        self.stream = Some(Connection {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        });
+        self.stop = Some("S05".to_string());
+        Ok(())
+    }
+
+    fn disconnect<M, Io>(&mut self, mcu: &mut Mcu<M, Io>)
+    where
+        M: McuModel + 'static,
+        Io: IoControllerTrait,
+    {
+        mcu.clear_debug_points();
+        mcu.resume();
+        self.stream = None;
+        self.stop = None;
+        self.step = false;
+        // Later connections are only polled for, without stopping the CPU
+        if let Err(e) = self.listener.set_nonblocking(true) {
+            self.error = Some(e);
+            self.listening = false;
+        }
+    }
+
+    /// Drops the connection after a socket error, GDB closing the connection isn't an error.
+    fn fail<M, Io>(&mut self, mcu: &mut Mcu<M, Io>, error: io::Error)
+    where
+        M: McuModel + 'static,
+        Io: IoControllerTrait,
+    {
+        if error.kind() != io::ErrorKind::UnexpectedEof {
+            self.error = Some(error);
+        }
+        self.disconnect(mcu);
+    }
+
+    /// Checks for a Ctrl-C from GDB or for a new connection, without blocking.
+    fn poll<M, Io>(&mut self, mcu: &mut Mcu<M, Io>)
+    where
+        M: McuModel + 'static,
+        Io: IoControllerTrait,
+    {
+        let Some(Connection {reader: stream, ..}) = &mut self.stream else {
+            if !self.listening {
+                return;
+            }
+            if let Ok((stream, _)) = self.listener.accept() {
+                if let Err(e) = self.connect(stream) {
+                    self.stream = None;
+                    self.error = Some(e);
+                }
+            }
+            return;
+        };
+        if stream.buffer().is_empty() {
+            if let Err(e) = stream.get_ref().set_nonblocking(true) {
+                return self.fail(mcu, e);
+            }
+            let result = stream.fill_buf().map(|buf| buf.len());
+            if let Err(e) = stream.get_ref().set_nonblocking(false) {
+                return self.fail(mcu, e);
+            }
+            match result {
+                Ok(0) => return self.disconnect(mcu),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => return self.fail(mcu, e),
+            }
+        }
+        let interrupted = stream.buffer().contains(&0x03);
+        let len = stream.buffer().len();
+        stream.consume(len);
+        if interrupted {
+            self.stop = Some("S02".to_string());
+        }
+    }
+
+    /// Called before every MCU step, serves GDB while the CPU is stopped.
+    pub fn before_step<M, Io>(&mut self, mcu: &mut Mcu<M, Io>)
+    where
+        M: McuModel + 'static,
+        Io: IoControllerTrait,
+    {
+        if self.waiting {
+            self.waiting = false;
+            let connected = self.listener.accept().and_then(|(stream, _)| self.connect(stream));
+            if let Err(e) = connected {
+                self.fail(mcu, e);
+            }
+            self.prev_state = mcu.state();
+        }
+        self.poll_counter = self.poll_counter.wrapping_add(1);
+        if self.poll_counter.is_multiple_of(POLL_INTERVAL) {
+            self.poll(mcu);
+        }
+        if self.stream.is_none() {
+            return;
+        }
+
+        // Breakpoints are checked before serving, so resuming from one executes its instruction
+        if self.stop.is_none() && mcu.state() == CpuState::Running && mcu.at_breakpoint() {
+            self.stop = Some("S05".to_string());
+        }
+        if let Some(reply) = self.stop.take() {
+            self.serve(mcu, reply);
+        }
+    }
+
+    /// Called after every MCU step, detects watchpoints, single steps and CPU stops.
+    pub fn after_step<M, Io>(&mut self, mcu: &mut Mcu<M, Io>)
+    where
+        M: McuModel + 'static,
+        Io: IoControllerTrait,
+    {
+        if self.stream.is_none() {
+            return;
+        }
+        let state = mcu.state();
+        if let Some((addr, kind)) = mcu.take_watch_hit() {
+            let name = match kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write => "watch",
+                WatchKind::Access => "awatch",
+            };
+            self.stop = Some(format!("T05{}:{:x};", name, DATA_OFFSET + addr as u32));
+        } else if mem::take(&mut self.step) {
+            self.stop = Some("S05".to_string());
+        } else if state != self.prev_state {
+            match state {
+                CpuState::Halted => self.stop = Some("S05".to_string()),
+                CpuState::Faulted(_) => self.stop = Some("S0B".to_string()),
+                _ => {}
+            }
+        }
+        self.prev_state = state;
+    }
+
+    /// Handles packets until GDB resumes the CPU or disconnects.
+    fn serve<M, Io>(&mut self, mcu: &mut Mcu<M, Io>, reply: String)
+    where
+        M: McuModel + 'static,
+        Io: IoControllerTrait,
+    {
+        self.last_stop = reply.clone();
+        let mut reply = Some(reply);
+        loop {
+            let Some(Connection {reader, writer}) = &mut self.stream else {
+                return;
+            };
+            if let Some(r) = reply.take() {
+                if let Err(e) = send_packet(writer, &r) {
+                    return self.fail(mcu, e);
+                }
+            }
+            let packet = match read_packet(reader, writer) {
+                Ok(packet) => packet,
+                Err(e) => return self.fail(mcu, e),
+            };
+            match handle_packet(mcu, &packet, &self.last_stop) {
+                Action::Reply(r) => reply = Some(r),
+                Action::Continue => {
+                    mcu.resume();
+                    return;
+                }
+                Action::Step => {
+                    mcu.resume();
+                    self.step = true;
+                    return;
+                }
+                Action::Detach => {
+                    let _ = send_packet(writer, "OK");
+                    return self.disconnect(mcu);
+                }
+            }
+        }
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |acc, x| acc.wrapping_add(x))
+}
+
+fn send_packet<W: Write>(stream: &mut W, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))
+}
+
+/// Reads a single packet, acknowledging it.
+fn read_packet<R: BufRead, W: Write>(stream: &mut R, ack: &mut W) -> io::Result<String> {
+    loop {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte)?;
+        if byte[0] != b'$' {
+            // Acknowledgements and late interrupts
+            continue;
+        }
+        let mut data = Vec::new();
+        stream.read_until(b'#', &mut data)?;
+        data.pop();
+        let mut cs = [0u8; 2];
+        stream.read_exact(&mut cs)?;
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&cs).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+        if expected == Some(checksum(&data)) {
+            ack.write_all(b"+")?;
+            return Ok(data);
+        }
+        ack.write_all(b"-")?;
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i+2).and_then(|x| u8::from_str_radix(x, 16).ok()))
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Parses `addr,len` (`addr,kind` for breakpoints).
+fn parse_addr_len(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn read_memory<M, Io>(mcu: &Mcu<M, Io>, addr: u32) -> Option<u8>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    match addr {
+        0..DATA_OFFSET => mcu.peek_flash_byte(addr),
+        DATA_OFFSET..EEPROM_OFFSET => Some(mcu.peek((addr - DATA_OFFSET) as u16)),
+        _ => mcu.read_eeprom(addr.checked_sub(EEPROM_OFFSET)?.try_into().ok()?),
+    }
+}
+
+fn write_memory<M, Io>(mcu: &mut Mcu<M, Io>, addr: u32, val: u8) -> Option<()>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    match addr {
+        0..DATA_OFFSET => mcu.poke_flash_byte(addr, val),
+        DATA_OFFSET..EEPROM_OFFSET => {
+            mcu.poke((addr - DATA_OFFSET) as u16, val);
+            Some(())
+        }
+        _ => mcu.write_eeprom(addr.checked_sub(EEPROM_OFFSET)?.try_into().ok()?, val),
+    }
+}
+
+/// Register `n` in `avr-gdb` numbering: r0-r31, SREG, SP, PC (byte address).
+fn read_gdb_register<M, Io>(mcu: &Mcu<M, Io>, n: u32) -> Option<Vec<u8>>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    match n {
+        0..=31 => Some(vec![mcu.read_register(n as u8)]),
+        32 => Some(vec![mcu.sreg()]),
+        33 => Some(mcu.sp().to_le_bytes().to_vec()),
+        34 => Some((mcu.pc() << 1).to_le_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+fn write_gdb_register<M, Io>(mcu: &mut Mcu<M, Io>, n: u32, val: &[u8]) -> Option<()>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    match (n, val) {
+        (0..=31, &[x]) => mcu.write_register(n as u8, x),
+        (32, &[x]) => mcu.set_sreg(x),
+        (33, &[a, b]) => mcu.set_sp(u16::from_le_bytes([a, b])),
+        (34, &[a, b, c, d]) => mcu.set_pc(u32::from_le_bytes([a, b, c, d]) >> 1),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Parses a `Z`/`z` packet body into a breakpoint word address or a watchpoint.
+fn parse_point(s: &str) -> Option<Result<u32, Watchpoint>> {
+    let (t, rest) = s.split_once(',')?;
+    let (addr, len) = parse_addr_len(rest)?;
+    let kind = match t {
+        "0" | "1" => return Some(Ok(addr >> 1)),
+        "2" => WatchKind::Write,
+        "3" => WatchKind::Read,
+        "4" => WatchKind::Access,
+        _ => return None,
+    };
+    if !(DATA_OFFSET..EEPROM_OFFSET).contains(&addr) {
+        return None;
+    }
+    Some(Err(Watchpoint {addr: (addr - DATA_OFFSET) as u16, len: len as u16, kind, value: None}))
+}
+
+fn handle_packet<M, Io>(mcu: &mut Mcu<M, Io>, packet: &str, last_stop: &str) -> Action
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    let ok = |x: Option<()>| Action::Reply(if x.is_some() {"OK"} else {"E01"}.to_string());
+    let Some(command) = packet.chars().next() else {
+        return Action::Reply(String::new());
+    };
+    let args = &packet[1..];
+    match command {
+        '?' => Action::Reply(last_stop.to_string()),
+        'g' => {
+            let regs: Vec<u8> = (0..35)
+                .flat_map(|n| read_gdb_register(mcu, n).unwrap_or_default())
+                .collect();
+            Action::Reply(encode_hex(&regs))
+        }
+        'G' => ok(decode_hex(args).filter(|regs| regs.len() == 39).map(|regs| {
+            for (i, &x) in regs[..32].iter().enumerate() {
+                mcu.write_register(i as u8, x);
+            }
+            mcu.set_sreg(regs[32]);
+            mcu.set_sp(u16::from_le_bytes([regs[33], regs[34]]));
+            mcu.set_pc(u32::from_le_bytes([regs[35], regs[36], regs[37], regs[38]]) >> 1);
+        })),
+        'p' => match parse_hex(args).and_then(|n| read_gdb_register(mcu, n)) {
+            Some(val) => Action::Reply(encode_hex(&val)),
+            None => Action::Reply("E01".to_string()),
+        },
+        'P' => ok((|| {
+            let (n, val) = args.split_once('=')?;
+            write_gdb_register(mcu, parse_hex(n)?, &decode_hex(val)?)
+        })()),
+        'm' => {
+            let bytes = parse_addr_len(args).and_then(|(addr, len)| {
+                (addr..addr.checked_add(len)?).map(|a| read_memory(mcu, a)).collect::<Option<Vec<u8>>>()
+            });
+            match bytes {
+                Some(bytes) => Action::Reply(encode_hex(&bytes)),
+                None => Action::Reply("E01".to_string()),
+            }
+        }
+        'M' => ok((|| {
+            let (range, data) = args.split_once(':')?;
+            let (addr, len) = parse_addr_len(range)?;
+            let data = decode_hex(data)?;
+            if data.len() != len as usize {
+                return None;
+            }
+            for (a, &x) in (addr..addr.checked_add(len)?).zip(&data) {
+                write_memory(mcu, a, x)?;
+            }
+            Some(())
+        })()),
+        'c' | 's' => {
+            if let Some(addr) = parse_hex(args) {
+                mcu.set_pc(addr >> 1);
+            }
+            if command == 'c' {Action::Continue} else {Action::Step}
+        }
+        'Z' => ok(parse_point(args).map(|point| match point {
+            Ok(addr) => mcu.add_breakpoint(addr),
+            Err(watchpoint) => mcu.add_watchpoint(watchpoint),
+        })),
+        'z' => ok(parse_point(args).map(|point| match point {
+            Ok(addr) => mcu.remove_breakpoint(addr),
+            Err(watchpoint) => mcu.remove_watchpoint(watchpoint),
+        })),
+        'D' | 'k' => Action::Detach,
+        'H' => Action::Reply("OK".to_string()),
+        'q' if args.starts_with("Supported") => Action::Reply("PacketSize=1000".to_string()),
+        'q' if args == "Attached" => Action::Reply("1".to_string()),
+        _ => Action::Reply(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::components::avr::mcu_model::Atmega2560;
+
+    use super::*;
+
+    fn reply(action: Action) -> String {
+        match action {
+            Action::Reply(r) => r,
+            a => panic!("Expected a reply, got {:?}", a),
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let mut input = io::Cursor::new(b"+$qAttached#8f$m0,2#fb".to_vec());
+        let mut acks = Vec::new();
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), "qAttached");
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), "m0,2");
+        assert!(read_packet(&mut input, &mut acks).is_err());
+
+        let mut input = io::Cursor::new(b"$g#00$g#67".to_vec());
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), "g");
+        assert_eq!(acks, b"++-+");
+
+        let mut output = Vec::new();
+        send_packet(&mut output, "OK").unwrap();
+        assert_eq!(output, b"$OK#9a");
+    }
+
+    #[test]
+    fn registers() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.write_register(1, 0xAB);
+        mcu.set_pc(0x10002);
+        let regs = reply(handle_packet(&mut mcu, "g", "S05"));
+        assert_eq!(regs.len(), 78);
+        assert_eq!(&regs[..4], "00ab");
+        assert_eq!(&regs[64..], "00ff2104000200");
+
+        assert_eq!(reply(handle_packet(&mut mcu, "P20=83", "S05")), "OK");
+        assert_eq!(mcu.sreg(), 0x83);
+        assert_eq!(reply(handle_packet(&mut mcu, "P22=08010000", "S05")), "OK");
+        assert_eq!(mcu.pc(), 0x84);
+        assert_eq!(reply(handle_packet(&mut mcu, "p21", "S05")), "ff21");
+        assert_eq!(reply(handle_packet(&mut mcu, "p23", "S05")), "E01");
+
+        let mut regs = regs.into_bytes();
+        regs[64..78].copy_from_slice(b"01fe1000010000");
+        let regs = String::from_utf8(regs).unwrap();
+        assert_eq!(reply(handle_packet(&mut mcu, &format!("G{}", regs), "S05")), "OK");
+        assert_eq!((mcu.sreg(), mcu.sp(), mcu.pc()), (0x01, 0x10FE, 0x80));
+    }
+
+    #[test]
+    fn memory() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.load_flash(&[0x940C, 0x0072]);
+        assert_eq!(reply(handle_packet(&mut mcu, "m1,3", "S05")), "947200");
+        assert_eq!(reply(handle_packet(&mut mcu, "m3fffe,4", "S05")), "E01");
+
+        assert_eq!(reply(handle_packet(&mut mcu, "M800300,2:1234", "S05")), "OK");
+        assert_eq!(mcu.peek(0x0301), 0x34);
+        assert_eq!(reply(handle_packet(&mut mcu, "m8002ff,3", "S05")), "001234");
+
+        assert_eq!(reply(handle_packet(&mut mcu, "M810ffe,1:5a", "S05")), "OK");
+        assert_eq!(reply(handle_packet(&mut mcu, "m810ffe,2", "S05")), "5aff");
+        assert_eq!(reply(handle_packet(&mut mcu, "m810fff,2", "S05")), "E01");
+
+        assert_eq!(reply(handle_packet(&mut mcu, "M2,2:ffcf", "S05")), "OK");
+        assert_eq!(mcu.peek_flash_byte(3), Some(0xCF));
+        assert_eq!(reply(handle_packet(&mut mcu, "Mfffffffe,3:010203", "S05")), "E01");
+    }
+
+    #[test]
+    fn points() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        assert_eq!(reply(handle_packet(&mut mcu, "Z0,4,2", "S05")), "OK");
+        mcu.set_pc(2);
+        assert!(mcu.at_breakpoint());
+        assert_eq!(reply(handle_packet(&mut mcu, "z0,4,2", "S05")), "OK");
+        assert!(!mcu.at_breakpoint());
+
+        assert_eq!(reply(handle_packet(&mut mcu, "Z2,800300,2", "S05")), "OK");
+        assert_eq!(reply(handle_packet(&mut mcu, "Z3,300,1", "S05")), "E01");
+        mcu.write(0x0301, 0);
+        assert_eq!(mcu.take_watch_hit(), Some((0x0301, WatchKind::Write)));
+        assert_eq!(reply(handle_packet(&mut mcu, "z2,800300,2", "S05")), "OK");
+        mcu.write(0x0301, 0);
+        assert_eq!(mcu.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn session() {
+        let mut stub = GdbStub::listen("127.0.0.1:0").unwrap();
+        let addr = stub.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut writer = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(writer.try_clone().unwrap());
+            let mut exchange = |packet: &str| {
+                if !packet.is_empty() {
+                    send_packet(&mut writer, packet).unwrap();
+                }
+                read_packet(&mut reader, &mut writer).unwrap()
+            };
+            let mut log = vec![exchange("")];
+            log.push(exchange("Z0,4,2"));
+            log.push(exchange("c"));
+            log.push(exchange("s"));
+            log.push(exchange("p22"));
+            log.push(exchange("Z2,800300,1"));
+            log.push(exchange("c"));
+            log.push(exchange("p22"));
+            log.push(exchange("D"));
+            log
+        });
+
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.load_flash(&[
+            0xE50A, // ldi r16, 0x5A
+            0x0000, // nop
+            0x0000, // nop
+            0x0000, // nop
+            0x9300, 0x0300, // sts 0x0300, r16
+            0xCFFF, // rjmp .-2
+        ]);
+        for _ in 0..20 {
+            stub.before_step(&mut mcu);
+            mcu.step();
+            stub.after_step(&mut mcu);
+        }
+        let log = client.join().unwrap();
+        assert_eq!(log, [
+            "S05", "OK", "S05", "S05", "06000000", "OK", "T05watch:800300;", "0c000000", "OK",
+        ]);
+        assert!(!stub.is_connected());
+        assert!(stub.error().is_none());
+        assert_eq!(mcu.pc(), 6);
+    }
+
+    #[test]
+    fn dropped_connection() {
+        let mut stub = GdbStub::listen("127.0.0.1:0").unwrap();
+        let addr = stub.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut writer = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(writer.try_clone().unwrap());
+            read_packet(&mut reader, &mut writer).unwrap()
+        });
+
+        // The MCU runs on without GDB after the connection is closed
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        for _ in 0..10 {
+            stub.before_step(&mut mcu);
+            mcu.step();
+            stub.after_step(&mut mcu);
+        }
+        assert_eq!(client.join().unwrap(), "S05");
+        assert!(!stub.is_connected());
+        assert!(stub.error().is_none());
+        assert_eq!(mcu.pc(), 10);
+    }
+}
@@ -0,0 +1,357 @@
+use bitfield::Bit;
+
+use super::bit_helpers::{get_rd_fields, get_d_field, get_k6, get_k8, get_io5, get_io6};
+
+/// Pointer register used by indirect loads and stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pointer {
+    X,
+    Y,
+    Z,
+}
+
+impl Pointer {
+    /// Index of the low register of the pointer register pair.
+    #[inline]
+    pub fn register(self) -> u8 {
+        match self {
+            Pointer::X => 26,
+            Pointer::Y => 28,
+            Pointer::Z => 30,
+        }
+    }
+}
+
+/// Pointer change done by an indirect load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerMode {
+    Unchanged,
+    PostIncrement,
+    PreDecrement,
+}
+
+/// A decoded AVR instruction with all the operands extracted.
+///
+/// Register operands are register indices, `k` are constants and addresses,
+/// `a` are IO register indices, `b` are bit indices and `s` are status register bit indices.
+/// Relative jumps and branches keep signed word offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Movw { d: u8, r: u8 },
+    Muls { d: u8, r: u8 },
+    Mulsu { d: u8, r: u8 },
+    Fmul { d: u8, r: u8 },
+    Fmuls { d: u8, r: u8 },
+    Fmulsu { d: u8, r: u8 },
+    Cpc { d: u8, r: u8 },
+    Sbc { d: u8, r: u8 },
+    Add { d: u8, r: u8 },
+    Cpse { d: u8, r: u8 },
+    Cp { d: u8, r: u8 },
+    Sub { d: u8, r: u8 },
+    Adc { d: u8, r: u8 },
+    And { d: u8, r: u8 },
+    Eor { d: u8, r: u8 },
+    Or { d: u8, r: u8 },
+    Mov { d: u8, r: u8 },
+    Mul { d: u8, r: u8 },
+
+    Cpi { d: u8, k: u8 },
+    Sbci { d: u8, k: u8 },
+    Subi { d: u8, k: u8 },
+    Ori { d: u8, k: u8 },
+    Andi { d: u8, k: u8 },
+    Ldi { d: u8, k: u8 },
+    Adiw { d: u8, k: u8 },
+    Sbiw { d: u8, k: u8 },
+
+    Ldd { d: u8, ptr: Pointer, q: u8 },
+    Std { ptr: Pointer, q: u8, r: u8 },
+    Ld { d: u8, ptr: Pointer, mode: PointerMode },
+    St { ptr: Pointer, mode: PointerMode, r: u8 },
+    Lds { d: u8, k: u16 },
+    Sts { k: u16, r: u8 },
+    /// `post_increment` is `None` for the implicit `R0, Z` form.
+    Lpm { d: u8, post_increment: Option<bool> },
+    /// `post_increment` is `None` for the implicit `R0, Z` form.
+    Elpm { d: u8, post_increment: Option<bool> },
+    Spm,
+    Pop { d: u8 },
+    Push { r: u8 },
+    In { d: u8, a: u8 },
+    Out { a: u8, r: u8 },
+
+    Com { d: u8 },
+    Neg { d: u8 },
+    Swap { d: u8 },
+    Inc { d: u8 },
+    Dec { d: u8 },
+    Asr { d: u8 },
+    Lsr { d: u8 },
+    Ror { d: u8 },
+    Bset { s: u8 },
+    Bclr { s: u8 },
+    Bld { d: u8, b: u8 },
+    Bst { d: u8, b: u8 },
+    Sbi { a: u8, b: u8 },
+    Cbi { a: u8, b: u8 },
+
+    Rjmp { k: i16 },
+    Rcall { k: i16 },
+    Jmp { k: u32 },
+    Call { k: u32 },
+    Ijmp,
+    Eijmp,
+    Icall,
+    Eicall,
+    Ret,
+    Reti,
+    Sbrc { r: u8, b: u8 },
+    Sbrs { r: u8, b: u8 },
+    Sbic { a: u8, b: u8 },
+    Sbis { a: u8, b: u8 },
+    Brbs { s: u8, k: i8 },
+    Brbc { s: u8, k: i8 },
+
+    Sleep,
+    Break,
+    Wdr,
+
+    /// A reserved opcode.
+    Reserved,
+}
+
+/// Extracts `d` and `r` register fields of a given size.
+#[inline]
+fn rd(opcode: u16, size: usize) -> (u8, u8) {
+    let (r, d) = get_rd_fields(opcode, size);
+    (d as u8, r as u8)
+}
+
+/// Extracts a 5-bit `d` register field.
+#[inline]
+fn d5(opcode: u16) -> u8 {
+    get_d_field(opcode, 5) as u8
+}
+
+/// Extracts a 3-bit bit index.
+#[inline]
+fn b3(opcode: u16) -> u8 {
+    (opcode & 0x0007) as u8
+}
+
+/// Sign-extends a `bits`-wide field.
+#[inline]
+fn signed(k: u16, bits: u32) -> i16 {
+    let shift = 16 - bits;
+    ((k << shift) as i16) >> shift
+}
+
+impl Instruction {
+    /// Decodes an opcode. `next` is the following flash word, used only by two-word instructions.
+    pub fn decode(opcode: u16, next: u16) -> Instruction {
+        use Instruction::*;
+
+        let head = (opcode >> 8) as u8;
+        match head {
+            0x00 => if opcode == 0x0000 {Nop} else {Reserved},
+            0x01 => Movw {
+                d: ((opcode >> 4) & 0xF) as u8 * 2,
+                r: (opcode & 0xF) as u8 * 2,
+            },
+            0x02 => {
+                let (d, r) = rd(opcode, 4);
+                Muls { d, r }
+            }
+            0x03 => {
+                let (d, r) = rd(opcode, 3);
+                match (opcode.bit(7), opcode.bit(3)) {
+                    (false, false) => Mulsu { d, r },
+                    (false, true) => Fmul { d, r },
+                    (true, false) => Fmuls { d, r },
+                    (true, true) => Fmulsu { d, r },
+                }
+            }
+            0x04..=0x2F | 0x9C..=0x9F => {
+                let (d, r) = rd(opcode, 5);
+                match head {
+                    0x04..=0x07 => Cpc { d, r },
+                    0x08..=0x0B => Sbc { d, r },
+                    0x0C..=0x0F => Add { d, r },
+                    0x10..=0x13 => Cpse { d, r },
+                    0x14..=0x17 => Cp { d, r },
+                    0x18..=0x1B => Sub { d, r },
+                    0x1C..=0x1F => Adc { d, r },
+                    0x20..=0x23 => And { d, r },
+                    0x24..=0x27 => Eor { d, r },
+                    0x28..=0x2B => Or { d, r },
+                    0x2C..=0x2F => Mov { d, r },
+                    _ => Mul { d, r },
+                }
+            }
+
+            0x30..=0x7F | 0xE0..=0xEF => {
+                let d = get_d_field(opcode, 4) as u8;
+                let k = get_k8(opcode);
+                match head {
+                    0x30..=0x3F => Cpi { d, k },
+                    0x40..=0x4F => Sbci { d, k },
+                    0x50..=0x5F => Subi { d, k },
+                    0x60..=0x6F => Ori { d, k },
+                    0x70..=0x7F => Andi { d, k },
+                    _ => Ldi { d, k },
+                }
+            }
+
+            0x80..=0x8F |
+            0xA0..=0xAF => {
+                let q = ((opcode >> 8) & 0x20 | (opcode >> 7) & 0x18 | opcode & 0x07) as u8;
+                let ptr = if opcode.bit(3) {Pointer::Y} else {Pointer::Z};
+                if head.bit(1) {
+                    Std { ptr, q, r: d5(opcode) }
+                } else {
+                    Ldd { d: d5(opcode), ptr, q }
+                }
+            }
+
+            0x90..=0x93 => {
+                let reg = d5(opcode);
+                let store = head.bit(1);
+                let (ptr, mode) = match opcode & 0x000F {
+                    0x0 => return if store {
+                        Sts { k: next, r: reg }
+                    } else {
+                        Lds { d: reg, k: next }
+                    },
+                    0x1 => (Pointer::Z, PointerMode::PostIncrement),
+                    0x2 => (Pointer::Z, PointerMode::PreDecrement),
+                    0x9 => (Pointer::Y, PointerMode::PostIncrement),
+                    0xA => (Pointer::Y, PointerMode::PreDecrement),
+                    0xC => (Pointer::X, PointerMode::Unchanged),
+                    0xD => (Pointer::X, PointerMode::PostIncrement),
+                    0xE => (Pointer::X, PointerMode::PreDecrement),
+                    0x4 | 0x5 if !store => return Lpm { d: reg, post_increment: Some(opcode.bit(0)) },
+                    0x6 | 0x7 if !store => return Elpm { d: reg, post_increment: Some(opcode.bit(0)) },
+                    0xF => return if store {Push { r: reg }} else {Pop { d: reg }},
+                    _ => return Reserved,
+                };
+                if store {
+                    St { ptr, mode, r: reg }
+                } else {
+                    Ld { d: reg, ptr, mode }
+                }
+            }
+
+            0x94 | 0x95 => {
+                let d = d5(opcode);
+                match opcode & 0x000F {
+                    0x0 => Com { d },
+                    0x1 => Neg { d },
+                    0x2 => Swap { d },
+                    0x3 => Inc { d },
+                    0x5 => Asr { d },
+                    0x6 => Lsr { d },
+                    0x7 => Ror { d },
+                    0xA => Dec { d },
+                    0x8 if head == 0x94 => {
+                        let s = ((opcode >> 4) & 0x7) as u8;
+                        if opcode.bit(7) {Bclr { s }} else {Bset { s }}
+                    }
+                    0x8 => match opcode {
+                        0x9508 => Ret,
+                        0x9518 => Reti,
+                        0x9588 => Sleep,
+                        0x9598 => Break,
+                        0x95A8 => Wdr,
+                        0x95C8 => Lpm { d: 0, post_increment: None },
+                        0x95D8 => Elpm { d: 0, post_increment: None },
+                        0x95E8 => Spm,
+                        _ => Reserved,
+                    },
+                    0x9 => match opcode {
+                        0x9409 => Ijmp,
+                        0x9419 => Eijmp,
+                        0x9509 => Icall,
+                        0x9519 => Eicall,
+                        _ => Reserved,
+                    },
+                    0xC..=0xF => {
+                        let k = ((opcode as u32 >> 3) & 0x3E | opcode as u32 & 0x01) << 16 | next as u32;
+                        if opcode.bit(1) {Call { k }} else {Jmp { k }}
+                    }
+                    _ => Reserved,
+                }
+            }
+
+            0x96 | 0x97 => {
+                let d = get_d_field(opcode, 2) as u8;
+                let k = get_k6(opcode);
+                if head == 0x96 {Adiw { d, k }} else {Sbiw { d, k }}
+            }
+            0x98..=0x9B => {
+                let a = get_io5(opcode);
+                let b = b3(opcode);
+                match head {
+                    0x98 => Cbi { a, b },
+                    0x99 => Sbic { a, b },
+                    0x9A => Sbi { a, b },
+                    _ => Sbis { a, b },
+                }
+            }
+
+            0xB0..=0xB7 => In { d: d5(opcode), a: get_io6(opcode) },
+            0xB8..=0xBF => Out { a: get_io6(opcode), r: d5(opcode) },
+
+            0xC0..=0xCF => Rjmp { k: signed(opcode & 0x0FFF, 12) },
+            0xD0..=0xDF => Rcall { k: signed(opcode & 0x0FFF, 12) },
+
+            0xF0..=0xF7 => {
+                let k = signed((opcode >> 3) & 0x7F, 7) as i8;
+                let s = b3(opcode);
+                if head.bit(2) {Brbc { s, k }} else {Brbs { s, k }}
+            }
+            0xF8..=0xF9 => Bld { d: d5(opcode), b: b3(opcode) },
+            0xFA..=0xFB => Bst { d: d5(opcode), b: b3(opcode) },
+            0xFC..=0xFD => Sbrc { r: d5(opcode), b: b3(opcode) },
+            0xFE..=0xFF => Sbrs { r: d5(opcode), b: b3(opcode) },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_operands() {
+        assert_eq!(Instruction::decode(0x0C01, 0), Instruction::Add { d: 0, r: 1 });
+        assert_eq!(Instruction::decode(0xE5AF, 0), Instruction::Ldi { d: 26, k: 0x5F });
+        assert_eq!(Instruction::decode(0x9711, 0), Instruction::Sbiw { d: 26, k: 1 });
+        assert_eq!(Instruction::decode(0xA9EA, 0), Instruction::Ldd { d: 30, ptr: Pointer::Y, q: 0x32 });
+        assert_eq!(Instruction::decode(0x921D, 0), Instruction::St { ptr: Pointer::X, mode: PointerMode::PostIncrement, r: 1 });
+        assert_eq!(Instruction::decode(0x9100, 0x1234), Instruction::Lds { d: 16, k: 0x1234 });
+        assert_eq!(Instruction::decode(0x95C8, 0), Instruction::Lpm { d: 0, post_increment: None });
+        assert_eq!(Instruction::decode(0x9005, 0), Instruction::Lpm { d: 0, post_increment: Some(true) });
+        assert_eq!(Instruction::decode(0xB7F6, 0), Instruction::In { d: 31, a: 0x36 });
+        assert_eq!(Instruction::decode(0x94F8, 0), Instruction::Bclr { s: 7 });
+    }
+
+    #[test]
+    fn decode_jumps() {
+        assert_eq!(Instruction::decode(0xC123, 0), Instruction::Rjmp { k: 0x123 });
+        assert_eq!(Instruction::decode(0xDA99, 0), Instruction::Rcall { k: -0x567 });
+        assert_eq!(Instruction::decode(0x940F, 0x5678), Instruction::Call { k: 0x15678 });
+        assert_eq!(Instruction::decode(0xF7F9, 0), Instruction::Brbc { s: 1, k: -1 });
+        assert_eq!(Instruction::decode(0xF01C, 0), Instruction::Brbs { s: 4, k: 3 });
+    }
+
+    #[test]
+    fn decode_reserved() {
+        assert_eq!(Instruction::decode(0x0001, 0), Instruction::Reserved);
+        assert_eq!(Instruction::decode(0x9003, 0), Instruction::Reserved);
+        assert_eq!(Instruction::decode(0x9204, 0), Instruction::Reserved);
+        assert_eq!(Instruction::decode(0x940B, 0), Instruction::Reserved);
+        assert_eq!(Instruction::decode(0x95F8, 0), Instruction::Reserved);
+    }
+}
@@ -1,15 +1,27 @@
 mod gpio;
+mod timer8;
 mod timer16;
+mod pll_timer;
 mod uart;
-
-use std::marker::PhantomData;
+mod usi;
+mod xmem;
+mod sleep;
+mod watchdog;
+mod clock;
+mod reset;
+mod interrupts;
+
+use std::{marker::PhantomData, cell::Cell};
+use bitfield::Bit;
 use mockall::*;
 
 use crate::pins::{PinId, PinState};
 
-use self::{gpio::GpioPort, timer16::Timer16, uart::UartController};
+use self::{gpio::GpioPort, timer8::{Timer8, Timer8Interrupts}, timer16::{Timer16, Timer16Interrupts}, pll_timer::PllTimer, uart::{UartController, UsartInterrupts}, usi::{Usi, UsiPins}, xmem::{Xmem, XmemPins}, sleep::SleepController, watchdog::Watchdog, clock::ClockPrescaler, reset::ResetFlags, interrupts::InterruptController};
+
+pub use self::{sleep::SleepMode, interrupts::InterruptSource, reset::ResetSource};
 
-use super::mcu_model::McuModel;
+use super::{mcu_model::{McuModel, Peripherals}, snapshot::{StateWriter, StateReader, SnapshotError}};
 
 /// An AVR IO controller (can be mocked)
 #[automock]
@@ -22,6 +34,8 @@ pub trait IoControllerTrait: Send {
     fn write_internal_u8(&mut self, id: u8, val: u8);
     /// Writes to external IO port
     fn write_external_u8(&mut self, addr: u16, val: u8);
+    /// Returns `true` if reading an IO port changes IO state (like UDR clearing RXC, or recording an unmodeled access)
+    fn read_has_side_effects(&self, addr: u16) -> bool;
 
     /// Returns `true` if is on rising edge of the clock
     fn clock_rising_edge(&mut self);
@@ -39,456 +53,686 @@ pub trait IoControllerTrait: Send {
     /// Get output pin changes (by filling a [HashMap])
     fn get_output_changes(&mut self) -> &[(PinId, PinState)];
 
-    fn has_interrupt(&self) -> bool;
-    fn get_interrupt_address(&mut self) -> Option<u16>;
-
-    fn timer1(&self) -> &Timer16;
-    fn timer3(&self) -> &Timer16;
-    fn timer4(&self) -> &Timer16;
-    fn timer5(&self) -> &Timer16;
+    /// Get the highest priority pending interrupt vector number
+    fn pending_interrupt(&self) -> Option<u8>;
+    /// Notify IO about the CPU executing an interrupt vector (clears the interrupt flag)
+    fn acknowledge_interrupt(&mut self, vector: u8);
+
+    /// Get sleep mode selected in SMCR, or `None` if sleep is disabled
+    fn sleep_mode(&self) -> Option<SleepMode>;
+    /// Notify IO about the CPU entering (`Some`) or leaving (`None`) a sleep mode
+    fn set_sleep_mode(&mut self, mode: Option<SleepMode>);
+    /// Returns `true` if there is a pending interrupt able to wake the CPU from current sleep mode
+    fn has_wake_up_interrupt(&self) -> bool;
+
+    /// Reset watchdog counter (WDR instruction)
+    fn watchdog_reset(&mut self);
+    /// Returns `true` once if the watchdog has requested a system reset
+    fn take_watchdog_reset(&mut self) -> bool;
+    /// Set WDTON fuse, keeping the watchdog always on in system reset mode
+    fn set_watchdog_always_on(&mut self, always_on: bool);
+
+    /// Get current system clock division factor set in CLKPR
+    fn clock_division(&self) -> u16;
+    /// Reset the system clock prescaler, dividing by 8 if CKDIV8 fuse is programmed
+    fn reset_clock_prescaler(&mut self, ckdiv8: bool);
+    /// Set the effective CPU clock frequency in Hz, for peripherals with their own oscillators
+    fn set_cpu_frequency(&mut self, frequency: u32);
+
+    /// Return all peripherals to their reset state and record the reset source in MCUSR
+    fn reset(&mut self, source: ResetSource);
+
+    /// Get all 8-bit timers, in the order of the model description
+    fn timers8(&self) -> &[Timer8];
+    /// Get all 16-bit timers, in the order of the model description
+    fn timers16(&self) -> &[Timer16];
+    /// Get the PLL clocked timer (empty if the model doesn't have it)
+    fn pll_timer(&self) -> &[PllTimer];
+
+    /// Returns `true` if the external memory interface is enabled
+    fn xmem_enabled(&self) -> bool;
+    /// Get number of extra CPU cycles of an access to an external data address
+    fn xmem_access_cycles(&self, addr: u16) -> u8;
+    /// Get data sampled by an external memory read during the current instruction
+    fn xmem_read_data(&self, addr: u16) -> Option<u8>;
+    /// Starts an external memory read cycle, returns number of cycles until its data is sampled
+    fn xmem_start_read(&mut self, addr: u16) -> u8;
+    /// Starts an external memory write cycle
+    fn xmem_write(&mut self, addr: u16, val: u8);
+    /// Notify IO about the CPU finishing an instruction which accessed external memory
+    fn xmem_finish_instruction(&mut self);
+
+    /// Returns data addresses of IO registers accessed since the last call which are not modelled
+    fn take_unmodeled_accesses(&mut self) -> Vec<u16>;
+
+    /// Writes the state of all peripherals into a snapshot
+    fn save_state(&self, w: &mut StateWriter);
+    /// Restores the state of all peripherals from a snapshot, announcing all outputs on the next clock
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError>;
     
 }
+
+/// A 16-bit timer register, relative to TCCRnA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimerRegister {
+    Tccra,
+    Tccrb,
+    Tccrc,
+    Tcntl,
+    Tcnth,
+    Ocral,
+    Ocrah,
+    Ocrbl,
+    Ocrbh,
+    Ocrcl,
+    Ocrch,
+}
+
+/// An 8-bit timer register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timer8Register {
+    Tccra,
+    Tccrb,
+    Tcnt,
+    Ocra,
+    Ocrb,
+}
+
+/// A register of the PLL clocked timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PllTimerRegister {
+    Tccr,
+    Gtccr,
+    Tcnt,
+    Ocr(usize),
+    Pllcsr,
+}
+
+/// A USI register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UsiRegister {
+    Usibr,
+    Usidr,
+    Usisr,
+    Usicr,
+}
+
+/// An external memory interface register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XmemRegister {
+    Xmcra,
+    Xmcrb,
+}
+
+/// A USART register, relative to UCSRnA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UsartRegister {
+    Ucsra,
+    Ucsrb,
+    Ucsrc,
+    Ubrrl,
+    Ubrrh,
+    Udr,
+}
+
+/// An IO register in the data address space, with indices of its peripheral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoRegister {
+    Unmapped,
+    GpioPin(usize),
+    GpioDdr(usize),
+    GpioPort(usize),
+    Timer8(usize, Timer8Register),
+    Timer(usize, TimerRegister),
+    PllTimer(PllTimerRegister),
+    /// TIMSK, may be shared by several timers.
+    Timsk,
+    /// TIFR, may be shared by several timers.
+    Tifr,
+    Usart(usize, UsartRegister),
+    Usi(UsiRegister),
+    Xmem(XmemRegister),
+    Wdtcsr,
+    Clkpr,
+    Mcusr,
+    Sleep,
+}
+
+/// Builds the IO register map of a model, indexed by data address.
+fn build_io_map(peripherals: &Peripherals, size: usize) -> Vec<IoRegister> {
+    const TIMER8_REGISTERS: [Timer8Register; 5] = [
+        Timer8Register::Tccra, Timer8Register::Tccrb, Timer8Register::Tcnt, Timer8Register::Ocra, Timer8Register::Ocrb,
+    ];
+    const PLL_TIMER_REGISTERS: [PllTimerRegister; 7] = [
+        PllTimerRegister::Tccr, PllTimerRegister::Gtccr, PllTimerRegister::Tcnt,
+        PllTimerRegister::Ocr(0), PllTimerRegister::Ocr(1), PllTimerRegister::Ocr(2), PllTimerRegister::Pllcsr,
+    ];
+    const USI_REGISTERS: [UsiRegister; 4] = [UsiRegister::Usibr, UsiRegister::Usidr, UsiRegister::Usisr, UsiRegister::Usicr];
+    // OCRnC is only mapped for timers with three channels
+    const TIMER_REGISTERS: [(u16, TimerRegister); 11] = [
+        (0x0, TimerRegister::Tccra), (0x1, TimerRegister::Tccrb), (0x2, TimerRegister::Tccrc),
+        (0x4, TimerRegister::Tcntl), (0x5, TimerRegister::Tcnth),
+        (0x8, TimerRegister::Ocral), (0x9, TimerRegister::Ocrah),
+        (0xA, TimerRegister::Ocrbl), (0xB, TimerRegister::Ocrbh),
+        (0xC, TimerRegister::Ocrcl), (0xD, TimerRegister::Ocrch),
+    ];
+    const USART_REGISTERS: [(u16, UsartRegister); 6] = [
+        (0x0, UsartRegister::Ucsra), (0x1, UsartRegister::Ucsrb), (0x2, UsartRegister::Ucsrc),
+        (0x4, UsartRegister::Ubrrl), (0x5, UsartRegister::Ubrrh), (0x6, UsartRegister::Udr),
+    ];
+
+    let mut map = vec![IoRegister::Unmapped; size];
+    let mut set = |addr: u16, reg: IoRegister| {
+        let entry = &mut map[addr as usize];
+        // Only TIMSK and TIFR can be shared
+        let shared = *entry == reg && matches!(reg, IoRegister::Timsk | IoRegister::Tifr);
+        assert!(*entry == IoRegister::Unmapped || shared, "IO register 0x{:X} is defined twice", addr);
+        *entry = reg;
+    };
+    for (i, port) in peripherals.gpio.iter().enumerate() {
+        set(port.pin_addr, IoRegister::GpioPin(i));
+        set(port.pin_addr + 1, IoRegister::GpioDdr(i));
+        set(port.pin_addr + 2, IoRegister::GpioPort(i));
+    }
+    for (i, timer) in peripherals.timers8.iter().enumerate() {
+        for (addr, reg) in timer.regs.into_iter().zip(TIMER8_REGISTERS) {
+            set(addr, IoRegister::Timer8(i, reg));
+        }
+        set(timer.timsk, IoRegister::Timsk);
+        set(timer.tifr, IoRegister::Tifr);
+    }
+    for (i, timer) in peripherals.timers16.iter().enumerate() {
+        let registers = if timer.oc_pins.len() < 3 {&TIMER_REGISTERS[..9]} else {&TIMER_REGISTERS[..]};
+        for &(offset, reg) in registers {
+            set(timer.base + offset, IoRegister::Timer(i, reg));
+        }
+        set(timer.timsk, IoRegister::Timsk);
+        set(timer.tifr, IoRegister::Tifr);
+    }
+    if let Some(timer) = &peripherals.pll_timer {
+        for (addr, reg) in timer.regs.into_iter().zip(PLL_TIMER_REGISTERS) {
+            set(addr, IoRegister::PllTimer(reg));
+        }
+        set(timer.timsk, IoRegister::Timsk);
+        set(timer.tifr, IoRegister::Tifr);
+    }
+    for (i, usart) in peripherals.usarts.iter().enumerate() {
+        for (offset, reg) in USART_REGISTERS {
+            set(usart.base + offset, IoRegister::Usart(i, reg));
+        }
+    }
+    if let Some(usi) = &peripherals.usi {
+        for (addr, reg) in usi.regs.into_iter().zip(USI_REGISTERS) {
+            set(addr, IoRegister::Usi(reg));
+        }
+    }
+    if let Some(xmem) = &peripherals.xmem {
+        set(xmem.regs[0], IoRegister::Xmem(XmemRegister::Xmcra));
+        set(xmem.regs[1], IoRegister::Xmem(XmemRegister::Xmcrb));
+    }
+    set(peripherals.wdtcsr, IoRegister::Wdtcsr);
+    set(peripherals.clkpr, IoRegister::Clkpr);
+    set(peripherals.mcusr, IoRegister::Mcusr);
+    set(peripherals.sleep.addr, IoRegister::Sleep);
+    map
+}
+
 /// Main implementation for [IoControllerTrait]
+/// 
+/// Peripherals and their registers are taken from [McuModel::peripherals].
 pub struct IoController<M: McuModel> {
     model: PhantomData<M>,
     clock_pin: PinState,
 
     output_changes: Vec<(PinId, PinState)>,
-    interrupt: bool,
+    irq: InterruptController,
+    /// IO registers, indexed by data address.
+    io_map: Vec<IoRegister>,
+    /// Accesses to unmapped addresses of [IoController::io_map].
+    unmodeled_accesses: Vec<Cell<bool>>,
 
-    gpio: [GpioPort; 11],
+    gpio: Vec<GpioPort>,
 
     timer_prescaler: u16,
-
-    timer1: Timer16,
-    timer3: Timer16,
-    timer4: Timer16,
-    timer5: Timer16,
-
-    uart0: UartController,
-
-    gpio_pins: [(bool, PinState); 86],
+    timers8: Vec<Timer8>,
+    timers16: Vec<Timer16>,
+    pll_timer: Option<PllTimer>,
+
+    usarts: Vec<UartController>,
+    usi: Option<Usi>,
+    xmem: Option<Xmem>,
+
+    sleep: SleepController,
+    sleep_mode: Option<SleepMode>,
+    watchdog: Watchdog,
+    clock_prescaler: ClockPrescaler,
+    reset_flags: ResetFlags,
+
+    gpio_pins: Vec<(bool, PinState)>,
+    /// All outputs have to be pushed as changes after restoring a snapshot.
+    outputs_restored: bool,
 }
 
 impl<M: McuModel + 'static> IoController<M>{
     pub fn new() -> IoController<M> {
+        let peripherals = M::peripherals();
         IoController { 
             model: PhantomData,
             clock_pin: PinState::Low,
-            gpio: std::array::from_fn(|_| GpioPort::new()),
+            gpio: peripherals.gpio.iter().map(|_| GpioPort::new()).collect(),
             output_changes: Vec::with_capacity(8),
-            interrupt: false,
-            gpio_pins: [(true, PinState::Z); 86],
+            irq: InterruptController::new(),
+            io_map: build_io_map(peripherals, M::sram_start() as usize),
+            unmodeled_accesses: vec![Cell::new(false); M::sram_start() as usize],
+            gpio_pins: vec![(true, PinState::Z); peripherals.pin_count()],
             timer_prescaler: 0,
-            timer1: Timer16::new([Self::PIN_PB5, Self::PIN_PB6, Self::PIN_PB7]),
-            timer3: Timer16::new([Self::PIN_PE3, Self::PIN_PE4, Self::PIN_PE5]),
-            timer4: Timer16::new([Self::PIN_PH3, Self::PIN_PH4, Self::PIN_PH5]),
-            timer5: Timer16::new([Self::PIN_PL3, Self::PIN_PL4, Self::PIN_PL5]),
-            uart0: UartController::new(Self::PIN_PE2, Self::PIN_PE1),
+            timers8: peripherals.timers8.iter()
+                .map(|timer| Timer8::new(
+                    timer.oc_pins.map(|pin| peripherals.pin_id(pin)),
+                    Self::timer8_vectors(timer.index),
+                    timer.int_bits,
+                    timer.async_prescaler,
+                ))
+                .collect(),
+            timers16: peripherals.timers16.iter()
+                .map(|timer| Timer16::new(
+                    &timer.oc_pins.iter().map(|&pin| peripherals.pin_id(pin)).collect::<Vec<_>>(),
+                    Self::timer16_vectors(timer.index, timer.oc_pins.len()),
+                ))
+                .collect(),
+            pll_timer: peripherals.pll_timer.as_ref()
+                .map(|timer| PllTimer::new(
+                    timer.oc_pins.map(|pin| peripherals.pin_id(pin)),
+                    Self::timer8_vectors(timer.index),
+                    timer.int_bits,
+                )),
+            usarts: peripherals.usarts.iter()
+                .map(|usart| UartController::new(
+                    peripherals.pin_id(usart.xck_pin),
+                    peripherals.pin_id(usart.tx_pin),
+                    UsartInterrupts {
+                        rx: Self::vector(InterruptSource::UsartRx(usart.index)),
+                        udre: Self::vector(InterruptSource::UsartUdre(usart.index)),
+                        tx: Self::vector(InterruptSource::UsartTx(usart.index)),
+                    },
+                ))
+                .collect(),
+            usi: peripherals.usi.as_ref()
+                .map(|usi| Usi::new(
+                    UsiPins {
+                        di: peripherals.pin_id(usi.di_pin),
+                        do_: peripherals.pin_id(usi.do_pin),
+                        usck: peripherals.pin_id(usi.usck_pin),
+                    },
+                    Self::vector(InterruptSource::UsiStart),
+                    Self::vector(InterruptSource::UsiOverflow),
+                )),
+            xmem: peripherals.xmem.as_ref()
+                .map(|xmem| Xmem::new(XmemPins {
+                    ad: std::array::from_fn(|i| peripherals.pin_id((xmem.ad_port, i as u8))),
+                    high: std::array::from_fn(|i| peripherals.pin_id((xmem.high_port, i as u8))),
+                    ale: peripherals.pin_id(xmem.ale_pin),
+                    rd: peripherals.pin_id(xmem.rd_pin),
+                    wr: peripherals.pin_id(xmem.wr_pin),
+                })),
+            sleep: SleepController::new(peripherals.sleep),
+            sleep_mode: None,
+            watchdog: Watchdog::new(Self::vector(InterruptSource::Watchdog)),
+            clock_prescaler: ClockPrescaler::new(),
+            reset_flags: ResetFlags::new(peripherals.reset_flags),
+            outputs_restored: false,
         }
     }
-}
 
-impl<M: McuModel> IoController<M> {
-    const _PIN_PA0: PinId = 0;
-    const _PIN_PA1: PinId = 1;
-    const _PIN_PA2: PinId = 2;
-    const _PIN_PA3: PinId = 3;
-    const _PIN_PA4: PinId = 4;
-    const _PIN_PA5: PinId = 5;
-    const _PIN_PA6: PinId = 6;
-    const _PIN_PA7: PinId = 7;
-
-    const _PIN_PB0: PinId = 1*8 + 0;
-    const _PIN_PB1: PinId = 1*8 + 1;
-    const _PIN_PB2: PinId = 1*8 + 2;
-    const _PIN_PB3: PinId = 1*8 + 3;
-    const _PIN_PB4: PinId = 1*8 + 4;
-    const PIN_PB5: PinId = 1*8 + 5;
-    const PIN_PB6: PinId = 1*8 + 6;
-    const PIN_PB7: PinId = 1*8 + 7;
-
-    const _PIN_PC0: PinId = 2*8 + 0;
-    const _PIN_PC1: PinId = 2*8 + 1;
-    const _PIN_PC2: PinId = 2*8 + 2;
-    const _PIN_PC3: PinId = 2*8 + 3;
-    const _PIN_PC4: PinId = 2*8 + 4;
-    const _PIN_PC5: PinId = 2*8 + 5;
-    const _PIN_PC6: PinId = 2*8 + 6;
-    const _PIN_PC7: PinId = 2*8 + 7;
-
-    const _PIN_PD0: PinId = 3*8 + 0;
-    const _PIN_PD1: PinId = 3*8 + 1;
-    const _PIN_PD2: PinId = 3*8 + 2;
-    const _PIN_PD3: PinId = 3*8 + 3;
-    const _PIN_PD4: PinId = 3*8 + 4;
-    const _PIN_PD5: PinId = 3*8 + 5;
-    const _PIN_PD6: PinId = 3*8 + 6;
-    const _PIN_PD7: PinId = 3*8 + 7;
-
-    const _PIN_PE0: PinId = 4*8 + 0;
-    const PIN_PE1: PinId = 4*8 + 1;
-    const PIN_PE2: PinId = 4*8 + 2;
-    const PIN_PE3: PinId = 4*8 + 3;
-    const PIN_PE4: PinId = 4*8 + 4;
-    const PIN_PE5: PinId = 4*8 + 5;
-    const _PIN_PE6: PinId = 4*8 + 6;
-    const _PIN_PE7: PinId = 4*8 + 7;
-
-    const _PIN_PF0: PinId = 5*8 + 0;
-    const _PIN_PF1: PinId = 5*8 + 1;
-    const _PIN_PF2: PinId = 5*8 + 2;
-    const _PIN_PF3: PinId = 5*8 + 3;
-    const _PIN_PF4: PinId = 5*8 + 4;
-    const _PIN_PF5: PinId = 5*8 + 5;
-    const _PIN_PF6: PinId = 5*8 + 6;
-    const _PIN_PF7: PinId = 5*8 + 7;
-
-    const _PIN_PG0: PinId = 6*8 + 0;
-    const _PIN_PG1: PinId = 6*8 + 1;
-    const _PIN_PG2: PinId = 6*8 + 2;
-    const _PIN_PG3: PinId = 6*8 + 3;
-    const _PIN_PG4: PinId = 6*8 + 4;
-    const _PIN_PG5: PinId = 6*8 + 5;
-
-    const _PIN_PH0: PinId = 6*8 + 6 + 0;
-    const _PIN_PH1: PinId = 6*8 + 6 + 1;
-    const _PIN_PH2: PinId = 6*8 + 6 + 2;
-    const PIN_PH3: PinId = 6*8 + 6 + 3;
-    const PIN_PH4: PinId = 6*8 + 6 + 4;
-    const PIN_PH5: PinId = 6*8 + 6 + 5;
-    const _PIN_PH6: PinId = 6*8 + 6 + 6;
-    const _PIN_PH7: PinId = 6*8 + 6 + 7;
-
-    const _PIN_PJ0: PinId = 7*8 + 6 + 0;
-    const _PIN_PJ1: PinId = 7*8 + 6 + 1;
-    const _PIN_PJ2: PinId = 7*8 + 6 + 2;
-    const _PIN_PJ3: PinId = 7*8 + 6 + 3;
-    const _PIN_PJ4: PinId = 7*8 + 6 + 4;
-    const _PIN_PJ5: PinId = 7*8 + 6 + 5;
-    const _PIN_PJ6: PinId = 7*8 + 6 + 6;
-    const _PIN_PJ7: PinId = 7*8 + 6 + 7;
-
-    const _PIN_PK0: PinId = 8*8 + 6 + 0;
-    const _PIN_PK1: PinId = 8*8 + 6 + 1;
-    const _PIN_PK2: PinId = 8*8 + 6 + 2;
-    const _PIN_PK3: PinId = 8*8 + 6 + 3;
-    const _PIN_PK4: PinId = 8*8 + 6 + 4;
-    const _PIN_PK5: PinId = 8*8 + 6 + 5;
-    const _PIN_PK6: PinId = 8*8 + 6 + 6;
-    const _PIN_PK7: PinId = 8*8 + 6 + 7;
-
-    const _PIN_PL0: PinId = 9*8 + 6 + 0;
-    const _PIN_PL1: PinId = 9*8 + 6 + 1;
-    const _PIN_PL2: PinId = 9*8 + 6 + 2;
-    const PIN_PL3: PinId = 9*8 + 6 + 3;
-    const PIN_PL4: PinId = 9*8 + 6 + 4;
-    const PIN_PL5: PinId = 9*8 + 6 + 5;
-    const _PIN_PL6: PinId = 9*8 + 6 + 6;
-    const _PIN_PL7: PinId = 9*8 + 6 + 7;
-}
+    fn vector(source: InterruptSource) -> u8 {
+        M::interrupt_vector(source).expect("Interrupt source is not supported by the MCU model")
+    }
 
-fn update_changes(output_changes: &mut Vec<(PinId, PinState)>, gpio_bank: usize, changes: &[(PinId, PinState)], gpio_pins: &mut [(bool, PinState); 86]) {
-    const GPIO_STARTS: [u16; 11] = [0, 8, 16, 24, 32, 40, 48, 54, 62, 70, 78];
-    for &(pin_index, state) in changes {
-        let pin = GPIO_STARTS[gpio_bank] + pin_index;
-        if gpio_pins[pin as usize].0 {
-            output_changes.push((pin, state));
+    fn timer8_vectors(timer: u8) -> Timer8Interrupts<u8> {
+        Timer8Interrupts {
+            overflow: Self::vector(InterruptSource::TimerOverflow(timer)),
+            oc: std::array::from_fn(|i| Self::vector(InterruptSource::TimerCompare(timer, i as u8))),
         }
-        gpio_pins[pin as usize].1 = state;
     }
-}
 
-impl<M: McuModel + 'static> IoControllerTrait for IoController<M> {
-    fn read_internal_u8(&self, id: u8) -> u8 {
-        match id {
-            0x00 => self.gpio[0].read_pin(), // PINA
-            0x01 => self.gpio[0].read_ddr(), // DDRA
-            0x02 => self.gpio[0].read_port(), // PORTA
+    fn timer16_vectors(timer: u8, channels: usize) -> Timer16Interrupts<u8> {
+        Timer16Interrupts {
+            overflow: Self::vector(InterruptSource::TimerOverflow(timer)),
+            // Vectors of missing channels are never used
+            oc: std::array::from_fn(|i| if i < channels {Self::vector(InterruptSource::TimerCompare(timer, i as u8))} else {0}),
+            input_capture: Self::vector(InterruptSource::TimerCapture(timer)),
+        }
+    }
 
-            0x03 => self.gpio[1].read_pin(), // PINB
-            0x04 => self.gpio[1].read_ddr(), // DDRB
-            0x05 => self.gpio[1].read_port(), // PORTB
+    /// Remembers an access to a register which is not modelled.
+    fn unmodeled_access(&self, addr: u16) {
+        if let Some(accessed) = self.unmodeled_accesses.get(addr as usize) {
+            accessed.set(true);
+        }
+    }
 
-            0x06 => self.gpio[2].read_pin(), // PINC
-            0x07 => self.gpio[2].read_ddr(), // DDRC
-            0x08 => self.gpio[2].read_port(), // PORTC
+    fn read_u8(&self, addr: u16) -> u8 {
+        match self.io_map.get(addr as usize).copied().unwrap_or(IoRegister::Unmapped) {
+            IoRegister::Unmapped => {
+                self.unmodeled_access(addr);
+                0
+            }
+            IoRegister::GpioPin(i) => self.gpio[i].read_pin(),
+            IoRegister::GpioDdr(i) => self.gpio[i].read_ddr(),
+            IoRegister::GpioPort(i) => self.gpio[i].read_port(),
+            IoRegister::Timer8(i, reg) => {
+                let timer = &self.timers8[i];
+                match reg {
+                    Timer8Register::Tccra => timer.read_tccra(),
+                    Timer8Register::Tccrb => timer.read_tccrb(),
+                    Timer8Register::Tcnt => timer.read_tcnt(),
+                    Timer8Register::Ocra => timer.read_ocra(),
+                    Timer8Register::Ocrb => timer.read_ocrb(),
+                }
+            }
+            IoRegister::Timer(i, reg) => {
+                let timer = &self.timers16[i];
+                match reg {
+                    TimerRegister::Tccra => timer.read_tccra(),
+                    TimerRegister::Tccrb => timer.read_tccrb(),
+                    TimerRegister::Tccrc => 0,
+                    TimerRegister::Tcntl => timer.read_tcntl(),
+                    TimerRegister::Tcnth => timer.read_tcnth(),
+                    TimerRegister::Ocral => timer.read_ocral(),
+                    TimerRegister::Ocrah => timer.read_ocrah(),
+                    TimerRegister::Ocrbl => timer.read_ocrbl(),
+                    TimerRegister::Ocrbh => timer.read_ocrbh(),
+                    TimerRegister::Ocrcl => timer.read_ocrcl(),
+                    TimerRegister::Ocrch => timer.read_ocrch(),
+                }
+            }
+            IoRegister::PllTimer(reg) => {
+                let timer = self.pll_timer.as_ref().unwrap();
+                match reg {
+                    PllTimerRegister::Tccr => timer.read_tccr(),
+                    PllTimerRegister::Gtccr => timer.read_gtccr(),
+                    PllTimerRegister::Tcnt => timer.read_tcnt(),
+                    PllTimerRegister::Ocr(i) => timer.read_ocr(i),
+                    PllTimerRegister::Pllcsr => timer.read_pllcsr(),
+                }
+            }
+            IoRegister::Timsk => self.read_timer_interrupts(addr, false),
+            IoRegister::Tifr => self.read_timer_interrupts(addr, true),
+            IoRegister::Usart(i, reg) => {
+                let usart = &self.usarts[i];
+                match reg {
+                    UsartRegister::Ucsra => usart.read_ucsra(),
+                    UsartRegister::Ucsrb => usart.read_ucsrb(),
+                    UsartRegister::Ucsrc => usart.read_ucsrc(),
+                    UsartRegister::Ubrrl => usart.read_ubrrl(),
+                    UsartRegister::Ubrrh => usart.read_ubrrh(),
+                    UsartRegister::Udr => usart.read_udr(),
+                }
+            }
+            IoRegister::Usi(reg) => {
+                let usi = self.usi.as_ref().unwrap();
+                match reg {
+                    UsiRegister::Usibr => usi.read_usibr(),
+                    UsiRegister::Usidr => usi.read_usidr(),
+                    UsiRegister::Usisr => usi.read_usisr(),
+                    UsiRegister::Usicr => usi.read_usicr(),
+                }
+            }
+            IoRegister::Xmem(reg) => {
+                let xmem = self.xmem.as_ref().unwrap();
+                match reg {
+                    XmemRegister::Xmcra => xmem.read_xmcra(),
+                    XmemRegister::Xmcrb => xmem.read_xmcrb(),
+                }
+            }
+            IoRegister::Wdtcsr => self.watchdog.read_wdtcsr(),
+            IoRegister::Clkpr => self.clock_prescaler.read_clkpr(),
+            IoRegister::Mcusr => self.reset_flags.read_mcusr(),
+            IoRegister::Sleep => self.sleep.read_smcr(),
+        }
+    }
 
-            0x09 => self.gpio[3].read_pin(), // PIND
-            0x0A => self.gpio[3].read_ddr(), // DDRD
-            0x0B => self.gpio[3].read_port(), // PORTD
+    fn write_u8(&mut self, addr: u16, val: u8) {
+        let reg = self.io_map.get(addr as usize).copied().unwrap_or(IoRegister::Unmapped);
+        let (gpio_bank, changes) = match reg {
+            IoRegister::GpioPin(i) => (i, self.gpio[i].write_pin(val)),
+            IoRegister::GpioDdr(i) => (i, self.gpio[i].write_ddr(val)),
+            IoRegister::GpioPort(i) => (i, self.gpio[i].write_port(val)),
+            _ => {
+                self.write_peripheral_u8(reg, addr, val);
+                self.update_usi();
+                return;
+            }
+        };
+        let start = M::peripherals().port_start(gpio_bank);
+        update_changes(&mut self.output_changes, start, changes, &mut self.gpio_pins);
+        self.update_usi();
+    }
 
-            0x0C => self.gpio[4].read_pin(), // PINE
-            0x0D => self.gpio[4].read_ddr(), // DDRE
-            0x0E => self.gpio[4].read_port(), // PORTE
+    fn write_peripheral_u8(&mut self, reg: IoRegister, addr: u16, val: u8) {
+        match reg {
+            IoRegister::Unmapped => self.unmodeled_access(addr),
+            IoRegister::GpioPin(_) |
+            IoRegister::GpioDdr(_) |
+            IoRegister::GpioPort(_) => {}
+            IoRegister::Timer8(i, reg) => {
+                let timer = &mut self.timers8[i];
+                match reg {
+                    Timer8Register::Tccra => timer.write_tccra(val, &mut self.output_changes, &mut self.gpio_pins),
+                    Timer8Register::Tccrb => timer.write_tccrb(val),
+                    Timer8Register::Tcnt => timer.write_tcnt(val),
+                    Timer8Register::Ocra => timer.write_ocra(val),
+                    Timer8Register::Ocrb => timer.write_ocrb(val),
+                }
+            }
+            IoRegister::Timer(i, reg) => {
+                let timer = &mut self.timers16[i];
+                match reg {
+                    TimerRegister::Tccra => timer.write_tccra(val, &mut self.output_changes, &mut self.gpio_pins),
+                    TimerRegister::Tccrb => timer.write_tccrb(val),
+                    TimerRegister::Tccrc => timer.write_tccrc(val, &mut self.output_changes),
+                    TimerRegister::Tcntl => timer.write_tcntl(val),
+                    TimerRegister::Tcnth => timer.write_tcnth(val),
+                    TimerRegister::Ocral => timer.write_ocral(val),
+                    TimerRegister::Ocrah => timer.write_ocrah(val),
+                    TimerRegister::Ocrbl => timer.write_ocrbl(val),
+                    TimerRegister::Ocrbh => timer.write_ocrbh(val),
+                    TimerRegister::Ocrcl => timer.write_ocrcl(val),
+                    TimerRegister::Ocrch => timer.write_ocrch(val),
+                }
+            }
+            IoRegister::PllTimer(reg) => {
+                let timer = self.pll_timer.as_mut().unwrap();
+                match reg {
+                    PllTimerRegister::Tccr => timer.write_tccr(val, &mut self.output_changes, &mut self.gpio_pins),
+                    PllTimerRegister::Gtccr => timer.write_gtccr(val, &mut self.output_changes, &mut self.gpio_pins),
+                    PllTimerRegister::Tcnt => timer.write_tcnt(val),
+                    PllTimerRegister::Ocr(i) => timer.write_ocr(i, val),
+                    PllTimerRegister::Pllcsr => timer.write_pllcsr(val),
+                }
+            }
+            IoRegister::Timsk => self.write_timer_interrupts(addr, false, val),
+            IoRegister::Tifr => self.write_timer_interrupts(addr, true, val),
+            IoRegister::Usart(i, reg) => {
+                let usart = &mut self.usarts[i];
+                match reg {
+                    UsartRegister::Ucsra => usart.write_ucsra(val),
+                    UsartRegister::Ucsrb => usart.write_ucsrb(val, &mut self.output_changes, &mut self.irq),
+                    UsartRegister::Ucsrc => usart.write_ucsrc(val),
+                    UsartRegister::Ubrrl => usart.write_ubrrl(val),
+                    UsartRegister::Ubrrh => usart.write_ubrrh(val),
+                    UsartRegister::Udr => usart.write_udr(val),
+                }
+            }
+            IoRegister::Usi(reg) => {
+                let usi = self.usi.as_mut().unwrap();
+                match reg {
+                    UsiRegister::Usibr => {}
+                    UsiRegister::Usidr => usi.write_usidr(val),
+                    UsiRegister::Usisr => usi.write_usisr(val, &mut self.irq),
+                    UsiRegister::Usicr => {
+                        if usi.write_usicr(val, &mut self.irq) {
+                            self.toggle_usck_port();
+                        }
+                    }
+                }
+            }
+            IoRegister::Xmem(reg) => {
+                let xmem = self.xmem.as_mut().unwrap();
+                match reg {
+                    XmemRegister::Xmcra => xmem.write_xmcra(val, &mut self.output_changes, &mut self.gpio_pins),
+                    XmemRegister::Xmcrb => xmem.write_xmcrb(val, &mut self.output_changes, &mut self.gpio_pins),
+                }
+            }
+            IoRegister::Wdtcsr => {
+                self.watchdog.write_wdtcsr(val);
+                self.watchdog.update_interrupt(&mut self.irq);
+            }
+            IoRegister::Clkpr => self.clock_prescaler.write_clkpr(val),
+            IoRegister::Mcusr => {
+                self.reset_flags.write_mcusr(val);
+                self.watchdog.set_reset_flag(self.reset_flags.watchdog_flag());
+            }
+            IoRegister::Sleep => self.sleep.write_smcr(val),
+        }
+    }
 
-            0x0F => self.gpio[5].read_pin(), // PINF
-            0x10 => self.gpio[5].read_ddr(), // DDRF
-            0x11 => self.gpio[5].read_port(), // PORTF
+    /// Reads TIMSK or TIFR register, combining the bits of all timers sharing it.
+    fn read_timer_interrupts(&self, addr: u16, flags: bool) -> u8 {
+        let peripherals = M::peripherals();
+        let selected = |timsk: u16, tifr: u16| addr == if flags {tifr} else {timsk};
+        let mut val = 0;
+        for (timer, desc) in self.timers8.iter().zip(peripherals.timers8) {
+            if selected(desc.timsk, desc.tifr) {
+                val |= if flags {timer.read_tifr()} else {timer.read_timsk()};
+            }
+        }
+        for (timer, desc) in self.timers16.iter().zip(peripherals.timers16) {
+            if selected(desc.timsk, desc.tifr) {
+                val |= if flags {timer.read_tifr()} else {timer.read_timsk()};
+            }
+        }
+        if let (Some(timer), Some(desc)) = (&self.pll_timer, &peripherals.pll_timer) {
+            if selected(desc.timsk, desc.tifr) {
+                val |= if flags {timer.read_tifr()} else {timer.read_timsk()};
+            }
+        }
+        val
+    }
+
+    /// Writes TIMSK or TIFR register to all timers sharing it.
+    fn write_timer_interrupts(&mut self, addr: u16, flags: bool, val: u8) {
+        let peripherals = M::peripherals();
+        let selected = |timsk: u16, tifr: u16| addr == if flags {tifr} else {timsk};
+        for (timer, desc) in self.timers8.iter_mut().zip(peripherals.timers8) {
+            if selected(desc.timsk, desc.tifr) {
+                if flags {timer.write_tifr(val)} else {timer.write_timsk(val)}
+                timer.update_interrupts(&mut self.irq);
+            }
+        }
+        for (timer, desc) in self.timers16.iter_mut().zip(peripherals.timers16) {
+            if selected(desc.timsk, desc.tifr) {
+                if flags {timer.write_tifr(val)} else {timer.write_timsk(val)}
+                timer.update_interrupts(&mut self.irq);
+            }
+        }
+        if let (Some(timer), Some(desc)) = (&mut self.pll_timer, &peripherals.pll_timer) {
+            if selected(desc.timsk, desc.tifr) {
+                if flags {timer.write_tifr(val)} else {timer.write_timsk(val)}
+                timer.update_interrupts(&mut self.irq);
+            }
+        }
+    }
 
-            0x12 => self.gpio[6].read_pin(), // PING
-            0x13 => self.gpio[6].read_ddr(), // DDRG
-            0x14 => self.gpio[6].read_port(), // PORTG
+    /// Toggles the PORT bit of USCK/SCL pin (USITC strobe).
+    fn toggle_usck_port(&mut self) {
+        let peripherals = M::peripherals();
+        let usck = peripherals.pin_id(peripherals.usi.as_ref().unwrap().usck_pin);
+        let (gpio_bank, bit) = peripherals.pin_port(usck).unwrap();
+        let port = self.gpio[gpio_bank].read_port() ^ (1 << bit);
+        let changes = self.gpio[gpio_bank].write_port(port);
+        update_changes(&mut self.output_changes, peripherals.port_start(gpio_bank), changes, &mut self.gpio_pins);
+    }
 
-            0x16 => self.timer1.read_tifr(),
-            0x18 => self.timer3.read_tifr(),
-            0x19 => self.timer4.read_tifr(),
-            0x1A => self.timer5.read_tifr(),
-            _ => 0
+    /// Returns the logic level of a pin: its output state if driven, otherwise its input state.
+    fn pin_level(&self, pin: PinId) -> bool {
+        let driven = self.usi.as_ref().and_then(|usi| usi.driven_state(pin))
+            .unwrap_or(self.gpio_pins[pin as usize].1);
+        match driven {
+            PinState::Low => false,
+            PinState::High => true,
+            _ => {
+                let (gpio_bank, bit) = M::peripherals().pin_port(pin).unwrap();
+                self.gpio[gpio_bank].read_pin().bit(bit as usize)
+            }
         }
     }
 
-    fn read_external_u8(&self, addr: u16) -> u8 {
-        match addr {
-            0x06F => self.timer1.read_timsk(),
-            0x071 => self.timer3.read_timsk(),
-            0x072 => self.timer4.read_timsk(),
-            0x073 => self.timer5.read_timsk(),
-
-            0x080 => self.timer1.read_tccra(),
-            0x081 => self.timer1.read_tccrb(),
-            0x082 => 0, // TCCRC
-            0x084 => self.timer1.read_tcntl(),
-            0x085 => self.timer1.read_tcnth(),
-            0x088 => self.timer1.read_ocral(),
-            0x089 => self.timer1.read_ocrah(),
-            0x08A => self.timer1.read_ocrbl(),
-            0x08B => self.timer1.read_ocrbh(),
-            0x08C => self.timer1.read_ocrcl(),
-            0x08D => self.timer1.read_ocrch(),
-
-            0x090 => self.timer3.read_tccra(),
-            0x091 => self.timer3.read_tccrb(),
-            0x092 => 0, // TCCRC
-            0x094 => self.timer3.read_tcntl(),
-            0x095 => self.timer3.read_tcnth(),
-            0x098 => self.timer3.read_ocral(),
-            0x099 => self.timer3.read_ocrah(),
-            0x09A => self.timer3.read_ocrbl(),
-            0x09B => self.timer3.read_ocrbh(),
-            0x09C => self.timer3.read_ocrcl(),
-            0x09D => self.timer3.read_ocrch(),
-
-            0x0A0 => self.timer4.read_tccra(),
-            0x0A1 => self.timer4.read_tccrb(),
-            0x0A2 => 0, // TCCRC
-            0x0A4 => self.timer4.read_tcntl(),
-            0x0A5 => self.timer4.read_tcnth(),
-            0x0A8 => self.timer4.read_ocral(),
-            0x0A9 => self.timer4.read_ocrah(),
-            0x0AA => self.timer4.read_ocrbl(),
-            0x0AB => self.timer4.read_ocrbh(),
-            0x0AC => self.timer4.read_ocrcl(),
-            0x0AD => self.timer4.read_ocrch(),
-
-            0x0C0 => self.uart0.read_ucsra(),
-            0x0C1 => self.uart0.read_ucsrb(),
-            0x0C2 => self.uart0.read_ucsrc(),
-            0x0C4 => self.uart0.read_ubrrl(),
-            0x0C5 => self.uart0.read_ubrrh(),
-            0x0C6 => self.uart0.read_udr(),
-
-            0x100 => self.gpio[7].read_pin(), // PINH
-            0x101 => self.gpio[7].read_ddr(), // DDRH
-            0x102 => self.gpio[7].read_port(), // PORTH
-
-            0x103 => self.gpio[8].read_pin(), // PINJ
-            0x104 => self.gpio[8].read_ddr(), // DDRJ
-            0x105 => self.gpio[8].read_port(), // PORTJ
-
-            0x106 => self.gpio[9].read_pin(), // PINK
-            0x107 => self.gpio[9].read_ddr(), // DDRK
-            0x108 => self.gpio[9].read_port(), // PORTK
-
-            0x109 => self.gpio[10].read_pin(), // PINL
-            0x10A => self.gpio[10].read_ddr(), // DDRL
-            0x10B => self.gpio[10].read_port(), // PORTL
-
-            0x120 => self.timer5.read_tccra(),
-            0x121 => self.timer5.read_tccrb(),
-            0x122 => 0, // TCCRC
-            0x124 => self.timer5.read_tcntl(),
-            0x125 => self.timer5.read_tcnth(),
-            0x128 => self.timer5.read_ocral(),
-            0x129 => self.timer5.read_ocrah(),
-            0x12A => self.timer5.read_ocrbl(),
-            0x12B => self.timer5.read_ocrbh(),
-            0x12C => self.timer5.read_ocrcl(),
-            0x12D => self.timer5.read_ocrch(),
-            _ => 0
+    /// Feeds the current USCK/SCL and DI/SDA levels to USI and updates the pins it drives.
+    fn update_usi(&mut self) {
+        let Some(desc) = &M::peripherals().usi else {
+            return;
+        };
+        let usck = self.pin_level(M::peripherals().pin_id(desc.usck_pin));
+        let di = self.pin_level(M::peripherals().pin_id(desc.di_pin));
+        let usi = self.usi.as_mut().unwrap();
+        usi.update_levels(usck, di, &mut self.irq);
+        usi.update_outputs(&mut self.output_changes, &mut self.gpio_pins);
+    }
+
+    /// Advances the external memory bus cycle by a half clock.
+    fn tick_xmem(&mut self) {
+        let Some(xmem) = &mut self.xmem else {
+            return;
+        };
+        let (ad_bank, _) = M::peripherals().pin_port(xmem.ad_pin()).unwrap();
+        xmem.tick(self.gpio[ad_bank].read_pin(), &mut self.output_changes, &mut self.gpio_pins);
+    }
+}
+
+fn update_changes(output_changes: &mut Vec<(PinId, PinState)>, start: PinId, changes: &[(PinId, PinState)], gpio_pins: &mut [(bool, PinState)]) {
+    for &(pin_index, state) in changes {
+        let pin = start + pin_index;
+        if gpio_pins[pin as usize].0 {
+            output_changes.push((pin, state));
         }
+        gpio_pins[pin as usize].1 = state;
+    }
+}
+
+impl<M: McuModel + 'static> IoControllerTrait for IoController<M> {
+    fn read_internal_u8(&self, id: u8) -> u8 {
+        self.read_u8(id as u16 + 0x20)
+    }
+
+    fn read_external_u8(&self, addr: u16) -> u8 {
+        self.read_u8(addr)
     }
 
     fn write_internal_u8(&mut self, id: u8, val: u8) {
-        const EMPTY: &[(PinId, PinState)] = &[];
-        let (gpio_bank, changes) = match id {
-            0x00 => (0, self.gpio[0].write_pin(val)), // PINA
-            0x01 => (0, self.gpio[0].write_ddr(val)), // DDRA
-            0x02 => (0, self.gpio[0].write_port(val)), // PORTA
-
-            0x03 => (1, self.gpio[1].write_pin(val)), // PINB
-            0x04 => (1, self.gpio[1].write_ddr(val)), // DDRB
-            0x05 => (1, self.gpio[1].write_port(val)), // PORTB
-
-            0x06 => (2, self.gpio[2].write_pin(val)), // PINC
-            0x07 => (2, self.gpio[2].write_ddr(val)), // DDRC
-            0x08 => (2, self.gpio[2].write_port(val)), // PORTC
-
-            0x09 => (3, self.gpio[3].write_pin(val)), // PIND
-            0x0A => (3, self.gpio[3].write_ddr(val)), // DDRD
-            0x0B => (3, self.gpio[3].write_port(val)), // PORTD
-
-            0x0C => (4, self.gpio[4].write_pin(val)), // PINE
-            0x0D => (4, self.gpio[4].write_ddr(val)), // DDRE
-            0x0E => (4, self.gpio[4].write_port(val)), // PORTE
-
-            0x0F => (5, self.gpio[5].write_pin(val)), // PINF
-            0x10 => (5, self.gpio[5].write_ddr(val)), // DDRF
-            0x11 => (5, self.gpio[5].write_port(val)), // PORTF
-
-            0x12 => (6, self.gpio[6].write_pin(val)), // PING
-            0x13 => (6, self.gpio[6].write_ddr(val)), // DDRG
-            0x14 => (6, self.gpio[6].write_port(val)), // PORTG
-
-            0x16 => {self.timer1.write_tifr(val); (0, EMPTY)}
-            0x18 => {self.timer3.write_tifr(val); (0, EMPTY)}
-            0x19 => {self.timer4.write_tifr(val); (0, EMPTY)}
-            0x1A => {self.timer5.write_tifr(val); (0, EMPTY)}
-            _ => {(0, EMPTY)}
-        };
-        update_changes(&mut self.output_changes, gpio_bank, changes, &mut self.gpio_pins)
+        self.write_u8(id as u16 + 0x20, val)
     }
 
     fn write_external_u8(&mut self, addr: u16, val: u8) {
-        const EMPTY: &[(PinId, PinState)] = &[];
-        let (gpio_bank, changes) = match addr {
-            0x06F => {self.timer1.write_timsk(val); (0, EMPTY)}
-            0x071 => {self.timer3.write_timsk(val); (0, EMPTY)}
-            0x072 => {self.timer4.write_timsk(val); (0, EMPTY)}
-            0x073 => {self.timer5.write_timsk(val); (0, EMPTY)}
-
-            0x080 => {self.timer1.write_tccra(val, &mut self.output_changes, &mut self.gpio_pins); (0, EMPTY)}
-            0x081 => {self.timer1.write_tccrb(val); (0, EMPTY)}
-            0x082 => todo!(),
-            0x084 => {self.timer1.write_tcntl(val); (0, EMPTY)}
-            0x085 => {self.timer1.write_tcnth(val); (0, EMPTY)}
-            0x088 => {self.timer1.write_ocral(val); (0, EMPTY)}
-            0x089 => {self.timer1.write_ocrah(val); (0, EMPTY)}
-            0x08A => {self.timer1.write_ocrbl(val); (0, EMPTY)}
-            0x08B => {self.timer1.write_ocrbh(val); (0, EMPTY)}
-            0x08C => {self.timer1.write_ocrcl(val); (0, EMPTY)}
-            0x08D => {self.timer1.write_ocrch(val); (0, EMPTY)}
-
-            0x090 => {self.timer3.write_tccra(val, &mut self.output_changes, &mut self.gpio_pins); (0, EMPTY)}
-            0x091 => {self.timer3.write_tccrb(val); (0, EMPTY)}
-            0x092 => todo!(),
-            0x094 => {self.timer3.write_tcntl(val); (0, EMPTY)}
-            0x095 => {self.timer3.write_tcnth(val); (0, EMPTY)}
-            0x098 => {self.timer3.write_ocral(val); (0, EMPTY)}
-            0x099 => {self.timer3.write_ocrah(val); (0, EMPTY)}
-            0x09A => {self.timer3.write_ocrbl(val); (0, EMPTY)}
-            0x09B => {self.timer3.write_ocrbh(val); (0, EMPTY)}
-            0x09C => {self.timer3.write_ocrcl(val); (0, EMPTY)}
-            0x09D => {self.timer3.write_ocrch(val); (0, EMPTY)}
-
-            0x0A0 => {self.timer4.write_tccra(val, &mut self.output_changes, &mut self.gpio_pins); (0, EMPTY)}
-            0x0A1 => {self.timer4.write_tccrb(val); (0, EMPTY)}
-            0x0A2 => todo!(),
-            0x0A4 => {self.timer4.write_tcntl(val); (0, EMPTY)}
-            0x0A5 => {self.timer4.write_tcnth(val); (0, EMPTY)}
-            0x0A8 => {self.timer4.write_ocral(val); (0, EMPTY)}
-            0x0A9 => {self.timer4.write_ocrah(val); (0, EMPTY)}
-            0x0AA => {self.timer4.write_ocrbl(val); (0, EMPTY)}
-            0x0AB => {self.timer4.write_ocrbh(val); (0, EMPTY)}
-            0x0AC => {self.timer4.write_ocrcl(val); (0, EMPTY)}
-            0x0AD => {self.timer4.write_ocrch(val); (0, EMPTY)}
-
-            0x0C0 => {self.uart0.write_ucsra(val); (0, EMPTY)}
-            0x0C1 => {self.uart0.write_ucsrb(val, &mut self.output_changes); (0, EMPTY)}
-            0x0C2 => {self.uart0.write_ucsrc(val); (0, EMPTY)}
-            0x0C4 => {self.uart0.write_ubrrl(val); (0, EMPTY)}
-            0x0C5 => {self.uart0.write_ubrrh(val); (0, EMPTY)}
-            0x0C6 => {self.uart0.write_udr(val); (0, EMPTY)}
-
-            0x100 => (7, self.gpio[7].write_pin(val)), // PINH
-            0x101 => (7, self.gpio[7].write_ddr(val)), // DDRH
-            0x102 => (7, self.gpio[7].write_port(val)), // PORTH
-
-            0x103 => (8, self.gpio[8].write_pin(val)), // PINJ
-            0x104 => (8, self.gpio[8].write_ddr(val)), // DDRJ
-            0x105 => (8, self.gpio[8].write_port(val)), // PORTJ
-
-            0x106 => (9, self.gpio[9].write_pin(val)), // PINK
-            0x107 => (9, self.gpio[9].write_ddr(val)), // DDRK
-            0x108 => (9, self.gpio[9].write_port(val)), // PORTK
-
-            0x109 => (10, self.gpio[10].write_pin(val)), // PINL
-            0x10A => (10, self.gpio[10].write_ddr(val)), // DDRL
-            0x10B => (10, self.gpio[10].write_port(val)), // PORTL
-
-            0x120 => {self.timer5.write_tccra(val, &mut self.output_changes, &mut self.gpio_pins); (0, EMPTY)}
-            0x121 => {self.timer5.write_tccrb(val); (0, EMPTY)}
-            0x122 => todo!(),
-            0x124 => {self.timer5.write_tcntl(val); (0, EMPTY)}
-            0x125 => {self.timer5.write_tcnth(val); (0, EMPTY)}
-            0x128 => {self.timer5.write_ocral(val); (0, EMPTY)}
-            0x129 => {self.timer5.write_ocrah(val); (0, EMPTY)}
-            0x12A => {self.timer5.write_ocrbl(val); (0, EMPTY)}
-            0x12B => {self.timer5.write_ocrbh(val); (0, EMPTY)}
-            0x12C => {self.timer5.write_ocrcl(val); (0, EMPTY)}
-            0x12D => {self.timer5.write_ocrch(val); (0, EMPTY)}
-            _ => {(0, EMPTY)}
-        };
-        update_changes(&mut self.output_changes, gpio_bank, changes, &mut self.gpio_pins)
+        self.write_u8(addr, val)
+    }
+
+    fn read_has_side_effects(&self, addr: u16) -> bool {
+        matches!(self.io_map.get(addr as usize).copied().unwrap_or(IoRegister::Unmapped),
+            IoRegister::Unmapped | IoRegister::Usart(_, UsartRegister::Udr))
     }
 
     fn set_pin(&mut self, pin: PinId, state: PinState) {
-        let (gpio_bank, gpio_index) = match pin {
-            0 ..=7  => (0, pin - 0),
-            8 ..=15 => (1, pin - 8),
-            16..=23 => (2, pin - 16),
-            24..=31 => (3, pin - 24),
-            32..=39 => (4, pin - 32),
-            40..=47 => (5, pin - 40),
-            48..=53 => (6, pin - 48),
-            54..=61 => (7, pin - 54),
-            62..=69 => (8, pin - 62),
-            70..=77 => (9, pin - 70),
-            78..=85 => (10, pin - 78),
-            _ => panic!("Invalid pin number")
-        };
-        self.gpio[gpio_bank as usize].set_input_pin(gpio_index, state);
+        let (gpio_bank, gpio_index) = M::peripherals().pin_port(pin).expect("Invalid pin number");
+        self.gpio[gpio_bank].set_input_pin(gpio_index as PinId, state);
     }
 
     fn pin_count() -> usize {
-        86
+        M::peripherals().pin_count()
     }
 
     fn pin_name(pin: PinId) -> String {
-        match pin {
-            0..=85 => {
-                let mut s = String::with_capacity(3);
-                s.push('P');
-                let (port, i) = match pin {
-                    0 ..=7  => ('A', pin - 0),
-                    8 ..=15 => ('B', pin - 8),
-                    16..=23 => ('C', pin - 16),
-                    24..=31 => ('D', pin - 24),
-                    32..=39 => ('E', pin - 32),
-                    40..=47 => ('F', pin - 40),
-                    48..=53 => ('G', pin - 48),
-                    54..=61 => ('H', pin - 54),
-                    62..=69 => ('J', pin - 62),
-                    70..=77 => ('K', pin - 70),
-                    78..=85 => ('L', pin - 78),
-                    _ => panic!("Invalid pin number")
-                };
-                s.push(port);
-                s.push(char::from_digit(i as u32, 10).unwrap());
-                s
-            }
-            _ => panic!("Invalid pin number")
-        }
+        M::peripherals().pin_name(pin).expect("Invalid pin number")
     }
     
     #[inline]
@@ -503,101 +747,434 @@ impl<M: McuModel + 'static> IoControllerTrait for IoController<M> {
     fn clock_rising_edge(&mut self) {
         self.clock_pin = PinState::High;
         self.output_changes.clear();
+        if std::mem::take(&mut self.outputs_restored) {
+            for (pin, &(gpio_driven, state)) in self.gpio_pins.iter().enumerate() {
+                if gpio_driven {
+                    self.output_changes.push((pin as PinId, state));
+                }
+            }
+            for timer in self.timers8.iter() {
+                timer.announce_outputs(&mut self.output_changes);
+            }
+            for timer in self.timers16.iter() {
+                timer.announce_outputs(&mut self.output_changes);
+            }
+            if let Some(timer) = &self.pll_timer {
+                timer.announce_outputs(&mut self.output_changes);
+            }
+            if let Some(usi) = &self.usi {
+                usi.announce_outputs(&mut self.output_changes);
+            }
+            if let Some(xmem) = &self.xmem {
+                xmem.announce_outputs(&mut self.output_changes);
+            }
+        }
         for gpio_bank in self.gpio.iter_mut() {
             gpio_bank.clock_rising_edge();
         }
-        if self.timer1.enabled() {
-            self.timer1.tick_prescaler(self.timer_prescaler, &mut self.output_changes, &mut self.interrupt);
+        self.tick_xmem();
+        self.watchdog.tick(&mut self.irq);
+        self.clock_prescaler.tick();
+
+        if matches!(self.sleep_mode, Some(mode) if !mode.io_clock_running()) {
+            return;
         }
-        if self.timer3.enabled() {
-            self.timer3.tick_prescaler(self.timer_prescaler, &mut self.output_changes, &mut self.interrupt);
+        for (timer, desc) in self.timers8.iter_mut().zip(M::peripherals().timers8) {
+            if timer.enabled() && timer.tick_prescaler(self.timer_prescaler, &mut self.output_changes, &mut self.irq) && desc.index == 0 {
+                // Timer0 compare match can clock USI
+                if let Some(usi) = &mut self.usi {
+                    usi.timer0_compare_match(&mut self.irq);
+                }
+            }
         }
-        if self.timer4.enabled() {
-            self.timer4.tick_prescaler(self.timer_prescaler, &mut self.output_changes, &mut self.interrupt);
+        for timer in self.timers16.iter_mut() {
+            if timer.enabled() {
+                timer.tick_prescaler(self.timer_prescaler, &mut self.output_changes, &mut self.irq);
+            }
         }
-        if self.timer5.enabled() {
-            self.timer5.tick_prescaler(self.timer_prescaler, &mut self.output_changes, &mut self.interrupt);
+        if let Some(timer) = &mut self.pll_timer {
+            if timer.enabled() {
+                timer.tick_clock(&mut self.output_changes, &mut self.irq);
+            }
         }
         self.timer_prescaler = (self.timer_prescaler + 1) % 1024;
 
-        self.uart0.tick(&mut self.output_changes, &mut self.interrupt);
+        for (i, desc) in M::peripherals().usarts.iter().enumerate() {
+            let rx = self.pin_level(M::peripherals().pin_id(desc.rx_pin));
+            self.usarts[i].tick(rx, &mut self.output_changes, &mut self.irq);
+        }
+        self.update_usi();
     }
 
-    #[inline]
     fn clock_falling_edge(&mut self) {
         self.clock_pin = PinState::Low;
+        self.output_changes.clear();
+        self.tick_xmem();
     }
 
     #[inline]
-    fn has_interrupt(&self) -> bool {
-        self.interrupt
-    }
-
-    fn get_interrupt_address(&mut self) -> Option<u16> {
-        let mut result = None;
-        let mut have_others = false;
-        fn update(addr: u16, result: &mut Option<u16>, have_others: &mut bool, flag: &mut bool) {
-            if *flag {
-                *result = match result {
-                    None => {
-                        *flag = false;
-                        Some(addr)
-                    },
-                    Some(x) => {
-                        *have_others = true; 
-                        Some(*x)
-                    },
-                }
-            }
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.irq.pending()
+    }
+
+    fn acknowledge_interrupt(&mut self, vector: u8) {
+        if !self.irq.is_raised(vector) {
+            return;
+        }
+        self.irq.clear(vector);
+        self.watchdog.acknowledge_interrupt(vector);
+        for timer in self.timers8.iter_mut() {
+            timer.acknowledge_interrupt(vector);
+        }
+        for timer in self.timers16.iter_mut() {
+            timer.acknowledge_interrupt(vector);
         }
+        if let Some(timer) = &mut self.pll_timer {
+            timer.acknowledge_interrupt(vector);
+        }
+        if let Some(usi) = &mut self.usi {
+            usi.acknowledge_interrupt(vector, &mut self.irq);
+        }
+        for usart in self.usarts.iter_mut() {
+            usart.acknowledge_interrupt(vector);
+        }
+    }
 
-        update(0x0020, &mut result, &mut have_others, &mut self.timer1.interrupt_flags.input_capture);
-        update(0x0022, &mut result, &mut have_others, &mut self.timer1.interrupt_flags.oc[0]);
-        update(0x0024, &mut result, &mut have_others, &mut self.timer1.interrupt_flags.oc[1]);
-        update(0x0026, &mut result, &mut have_others, &mut self.timer1.interrupt_flags.oc[2]);
-        update(0x0028, &mut result, &mut have_others, &mut self.timer1.interrupt_flags.overflow);
-        
-        update(0x003E, &mut result, &mut have_others, &mut self.timer3.interrupt_flags.input_capture);
-        update(0x0040, &mut result, &mut have_others, &mut self.timer3.interrupt_flags.oc[0]);
-        update(0x0042, &mut result, &mut have_others, &mut self.timer3.interrupt_flags.oc[1]);
-        update(0x0044, &mut result, &mut have_others, &mut self.timer3.interrupt_flags.oc[2]);
-        update(0x0046, &mut result, &mut have_others, &mut self.timer3.interrupt_flags.overflow);
+    #[inline]
+    fn sleep_mode(&self) -> Option<SleepMode> {
+        self.sleep.sleep_mode()
+    }
 
-        update(0x0052, &mut result, &mut have_others, &mut self.timer4.interrupt_flags.input_capture);
-        update(0x0054, &mut result, &mut have_others, &mut self.timer4.interrupt_flags.oc[0]);
-        update(0x0056, &mut result, &mut have_others, &mut self.timer4.interrupt_flags.oc[1]);
-        update(0x0058, &mut result, &mut have_others, &mut self.timer4.interrupt_flags.oc[2]);
-        update(0x005A, &mut result, &mut have_others, &mut self.timer4.interrupt_flags.overflow);
+    #[inline]
+    fn set_sleep_mode(&mut self, mode: Option<SleepMode>) {
+        self.sleep_mode = mode;
+    }
 
-        update(0x005C, &mut result, &mut have_others, &mut self.timer5.interrupt_flags.input_capture);
-        update(0x005E, &mut result, &mut have_others, &mut self.timer5.interrupt_flags.oc[0]);
-        update(0x0060, &mut result, &mut have_others, &mut self.timer5.interrupt_flags.oc[1]);
-        update(0x0062, &mut result, &mut have_others, &mut self.timer5.interrupt_flags.oc[2]);
-        update(0x0064, &mut result, &mut have_others, &mut self.timer5.interrupt_flags.overflow);
-        
-        if !have_others {
-            self.interrupt = false;
+    fn has_wake_up_interrupt(&self) -> bool {
+        match self.sleep_mode {
+            None | Some(SleepMode::Idle) => self.irq.pending().is_some(),
+            // Only asynchronous sources run without clk_IO
+            Some(_) => self.watchdog.interrupt_pending(),
         }
-        result
     }
 
     #[inline]
-    fn timer1(&self) -> &Timer16 {
-        &self.timer1
+    fn watchdog_reset(&mut self) {
+        self.watchdog.reset_counter();
+    }
+
+    #[inline]
+    fn take_watchdog_reset(&mut self) -> bool {
+        self.watchdog.take_reset_request()
+    }
+
+    #[inline]
+    fn set_watchdog_always_on(&mut self, always_on: bool) {
+        self.watchdog.set_always_on(always_on);
     }
 
     #[inline]
-    fn timer3(&self) -> &Timer16 {
-        &self.timer3
+    fn clock_division(&self) -> u16 {
+        self.clock_prescaler.division()
     }
 
     #[inline]
-    fn timer4(&self) -> &Timer16 {
-        &self.timer4
+    fn reset_clock_prescaler(&mut self, ckdiv8: bool) {
+        self.clock_prescaler.reset(ckdiv8);
     }
 
     #[inline]
-    fn timer5(&self) -> &Timer16 {
-        &self.timer5
+    fn set_cpu_frequency(&mut self, frequency: u32) {
+        self.watchdog.set_cpu_frequency(frequency);
     }
-}
\ No newline at end of file
+
+    fn reset(&mut self, source: ResetSource) {
+        let mut io = IoController::new();
+        io.clock_pin = self.clock_pin;
+        io.unmodeled_accesses = std::mem::take(&mut self.unmodeled_accesses);
+        io.gpio = std::mem::take(&mut self.gpio);
+        for gpio_bank in io.gpio.iter_mut() {
+            gpio_bank.reset();
+        }
+        io.reset_flags = std::mem::replace(&mut self.reset_flags, ResetFlags::new(0));
+        io.reset_flags.record(source);
+        io.watchdog.set_always_on(self.watchdog.always_on());
+        io.watchdog.set_reset_flag(io.reset_flags.watchdog_flag());
+        io.clock_prescaler.reset(self.clock_prescaler.ckdiv8());
+        // Pins which were driven before the reset are released
+        io.outputs_restored = true;
+        *self = io;
+    }
+
+    #[inline]
+    fn timers8(&self) -> &[Timer8] {
+        &self.timers8
+    }
+
+    #[inline]
+    fn timers16(&self) -> &[Timer16] {
+        &self.timers16
+    }
+
+    #[inline]
+    fn pll_timer(&self) -> &[PllTimer] {
+        self.pll_timer.as_slice()
+    }
+
+    fn xmem_enabled(&self) -> bool {
+        self.xmem.as_ref().is_some_and(|xmem| xmem.enabled())
+    }
+
+    fn xmem_access_cycles(&self, addr: u16) -> u8 {
+        self.xmem.as_ref().map_or(0, |xmem| xmem.access_cycles(addr))
+    }
+
+    fn xmem_read_data(&self, addr: u16) -> Option<u8> {
+        self.xmem.as_ref().and_then(|xmem| xmem.read_data(addr))
+    }
+
+    fn xmem_start_read(&mut self, addr: u16) -> u8 {
+        match &mut self.xmem {
+            Some(xmem) => xmem.start_read(addr, &mut self.output_changes, &mut self.gpio_pins),
+            None => 1,
+        }
+    }
+
+    fn xmem_write(&mut self, addr: u16, val: u8) {
+        if let Some(xmem) = &mut self.xmem {
+            xmem.write(addr, val, &mut self.output_changes, &mut self.gpio_pins);
+        }
+    }
+
+    fn xmem_finish_instruction(&mut self) {
+        if let Some(xmem) = &mut self.xmem {
+            xmem.finish_instruction();
+        }
+    }
+
+    fn take_unmodeled_accesses(&mut self) -> Vec<u16> {
+        self.unmodeled_accesses.iter()
+            .enumerate()
+            .filter(|(_, accessed)| accessed.take())
+            .map(|(addr, _)| addr as u16)
+            .collect()
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.irq.save_state(w);
+        for gpio_bank in self.gpio.iter() {
+            gpio_bank.save_state(w);
+        }
+        w.write_u16(self.timer_prescaler);
+        for timer in self.timers8.iter() {
+            timer.save_state(w);
+        }
+        for timer in self.timers16.iter() {
+            timer.save_state(w);
+        }
+        if let Some(timer) = &self.pll_timer {
+            timer.save_state(w);
+        }
+        for usart in self.usarts.iter() {
+            usart.save_state(w);
+        }
+        if let Some(usi) = &self.usi {
+            usi.save_state(w);
+        }
+        if let Some(xmem) = &self.xmem {
+            xmem.save_state(w);
+        }
+        self.sleep.save_state(w);
+        w.write_u8(match self.sleep_mode {
+            None => 0,
+            Some(SleepMode::Idle) => 1,
+            Some(SleepMode::AdcNoiseReduction) => 2,
+            Some(SleepMode::PowerDown) => 3,
+            Some(SleepMode::PowerSave) => 4,
+            Some(SleepMode::Standby) => 5,
+            Some(SleepMode::ExtendedStandby) => 6,
+        });
+        self.watchdog.save_state(w);
+        self.clock_prescaler.save_state(w);
+        self.reset_flags.save_state(w);
+        for &(gpio_driven, state) in self.gpio_pins.iter() {
+            w.write_bool(gpio_driven);
+            w.write_pin_state(state);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.irq.load_state(r)?;
+        for gpio_bank in self.gpio.iter_mut() {
+            gpio_bank.load_state(r)?;
+        }
+        self.timer_prescaler = r.read_u16()? % 1024;
+        for timer in self.timers8.iter_mut() {
+            timer.load_state(r)?;
+        }
+        for timer in self.timers16.iter_mut() {
+            timer.load_state(r)?;
+        }
+        if let Some(timer) = &mut self.pll_timer {
+            timer.load_state(r)?;
+        }
+        for usart in self.usarts.iter_mut() {
+            usart.load_state(r)?;
+        }
+        if let Some(usi) = &mut self.usi {
+            usi.load_state(r)?;
+        }
+        if let Some(xmem) = &mut self.xmem {
+            xmem.load_state(r)?;
+        }
+        self.sleep.load_state(r)?;
+        self.sleep_mode = match r.read_u8()? {
+            0 => None,
+            1 => Some(SleepMode::Idle),
+            2 => Some(SleepMode::AdcNoiseReduction),
+            3 => Some(SleepMode::PowerDown),
+            4 => Some(SleepMode::PowerSave),
+            5 => Some(SleepMode::Standby),
+            6 => Some(SleepMode::ExtendedStandby),
+            _ => return Err(SnapshotError::Format("invalid sleep mode")),
+        };
+        self.watchdog.load_state(r)?;
+        self.clock_prescaler.load_state(r)?;
+        self.reset_flags.load_state(r)?;
+        self.watchdog.set_reset_flag(self.reset_flags.watchdog_flag());
+        for (gpio_driven, state) in self.gpio_pins.iter_mut() {
+            *gpio_driven = r.read_bool()?;
+            *state = r.read_pin_state()?;
+        }
+        self.output_changes.clear();
+        self.outputs_restored = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::mcu_model::{Atmega2560, Atmega328P, Attiny85};
+
+    use super::*;
+
+    #[test]
+    fn io_map() {
+        let mut io: IoController<Atmega2560> = IoController::new();
+        assert_eq!(IoController::<Atmega2560>::pin_count(), 86);
+        assert_eq!(IoController::<Atmega2560>::pin_name(13), "PB5");
+        assert_eq!(IoController::<Atmega2560>::pin_name(54), "PH0");
+
+        io.write_internal_u8(0x04, 0x20); // DDRB
+        io.write_internal_u8(0x05, 0x20); // PORTB
+        assert_eq!(io.get_output_changes(), [(13, PinState::Low), (13, PinState::High)]);
+        assert_eq!(io.read_internal_u8(0x05), 0x20);
+
+        io.write_external_u8(0x10A, 0x01); // DDRL
+        io.write_external_u8(0x10B, 0x01); // PORTL
+        assert_eq!(io.get_output_changes()[2..], [(78, PinState::Low), (78, PinState::High)]);
+
+        io.write_external_u8(0x128, 0x34); // OCR5AL
+        assert_eq!(io.read_external_u8(0x128), 0x34);
+        assert_eq!(io.timers16()[3].read_ocral(), 0x34);
+        io.write_external_u8(0x073, 0x02); // TIMSK5
+        assert_eq!(io.read_internal_u8(0x1A), 0x00); // TIFR5
+        assert_eq!(io.read_external_u8(0x1FF), 0);
+        io.write_external_u8(0xB8, 0x48); // TWBR
+        assert_eq!(io.take_unmodeled_accesses(), [0xB8, 0x1FF]);
+        assert_eq!(io.take_unmodeled_accesses(), []);
+    }
+
+    #[test]
+    fn io_map_328p() {
+        let mut io: IoController<Atmega328P> = IoController::new();
+        assert_eq!(IoController::<Atmega328P>::pin_count(), 23);
+        assert_eq!(IoController::<Atmega328P>::pin_name(15), "PD0");
+        assert_eq!(io.timers8().len(), 2);
+        assert_eq!(io.timers16().len(), 1);
+
+        io.write_internal_u8(0x27, 0x12); // OCR0A
+        assert_eq!(io.timers8()[0].read_ocra(), 0x12);
+        io.write_external_u8(0xB4, 0x34); // OCR2B
+        assert_eq!(io.timers8()[1].read_ocrb(), 0x34);
+        io.write_external_u8(0x8C, 0x56); // OCR1C doesn't exist
+        assert_eq!(io.read_external_u8(0x8C), 0);
+        io.write_external_u8(0x6F, 0x0F); // TIMSK1
+        assert_eq!(io.read_external_u8(0x6F), 0x07);
+
+        io.write_external_u8(0x6E, 0x01); // TIMSK0
+        io.write_internal_u8(0x25, 0x01); // TCCR0B
+        for _ in 0..256 {
+            io.clock_rising_edge();
+        }
+        assert_eq!(io.pending_interrupt(), Some(16));
+        io.acknowledge_interrupt(16);
+        assert_eq!(io.read_internal_u8(0x15), 0x06); // TIFR0, both compare matches but no overflow
+
+        io.write_external_u8(0x80, 0x40); // TCCR1A, toggle OC1A
+        io.clock_rising_edge();
+        io.write_external_u8(0x82, 0x80); // TCCR1C, FOC1A
+        assert_eq!(io.get_output_changes(), [(1, PinState::High)]);
+        assert_eq!(io.read_internal_u8(0x16), 0x00); // TIFR1
+    }
+
+    #[test]
+    fn io_map_attiny85() {
+        let mut io: IoController<Attiny85> = IoController::new();
+        assert_eq!(IoController::<Attiny85>::pin_count(), 6);
+        assert_eq!(IoController::<Attiny85>::pin_name(2), "PB2");
+
+        io.write_internal_u8(0x39, 0x06); // TIMSK, TOIE0 and TOIE1
+        assert_eq!(io.timers8()[0].read_timsk(), 0x02);
+        assert_eq!(io.pll_timer()[0].read_timsk(), 0x04);
+        assert_eq!(io.read_internal_u8(0x39), 0x06);
+        io.write_internal_u8(0x35, 0x30); // MCUCR, SE and power-down
+        assert_eq!(io.sleep_mode(), Some(SleepMode::PowerDown));
+
+        io.write_internal_u8(0x17, 0x02); // DDRB, DO is an output
+        io.write_internal_u8(0x0F, 0x80); // USIDR
+        io.write_internal_u8(0x0D, 0x14); // USICR, three-wire mode clocked by Timer0
+        assert_eq!(io.get_output_changes(), [(1, PinState::Low), (1, PinState::High)]);
+
+        io.set_pin(0, PinState::High);
+        io.write_internal_u8(0x2A, 0x02); // TCCR0A, CTC
+        io.write_internal_u8(0x33, 0x01); // TCCR0B
+        for _ in 0..16 {
+            io.clock_rising_edge();
+        }
+        assert_eq!(io.read_internal_u8(0x0F), 0xFF); // USIDR
+        assert_eq!(io.read_internal_u8(0x0E), 0x40); // USISR, counter overflow
+        assert_eq!(io.read_internal_u8(0x10), 0xFF); // USIBR
+    }
+
+    #[test]
+    fn reset() {
+        let mut io: IoController<Atmega328P> = IoController::new();
+        assert_eq!(io.read_internal_u8(0x34), 0x01); // MCUSR, PORF
+        io.write_internal_u8(0x34, 0x00);
+
+        io.set_pin(0, PinState::High);
+        io.write_internal_u8(0x04, 0x02); // DDRB
+        io.write_internal_u8(0x05, 0x02); // PORTB
+        io.write_internal_u8(0x25, 0x01); // TCCR0B
+        io.write_external_u8(0x60, 0x18); // WDTCSR, WDCE and WDE
+        io.write_external_u8(0x60, 0x00);
+        io.reset(ResetSource::Watchdog);
+
+        assert_eq!(io.read_internal_u8(0x04), 0x00);
+        assert_eq!(io.read_internal_u8(0x25), 0x00);
+        assert_eq!(io.read_internal_u8(0x34), 0x08); // WDRF
+        // WDRF keeps the watchdog in system reset mode
+        assert_eq!(io.read_external_u8(0x60), 0x08);
+        io.clock_rising_edge();
+        assert_eq!(io.get_output_changes()[9], (9, PinState::Z));
+        assert_eq!(io.read_internal_u8(0x03), 0x01); // PINB
+
+        io.write_internal_u8(0x34, 0x00);
+        io.write_external_u8(0x60, 0x18);
+        io.write_external_u8(0x60, 0x00);
+        assert_eq!(io.read_external_u8(0x60), 0x00);
+        io.reset(ResetSource::Jtag);
+        assert_eq!(io.read_internal_u8(0x34), 0x00); // No JTRF on ATmega328P
+    }
+}
@@ -0,0 +1,109 @@
+use bitfield::Bit;
+
+use crate::components::avr::snapshot::{StateWriter, StateReader, SnapshotError};
+
+/// Number of CPU clock cycles the CLKPCE bit stays set after being written.
+const CHANGE_ENABLE_CYCLES: u8 = 4;
+
+/// System clock prescaler, together with CLKPR register.
+pub struct ClockPrescaler {
+    /// Remaining CPU clock cycles of the timed CLKPCE sequence.
+    change_enable: u8,
+    /// CLKPS3:0 bits.
+    select: u8,
+    /// CKDIV8 fuse, selecting the reset value of CLKPS.
+    ckdiv8: bool,
+}
+
+impl ClockPrescaler {
+    pub fn new() -> ClockPrescaler {
+        ClockPrescaler {
+            change_enable: 0,
+            select: 0,
+            ckdiv8: false,
+        }
+    }
+
+    /// Resets the prescaler, dividing the clock by 8 if CKDIV8 fuse is programmed.
+    pub fn reset(&mut self, ckdiv8: bool) {
+        self.ckdiv8 = ckdiv8;
+        self.change_enable = 0;
+        self.select = if ckdiv8 {3} else {0};
+    }
+
+    #[inline]
+    pub fn ckdiv8(&self) -> bool {
+        self.ckdiv8
+    }
+
+    /// Returns the system clock division factor.
+    #[inline]
+    pub fn division(&self) -> u16 {
+        // Reserved CLKPS values are treated as the largest division
+        1 << self.select.min(8)
+    }
+
+    /// Advances the prescaler by a single CPU clock cycle.
+    #[inline]
+    pub fn tick(&mut self) {
+        if self.change_enable > 0 {
+            self.change_enable -= 1;
+        }
+    }
+
+    #[inline]
+    pub fn read_clkpr(&self) -> u8 {
+        ((self.change_enable > 0) as u8) << 7 | self.select
+    }
+
+    pub fn write_clkpr(&mut self, val: u8) {
+        if val == 0x80 {
+            self.change_enable = CHANGE_ENABLE_CYCLES;
+        } else if !val.bit(7) && self.change_enable > 0 {
+            self.select = val & 0x0F;
+            self.change_enable = 0;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.change_enable);
+        w.write_u8(self.select);
+        w.write_bool(self.ckdiv8);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.change_enable = r.read_u8()?;
+        self.select = r.read_u8()? & 0x0F;
+        self.ckdiv8 = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timed_sequence() {
+        let mut prescaler = ClockPrescaler::new();
+        prescaler.reset(true);
+        assert_eq!(prescaler.division(), 8);
+
+        // Cannot change CLKPS without CLKPCE
+        prescaler.write_clkpr(0x00);
+        assert_eq!(prescaler.read_clkpr(), 0x03);
+
+        prescaler.write_clkpr(0x80);
+        assert_eq!(prescaler.read_clkpr(), 0x83);
+        prescaler.write_clkpr(0x01);
+        assert_eq!(prescaler.division(), 2);
+
+        // CLKPCE expires after 4 cycles
+        prescaler.write_clkpr(0x80);
+        for _ in 0..4 {
+            prescaler.tick();
+        }
+        prescaler.write_clkpr(0x00);
+        assert_eq!(prescaler.read_clkpr(), 0x01);
+    }
+}
@@ -1,6 +1,6 @@
 use bitfield::Bit;
 
-use crate::pins::{PinState, PinId};
+use crate::{pins::{PinState, PinId}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
 
 /// GPIO port, together with IO registers.
 #[derive(Debug, Clone)]
@@ -28,6 +28,15 @@ impl GpioPort {
         }
     }
 
+    /// Resets registers and outputs, keeping the input pin states.
+    pub fn reset(&mut self) {
+        *self = GpioPort {
+            input_states: self.input_states,
+            readable_states: self.readable_states,
+            ..GpioPort::new()
+        };
+    }
+
     #[inline]
     pub fn set_input_pin(&mut self, pin: PinId, state: PinState) {
         assert!(pin < 8);
@@ -102,4 +111,26 @@ impl GpioPort {
         }
         self.update_outputs()
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.port_register);
+        w.write_u8(self.ddr_register);
+        for states in [&self.output_states, &self.readable_states, &self.input_states] {
+            for &state in states.iter() {
+                w.write_pin_state(state);
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.port_register = r.read_u8()?;
+        self.ddr_register = r.read_u8()?;
+        for states in [&mut self.output_states, &mut self.readable_states, &mut self.input_states] {
+            for state in states.iter_mut() {
+                *state = r.read_pin_state()?;
+            }
+        }
+        self.output_changes.clear();
+        Ok(())
+    }
 }
\ No newline at end of file
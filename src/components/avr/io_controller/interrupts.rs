@@ -0,0 +1,107 @@
+use crate::components::avr::snapshot::{StateWriter, StateReader, SnapshotError};
+
+/// A peripheral event that can request an interrupt.
+///
+/// Each [McuModel](crate::components::avr::mcu_model::McuModel) maps sources into its own vector numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptSource {
+    Watchdog,
+    /// Input capture of a timer with a given index.
+    TimerCapture(u8),
+    /// Output compare match of a timer with a given index and channel (0 for A, 1 for B, ...).
+    TimerCompare(u8, u8),
+    /// Overflow of a timer with a given index.
+    TimerOverflow(u8),
+    SpmReady,
+    /// USI start condition.
+    UsiStart,
+    /// USI counter overflow.
+    UsiOverflow,
+    /// Receive complete of a USART with a given index.
+    UsartRx(u8),
+    /// Data register empty of a USART with a given index.
+    UsartUdre(u8),
+    /// Transmit complete of a USART with a given index.
+    UsartTx(u8),
+}
+
+/// Interrupt request lines of all IO peripherals.
+///
+/// Every vector has a single line, raised while its interrupt flag and enable bit are both set.
+/// Lower vector numbers have higher priority.
+pub struct InterruptController {
+    lines: u64,
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController { lines: 0 }
+    }
+
+    #[inline]
+    pub fn raise(&mut self, vector: u8) {
+        self.lines |= 1 << vector;
+    }
+
+    #[inline]
+    pub fn clear(&mut self, vector: u8) {
+        self.lines &= !(1 << vector);
+    }
+
+    #[inline]
+    pub fn set(&mut self, vector: u8, active: bool) {
+        if active {
+            self.raise(vector);
+        } else {
+            self.clear(vector);
+        }
+    }
+
+    #[inline]
+    pub fn is_raised(&self, vector: u8) -> bool {
+        self.lines & (1 << vector) != 0
+    }
+
+    /// Returns the highest priority raised vector.
+    #[inline]
+    pub fn pending(&self) -> Option<u8> {
+        if self.lines == 0 {
+            None
+        } else {
+            Some(self.lines.trailing_zeros() as u8)
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.lines);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.lines = r.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority() {
+        let mut irq = InterruptController::new();
+        assert_eq!(irq.pending(), None);
+
+        irq.raise(20);
+        irq.raise(46);
+        assert_eq!(irq.pending(), Some(20));
+        irq.set(12, true);
+        assert_eq!(irq.pending(), Some(12));
+        assert!(irq.is_raised(46));
+
+        irq.clear(12);
+        irq.set(20, false);
+        assert_eq!(irq.pending(), Some(46));
+        irq.clear(46);
+        assert_eq!(irq.pending(), None);
+    }
+}
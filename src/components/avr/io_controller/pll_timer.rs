@@ -0,0 +1,380 @@
+use bitfield::Bit;
+
+use crate::{pins::{PinId, PinState, PinVec}, vcd::{VcdFiller, VcdModuleBuilder, VcdTreeModule}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
+
+use super::{interrupts::InterruptController, timer8::Timer8Interrupts, timer16::CompareOutputMode};
+
+/// High speed 8-bit Timer/Counter1 of ATtiny25/45/85.
+///
+/// It can be clocked from the 64 MHz PLL clock (PCK). Timings assume a 16 MHz system clock,
+/// so PCK clocks the timer 4 times per CPU clock (2 times in low speed mode).
+/// Dead time generators and complementary outputs are not emulated.
+pub struct PllTimer {
+    counter: u8,
+    /// OCR1A, OCR1B and OCR1C (TOP).
+    ocr: [u8; 3],
+    pins: [bool; 2],
+    compare_output_mode: [CompareOutputMode; 2],
+    pwm: [bool; 2],
+    ctc: bool,
+    clock_select: u8,
+    prescaler: u16,
+    /// TSM bit of GTCCR.
+    tsm: bool,
+    pllcsr: u8,
+    pin_ids: [PinId; 2],
+
+    interrupt_masks: Timer8Interrupts,
+    interrupt_flags: Timer8Interrupts,
+    vectors: Timer8Interrupts<u8>,
+    /// Bits of overflow, compare A and compare B flags in TIFR and TIMSK.
+    int_bits: [usize; 3],
+}
+
+impl PllTimer {
+    pub fn new(pin_ids: [PinId; 2], vectors: Timer8Interrupts<u8>, int_bits: [u8; 3]) -> PllTimer {
+        PllTimer {
+            counter: 0,
+            ocr: [0, 0, 0xFF],
+            pins: [false; 2],
+            compare_output_mode: [CompareOutputMode::Disabled; 2],
+            pwm: [false; 2],
+            ctc: false,
+            clock_select: 0,
+            prescaler: 0,
+            tsm: false,
+            pllcsr: 0,
+            pin_ids,
+            interrupt_masks: Timer8Interrupts { overflow: false, oc: [false; 2] },
+            interrupt_flags: Timer8Interrupts { overflow: false, oc: [false; 2] },
+            vectors,
+            int_bits: int_bits.map(|bit| bit as usize),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.clock_select != 0
+    }
+
+    /// Returns `true` if the timer is clocked from PCK (PCKE and PLLE bits are set).
+    #[inline]
+    fn pck_enabled(&self) -> bool {
+        self.pllcsr.bit(2) && self.pllcsr.bit(1)
+    }
+
+    /// Advances the timer by one system clock.
+    pub fn tick_clock(&mut self, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        let ticks = match (self.pck_enabled(), self.pllcsr.bit(7)) {
+            (false, _) => 1,
+            (true, false) => 4,
+            (true, true) => 2,
+        };
+        for _ in 0..ticks {
+            self.prescaler = (self.prescaler + 1) & 0x3FFF;
+            if self.prescaler.is_multiple_of(1 << (self.clock_select - 1)) {
+                self.tick(output_changes, irq);
+            }
+        }
+    }
+
+    fn set_overflow(&mut self, irq: &mut InterruptController) {
+        self.interrupt_flags.overflow = true;
+        if self.interrupt_masks.overflow {
+            irq.raise(self.vectors.overflow);
+        }
+    }
+
+    fn set_output(&mut self, i: usize, state: bool, output_changes: &mut Vec<(PinId, PinState)>) {
+        self.pins[i] = state;
+        output_changes.push((self.pin_ids[i], PinState::from_bool(state)));
+    }
+
+    /// Changes the output on OCR1x compare match.
+    fn compare_output(&mut self, i: usize, output_changes: &mut Vec<(PinId, PinState)>) {
+        match (self.compare_output_mode[i], self.pwm[i]) {
+            (CompareOutputMode::Disabled, _) => {}
+            (CompareOutputMode::Toggle, false) => self.set_output(i, !self.pins[i], output_changes),
+            (CompareOutputMode::Toggle | CompareOutputMode::Clear, _) => self.set_output(i, false, output_changes),
+            (CompareOutputMode::Set, _) => self.set_output(i, true, output_changes),
+        }
+    }
+
+    fn tick(&mut self, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        for i in 0..2 {
+            if self.counter == self.ocr[i] {
+                self.compare_output(i, output_changes);
+                self.interrupt_flags.oc[i] = true;
+                if self.interrupt_masks.oc[i] {
+                    irq.raise(self.vectors.oc[i]);
+                }
+            }
+        }
+
+        let top = if self.ctc || self.pwm[0] || self.pwm[1] {self.ocr[2]} else {0xFF};
+        if self.counter == top {
+            self.counter = 0;
+            self.set_overflow(irq);
+            for i in 0..2 {
+                if self.pwm[i] {
+                    match self.compare_output_mode[i] {
+                        CompareOutputMode::Disabled => {}
+                        CompareOutputMode::Set => self.set_output(i, false, output_changes),
+                        _ => self.set_output(i, true, output_changes),
+                    }
+                }
+            }
+        } else {
+            self.counter = self.counter.wrapping_add(1);
+        }
+    }
+
+    /// Gives output compare pins to the timer or back to GPIO, after a COM1x change.
+    fn update_pin_owners(&mut self, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        for i in 0..2 {
+            let gpio_pin = &mut gpio_pins[self.pin_ids[i] as usize];
+            if self.compare_output_mode[i] != CompareOutputMode::Disabled {
+                output_changes.push((self.pin_ids[i], PinState::from_bool(self.pins[i])));
+                gpio_pin.0 = false;
+            } else {
+                output_changes.push((self.pin_ids[i], gpio_pin.1));
+                gpio_pin.0 = true;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn read_tccr(&self) -> u8 {
+        (self.ctc as u8) << 7 |
+        (self.pwm[0] as u8) << 6 |
+        (self.compare_output_mode[0] as u8) << 4 |
+        self.clock_select
+    }
+    #[inline]
+    pub fn read_gtccr(&self) -> u8 {
+        (self.tsm as u8) << 7 |
+        (self.pwm[1] as u8) << 6 |
+        (self.compare_output_mode[1] as u8) << 4
+    }
+    #[inline]
+    pub fn read_tcnt(&self) -> u8 {
+        self.counter
+    }
+    /// Reads OCR1A, OCR1B or OCR1C.
+    #[inline]
+    pub fn read_ocr(&self, i: usize) -> u8 {
+        self.ocr[i]
+    }
+    /// Reads PLLCSR, the PLL locks immediately.
+    #[inline]
+    pub fn read_pllcsr(&self) -> u8 {
+        self.pllcsr | self.pllcsr.bit(1) as u8
+    }
+
+    fn interrupt_bits(&self, bits: &Timer8Interrupts) -> u8 {
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        (bits.oc[1] as u8) << oc_b |
+        (bits.oc[0] as u8) << oc_a |
+        (bits.overflow as u8) << overflow
+    }
+    /// Reads this timer's bits of TIMSK, which may be shared with other timers.
+    #[inline]
+    pub fn read_timsk(&self) -> u8 {
+        self.interrupt_bits(&self.interrupt_masks)
+    }
+    /// Reads this timer's bits of TIFR, which may be shared with other timers.
+    #[inline]
+    pub fn read_tifr(&self) -> u8 {
+        self.interrupt_bits(&self.interrupt_flags)
+    }
+
+    pub fn write_tccr(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        self.ctc = val.bit(7);
+        self.pwm[0] = val.bit(6);
+        self.compare_output_mode[0] = unsafe { std::mem::transmute::<u8, CompareOutputMode>((val >> 4) & 0x3) };
+        self.clock_select = val & 0x0F;
+        self.update_pin_owners(output_changes, gpio_pins);
+    }
+
+    pub fn write_gtccr(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        self.tsm = val.bit(7);
+        self.pwm[1] = val.bit(6);
+        self.compare_output_mode[1] = unsafe { std::mem::transmute::<u8, CompareOutputMode>((val >> 4) & 0x3) };
+        self.update_pin_owners(output_changes, gpio_pins);
+        // Force output compare strobes, only in non-PWM modes
+        for i in 0..2 {
+            if val.bit(2 + i) && !self.pwm[i] {
+                self.compare_output(i, output_changes);
+            }
+        }
+        if val.bit(1) {
+            self.prescaler = 0;
+        }
+    }
+
+    #[inline]
+    pub fn write_tcnt(&mut self, val: u8) {
+        self.counter = val;
+    }
+    /// Writes OCR1A, OCR1B or OCR1C.
+    #[inline]
+    pub fn write_ocr(&mut self, i: usize, val: u8) {
+        self.ocr[i] = val;
+    }
+    #[inline]
+    pub fn write_pllcsr(&mut self, val: u8) {
+        self.pllcsr = val & 0x86;
+    }
+
+    #[inline]
+    pub fn write_timsk(&mut self, val: u8) {
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        self.interrupt_masks.oc[1] = val.bit(oc_b);
+        self.interrupt_masks.oc[0] = val.bit(oc_a);
+        self.interrupt_masks.overflow = val.bit(overflow);
+    }
+    #[inline]
+    pub fn write_tifr(&mut self, val: u8) {
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        if val.bit(oc_b) {
+            self.interrupt_flags.oc[1] = false;
+        }
+        if val.bit(oc_a) {
+            self.interrupt_flags.oc[0] = false;
+        }
+        if val.bit(overflow) {
+            self.interrupt_flags.overflow = false;
+        }
+    }
+
+    /// Updates interrupt request lines after a change of TIFR or TIMSK.
+    pub fn update_interrupts(&self, irq: &mut InterruptController) {
+        let (flags, masks) = (&self.interrupt_flags, &self.interrupt_masks);
+        for i in 0..2 {
+            irq.set(self.vectors.oc[i], flags.oc[i] && masks.oc[i]);
+        }
+        irq.set(self.vectors.overflow, flags.overflow && masks.overflow);
+    }
+
+    /// Clears the interrupt flag of an executed interrupt vector, if it belongs to this timer.
+    pub fn acknowledge_interrupt(&mut self, vector: u8) {
+        let (flags, vectors) = (&mut self.interrupt_flags, &self.vectors);
+        if vector == vectors.overflow {
+            flags.overflow = false;
+        } else if let Some(i) = vectors.oc.iter().position(|&v| v == vector) {
+            flags.oc[i] = false;
+        }
+    }
+
+    /// Pushes current states of the output compare pins it drives.
+    pub fn announce_outputs(&self, output_changes: &mut Vec<(PinId, PinState)>) {
+        for i in 0..2 {
+            if self.compare_output_mode[i] != CompareOutputMode::Disabled {
+                output_changes.push((self.pin_ids[i], PinState::from_bool(self.pins[i])));
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.counter);
+        w.write_bytes(&self.ocr);
+        w.write_bool(self.pins[0]);
+        w.write_bool(self.pins[1]);
+        w.write_u16(self.prescaler);
+        w.write_u8(self.read_tccr());
+        w.write_u8(self.read_gtccr());
+        w.write_u8(self.pllcsr);
+        w.write_u8(self.read_timsk());
+        w.write_u8(self.read_tifr());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.counter = r.read_u8()?;
+        r.read_bytes(&mut self.ocr)?;
+        self.pins = [r.read_bool()?, r.read_bool()?];
+        self.prescaler = r.read_u16()? & 0x3FFF;
+        let tccr = r.read_u8()?;
+        let gtccr = r.read_u8()?;
+        self.ctc = tccr.bit(7);
+        self.pwm = [tccr.bit(6), gtccr.bit(6)];
+        self.tsm = gtccr.bit(7);
+        unsafe {
+            self.compare_output_mode[0] = std::mem::transmute::<u8, CompareOutputMode>((tccr >> 4) & 0x3);
+            self.compare_output_mode[1] = std::mem::transmute::<u8, CompareOutputMode>((gtccr >> 4) & 0x3);
+        }
+        self.clock_select = tccr & 0x0F;
+        self.write_pllcsr(r.read_u8()?);
+        self.write_timsk(r.read_u8()?);
+        let tifr = r.read_u8()?;
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        self.interrupt_flags = Timer8Interrupts {
+            overflow: tifr.bit(overflow),
+            oc: [tifr.bit(oc_a), tifr.bit(oc_b)],
+        };
+        Ok(())
+    }
+}
+
+impl VcdFiller for PllTimer {
+    const IS_SIGNAL: bool = false;
+
+    fn init_vcd_module(&self, builder: &mut VcdModuleBuilder) {
+        builder.add_signal("counter", 8, PinState::Low);
+        builder.add_signal("ocra", 8, PinState::Low);
+        builder.add_signal("ocrb", 8, PinState::Low);
+        builder.add_signal("ocrc", 8, PinState::Low);
+        builder.add_signal("coma", 2, PinState::Low);
+        builder.add_signal("comb", 2, PinState::Low);
+        builder.add_signal("cs", 4, PinState::Low);
+    }
+
+    fn fill_module(&self, module: &mut VcdTreeModule) -> bool {
+        let mut r = module.update_subsignal(0, self.counter);
+        r |= module.update_subsignal(1, self.ocr[0]);
+        r |= module.update_subsignal(2, self.ocr[1]);
+        r |= module.update_subsignal(3, self.ocr[2]);
+        r |= module.update_subsignal(4,
+            PinVec::init_logical(2, self.compare_output_mode[0] as u32));
+        r |= module.update_subsignal(5,
+            PinVec::init_logical(2, self.compare_output_mode[1] as u32));
+        r |= module.update_subsignal(6,
+            PinVec::init_logical(4, self.clock_select as u32));
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pwm_from_pll() {
+        let mut t = PllTimer::new([0, 1], Timer8Interrupts { overflow: 4, oc: [3, 9] }, [2, 6, 5]);
+        let mut irq = InterruptController::new();
+        let mut changes = Vec::new();
+        let mut gpio_pins = [(true, PinState::Z); 2];
+        t.write_pllcsr(0x06); // PLLE, PCKE
+        assert_eq!(t.read_pllcsr(), 0x07);
+        t.write_ocr(0, 2);
+        t.write_ocr(1, 0xFF);
+        t.write_ocr(2, 7);
+        t.write_timsk(0x04);
+        t.write_tccr(0x61, &mut changes, &mut gpio_pins); // PWM1A, COM1A = clear on match, CK
+        assert!(!gpio_pins[0].0);
+        changes.clear();
+
+        // 4 timer clocks per system clock
+        t.tick_clock(&mut changes, &mut irq);
+        assert_eq!(t.read_tcnt(), 4);
+        assert_eq!(changes, [(0, PinState::Low)]);
+        assert_eq!(irq.pending(), None);
+        t.tick_clock(&mut changes, &mut irq);
+        assert_eq!(t.read_tcnt(), 0);
+        assert_eq!(changes[1..], [(0, PinState::High)]);
+        t.tick_clock(&mut changes, &mut irq);
+        assert_eq!(t.read_tcnt(), 4);
+        assert_eq!(changes[2..], [(0, PinState::Low)]);
+        assert_eq!(t.read_tifr(), 0x44);
+        assert_eq!(irq.pending(), Some(4));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::components::avr::snapshot::{StateWriter, StateReader, SnapshotError};
+
+/// A source of an MCU reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetSource {
+    PowerOn,
+    /// Low level on RESET pin.
+    External,
+    /// Supply voltage below the BODLEVEL fuses level.
+    BrownOut,
+    Watchdog,
+    /// AVR_RESET instruction of the JTAG interface.
+    Jtag,
+}
+
+impl ResetSource {
+    /// Returns the MCUSR flag of the reset source.
+    #[inline]
+    pub fn flag(self) -> u8 {
+        match self {
+            ResetSource::PowerOn => 0x01,
+            ResetSource::External => 0x02,
+            ResetSource::BrownOut => 0x04,
+            ResetSource::Watchdog => 0x08,
+            ResetSource::Jtag => 0x10,
+        }
+    }
+}
+
+/// MCU status register (MCUSR), recording the sources of resets.
+pub struct ResetFlags {
+    mcusr: u8,
+    /// Flags present in the model.
+    mask: u8,
+}
+
+impl ResetFlags {
+    /// Creates MCUSR in its power-on state.
+    pub fn new(mask: u8) -> ResetFlags {
+        ResetFlags {
+            mcusr: ResetSource::PowerOn.flag(),
+            mask,
+        }
+    }
+
+    /// Records a reset. Flags are kept until cleared by software, except for a power-on reset clearing them.
+    pub fn record(&mut self, source: ResetSource) {
+        if source == ResetSource::PowerOn {
+            self.mcusr = 0;
+        }
+        self.mcusr |= source.flag() & self.mask;
+    }
+
+    /// Returns `true` if WDRF is set, which keeps the watchdog in system reset mode.
+    #[inline]
+    pub fn watchdog_flag(&self) -> bool {
+        self.mcusr & ResetSource::Watchdog.flag() != 0
+    }
+
+    #[inline]
+    pub fn read_mcusr(&self) -> u8 {
+        self.mcusr
+    }
+
+    /// Writes MCUSR. Flags can only be cleared by writing zeros.
+    #[inline]
+    pub fn write_mcusr(&mut self, val: u8) {
+        self.mcusr &= val;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.mcusr);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.mcusr = r.read_u8()? & self.mask;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags() {
+        let mut flags = ResetFlags::new(0x0F);
+        assert_eq!(flags.read_mcusr(), 0x01);
+
+        flags.record(ResetSource::Watchdog);
+        flags.record(ResetSource::External);
+        assert_eq!(flags.read_mcusr(), 0x0B);
+        assert!(flags.watchdog_flag());
+
+        // Writing ones doesn't set flags, JTRF is missing from the mask
+        flags.write_mcusr(0xF2);
+        flags.record(ResetSource::Jtag);
+        assert_eq!(flags.read_mcusr(), 0x02);
+
+        flags.record(ResetSource::PowerOn);
+        assert_eq!(flags.read_mcusr(), 0x01);
+    }
+}
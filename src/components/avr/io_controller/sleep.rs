@@ -0,0 +1,112 @@
+use bitfield::Bit;
+
+use crate::components::avr::{mcu_model::SleepDesc, snapshot::{StateWriter, StateReader, SnapshotError}};
+
+/// AVR sleep mode, selected by SM2:0 bits of SMCR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    Idle,
+    AdcNoiseReduction,
+    PowerDown,
+    PowerSave,
+    Standby,
+    ExtendedStandby,
+}
+
+impl SleepMode {
+    /// Returns `true` if clk_IO keeps running in this mode.
+    ///
+    /// Synchronous peripherals (timers, USART) are only clocked in Idle mode.
+    #[inline]
+    pub fn io_clock_running(self) -> bool {
+        self == SleepMode::Idle
+    }
+}
+
+/// Sleep mode control register (SMCR, or MCUCR on ATtiny).
+pub struct SleepController {
+    desc: SleepDesc,
+    smcr: u8,
+}
+
+impl SleepController {
+    pub fn new(desc: SleepDesc) -> SleepController {
+        SleepController { desc, smcr: 0 }
+    }
+
+    /// Returns a mode the CPU enters on SLEEP instruction.
+    ///
+    /// Returns `None` if sleep is not enabled (SE bit is cleared)
+    /// or if the selected mode is reserved.
+    pub fn sleep_mode(&self) -> Option<SleepMode> {
+        if !self.smcr.bit(self.desc.se_bit as usize) {
+            return None;
+        }
+        let mode_mask = self.desc.modes.len() - 1;
+        let mode = (self.smcr >> self.desc.sm_shift) as usize & mode_mask;
+        self.desc.modes[mode]
+    }
+
+    #[inline]
+    pub fn read_smcr(&self) -> u8 {
+        self.smcr
+    }
+
+    #[inline]
+    pub fn write_smcr(&mut self, val: u8) {
+        self.smcr = val & self.desc.mask;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.smcr);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.write_smcr(r.read_u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::mcu_model::{McuModel, Atmega2560, Attiny85};
+
+    use super::*;
+
+    #[test]
+    fn sleep_modes() {
+        let mut sleep = SleepController::new(Atmega2560::peripherals().sleep);
+        assert_eq!(sleep.sleep_mode(), None);
+
+        sleep.write_smcr(0b0000_010_0);
+        assert_eq!(sleep.sleep_mode(), None);
+
+        sleep.write_smcr(0b0000_000_1);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::Idle));
+        sleep.write_smcr(0b0000_001_1);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::AdcNoiseReduction));
+        sleep.write_smcr(0b0000_010_1);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::PowerDown));
+        sleep.write_smcr(0b0000_011_1);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::PowerSave));
+        sleep.write_smcr(0b0000_100_1);
+        assert_eq!(sleep.sleep_mode(), None);
+        sleep.write_smcr(0b0000_110_1);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::Standby));
+        sleep.write_smcr(0b1111_111_1);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::ExtendedStandby));
+        assert_eq!(sleep.read_smcr(), 0x0F);
+    }
+
+    #[test]
+    fn mcucr_sleep_modes() {
+        let mut sleep = SleepController::new(Attiny85::peripherals().sleep);
+        sleep.write_smcr(0b00_1_10_000);
+        assert_eq!(sleep.sleep_mode(), Some(SleepMode::PowerDown));
+        sleep.write_smcr(0b00_1_11_000);
+        assert_eq!(sleep.sleep_mode(), None);
+        sleep.write_smcr(0b01_0_00_011);
+        assert_eq!(sleep.sleep_mode(), None);
+        assert_eq!(sleep.read_smcr(), 0b01_0_00_011);
+    }
+}
@@ -1,11 +1,13 @@
 use bitfield::Bit;
 
-use crate::{pins::{PinId, PinState, PinVec}, vcd::{VcdFiller, VcdModuleBuilder, VcdTreeModule}};
+use crate::{pins::{PinId, PinState, PinVec}, vcd::{VcdFiller, VcdModuleBuilder, VcdTreeModule}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
+
+use super::interrupts::InterruptController;
 
 #[allow(dead_code)]
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum CompareOutputMode {
+pub(super) enum CompareOutputMode {
     Disabled = 0,
     Toggle = 1,
     Clear = 2,
@@ -51,10 +53,10 @@ enum WaveformGenerationMode {
     FastPwmOcrA = 15,
 }
 
-pub struct Timer16Interrupts {
-    pub overflow: bool,
-    pub oc: [bool; 3],
-    pub input_capture: bool,
+pub struct Timer16Interrupts<T = bool> {
+    pub overflow: T,
+    pub oc: [T; 3],
+    pub input_capture: T,
 }
 
 pub struct Timer16 {
@@ -64,6 +66,8 @@ pub struct Timer16 {
     active_ocr: [u16; 3],
     compare_output_mode: [CompareOutputMode; 3],
     pin_ids: [PinId; 3],
+    /// Number of output compare channels (2 or 3).
+    channels: usize,
 
     upcounting: bool,
     clock_mode: ClockMode,
@@ -71,16 +75,24 @@ pub struct Timer16 {
 
     interrupt_masks: Timer16Interrupts,
     pub interrupt_flags: Timer16Interrupts,
+    vectors: Timer16Interrupts<u8>,
 }
 
 impl Timer16 {
-    pub fn new(pin_ids: [PinId; 3]) -> Timer16 {
+    /// Creates a timer with an output compare channel for each OC pin.
+    /// 
+    /// Vectors of missing channels are never raised.
+    pub fn new(oc_pins: &[PinId], vectors: Timer16Interrupts<u8>) -> Timer16 {
+        assert!((1..=3).contains(&oc_pins.len()), "A 16-bit timer has 1 to 3 output compare channels");
+        let mut pin_ids = [0; 3];
+        pin_ids[..oc_pins.len()].copy_from_slice(oc_pins);
         Timer16 { 
             counter: 0,
             pins: [false; 3],
             reg_ocr: [0, 0, 0],
             active_ocr: [0, 0, 0],
             pin_ids,
+            channels: oc_pins.len(),
             compare_output_mode: [CompareOutputMode::Disabled; 3],
             clock_mode: ClockMode::Disabled,
             upcounting: true,
@@ -94,7 +106,8 @@ impl Timer16 {
                 overflow: false,
                 oc: [false; 3],
                 input_capture: false
-            }
+            },
+            vectors,
         }
     }
 
@@ -121,7 +134,17 @@ impl Timer16 {
     }
 
     #[inline]
-    pub fn update_oc(&mut self, i: usize, output_changes: &mut Vec<(PinId, PinState)>, interrupt: &mut bool) {
+    pub fn update_oc(&mut self, i: usize, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        self.compare_output(i, output_changes);
+        if self.interrupt_masks.oc[i] {
+            self.interrupt_flags.oc[i] = true;
+            irq.raise(self.vectors.oc[i]);
+        }
+    }
+
+    /// Changes an OC pin on a compare match.
+    #[inline]
+    fn compare_output(&mut self, i: usize, output_changes: &mut Vec<(PinId, PinState)>) {
         // TODO: This shouldn't work with incorrect DDR
         match self.compare_output_mode[i] {
             CompareOutputMode::Disabled => {},
@@ -146,10 +169,6 @@ impl Timer16 {
                 output_changes.push((self.pin_ids[i], PinState::Low))
             }
         }
-        if self.interrupt_masks.oc[i] {
-            self.interrupt_flags.oc[i] = true;
-            *interrupt = true;
-        }
     }
 
     #[inline]
@@ -174,7 +193,7 @@ impl Timer16 {
         self.clock_mode != ClockMode::Disabled
     }
 
-    pub fn tick_prescaler(&mut self, prescaler: u16, output_changes: &mut Vec<(PinId, PinState)>, interrupt: &mut bool) {
+    pub fn tick_prescaler(&mut self, prescaler: u16, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
         let should_tick = match self.clock_mode {
             ClockMode::Disabled => false,
             ClockMode::Clk1 => true,
@@ -186,11 +205,11 @@ impl Timer16 {
             ClockMode::ExternalRising => todo!(),
         };
         if should_tick {
-            self.tick(output_changes, interrupt)
+            self.tick(output_changes, irq)
         }
     }
 
-    fn tick(&mut self, output_changes: &mut Vec<(PinId, PinState)>, interrupt: &mut bool) {
+    fn tick(&mut self, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
         if self.counter == 0 {
             match self.waveform_mode {
                 WaveformGenerationMode::FastPwm8Bit |
@@ -208,7 +227,7 @@ impl Timer16 {
                     self.upcounting = true;
                     if self.interrupt_masks.overflow {
                         self.interrupt_flags.overflow = true;
-                        *interrupt = true;
+                        irq.raise(self.vectors.overflow);
                     }
                 }
 
@@ -218,16 +237,16 @@ impl Timer16 {
                     self.active_ocr = self.reg_ocr;
                     if self.interrupt_masks.overflow {
                         self.interrupt_flags.overflow = true;
-                        *interrupt = true;
+                        irq.raise(self.vectors.overflow);
                     }
                 }
                 _ => {}
             }
         }
 
-        for i in 0..3 {
+        for i in 0..self.channels {
             if self.active_ocr[i] == self.counter {
-                self.update_oc(i, output_changes, interrupt);
+                self.update_oc(i, output_changes, irq);
             }
         }
 
@@ -257,9 +276,9 @@ impl Timer16 {
                     self.counter = 0;
                     if self.interrupt_masks.overflow {
                         self.interrupt_flags.overflow = true;
-                        *interrupt = true;
+                        irq.raise(self.vectors.overflow);
                     }
-                    for i in 0..3 {
+                    for i in 0..self.channels {
                         self.reset_oc_pwm(i, output_changes);
                     }
                 }
@@ -272,7 +291,7 @@ impl Timer16 {
                     WaveformGenerationMode::Ctc |
                     WaveformGenerationMode::CtcIcr => {
                         self.interrupt_flags.overflow = true;
-                        *interrupt = true;
+                        irq.raise(self.vectors.overflow);
                     }
                     _ => {}
                 }
@@ -352,14 +371,15 @@ impl Timer16 {
     }
 
     #[inline]
-    pub fn write_tccra(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState); 86]) {
+    pub fn write_tccra(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
         unsafe{
             self.compare_output_mode[0] = std::mem::transmute((val >> 6) & 0x3);
             self.compare_output_mode[1] = std::mem::transmute((val >> 4) & 0x3);
             self.compare_output_mode[2] = std::mem::transmute((val >> 2) & 0x3);
             self.waveform_mode = std::mem::transmute(self.waveform_mode as u8 & 0xC | val & 0x3);
         }
-        for i in 0..3 {
+        self.compare_output_mode[self.channels..].fill(CompareOutputMode::Disabled);
+        for i in 0..self.channels {
             let gpio_pin = &mut gpio_pins[self.pin_ids[i] as usize];
             if self.compare_output_mode[i] != CompareOutputMode::Disabled {
                 output_changes.push((self.pin_ids[i], PinState::from_bool(self.pins[i])));
@@ -379,6 +399,22 @@ impl Timer16 {
         self.upcounting = true;
     }
 
+    /// Writes TCCRnC, FOCnx strobes change OC pins like a compare match, only in non-PWM modes.
+    ///
+    /// A forced compare match doesn't set the interrupt flag or clear the timer.
+    #[inline]
+    pub fn write_tccrc(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>) {
+        if !matches!(self.waveform_mode,
+                WaveformGenerationMode::Normal | WaveformGenerationMode::Ctc | WaveformGenerationMode::CtcIcr) {
+            return;
+        }
+        for i in 0..self.channels {
+            if val.bit(7 - i) {
+                self.compare_output(i, output_changes);
+            }
+        }
+    }
+
     #[inline]
     pub fn write_tcntl(&mut self, val: u8) {
         self.counter = self.counter & 0xFF00 | val as u16;
@@ -459,6 +495,7 @@ impl Timer16 {
         self.interrupt_masks.oc[1] = val.bit(2);
         self.interrupt_masks.oc[0] = val.bit(1);
         self.interrupt_masks.overflow = val.bit(0);
+        self.interrupt_masks.oc[self.channels..].fill(false);
     }
     #[inline]
     pub fn write_tifr(&mut self, val: u8) {
@@ -478,6 +515,78 @@ impl Timer16 {
             self.interrupt_flags.overflow = false;
         }
     }
+
+    /// Updates interrupt request lines after a change of TIFR or TIMSK.
+    pub fn update_interrupts(&self, irq: &mut InterruptController) {
+        let (flags, masks) = (&self.interrupt_flags, &self.interrupt_masks);
+        irq.set(self.vectors.input_capture, flags.input_capture && masks.input_capture);
+        for i in 0..self.channels {
+            irq.set(self.vectors.oc[i], flags.oc[i] && masks.oc[i]);
+        }
+        irq.set(self.vectors.overflow, flags.overflow && masks.overflow);
+    }
+
+    /// Clears the interrupt flag of an executed interrupt vector, if it belongs to this timer.
+    pub fn acknowledge_interrupt(&mut self, vector: u8) {
+        let (flags, vectors) = (&mut self.interrupt_flags, &self.vectors);
+        if vector == vectors.input_capture {
+            flags.input_capture = false;
+        } else if vector == vectors.overflow {
+            flags.overflow = false;
+        } else if let Some(i) = vectors.oc[..self.channels].iter().position(|&v| v == vector) {
+            flags.oc[i] = false;
+        }
+    }
+
+    /// Pushes current states of the output compare pins it drives.
+    pub fn announce_outputs(&self, output_changes: &mut Vec<(PinId, PinState)>) {
+        for i in 0..self.channels {
+            if self.compare_output_mode[i] != CompareOutputMode::Disabled {
+                output_changes.push((self.pin_ids[i], PinState::from_bool(self.pins[i])));
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        for i in 0..3 {
+            w.write_bool(self.pins[i]);
+            w.write_u16(self.reg_ocr[i]);
+            w.write_u16(self.active_ocr[i]);
+        }
+        w.write_bool(self.upcounting);
+        w.write_u8(self.read_tccra());
+        w.write_u8(self.read_tccrb());
+        w.write_u8(self.read_timsk());
+        w.write_u8(self.read_tifr());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.counter = r.read_u16()?;
+        for i in 0..3 {
+            self.pins[i] = r.read_bool()?;
+            self.reg_ocr[i] = r.read_u16()?;
+            self.active_ocr[i] = r.read_u16()?;
+        }
+        self.upcounting = r.read_bool()?;
+        let tccra = r.read_u8()?;
+        let tccrb = r.read_u8()?;
+        unsafe{
+            self.compare_output_mode[0] = std::mem::transmute::<u8, CompareOutputMode>((tccra >> 6) & 0x3);
+            self.compare_output_mode[1] = std::mem::transmute::<u8, CompareOutputMode>((tccra >> 4) & 0x3);
+            self.compare_output_mode[2] = std::mem::transmute::<u8, CompareOutputMode>((tccra >> 2) & 0x3);
+            self.waveform_mode = std::mem::transmute::<u8, WaveformGenerationMode>((tccrb & 0x18) >> 1 | tccra & 0x3);
+            self.clock_mode = std::mem::transmute::<u8, ClockMode>(tccrb & 0x7);
+        }
+        self.write_timsk(r.read_u8()?);
+        let tifr = r.read_u8()?;
+        self.interrupt_flags = Timer16Interrupts {
+            overflow: tifr.bit(0),
+            oc: [tifr.bit(1), tifr.bit(2), tifr.bit(3)],
+            input_capture: tifr.bit(5),
+        };
+        Ok(())
+    }
 }
 
 impl VcdFiller for Timer16 {
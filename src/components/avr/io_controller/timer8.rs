@@ -0,0 +1,445 @@
+use bitfield::Bit;
+
+use crate::{pins::{PinId, PinState, PinVec}, vcd::{VcdFiller, VcdModuleBuilder, VcdTreeModule}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
+
+use super::{interrupts::InterruptController, timer16::CompareOutputMode};
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WaveformGenerationMode {
+    Normal = 0,
+    PwmPhase = 1,
+    Ctc = 2,
+    FastPwm = 3,
+    Reserved4 = 4,
+    PwmPhaseOcrA = 5,
+    Reserved6 = 6,
+    FastPwmOcrA = 7,
+}
+
+/// Prescaler division factors for clock select values 1 to 7, 0 meaning an external clock.
+const PRESCALER_SYNC: [u16; 7] = [1, 8, 64, 256, 1024, 0, 0];
+/// Timer2 has its own prescaler with more division factors and no external clock input.
+const PRESCALER_ASYNC: [u16; 7] = [1, 8, 32, 64, 128, 256, 1024];
+
+pub struct Timer8Interrupts<T = bool> {
+    pub overflow: T,
+    pub oc: [T; 2],
+}
+
+/// An 8-bit timer/counter (Timer0 or Timer2).
+///
+/// External clock inputs and asynchronous operation are not emulated.
+pub struct Timer8 {
+    counter: u8,
+    pins: [bool; 2],
+    reg_ocr: [u8; 2],
+    active_ocr: [u8; 2],
+    compare_output_mode: [CompareOutputMode; 2],
+    pin_ids: [PinId; 2],
+
+    upcounting: bool,
+    clock_select: u8,
+    prescaler: &'static [u16; 7],
+    waveform_mode: WaveformGenerationMode,
+    /// Set on OCRnA compare match, cleared on every prescaled tick.
+    compare_match_a: bool,
+
+    interrupt_masks: Timer8Interrupts,
+    interrupt_flags: Timer8Interrupts,
+    vectors: Timer8Interrupts<u8>,
+    /// Bits of overflow, compare A and compare B flags in TIFR and TIMSK.
+    int_bits: [usize; 3],
+}
+
+impl Timer8 {
+    /// Creates a timer, `async_prescaler` selects the Timer2 prescaler.
+    pub fn new(pin_ids: [PinId; 2], vectors: Timer8Interrupts<u8>, int_bits: [u8; 3], async_prescaler: bool) -> Timer8 {
+        Timer8 {
+            counter: 0,
+            pins: [false; 2],
+            reg_ocr: [0; 2],
+            active_ocr: [0; 2],
+            compare_output_mode: [CompareOutputMode::Disabled; 2],
+            pin_ids,
+            upcounting: true,
+            clock_select: 0,
+            prescaler: if async_prescaler {&PRESCALER_ASYNC} else {&PRESCALER_SYNC},
+            waveform_mode: WaveformGenerationMode::Normal,
+            compare_match_a: false,
+            interrupt_masks: Timer8Interrupts { overflow: false, oc: [false; 2] },
+            interrupt_flags: Timer8Interrupts { overflow: false, oc: [false; 2] },
+            vectors,
+            int_bits: int_bits.map(|bit| bit as usize),
+        }
+    }
+
+    #[inline]
+    fn top_value(&self) -> u8 {
+        match self.waveform_mode {
+            WaveformGenerationMode::Ctc |
+            WaveformGenerationMode::PwmPhaseOcrA |
+            WaveformGenerationMode::FastPwmOcrA => self.active_ocr[0],
+            _ => 0xFF,
+        }
+    }
+
+    #[inline]
+    fn is_fast_pwm(&self) -> bool {
+        matches!(self.waveform_mode, WaveformGenerationMode::FastPwm | WaveformGenerationMode::FastPwmOcrA)
+    }
+
+    #[inline]
+    fn is_phase_pwm(&self) -> bool {
+        matches!(self.waveform_mode, WaveformGenerationMode::PwmPhase | WaveformGenerationMode::PwmPhaseOcrA)
+    }
+
+    fn set_overflow(&mut self, irq: &mut InterruptController) {
+        self.interrupt_flags.overflow = true;
+        if self.interrupt_masks.overflow {
+            irq.raise(self.vectors.overflow);
+        }
+    }
+
+    fn update_oc(&mut self, i: usize, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        let state = match self.compare_output_mode[i] {
+            CompareOutputMode::Disabled => None,
+            CompareOutputMode::Toggle => Some(!self.pins[i]),
+            CompareOutputMode::Clear => Some(!self.upcounting),
+            CompareOutputMode::Set => Some(self.upcounting),
+        };
+        if let Some(state) = state {
+            self.pins[i] = state;
+            output_changes.push((self.pin_ids[i], PinState::from_bool(state)));
+        }
+        self.interrupt_flags.oc[i] = true;
+        self.compare_match_a |= i == 0;
+        if self.interrupt_masks.oc[i] {
+            irq.raise(self.vectors.oc[i]);
+        }
+    }
+
+    fn reset_oc_pwm(&mut self, i: usize, output_changes: &mut Vec<(PinId, PinState)>) {
+        let state = match self.compare_output_mode[i] {
+            CompareOutputMode::Clear => true,
+            CompareOutputMode::Set => false,
+            _ => return,
+        };
+        self.pins[i] = state;
+        output_changes.push((self.pin_ids[i], PinState::from_bool(state)));
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.clock_select != 0
+    }
+
+    /// Advances the timer by one clock, returns `true` on OCRnA compare match.
+    pub fn tick_prescaler(&mut self, prescaler: u16, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) -> bool {
+        let should_tick = match self.prescaler[self.clock_select as usize - 1] {
+            0 => false,
+            div => prescaler.is_multiple_of(div),
+        };
+        self.compare_match_a = false;
+        if should_tick {
+            self.tick(output_changes, irq)
+        }
+        self.compare_match_a
+    }
+
+    fn tick(&mut self, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        if self.counter == 0 {
+            if self.is_fast_pwm() {
+                self.active_ocr = self.reg_ocr;
+            } else if self.is_phase_pwm() {
+                self.upcounting = true;
+                self.set_overflow(irq);
+            }
+        }
+
+        for i in 0..2 {
+            if self.active_ocr[i] == self.counter {
+                self.update_oc(i, output_changes, irq);
+            }
+        }
+
+        if self.counter == self.top_value() && self.upcounting {
+            if self.is_phase_pwm() {
+                self.upcounting = false;
+                self.active_ocr = self.reg_ocr;
+                self.counter = self.counter.wrapping_sub(1);
+            } else {
+                if self.counter == 0xFF || self.is_fast_pwm() {
+                    self.set_overflow(irq);
+                }
+                if self.is_fast_pwm() {
+                    for i in 0..2 {
+                        self.reset_oc_pwm(i, output_changes);
+                    }
+                }
+                self.counter = 0;
+            }
+        } else if self.upcounting {
+            self.counter = self.counter.wrapping_add(1);
+        } else {
+            self.counter = self.counter.wrapping_sub(1);
+        }
+    }
+
+    #[inline]
+    pub fn read_tccra(&self) -> u8 {
+        (self.compare_output_mode[0] as u8) << 6 |
+        (self.compare_output_mode[1] as u8) << 4 |
+        (self.waveform_mode as u8) & 0x3
+    }
+    #[inline]
+    pub fn read_tccrb(&self) -> u8 {
+        ((self.waveform_mode as u8) & 0x4) << 1 |
+        self.clock_select
+    }
+
+    #[inline]
+    pub fn read_tcnt(&self) -> u8 {
+        self.counter
+    }
+
+    #[inline]
+    pub fn read_ocra(&self) -> u8 {
+        self.reg_ocr[0]
+    }
+    #[inline]
+    pub fn read_ocrb(&self) -> u8 {
+        self.reg_ocr[1]
+    }
+
+    fn interrupt_bits(&self, bits: &Timer8Interrupts) -> u8 {
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        (bits.oc[1] as u8) << oc_b |
+        (bits.oc[0] as u8) << oc_a |
+        (bits.overflow as u8) << overflow
+    }
+
+    /// Reads this timer's bits of TIMSK, which may be shared with other timers.
+    #[inline]
+    pub fn read_timsk(&self) -> u8 {
+        self.interrupt_bits(&self.interrupt_masks)
+    }
+    /// Reads this timer's bits of TIFR, which may be shared with other timers.
+    #[inline]
+    pub fn read_tifr(&self) -> u8 {
+        self.interrupt_bits(&self.interrupt_flags)
+    }
+
+    fn set_waveform_mode(&mut self, wgm: u8) {
+        self.waveform_mode = unsafe { std::mem::transmute::<u8, WaveformGenerationMode>(wgm & 0x7) };
+    }
+
+    pub fn write_tccra(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        unsafe {
+            self.compare_output_mode[0] = std::mem::transmute::<u8, CompareOutputMode>((val >> 6) & 0x3);
+            self.compare_output_mode[1] = std::mem::transmute::<u8, CompareOutputMode>((val >> 4) & 0x3);
+        }
+        self.set_waveform_mode(self.waveform_mode as u8 & 0x4 | val & 0x3);
+        for i in 0..2 {
+            let gpio_pin = &mut gpio_pins[self.pin_ids[i] as usize];
+            if self.compare_output_mode[i] != CompareOutputMode::Disabled {
+                output_changes.push((self.pin_ids[i], PinState::from_bool(self.pins[i])));
+                gpio_pin.0 = false;
+            } else {
+                output_changes.push((self.pin_ids[i], gpio_pin.1));
+                gpio_pin.0 = true;
+            }
+        }
+    }
+    #[inline]
+    pub fn write_tccrb(&mut self, val: u8) {
+        self.set_waveform_mode(self.waveform_mode as u8 & 0x3 | (val & 0x08) >> 1);
+        self.clock_select = val & 0x7;
+        self.upcounting = true;
+    }
+
+    #[inline]
+    pub fn write_tcnt(&mut self, val: u8) {
+        self.counter = val;
+    }
+
+    fn write_ocr(&mut self, i: usize, val: u8) {
+        self.reg_ocr[i] = val;
+        if matches!(self.waveform_mode, WaveformGenerationMode::Normal | WaveformGenerationMode::Ctc) {
+            self.active_ocr[i] = val;
+        }
+    }
+    #[inline]
+    pub fn write_ocra(&mut self, val: u8) {
+        self.write_ocr(0, val)
+    }
+    #[inline]
+    pub fn write_ocrb(&mut self, val: u8) {
+        self.write_ocr(1, val)
+    }
+
+    #[inline]
+    pub fn write_timsk(&mut self, val: u8) {
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        self.interrupt_masks.oc[1] = val.bit(oc_b);
+        self.interrupt_masks.oc[0] = val.bit(oc_a);
+        self.interrupt_masks.overflow = val.bit(overflow);
+    }
+    #[inline]
+    pub fn write_tifr(&mut self, val: u8) {
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        if val.bit(oc_b) {
+            self.interrupt_flags.oc[1] = false;
+        }
+        if val.bit(oc_a) {
+            self.interrupt_flags.oc[0] = false;
+        }
+        if val.bit(overflow) {
+            self.interrupt_flags.overflow = false;
+        }
+    }
+
+    /// Updates interrupt request lines after a change of TIFR or TIMSK.
+    pub fn update_interrupts(&self, irq: &mut InterruptController) {
+        let (flags, masks) = (&self.interrupt_flags, &self.interrupt_masks);
+        for i in 0..2 {
+            irq.set(self.vectors.oc[i], flags.oc[i] && masks.oc[i]);
+        }
+        irq.set(self.vectors.overflow, flags.overflow && masks.overflow);
+    }
+
+    /// Clears the interrupt flag of an executed interrupt vector, if it belongs to this timer.
+    pub fn acknowledge_interrupt(&mut self, vector: u8) {
+        let (flags, vectors) = (&mut self.interrupt_flags, &self.vectors);
+        if vector == vectors.overflow {
+            flags.overflow = false;
+        } else if let Some(i) = vectors.oc.iter().position(|&v| v == vector) {
+            flags.oc[i] = false;
+        }
+    }
+
+    /// Pushes current states of the output compare pins it drives.
+    pub fn announce_outputs(&self, output_changes: &mut Vec<(PinId, PinState)>) {
+        for i in 0..2 {
+            if self.compare_output_mode[i] != CompareOutputMode::Disabled {
+                output_changes.push((self.pin_ids[i], PinState::from_bool(self.pins[i])));
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.counter);
+        for i in 0..2 {
+            w.write_bool(self.pins[i]);
+            w.write_u8(self.reg_ocr[i]);
+            w.write_u8(self.active_ocr[i]);
+        }
+        w.write_bool(self.upcounting);
+        w.write_u8(self.read_tccra());
+        w.write_u8(self.read_tccrb());
+        w.write_u8(self.read_timsk());
+        w.write_u8(self.read_tifr());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.counter = r.read_u8()?;
+        for i in 0..2 {
+            self.pins[i] = r.read_bool()?;
+            self.reg_ocr[i] = r.read_u8()?;
+            self.active_ocr[i] = r.read_u8()?;
+        }
+        self.upcounting = r.read_bool()?;
+        let tccra = r.read_u8()?;
+        let tccrb = r.read_u8()?;
+        unsafe {
+            self.compare_output_mode[0] = std::mem::transmute::<u8, CompareOutputMode>((tccra >> 6) & 0x3);
+            self.compare_output_mode[1] = std::mem::transmute::<u8, CompareOutputMode>((tccra >> 4) & 0x3);
+        }
+        self.set_waveform_mode((tccrb & 0x08) >> 1 | tccra & 0x3);
+        self.clock_select = tccrb & 0x7;
+        self.write_timsk(r.read_u8()?);
+        let tifr = r.read_u8()?;
+        let [overflow, oc_a, oc_b] = self.int_bits;
+        self.interrupt_flags = Timer8Interrupts {
+            overflow: tifr.bit(overflow),
+            oc: [tifr.bit(oc_a), tifr.bit(oc_b)],
+        };
+        Ok(())
+    }
+}
+
+impl VcdFiller for Timer8 {
+    const IS_SIGNAL: bool = false;
+
+    fn init_vcd_module(&self, builder: &mut VcdModuleBuilder) {
+        builder.add_signal("counter", 8, PinState::Low);
+        builder.add_signal("ocra", 8, PinState::Low);
+        builder.add_signal("ocrb", 8, PinState::Low);
+        builder.add_signal("coma", 2, PinState::Low);
+        builder.add_signal("comb", 2, PinState::Low);
+        builder.add_signal("wgm", 3, PinState::Low);
+        builder.add_signal("cs", 3, PinState::Low);
+    }
+
+    fn fill_module(&self, module: &mut VcdTreeModule) -> bool {
+        let mut r = module.update_subsignal(0, self.counter);
+        r |= module.update_subsignal(1, self.active_ocr[0]);
+        r |= module.update_subsignal(2, self.active_ocr[1]);
+        r |= module.update_subsignal(3,
+            PinVec::init_logical(2, self.compare_output_mode[0] as u32));
+        r |= module.update_subsignal(4,
+            PinVec::init_logical(2, self.compare_output_mode[1] as u32));
+        r |= module.update_subsignal(5,
+            PinVec::init_logical(3, self.waveform_mode as u32));
+        r |= module.update_subsignal(6,
+            PinVec::init_logical(3, self.clock_select as u32));
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer() -> Timer8 {
+        Timer8::new([0, 1], Timer8Interrupts { overflow: 3, oc: [1, 2] }, [0, 1, 2], false)
+    }
+
+    #[test]
+    fn normal_overflow() {
+        let mut t = timer();
+        let mut irq = InterruptController::new();
+        let mut changes = Vec::new();
+        t.write_timsk(0x01);
+        t.write_tcnt(0xFE);
+        t.write_tccrb(0x01);
+        t.tick_prescaler(1, &mut changes, &mut irq);
+        assert_eq!(t.read_tcnt(), 0xFF);
+        assert_eq!(irq.pending(), None);
+        t.tick_prescaler(1, &mut changes, &mut irq);
+        assert_eq!(t.read_tcnt(), 0x00);
+        assert_eq!(t.read_tifr(), 0x01);
+        assert_eq!(irq.pending(), Some(3));
+    }
+
+    #[test]
+    fn ctc_toggle() {
+        let mut t = timer();
+        let mut irq = InterruptController::new();
+        let mut changes = Vec::new();
+        let mut gpio_pins = [(true, PinState::Z); 2];
+        t.write_tccra(0x42, &mut changes, &mut gpio_pins); // COM0A = toggle, WGM = CTC
+        t.write_ocra(2);
+        t.write_ocrb(0xFF);
+        t.write_tccrb(0x02); // clk/8
+        changes.clear();
+        for prescaler in 0..24 {
+            t.tick_prescaler(prescaler, &mut changes, &mut irq);
+        }
+        assert_eq!(changes, [(0, PinState::High)]);
+        assert_eq!(t.read_tcnt(), 0);
+        assert_eq!(t.read_tifr(), 0x02);
+        assert_eq!(irq.pending(), None);
+    }
+}
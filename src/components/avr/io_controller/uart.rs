@@ -1,6 +1,10 @@
+use std::cell::Cell;
+
 use bitfield::Bit;
 
-use crate::pins::{PinId, PinState};
+use crate::{pins::{PinId, PinState}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
+
+use super::interrupts::InterruptController;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum UartMode {
@@ -16,6 +20,15 @@ enum ParityMode {
     Odd = 3,
 }
 
+pub struct UsartInterrupts<T = bool> {
+    /// Receive complete.
+    pub rx: T,
+    /// Data register empty.
+    pub udre: T,
+    /// Transmit complete.
+    pub tx: T,
+}
+
 pub struct UartController {
     ubbr: u16,
     counter: u16,
@@ -43,10 +56,33 @@ pub struct UartController {
     transmitter_shift: u16,
     transmitter_pos: u8,
     transmitter_parity: bool,
+    /// A frame is being sent, until the end of its stop bit.
+    transmitter_busy: bool,
+    /// TXC flag, cleared by writing 1 or by executing its vector.
+    transmit_complete: bool,
+
+    receiver_busy: bool,
+    /// Clock ticks left until the next RX sample.
+    receiver_ticks: u8,
+    receiver_shift: u16,
+    receiver_pos: u8,
+    receiver_parity: bool,
+    receiver_parity_error: bool,
+    /// Received character, with the 9th bit in bit 8.
+    receiver_udr: u16,
+    /// RXC flag, cleared by reading UDR.
+    receive_complete: Cell<bool>,
+    frame_error: bool,
+    data_overrun: bool,
+    parity_error: bool,
+
+    /// RXCIE, UDRIE and TXCIE bits of UCSRB.
+    interrupt_masks: UsartInterrupts,
+    vectors: UsartInterrupts<u8>,
 }
 
 impl UartController {
-    pub fn new(xck_pin: PinId, tx_pin: PinId) -> UartController {
+    pub fn new(xck_pin: PinId, tx_pin: PinId, vectors: UsartInterrupts<u8>) -> UartController {
         UartController { 
             ubbr: 0,
             counter: 0,
@@ -74,38 +110,78 @@ impl UartController {
             transmitter_shift: 0,
             transmitter_parity: false,
             transmitter_pos: 0,
+            transmitter_busy: false,
+            transmit_complete: false,
+
+            receiver_busy: false,
+            receiver_ticks: 0,
+            receiver_shift: 0,
+            receiver_pos: 0,
+            receiver_parity: false,
+            receiver_parity_error: false,
+            receiver_udr: 0,
+            receive_complete: Cell::new(false),
+            frame_error: false,
+            data_overrun: false,
+            parity_error: false,
+            interrupt_masks: UsartInterrupts { rx: false, udre: false, tx: false },
+            vectors,
         }
     }
 
-    pub fn tick(&mut self, output_changes: &mut Vec<(PinId, PinState)>, interrupt: &mut bool) {
+    /// Advances the USART by a clock cycle, `rx` is the current level of the RXD pin.
+    pub fn tick(&mut self, rx: bool, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        // RXC is also cleared by reading UDR, so the lines are updated every cycle
+        self.update_interrupts(irq);
         if !self.transmitter_enabled && !self.reciever_enabled {
             return;
         }
         if self.counter == 0 {
             self.counter = self.ubbr;
             self.prescaler = (self.prescaler + 1) % 16;
-            if self.mode == UartMode::Sync && self.xck_ddr {
-                let xck = self.prescaler.bit(0);
-                output_changes.push((self.xck_pin, PinState::from_bool(xck)));
-                if self.transmitter_enabled && xck != self.polarity_inverted {
-                    self.tick_transmitter(output_changes, interrupt);
+            match self.mode {
+                UartMode::Sync if self.xck_ddr => {
+                    let xck = self.prescaler.bit(0);
+                    output_changes.push((self.xck_pin, PinState::from_bool(xck)));
+                    if xck != self.polarity_inverted {
+                        if self.transmitter_enabled {
+                            self.tick_transmitter(output_changes);
+                        }
+                    } else if self.reciever_enabled {
+                        // Data is sampled on the opposite XCK edge
+                        self.tick_receiver(rx, 1);
+                    }
                 }
+                UartMode::Async => {
+                    // RXD is sampled 16 times per bit (8 times in double speed mode)
+                    let bit_ticks = if self.u2x {8} else {16};
+                    if self.reciever_enabled {
+                        self.tick_receiver(rx, bit_ticks);
+                    }
+                    if self.transmitter_enabled && self.prescaler.is_multiple_of(bit_ticks) {
+                        self.tick_transmitter(output_changes);
+                    }
+                }
+                _ => {}
             }
         } else {
             self.counter = self.counter.wrapping_sub(1);
         }
     }
 
-    fn tick_transmitter(&mut self, output_changes: &mut Vec<(PinId, PinState)>, interrupt: &mut bool) {
+    fn tick_transmitter(&mut self, output_changes: &mut Vec<(PinId, PinState)>) {
         if self.transmitter_pos == 0 {
             if !self.data_register_empty {
                 self.transmitter_shift = self.transmitter_udr;
                 self.data_register_empty = true;
                 self.transmitter_pos = 1;
                 self.transmitter_parity = self.parity == ParityMode::Odd;
+                self.transmitter_busy = true;
                 output_changes.push((self.tx_pin, PinState::Low)); // Start bit
-            } else {
-                return;
+            } else if self.transmitter_busy {
+                // The stop bit is over and there is no next character
+                self.transmitter_busy = false;
+                self.transmit_complete = true;
             }
         } else {
             if self.transmitter_pos <= self.char_size {
@@ -128,9 +204,77 @@ impl UartController {
         }
     }
 
+    /// Samples RXD once, with `bit_ticks` samples per bit.
+    ///
+    /// Only the middle sample of every bit is used, the second stop bit is ignored.
+    fn tick_receiver(&mut self, rx: bool, bit_ticks: u8) {
+        if !self.receiver_busy {
+            if rx {
+                return;
+            }
+            self.receiver_busy = true;
+            self.receiver_ticks = bit_ticks / 2;
+            self.receiver_pos = 0;
+            self.receiver_shift = 0;
+            self.receiver_parity = self.parity == ParityMode::Odd;
+            self.receiver_parity_error = false;
+        }
+        if self.receiver_ticks > 0 {
+            self.receiver_ticks -= 1;
+            return;
+        }
+        self.receiver_ticks = bit_ticks - 1;
+
+        if self.receiver_pos == 0 {
+            // A start bit shorter than half a bit is treated as a glitch
+            if rx {
+                self.receiver_busy = false;
+            } else {
+                self.receiver_pos = 1;
+            }
+        } else if self.receiver_pos <= self.char_size {
+            self.receiver_shift.set_bit(self.receiver_pos as usize - 1, rx);
+            self.receiver_parity ^= rx;
+            self.receiver_pos += 1;
+        } else if self.parity != ParityMode::Disabled && self.receiver_pos == self.parity_pos {
+            self.receiver_parity_error = self.receiver_parity != rx;
+            self.receiver_pos += 1;
+        } else {
+            if self.receive_complete.get() {
+                self.data_overrun = true;
+            } else {
+                self.receiver_udr = self.receiver_shift;
+                self.frame_error = !rx;
+                self.parity_error = self.receiver_parity_error;
+                self.data_overrun = false;
+                self.receive_complete.set(true);
+            }
+            self.receiver_busy = false;
+        }
+    }
+
+    /// Raises interrupt lines of set flags with enabled interrupts.
+    ///
+    /// RXC and UDRE aren't cleared by executing their vectors, so the interrupts stay pending
+    /// until the handler reads or writes UDR.
+    pub fn update_interrupts(&self, irq: &mut InterruptController) {
+        let masks = &self.interrupt_masks;
+        irq.set(self.vectors.rx, self.receive_complete.get() && masks.rx);
+        irq.set(self.vectors.udre, self.data_register_empty && masks.udre);
+        irq.set(self.vectors.tx, self.transmit_complete && masks.tx);
+    }
+
+    /// Clears TXC if its vector is executed.
+    pub fn acknowledge_interrupt(&mut self, vector: u8) {
+        if vector == self.vectors.tx {
+            self.transmit_complete = false;
+        }
+    }
+
     #[inline]
     pub fn read_udr(&self) -> u8 {
-        todo!()
+        self.receive_complete.set(false);
+        self.receiver_udr as u8
     }
 
     #[inline]
@@ -142,28 +286,41 @@ impl UartController {
 
     #[inline]
     pub fn read_ucsra(&self) -> u8 {
+        (self.receive_complete.get() as u8) << 7 |
+        (self.transmit_complete as u8) << 6 |
         (self.data_register_empty as u8) << 5 |
+        (self.frame_error as u8) << 4 |
+        (self.data_overrun as u8) << 3 |
+        (self.parity_error as u8) << 2 |
         (self.u2x as u8) << 1 |
         (self.mpcm as u8)
     }
 
     #[inline]
     pub fn write_ucsra(&mut self, val: u8) {
+        if val.bit(6) {
+            self.transmit_complete = false;
+        }
         self.u2x = val.bit(1);
         self.mpcm = val.bit(0);
     }
 
     #[inline]
     pub fn read_ucsrb(&self) -> u8 {
-        // TODO: interrupts
+        let masks = &self.interrupt_masks;
+        (masks.rx as u8) << 7 |
+        (masks.tx as u8) << 6 |
+        (masks.udre as u8) << 5 |
         (self.reciever_enabled as u8) << 4 |
         (self.transmitter_enabled as u8) << 3 |
         ((self.char_size == 9) as u8) << 2 |
+        (self.receiver_udr.bit(8) as u8) << 1 |
         (self.transmitter_udr.bit(8) as u8)
     }
 
     #[inline]
-    pub fn write_ucsrb(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>) {
+    pub fn write_ucsrb(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, irq: &mut InterruptController) {
+        self.interrupt_masks = UsartInterrupts { rx: val.bit(7), udre: val.bit(5), tx: val.bit(6) };
         self.transmitter_udr = self.transmitter_udr & 0xFF | (val as u16 & 0x1) << 8;
         if val.bit(2) {
             self.char_size = 9
@@ -171,6 +328,11 @@ impl UartController {
             self.char_size = 8;
         }
         self.reciever_enabled = val.bit(4);
+        if !self.reciever_enabled {
+            // Disabling the receiver flushes the receive buffer
+            self.receiver_busy = false;
+            self.receive_complete.set(false);
+        }
         self.transmitter_enabled = val.bit(3);
         if self.transmitter_enabled {
             output_changes.push((self.tx_pin, PinState::High));
@@ -182,6 +344,7 @@ impl UartController {
         } else {
             self.char_size + 2
         };
+        self.update_interrupts(irq);
     }
 
     #[inline]
@@ -193,7 +356,6 @@ impl UartController {
             8 | 9 => 3,
             _ => 0
         };
-        // TODO: interrupts
         (self.mode as u8) << 6 |
         (self.parity as u8) << 4 |
         (self.stop_two_bit as u8) << 3 |
@@ -250,4 +412,179 @@ impl UartController {
     pub fn write_ubrrh(&mut self, val: u8) {
         self.ubbr = self.ubbr & 0x00FF | ((val as u16) & 0xF) << 8;
     }
-}
\ No newline at end of file
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.ubbr);
+        w.write_u16(self.counter);
+        w.write_u8(self.prescaler);
+        w.write_bool(self.u2x);
+        w.write_bool(self.mpcm);
+        w.write_bool(self.reciever_enabled);
+        w.write_bool(self.transmitter_enabled);
+        w.write_u8(self.char_size);
+        w.write_u8(self.parity_pos);
+        w.write_u8(self.stop_bit_pos);
+        w.write_u8(self.mode as u8);
+        w.write_u8(self.parity as u8);
+        w.write_bool(self.stop_two_bit);
+        w.write_bool(self.polarity_inverted);
+        w.write_bool(self.data_register_empty);
+        w.write_bool(self.xck_ddr);
+        w.write_u16(self.transmitter_udr);
+        w.write_u16(self.transmitter_shift);
+        w.write_u8(self.transmitter_pos);
+        w.write_bool(self.transmitter_parity);
+        w.write_bool(self.transmitter_busy);
+        w.write_bool(self.transmit_complete);
+        w.write_bool(self.receiver_busy);
+        w.write_u8(self.receiver_ticks);
+        w.write_u16(self.receiver_shift);
+        w.write_u8(self.receiver_pos);
+        w.write_bool(self.receiver_parity);
+        w.write_bool(self.receiver_parity_error);
+        w.write_u16(self.receiver_udr);
+        w.write_bool(self.receive_complete.get());
+        w.write_bool(self.frame_error);
+        w.write_bool(self.data_overrun);
+        w.write_bool(self.parity_error);
+        let masks = &self.interrupt_masks;
+        w.write_bool(masks.rx);
+        w.write_bool(masks.udre);
+        w.write_bool(masks.tx);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.ubbr = r.read_u16()?;
+        self.counter = r.read_u16()?;
+        self.prescaler = r.read_u8()?;
+        self.u2x = r.read_bool()?;
+        self.mpcm = r.read_bool()?;
+        self.reciever_enabled = r.read_bool()?;
+        self.transmitter_enabled = r.read_bool()?;
+        self.char_size = r.read_u8()?;
+        self.parity_pos = r.read_u8()?;
+        self.stop_bit_pos = r.read_u8()?;
+        self.mode = match r.read_u8()? {
+            0 => UartMode::Async,
+            1 => UartMode::Sync,
+            3 => UartMode::MasterSpi,
+            _ => return Err(SnapshotError::Format("invalid USART mode")),
+        };
+        self.parity = match r.read_u8()? {
+            0 => ParityMode::Disabled,
+            2 => ParityMode::Even,
+            3 => ParityMode::Odd,
+            _ => return Err(SnapshotError::Format("invalid USART parity mode")),
+        };
+        self.stop_two_bit = r.read_bool()?;
+        self.polarity_inverted = r.read_bool()?;
+        self.data_register_empty = r.read_bool()?;
+        self.xck_ddr = r.read_bool()?;
+        self.transmitter_udr = r.read_u16()?;
+        self.transmitter_shift = r.read_u16()?;
+        self.transmitter_pos = r.read_u8()?;
+        self.transmitter_parity = r.read_bool()?;
+        self.transmitter_busy = r.read_bool()?;
+        self.transmit_complete = r.read_bool()?;
+        self.receiver_busy = r.read_bool()?;
+        self.receiver_ticks = r.read_u8()?;
+        self.receiver_shift = r.read_u16()?;
+        self.receiver_pos = r.read_u8()?;
+        self.receiver_parity = r.read_bool()?;
+        self.receiver_parity_error = r.read_bool()?;
+        self.receiver_udr = r.read_u16()?;
+        self.receive_complete.set(r.read_bool()?);
+        self.frame_error = r.read_bool()?;
+        self.data_overrun = r.read_bool()?;
+        self.parity_error = r.read_bool()?;
+        self.interrupt_masks = UsartInterrupts { rx: r.read_bool()?, udre: r.read_bool()?, tx: r.read_bool()? };
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VECTORS: UsartInterrupts<u8> = UsartInterrupts { rx: 18, udre: 19, tx: 20 };
+
+    /// Runs a transmitter with TXD looped back to RXD, until it goes idle.
+    fn loopback(usart: &mut UartController, irq: &mut InterruptController) {
+        let mut output_changes = Vec::new();
+        let mut tx = true;
+        for _ in 0..2000 {
+            output_changes.clear();
+            usart.tick(tx, &mut output_changes, irq);
+            if let Some(&(_, state)) = output_changes.iter().rev().find(|(pin, _)| *pin == 1) {
+                tx = state == PinState::High;
+            }
+        }
+    }
+
+    #[test]
+    fn async_loopback() {
+        let mut irq = InterruptController::new();
+        let mut usart = UartController::new(0, 1, VECTORS);
+        usart.write_ucsrc(0x26); // Even parity, 8 bits
+        usart.write_ucsrb(0x18, &mut Vec::new(), &mut irq);
+        usart.write_udr(0xA5);
+        loopback(&mut usart, &mut irq);
+        assert_eq!(usart.read_ucsra(), 0xE0);
+        assert_eq!(usart.read_udr(), 0xA5);
+        assert_eq!(usart.read_ucsra(), 0x60);
+
+        // A second character arriving before UDR is read is lost
+        usart.write_udr(0x12);
+        loopback(&mut usart, &mut irq);
+        usart.write_udr(0x34);
+        loopback(&mut usart, &mut irq);
+        assert_eq!(usart.read_ucsra(), 0xE8);
+        assert_eq!(usart.read_udr(), 0x12);
+    }
+
+    #[test]
+    fn transmit_complete() {
+        let mut irq = InterruptController::new();
+        let mut usart = UartController::new(0, 1, VECTORS);
+        usart.write_ucsrb(0x48, &mut Vec::new(), &mut irq); // TXCIE, TXEN
+        usart.write_udr(0x55);
+        let mut output_changes = Vec::new();
+        while usart.read_ucsra() & 0x20 == 0 {
+            usart.tick(true, &mut output_changes, &mut irq);
+        }
+        usart.write_udr(0x55);
+        // TXC isn't set between characters
+        for _ in 0..200 {
+            usart.tick(true, &mut output_changes, &mut irq);
+            assert_eq!(usart.read_ucsra() & 0x40, 0);
+        }
+        loopback(&mut usart, &mut irq);
+        assert_eq!(usart.read_ucsra() & 0x40, 0x40);
+        assert!(irq.is_raised(VECTORS.tx));
+
+        // Executing the vector clears the flag
+        usart.acknowledge_interrupt(VECTORS.tx);
+        usart.update_interrupts(&mut irq);
+        assert_eq!(usart.read_ucsra() & 0x40, 0);
+        assert!(!irq.is_raised(VECTORS.tx));
+
+        // So does writing 1 into it
+        usart.write_udr(0x55);
+        loopback(&mut usart, &mut irq);
+        assert_eq!(usart.read_ucsra() & 0x40, 0x40);
+        usart.write_ucsra(0x40);
+        assert_eq!(usart.read_ucsra() & 0x40, 0);
+    }
+
+    #[test]
+    fn frame_error() {
+        let mut irq = InterruptController::new();
+        let mut usart = UartController::new(0, 1, VECTORS);
+        usart.write_ucsrb(0x10, &mut Vec::new(), &mut irq);
+        // RXD stuck low looks like a zero with a missing stop bit
+        for _ in 0..200 {
+            usart.tick(false, &mut Vec::new(), &mut irq);
+        }
+        assert_eq!(usart.read_ucsra() & 0x90, 0x90);
+        assert_eq!(usart.read_udr(), 0x00);
+    }
+}
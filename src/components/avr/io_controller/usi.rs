@@ -0,0 +1,391 @@
+use bitfield::Bit;
+
+use crate::{pins::{PinId, PinState}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
+
+use super::interrupts::InterruptController;
+
+/// USI wire mode, selected by USIWM1:0 bits of USICR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WireMode {
+    Disabled,
+    ThreeWire,
+    TwoWire,
+    /// Two-wire mode, holding SCL low on counter overflow.
+    TwoWireHold,
+}
+
+pub struct UsiPins {
+    /// DI in three-wire mode, SDA in two-wire mode.
+    pub di: PinId,
+    pub do_: PinId,
+    /// USCK in three-wire mode, SCL in two-wire mode.
+    pub usck: PinId,
+}
+
+/// Universal Serial Interface.
+///
+/// The shift register samples DI/SDA on its clock edge and DO follows the MSB immediately.
+/// SDA and SCL are open-drain in two-wire modes: they are driven low if either PORT
+/// or the USI output is low, and released otherwise.
+pub struct Usi {
+    usidr: u8,
+    usibr: u8,
+    counter: u8,
+    start_flag: bool,
+    overflow_flag: bool,
+    stop_flag: bool,
+    /// USICR without the strobe bits.
+    usicr: u8,
+
+    pins: UsiPins,
+    /// Last seen levels of USCK/SCL and DI/SDA pins.
+    usck_level: bool,
+    di_level: bool,
+    /// States of DO, SDA and SCL pins, when driven by USI instead of GPIO.
+    driven: [Option<PinState>; 3],
+
+    start_vector: u8,
+    overflow_vector: u8,
+}
+
+impl Usi {
+    pub fn new(pins: UsiPins, start_vector: u8, overflow_vector: u8) -> Usi {
+        Usi {
+            usidr: 0,
+            usibr: 0,
+            counter: 0,
+            start_flag: false,
+            overflow_flag: false,
+            stop_flag: false,
+            usicr: 0,
+            pins,
+            usck_level: false,
+            di_level: false,
+            driven: [None; 3],
+            start_vector,
+            overflow_vector,
+        }
+    }
+
+    #[inline]
+    fn wire_mode(&self) -> WireMode {
+        match (self.usicr >> 4) & 0x3 {
+            0 => WireMode::Disabled,
+            1 => WireMode::ThreeWire,
+            2 => WireMode::TwoWire,
+            _ => WireMode::TwoWireHold,
+        }
+    }
+
+    #[inline]
+    fn two_wire(&self) -> bool {
+        matches!(self.wire_mode(), WireMode::TwoWire | WireMode::TwoWireHold)
+    }
+
+    /// USICS1:0 clock source bits.
+    #[inline]
+    fn clock_source(&self) -> u8 {
+        (self.usicr >> 2) & 0x3
+    }
+
+    /// Returns `true` if SCL is held low by a start condition or a counter overflow.
+    fn scl_hold(&self) -> bool {
+        self.two_wire() && (self.start_flag || self.overflow_flag && self.wire_mode() == WireMode::TwoWireHold)
+    }
+
+    fn shift(&mut self) {
+        self.usidr = self.usidr << 1 | self.di_level as u8;
+    }
+
+    fn count(&mut self, irq: &mut InterruptController) {
+        self.counter = (self.counter + 1) & 0x0F;
+        if self.counter == 0 {
+            self.overflow_flag = true;
+            self.usibr = self.usidr;
+            self.update_interrupts(irq);
+        }
+    }
+
+    /// Notifies USI about a Timer0 compare match, which can clock it.
+    pub fn timer0_compare_match(&mut self, irq: &mut InterruptController) {
+        if self.clock_source() == 0b01 {
+            self.shift();
+            self.count(irq);
+        }
+    }
+
+    /// Notifies USI about the current levels of its USCK/SCL and DI/SDA pins.
+    pub fn update_levels(&mut self, usck: bool, di: bool, irq: &mut InterruptController) {
+        if self.two_wire() && self.usck_level && usck && di != self.di_level {
+            if di {
+                self.stop_flag = true;
+            } else {
+                self.start_flag = true;
+                self.update_interrupts(irq);
+            }
+        }
+        self.di_level = di;
+
+        if usck == self.usck_level {
+            return;
+        }
+        self.usck_level = usck;
+        let source = self.clock_source();
+        if source & 0b10 == 0 {
+            return;
+        }
+        // USICS0 selects the negative edge for the shift register
+        if usck != source.bit(0) {
+            self.shift();
+        }
+        // With USICLK set the counter is clocked by USITC strobes instead
+        if !self.usicr.bit(1) {
+            self.count(irq);
+        }
+    }
+
+    /// Computes states of pins driven by USI and takes them from GPIO or gives them back.
+    pub fn update_outputs(&mut self, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        let gpio_output = |pin: PinId| match gpio_pins[pin as usize].1 {
+            PinState::Low => Some(false),
+            PinState::High => Some(true),
+            _ => None,
+        };
+        let open_drain = |pin: PinId, released: bool| {
+            gpio_output(pin).map(|port| if port && released {PinState::Z} else {PinState::Low})
+        };
+        let msb = self.usidr.bit(7);
+        let states = match self.wire_mode() {
+            WireMode::Disabled => [None; 3],
+            WireMode::ThreeWire => [gpio_output(self.pins.do_).map(|_| PinState::from_bool(msb)), None, None],
+            WireMode::TwoWire | WireMode::TwoWireHold => [
+                None,
+                open_drain(self.pins.di, msb),
+                open_drain(self.pins.usck, !self.scl_hold()),
+            ],
+        };
+        let pins = [self.pins.do_, self.pins.di, self.pins.usck];
+        for i in 0..3 {
+            let gpio_pin = &mut gpio_pins[pins[i] as usize];
+            match states[i] {
+                Some(state) => {
+                    if self.driven[i] != Some(state) {
+                        output_changes.push((pins[i], state));
+                    }
+                    gpio_pin.0 = false;
+                }
+                None if self.driven[i].is_some() => {
+                    output_changes.push((pins[i], gpio_pin.1));
+                    gpio_pin.0 = true;
+                }
+                None => {}
+            }
+        }
+        self.driven = states;
+    }
+
+    /// Returns the state of a pin if it is driven by USI.
+    pub fn driven_state(&self, pin: PinId) -> Option<PinState> {
+        [self.pins.do_, self.pins.di, self.pins.usck].iter()
+            .position(|&p| p == pin)
+            .and_then(|i| self.driven[i])
+    }
+
+    #[inline]
+    pub fn read_usidr(&self) -> u8 {
+        self.usidr
+    }
+    #[inline]
+    pub fn read_usibr(&self) -> u8 {
+        self.usibr
+    }
+    pub fn read_usisr(&self) -> u8 {
+        // Collision: SDA is low while USI releases it
+        let collision = self.two_wire() && self.usidr.bit(7) && !self.di_level;
+        (self.start_flag as u8) << 7 |
+        (self.overflow_flag as u8) << 6 |
+        (self.stop_flag as u8) << 5 |
+        (collision as u8) << 4 |
+        self.counter
+    }
+    #[inline]
+    pub fn read_usicr(&self) -> u8 {
+        self.usicr
+    }
+
+    #[inline]
+    pub fn write_usidr(&mut self, val: u8) {
+        self.usidr = val;
+    }
+    pub fn write_usisr(&mut self, val: u8, irq: &mut InterruptController) {
+        if val.bit(7) {
+            self.start_flag = false;
+        }
+        if val.bit(6) {
+            self.overflow_flag = false;
+        }
+        if val.bit(5) {
+            self.stop_flag = false;
+        }
+        self.counter = val & 0x0F;
+        self.update_interrupts(irq);
+    }
+
+    /// Writes USICR, returns `true` if USITC strobe requests toggling USCK/SCL PORT bit.
+    pub fn write_usicr(&mut self, val: u8, irq: &mut InterruptController) -> bool {
+        self.usicr = val & 0xFC;
+        let clock_strobe = val.bit(1);
+        let toggle_strobe = val.bit(0);
+        match self.clock_source() {
+            0b00 if clock_strobe => {
+                self.shift();
+                self.count(irq);
+            }
+            0b10 | 0b11 if clock_strobe && toggle_strobe => self.count(irq),
+            _ => {}
+        }
+        self.update_interrupts(irq);
+        toggle_strobe
+    }
+
+    /// Updates interrupt request lines after a change of flags or enable bits.
+    pub fn update_interrupts(&self, irq: &mut InterruptController) {
+        irq.set(self.start_vector, self.start_flag && self.usicr.bit(7));
+        irq.set(self.overflow_vector, self.overflow_flag && self.usicr.bit(6));
+    }
+
+    /// USI flags are not cleared by executing the interrupt, so its request stays active.
+    pub fn acknowledge_interrupt(&mut self, vector: u8, irq: &mut InterruptController) {
+        if vector == self.start_vector || vector == self.overflow_vector {
+            self.update_interrupts(irq);
+        }
+    }
+
+    /// Pushes states of all pins driven by USI as changes.
+    pub fn announce_outputs(&self, output_changes: &mut Vec<(PinId, PinState)>) {
+        let pins = [self.pins.do_, self.pins.di, self.pins.usck];
+        for (pin, state) in pins.into_iter().zip(self.driven) {
+            if let Some(state) = state {
+                output_changes.push((pin, state));
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.usidr);
+        w.write_u8(self.usibr);
+        w.write_u8(self.read_usisr() & 0xEF);
+        w.write_u8(self.usicr);
+        w.write_bool(self.usck_level);
+        w.write_bool(self.di_level);
+        for state in self.driven {
+            w.write_bool(state.is_some());
+            w.write_pin_state(state.unwrap_or(PinState::Z));
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.usidr = r.read_u8()?;
+        self.usibr = r.read_u8()?;
+        let usisr = r.read_u8()?;
+        self.start_flag = usisr.bit(7);
+        self.overflow_flag = usisr.bit(6);
+        self.stop_flag = usisr.bit(5);
+        self.counter = usisr & 0x0F;
+        self.usicr = r.read_u8()? & 0xFC;
+        self.usck_level = r.read_bool()?;
+        self.di_level = r.read_bool()?;
+        for state in self.driven.iter_mut() {
+            let driven = r.read_bool()?;
+            let pin_state = r.read_pin_state()?;
+            *state = driven.then_some(pin_state);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usi() -> Usi {
+        Usi::new(UsiPins { di: 0, do_: 1, usck: 2 }, 13, 14)
+    }
+
+    #[test]
+    fn three_wire_master() {
+        let mut usi = usi();
+        let mut irq = InterruptController::new();
+        let mut changes = Vec::new();
+        // DO and USCK are outputs
+        let mut gpio_pins = [(true, PinState::Z), (true, PinState::Low), (true, PinState::Low)];
+        usi.write_usidr(0xA5);
+        usi.write_usicr(0x50, &mut irq); // three-wire, overflow interrupt enabled
+        usi.update_outputs(&mut changes, &mut gpio_pins);
+        assert_eq!(changes, [(1, PinState::High)]);
+        assert!(!gpio_pins[1].0);
+
+        let mut usck = false;
+        let mut received = 0x3C_u8;
+        for _ in 0..16 {
+            // USIWM0, USICS1, USICLK, USITC
+            assert!(usi.write_usicr(0x1B, &mut irq));
+            usck = !usck;
+            usi.update_levels(usck, received.bit(7), &mut irq);
+            if usck {
+                received <<= 1;
+            }
+        }
+        assert_eq!(usi.read_usisr(), 0x40);
+        assert_eq!(usi.read_usibr(), 0x3C);
+        assert_eq!(irq.pending(), None);
+
+        usi.write_usisr(0x40, &mut irq);
+        assert_eq!(usi.read_usisr(), 0x00);
+        usi.write_usicr(0x00, &mut irq);
+        usi.update_outputs(&mut changes, &mut gpio_pins);
+        assert!(gpio_pins[1].0);
+        assert_eq!(changes.last(), Some(&(1, PinState::Low)));
+    }
+
+    #[test]
+    fn software_strobe() {
+        let mut usi = usi();
+        let mut irq = InterruptController::new();
+        usi.write_usicr(0x40, &mut irq);
+        usi.write_usisr(0x08, &mut irq);
+        usi.update_levels(false, true, &mut irq);
+        for _ in 0..8 {
+            usi.write_usicr(0x42, &mut irq); // USICLK
+        }
+        assert_eq!(usi.read_usidr(), 0xFF);
+        assert_eq!(usi.read_usisr(), 0x40);
+        assert_eq!(irq.pending(), Some(14));
+        usi.acknowledge_interrupt(14, &mut irq);
+        assert_eq!(irq.pending(), Some(14));
+    }
+
+    #[test]
+    fn two_wire_start_condition() {
+        let mut usi = usi();
+        let mut irq = InterruptController::new();
+        let mut changes = Vec::new();
+        let mut gpio_pins = [(true, PinState::High), (true, PinState::Z), (true, PinState::High)];
+        usi.update_levels(true, true, &mut irq);
+        usi.write_usidr(0xFF);
+        usi.write_usicr(0xA8, &mut irq); // start interrupt, two-wire, external clock
+        usi.update_outputs(&mut changes, &mut gpio_pins);
+        assert_eq!(changes, [(0, PinState::Z), (2, PinState::Z)]);
+
+        usi.update_levels(true, false, &mut irq);
+        assert_eq!(usi.read_usisr(), 0x90);
+        assert_eq!(irq.pending(), Some(13));
+        usi.update_outputs(&mut changes, &mut gpio_pins);
+        assert_eq!(changes[2..], [(2, PinState::Low)]);
+
+        usi.write_usisr(0xF0, &mut irq);
+        usi.update_levels(true, true, &mut irq);
+        assert_eq!(usi.read_usisr(), 0x20);
+        assert_eq!(irq.pending(), None);
+    }
+}
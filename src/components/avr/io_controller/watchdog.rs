@@ -0,0 +1,329 @@
+use bitfield::Bit;
+
+use crate::components::avr::snapshot::{StateWriter, StateReader, SnapshotError};
+
+use super::interrupts::InterruptController;
+
+/// Frequency of the watchdog oscillator in Hz, independent of the CPU clock.
+const OSCILLATOR_FREQUENCY: u32 = 128_000;
+
+/// Number of CPU clock cycles the WDCE bit stays set after being written.
+const CHANGE_ENABLE_CYCLES: u8 = 4;
+
+/// Watchdog timer, together with WDTCSR register.
+pub struct Watchdog {
+    /// Watchdog oscillator cycles since the last reset of the watchdog.
+    counter: u32,
+    /// Phase of the watchdog oscillator, in units of 1/[OSCILLATOR_FREQUENCY] of a CPU cycle.
+    oscillator_phase: u32,
+    /// Effective CPU clock frequency in Hz.
+    cpu_frequency: u32,
+    /// Remaining CPU clock cycles of the timed WDCE sequence.
+    change_enable: u8,
+
+    prescaler: u8,
+    system_reset_enabled: bool,
+    interrupt_enabled: bool,
+    /// WDTON fuse: system reset mode can't be disabled.
+    always_on: bool,
+    /// WDRF flag of MCUSR, keeping WDE set.
+    reset_flag: bool,
+
+    interrupt_flag: bool,
+    reset_request: bool,
+    vector: u8,
+}
+
+impl Watchdog {
+    pub fn new(vector: u8) -> Watchdog {
+        Watchdog {
+            counter: 0,
+            oscillator_phase: 0,
+            cpu_frequency: 16_000_000,
+            change_enable: 0,
+            prescaler: 0,
+            system_reset_enabled: false,
+            interrupt_enabled: false,
+            always_on: false,
+            reset_flag: false,
+            interrupt_flag: false,
+            reset_request: false,
+            vector,
+        }
+    }
+
+    /// Returns number of watchdog oscillator cycles before a time-out.
+    #[inline]
+    fn timeout(&self) -> u32 {
+        2048 << self.prescaler
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.system_reset_enabled || self.interrupt_enabled
+    }
+
+    /// Advances the watchdog by a single CPU clock cycle.
+    pub fn tick(&mut self, irq: &mut InterruptController) {
+        if self.change_enable > 0 {
+            self.change_enable -= 1;
+        }
+        if !self.enabled() {
+            return;
+        }
+
+        // A slow CPU clock can see several oscillator cycles at once
+        self.oscillator_phase += OSCILLATOR_FREQUENCY;
+        while self.oscillator_phase >= self.cpu_frequency {
+            self.oscillator_phase -= self.cpu_frequency;
+            self.count(irq);
+        }
+    }
+
+    /// Advances the watchdog by a single watchdog oscillator cycle.
+    fn count(&mut self, irq: &mut InterruptController) {
+        self.counter += 1;
+        if self.counter >= self.timeout() {
+            self.counter = 0;
+            if self.interrupt_enabled {
+                self.interrupt_flag = true;
+                irq.raise(self.vector);
+            } else {
+                self.reset_request = true;
+            }
+        }
+    }
+
+    /// Sets the effective CPU clock frequency in Hz, the watchdog is ticked with.
+    pub fn set_cpu_frequency(&mut self, frequency: u32) {
+        self.cpu_frequency = frequency.max(1);
+    }
+
+    /// Sets WDTON fuse, forcing the watchdog into system reset mode.
+    pub fn set_always_on(&mut self, always_on: bool) {
+        self.always_on = always_on;
+        self.apply_overrides();
+    }
+
+    #[inline]
+    pub fn always_on(&self) -> bool {
+        self.always_on
+    }
+
+    /// Sets WDRF flag state. While it is set, WDE can't be cleared.
+    pub fn set_reset_flag(&mut self, reset_flag: bool) {
+        self.reset_flag = reset_flag;
+        self.apply_overrides();
+    }
+
+    /// Applies WDTON fuse and WDRF flag to WDE and WDIE bits.
+    fn apply_overrides(&mut self) {
+        if self.always_on || self.reset_flag {
+            self.system_reset_enabled = true;
+        }
+        if self.always_on {
+            self.interrupt_enabled = false;
+        }
+    }
+
+    /// Resets the watchdog counter (WDR instruction).
+    #[inline]
+    pub fn reset_counter(&mut self) {
+        self.counter = 0;
+        self.oscillator_phase = 0;
+    }
+
+    /// Called when an interrupt vector is executed.
+    ///
+    /// In "interrupt and system reset" mode WDIE is cleared by hardware,
+    /// so the next time-out resets the MCU.
+    pub fn acknowledge_interrupt(&mut self, vector: u8) {
+        if vector != self.vector {
+            return;
+        }
+        self.interrupt_flag = false;
+        if self.system_reset_enabled {
+            self.interrupt_enabled = false;
+        }
+    }
+
+    /// Updates interrupt request line after a change of WDTCSR.
+    #[inline]
+    pub fn update_interrupt(&self, irq: &mut InterruptController) {
+        irq.set(self.vector, self.interrupt_pending());
+    }
+
+    /// Returns `true` if the watchdog interrupt is pending.
+    #[inline]
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_flag && self.interrupt_enabled
+    }
+
+    /// Returns `true` once after the watchdog requested a system reset.
+    #[inline]
+    pub fn take_reset_request(&mut self) -> bool {
+        std::mem::replace(&mut self.reset_request, false)
+    }
+
+    #[inline]
+    pub fn read_wdtcsr(&self) -> u8 {
+        (self.interrupt_flag as u8) << 7 |
+        (self.interrupt_enabled as u8) << 6 |
+        (self.prescaler.bit(3) as u8) << 5 |
+        ((self.change_enable > 0) as u8) << 4 |
+        (self.system_reset_enabled as u8) << 3 |
+        self.prescaler & 0x7
+    }
+
+    pub fn write_wdtcsr(&mut self, val: u8) {
+        if val.bit(7) {
+            self.interrupt_flag = false;
+        }
+        self.interrupt_enabled = val.bit(6);
+
+        let wde = val.bit(3);
+        if self.change_enable > 0 {
+            // Timed sequence: WDE and prescaler can be changed freely
+            self.system_reset_enabled = wde;
+            self.prescaler = (val >> 2) & 0x8 | val & 0x7;
+            self.change_enable = 0;
+        } else if wde {
+            // WDE can always be set without a timed sequence
+            self.system_reset_enabled = true;
+        }
+
+        if val.bit(4) && wde {
+            self.change_enable = CHANGE_ENABLE_CYCLES;
+        }
+        self.apply_overrides();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.counter);
+        w.write_u32(self.oscillator_phase);
+        w.write_u8(self.change_enable);
+        w.write_u8(self.prescaler);
+        w.write_bool(self.system_reset_enabled);
+        w.write_bool(self.interrupt_enabled);
+        w.write_bool(self.always_on);
+        w.write_bool(self.interrupt_flag);
+        w.write_bool(self.reset_request);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.counter = r.read_u32()?;
+        self.oscillator_phase = r.read_u32()?;
+        self.change_enable = r.read_u8()?;
+        self.prescaler = r.read_u8()? & 0x0F;
+        self.system_reset_enabled = r.read_bool()?;
+        self.interrupt_enabled = r.read_bool()?;
+        self.always_on = r.read_bool()?;
+        self.interrupt_flag = r.read_bool()?;
+        self.reset_request = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of CPU clock cycles per watchdog oscillator cycle at 16 MHz.
+    const CPU_CYCLES_PER_WDT_CYCLE: u32 = 125;
+
+    fn run(watchdog: &mut Watchdog, wdt_cycles: u32, irq: &mut InterruptController) {
+        for _ in 0..wdt_cycles * CPU_CYCLES_PER_WDT_CYCLE {
+            watchdog.tick(irq);
+        }
+    }
+
+    #[test]
+    fn interrupt_mode() {
+        let mut watchdog = Watchdog::new(12);
+        let mut irq = InterruptController::new();
+        watchdog.write_wdtcsr(0b0100_0000);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0100_0000);
+
+        run(&mut watchdog, 2047, &mut irq);
+        assert!(!watchdog.interrupt_flag);
+        run(&mut watchdog, 1, &mut irq);
+        assert!(watchdog.interrupt_flag);
+        assert_eq!(irq.pending(), Some(12));
+        assert!(!watchdog.take_reset_request());
+
+        watchdog.write_wdtcsr(0b1100_0000);
+        watchdog.update_interrupt(&mut irq);
+        assert!(!watchdog.interrupt_flag);
+        assert_eq!(irq.pending(), None);
+    }
+
+    #[test]
+    fn reset_counter() {
+        let mut watchdog = Watchdog::new(12);
+        let mut irq = InterruptController::new();
+        watchdog.write_wdtcsr(0b0000_1000);
+
+        run(&mut watchdog, 2000, &mut irq);
+        watchdog.reset_counter();
+        run(&mut watchdog, 2000, &mut irq);
+        assert!(!watchdog.take_reset_request());
+        run(&mut watchdog, 48, &mut irq);
+        assert!(watchdog.take_reset_request());
+        assert!(!watchdog.take_reset_request());
+        assert_eq!(irq.pending(), None);
+    }
+
+    #[test]
+    fn timed_sequence() {
+        let mut watchdog = Watchdog::new(12);
+        let mut irq = InterruptController::new();
+        watchdog.write_wdtcsr(0b0000_1000);
+
+        // Cannot clear WDE or change prescaler without WDCE
+        watchdog.write_wdtcsr(0b0000_0111);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0000_1000);
+
+        watchdog.write_wdtcsr(0b0001_1000);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0001_1000);
+        watchdog.write_wdtcsr(0b0010_1001);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0010_1001);
+
+        // WDCE expires after 4 cycles
+        watchdog.write_wdtcsr(0b0001_1000);
+        run(&mut watchdog, 1, &mut irq);
+        watchdog.write_wdtcsr(0b0000_0000);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0010_1001);
+    }
+
+    #[test]
+    fn interrupt_and_reset_mode() {
+        let mut watchdog = Watchdog::new(12);
+        let mut irq = InterruptController::new();
+        watchdog.write_wdtcsr(0b0100_1000);
+
+        run(&mut watchdog, 2048, &mut irq);
+        assert!(watchdog.interrupt_flag);
+        assert!(!watchdog.take_reset_request());
+
+        watchdog.acknowledge_interrupt(12);
+        assert!(!watchdog.interrupt_flag);
+        run(&mut watchdog, 2048, &mut irq);
+        assert!(watchdog.take_reset_request());
+    }
+
+    #[test]
+    fn always_on() {
+        let mut watchdog = Watchdog::new(12);
+        let mut irq = InterruptController::new();
+        watchdog.set_always_on(true);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0000_1000);
+
+        // WDE can't be cleared and WDIE can't be set even with WDCE
+        watchdog.write_wdtcsr(0b0001_1000);
+        watchdog.write_wdtcsr(0b0100_0001);
+        assert_eq!(watchdog.read_wdtcsr(), 0b0000_1001);
+
+        run(&mut watchdog, 4096, &mut irq);
+        assert!(watchdog.take_reset_request());
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+
+use bitfield::Bit;
+
+use crate::{pins::{PinId, PinState}, components::avr::snapshot::{StateWriter, StateReader, SnapshotError}};
+
+pub struct XmemPins {
+    /// AD7:0, multiplexed low address byte and data.
+    pub ad: [PinId; 8],
+    /// A15:8.
+    pub high: [PinId; 8],
+    pub ale: PinId,
+    pub rd: PinId,
+    pub wr: PinId,
+}
+
+/// A single read or write on the external bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BusCycle {
+    addr: u16,
+    /// Data of a write cycle, `None` for a read cycle.
+    data: Option<u8>,
+    wait_states: u8,
+}
+
+impl BusCycle {
+    /// Half clock edge at which RD or WR goes high and read data is sampled.
+    fn strobe_end(&self) -> u8 {
+        4 + 2 * self.wait_states.min(2)
+    }
+
+    /// Half clock edge at which the next bus cycle can start.
+    fn end(&self) -> u8 {
+        // The last wait state setting adds a cycle after the strobe,
+        // and a read leaves a cycle for the device to release the bus
+        self.strobe_end() + if self.wait_states == 3 || self.data.is_none() {2} else {0}
+    }
+}
+
+/// External memory interface.
+///
+/// A bus cycle starts on a rising clock edge: ALE is high for the first half cycle, while AD7:0 and A15:8
+/// carry the address. RD or WR is strobed low from the next rising edge until the end of the wait states,
+/// and read data is sampled from AD7:0 on the edge ending the strobe.
+/// Cycles requested while the bus is busy are queued.
+pub struct Xmem {
+    xmcra: u8,
+    xmcrb: u8,
+
+    pins: XmemPins,
+    /// Pending bus cycles, the first one is in progress.
+    queue: VecDeque<BusCycle>,
+    /// Half clock edges since the start of the current bus cycle.
+    edge: u8,
+    /// Data sampled by read cycles, kept until the CPU finishes its instruction.
+    reads: Vec<(u16, u8)>,
+    /// Last value on AD7:0, held by the bus keeper.
+    bus_value: u8,
+    /// Last value on A15:8, held between bus cycles.
+    high_addr: u8,
+
+    /// States of ALE, RD, WR, AD7:0 and A15:8 pins, when driven by XMEM instead of GPIO.
+    driven: [Option<PinState>; 19],
+}
+
+impl Xmem {
+    pub fn new(pins: XmemPins) -> Xmem {
+        Xmem {
+            xmcra: 0,
+            xmcrb: 0,
+            pins,
+            queue: VecDeque::new(),
+            edge: 0,
+            reads: Vec::new(),
+            bus_value: 0,
+            high_addr: 0,
+            driven: [None; 19],
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.xmcra.bit(7)
+    }
+
+    /// Number of wait states (SRWn1:0) of the sector containing a data address.
+    fn wait_states(&self, addr: u16) -> u8 {
+        let limit = (self.xmcra >> 4) & 0x7;
+        // With SRL = 0 the whole external memory is the upper sector
+        let lower = limit != 0 && addr < (limit as u16 + 1) * 0x2000;
+        if lower {self.xmcra & 0x3} else {(self.xmcra >> 2) & 0x3}
+    }
+
+    /// Number of A15:8 pins used for the address, the rest is released to GPIO (XMM2:0).
+    fn high_pin_count(&self) -> usize {
+        match self.xmcrb & 0x7 {
+            7 => 0,
+            xmm => 8 - xmm as usize,
+        }
+    }
+
+    /// Extra CPU cycles of an access to an external data address.
+    #[inline]
+    pub fn access_cycles(&self, addr: u16) -> u8 {
+        1 + self.wait_states(addr)
+    }
+
+    /// Gets data sampled by a read cycle of the current instruction.
+    pub fn read_data(&self, addr: u16) -> Option<u8> {
+        self.reads.iter().find(|&&(a, _)| a == addr).map(|&(_, val)| val)
+    }
+
+    /// Queues a read cycle, returns number of CPU cycles until its data is sampled.
+    pub fn start_read(&mut self, addr: u16, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) -> u8 {
+        let busy: u16 = self.queue.iter().map(|cycle| cycle.end() as u16).sum::<u16>() - self.edge as u16;
+        let cycle = BusCycle {addr, data: None, wait_states: self.wait_states(addr)};
+        self.start(cycle, output_changes, gpio_pins);
+        ((busy + cycle.strobe_end() as u16) / 2) as u8
+    }
+
+    /// Queues a write cycle.
+    pub fn write(&mut self, addr: u16, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        let cycle = BusCycle {addr, data: Some(val), wait_states: self.wait_states(addr)};
+        self.start(cycle, output_changes, gpio_pins);
+    }
+
+    fn start(&mut self, cycle: BusCycle, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        self.queue.push_back(cycle);
+        if self.queue.len() == 1 {
+            // An idle bus starts the cycle on the current edge
+            self.edge = 0;
+            self.update_outputs(output_changes, gpio_pins);
+        }
+    }
+
+    /// Forgets read data, after the CPU has finished an instruction.
+    #[inline]
+    pub fn finish_instruction(&mut self) {
+        self.reads.clear();
+    }
+
+    /// Advances the current bus cycle by a half clock, `ad` is the input value of AD7:0.
+    pub fn tick(&mut self, ad: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        let Some(&cycle) = self.queue.front() else {
+            return;
+        };
+        self.edge += 1;
+        if self.edge == cycle.strobe_end() {
+            self.bus_value = match cycle.data {
+                Some(data) => data,
+                None => {
+                    self.reads.push((cycle.addr, ad));
+                    ad
+                }
+            };
+        }
+        if self.edge == cycle.end() {
+            self.queue.pop_front();
+            self.high_addr = (cycle.addr >> 8) as u8;
+            self.edge = 0;
+        }
+        self.update_outputs(output_changes, gpio_pins);
+    }
+
+    #[inline]
+    pub fn read_xmcra(&self) -> u8 {
+        self.xmcra
+    }
+    #[inline]
+    pub fn read_xmcrb(&self) -> u8 {
+        self.xmcrb
+    }
+
+    pub fn write_xmcra(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        self.xmcra = val;
+        if !self.enabled() {
+            self.queue.clear();
+            self.edge = 0;
+        }
+        self.update_outputs(output_changes, gpio_pins);
+    }
+
+    pub fn write_xmcrb(&mut self, val: u8, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        self.xmcrb = val & 0x87;
+        self.update_outputs(output_changes, gpio_pins);
+    }
+
+    /// AD0 pin, to find the GPIO port of the bus.
+    #[inline]
+    pub fn ad_pin(&self) -> PinId {
+        self.pins.ad[0]
+    }
+
+    fn pin_ids(&self) -> [PinId; 19] {
+        let mut pins = [0; 19];
+        pins[..3].copy_from_slice(&[self.pins.ale, self.pins.rd, self.pins.wr]);
+        pins[3..11].copy_from_slice(&self.pins.ad);
+        pins[11..19].copy_from_slice(&self.pins.high);
+        pins
+    }
+
+    /// Computes states of all pins driven by XMEM for the current bus cycle phase.
+    fn pin_states(&self) -> [Option<PinState>; 19] {
+        let mut states = [None; 19];
+        if !self.enabled() {
+            return states;
+        }
+        let (ale, rd, wr, ad, high) = match self.queue.front() {
+            Some(cycle) if self.edge < 2 => (self.edge == 0, false, false, Some(cycle.addr as u8), (cycle.addr >> 8) as u8),
+            Some(cycle) if self.edge < cycle.strobe_end() => {
+                (false, cycle.data.is_none(), cycle.data.is_some(), cycle.data, (cycle.addr >> 8) as u8)
+            }
+            Some(cycle) => (false, false, false, None, (cycle.addr >> 8) as u8),
+            None => (false, false, false, None, self.high_addr),
+        };
+        states[0] = Some(PinState::from_bool(ale));
+        states[1] = Some(PinState::from_bool(!rd));
+        states[2] = Some(PinState::from_bool(!wr));
+        for i in 0..8 {
+            states[3 + i] = Some(match ad {
+                Some(val) => PinState::from_bool(val.bit(i)),
+                // Bus keeper holds the last value weakly
+                None if self.xmcrb.bit(7) => if self.bus_value.bit(i) {PinState::WeakHigh} else {PinState::WeakLow},
+                None => PinState::Z,
+            });
+        }
+        for i in 0..self.high_pin_count() {
+            states[11 + i] = Some(PinState::from_bool(high.bit(i)));
+        }
+        states
+    }
+
+    /// Computes states of pins driven by XMEM and takes them from GPIO or gives them back.
+    ///
+    /// Strobes are pushed before the bus, so that a device sees WR rising while the data is still valid.
+    pub fn update_outputs(&mut self, output_changes: &mut Vec<(PinId, PinState)>, gpio_pins: &mut [(bool, PinState)]) {
+        let states = self.pin_states();
+        let pins = self.pin_ids();
+        for i in 0..19 {
+            let gpio_pin = &mut gpio_pins[pins[i] as usize];
+            match states[i] {
+                Some(state) => {
+                    if self.driven[i] != Some(state) {
+                        output_changes.push((pins[i], state));
+                    }
+                    gpio_pin.0 = false;
+                }
+                None if self.driven[i].is_some() => {
+                    output_changes.push((pins[i], gpio_pin.1));
+                    gpio_pin.0 = true;
+                }
+                None => {}
+            }
+        }
+        self.driven = states;
+    }
+
+    /// Pushes states of all pins driven by XMEM as changes.
+    pub fn announce_outputs(&self, output_changes: &mut Vec<(PinId, PinState)>) {
+        for (pin, state) in self.pin_ids().into_iter().zip(self.driven) {
+            if let Some(state) = state {
+                output_changes.push((pin, state));
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.xmcra);
+        w.write_u8(self.xmcrb);
+        w.write_u8(self.edge);
+        w.write_u8(self.bus_value);
+        w.write_u8(self.high_addr);
+        w.write_u8(self.queue.len() as u8);
+        for cycle in self.queue.iter() {
+            w.write_u16(cycle.addr);
+            w.write_bool(cycle.data.is_some());
+            w.write_u8(cycle.data.unwrap_or(0));
+            w.write_u8(cycle.wait_states);
+        }
+        w.write_u8(self.reads.len() as u8);
+        for &(addr, val) in self.reads.iter() {
+            w.write_u16(addr);
+            w.write_u8(val);
+        }
+        for state in self.driven {
+            w.write_bool(state.is_some());
+            w.write_pin_state(state.unwrap_or(PinState::Z));
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.xmcra = r.read_u8()?;
+        self.xmcrb = r.read_u8()? & 0x87;
+        self.edge = r.read_u8()?;
+        self.bus_value = r.read_u8()?;
+        self.high_addr = r.read_u8()?;
+        self.queue.clear();
+        for _ in 0..r.read_u8()? {
+            let addr = r.read_u16()?;
+            let is_write = r.read_bool()?;
+            let data = r.read_u8()?;
+            let wait_states = r.read_u8()? & 0x3;
+            self.queue.push_back(BusCycle {addr, data: is_write.then_some(data), wait_states});
+        }
+        if self.queue.front().is_some_and(|cycle| self.edge >= cycle.end()) {
+            return Err(SnapshotError::Format("invalid external memory bus cycle"));
+        }
+        self.reads.clear();
+        for _ in 0..r.read_u8()? {
+            let addr = r.read_u16()?;
+            self.reads.push((addr, r.read_u8()?));
+        }
+        for state in self.driven.iter_mut() {
+            let driven = r.read_bool()?;
+            let pin_state = r.read_pin_state()?;
+            *state = driven.then_some(pin_state);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xmem() -> (Xmem, Vec<(bool, PinState)>) {
+        let pins = XmemPins {
+            ad: std::array::from_fn(|i| i as PinId),
+            high: std::array::from_fn(|i| 8 + i as PinId),
+            ale: 16,
+            rd: 17,
+            wr: 18,
+        };
+        (Xmem::new(pins), vec![(true, PinState::Z); 19])
+    }
+
+    fn bus(val: u8, start: PinId) -> Vec<(PinId, PinState)> {
+        (0..8).map(|i| (start + i, PinState::from_bool(val.bit(i as usize)))).collect()
+    }
+
+    #[test]
+    fn read_cycle() {
+        let (mut xmem, mut gpio_pins) = xmem();
+        let mut changes = Vec::new();
+        xmem.write_xmcrb(0x04, &mut changes, &mut gpio_pins); // XMM = 4, PC7:4 released
+        xmem.write_xmcra(0x80, &mut changes, &mut gpio_pins);
+        assert_eq!(changes[..3], [(16, PinState::Low), (17, PinState::High), (18, PinState::High)]);
+        assert_eq!(changes[3..11], (0..8).map(|i| (i, PinState::Z)).collect::<Vec<_>>());
+        assert_eq!(changes.len(), 15);
+        assert!(!gpio_pins[11].0 && gpio_pins[12].0);
+
+        changes.clear();
+        assert_eq!(xmem.start_read(0x2345, &mut changes, &mut gpio_pins), 2);
+        let mut expected = vec![(16, PinState::High)];
+        expected.extend(bus(0x45, 0));
+        expected.extend(bus(0x23, 8).into_iter().take(4).filter(|&(_, state)| state == PinState::High));
+        assert_eq!(changes, expected);
+
+        changes.clear();
+        xmem.tick(0x00, &mut changes, &mut gpio_pins);
+        assert_eq!(changes, [(16, PinState::Low)]);
+        changes.clear();
+        xmem.tick(0x00, &mut changes, &mut gpio_pins);
+        assert_eq!(changes[0], (17, PinState::Low));
+        assert_eq!(changes[1..], (0..8).map(|i| (i, PinState::Z)).collect::<Vec<_>>());
+        xmem.tick(0x00, &mut changes, &mut gpio_pins);
+        assert_eq!(xmem.read_data(0x2345), None);
+        changes.clear();
+        xmem.tick(0xA5, &mut changes, &mut gpio_pins);
+        assert_eq!(changes, [(17, PinState::High)]);
+        assert_eq!(xmem.read_data(0x2345), Some(0xA5));
+        xmem.finish_instruction();
+        assert_eq!(xmem.read_data(0x2345), None);
+
+        changes.clear();
+        xmem.write_xmcra(0x00, &mut changes, &mut gpio_pins);
+        assert_eq!(changes.len(), 15);
+        assert!(gpio_pins.iter().all(|&(gpio_driven, _)| gpio_driven));
+    }
+
+    #[test]
+    fn write_cycles_with_wait_states() {
+        let (mut xmem, mut gpio_pins) = xmem();
+        let mut changes = Vec::new();
+        // Sector limit at 0x4000, one wait state below and three above it
+        xmem.write_xmcra(0x9D, &mut changes, &mut gpio_pins);
+        assert_eq!(xmem.access_cycles(0x3FFF), 2);
+        assert_eq!(xmem.access_cycles(0x4000), 4);
+
+        xmem.write(0x4000, 0x12, &mut changes, &mut gpio_pins);
+        // The read waits for the whole write cycle
+        assert_eq!(xmem.start_read(0x2200, &mut changes, &mut gpio_pins), 8);
+        let mut wr = Vec::new();
+        let mut rd = Vec::new();
+        for edge in 1..=16 {
+            changes.clear();
+            xmem.tick(0x34, &mut changes, &mut gpio_pins);
+            if changes.contains(&(18, PinState::Low)) || changes.contains(&(18, PinState::High)) {
+                wr.push(edge);
+            }
+            if changes.contains(&(17, PinState::Low)) || changes.contains(&(17, PinState::High)) {
+                rd.push(edge);
+            }
+        }
+        // Write strobe lasts three cycles, followed by a hold cycle
+        assert_eq!(wr, [2, 8]);
+        assert_eq!(rd, [12, 16]);
+        assert_eq!(xmem.read_data(0x2200), Some(0x34));
+    }
+
+    #[test]
+    fn bus_keeper() {
+        let (mut xmem, mut gpio_pins) = xmem();
+        let mut changes = Vec::new();
+        xmem.write_xmcrb(0x80, &mut changes, &mut gpio_pins);
+        xmem.write_xmcra(0x80, &mut changes, &mut gpio_pins);
+        assert_eq!(changes[3], (0, PinState::WeakLow));
+
+        xmem.write(0x8000, 0x81, &mut changes, &mut gpio_pins);
+        for _ in 0..4 {
+            xmem.tick(0x00, &mut changes, &mut gpio_pins);
+        }
+        changes.clear();
+        xmem.tick(0x00, &mut changes, &mut gpio_pins);
+        assert!(changes.is_empty());
+        assert_eq!(xmem.driven[3], Some(PinState::WeakHigh));
+        assert_eq!(xmem.driven[4], Some(PinState::WeakLow));
+        assert_eq!(xmem.driven[10], Some(PinState::WeakHigh));
+    }
+}
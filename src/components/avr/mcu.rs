@@ -4,17 +4,35 @@ mod mul;
 mod transfer;
 mod branches;
 mod bitops;
+mod control;
 mod memory_controller;
 pub mod hex;
+pub mod trace;
+pub mod debug;
+pub mod snapshot;
+pub mod semihost;
+pub mod coverage;
 
+use std::cell::Cell;
 use std::marker::PhantomData;
 
-use bitfield::Bit;
-
 use crate::pins::PinState;
 use crate::vcd::{VcdFiller, VcdTreeModule, VcdModuleBuilder};
 
-use super::{regfile::RegisterFile, mcu_model::McuModel, io_controller::{IoController, IoControllerTrait}, sreg::StatusRegister, bit_helpers::bit_field_combined};
+use super::{regfile::RegisterFile, mcu_model::McuModel, io_controller::{IoController, IoControllerTrait, SleepMode, ResetSource}, sreg::StatusRegister, spm_controller::SpmController, bit_helpers::is_two_word, instruction::Instruction, fault::{CpuFault, CpuFaultKind}, fuses::Fuses};
+
+/// Execution state of the CPU core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    /// CPU is fetching and executing instructions.
+    Running,
+    /// CPU has executed SLEEP and waits for a wake-up interrupt.
+    Sleeping(SleepMode),
+    /// CPU has executed BREAK and waits for a debugger to resume it.
+    Halted,
+    /// CPU has stopped because of an unrecoverable error, only a reset can restart it.
+    Faulted(CpuFault),
+}
 
 /// Internal AVR MCU structure.
 pub struct Mcu<M, Io>
@@ -26,6 +44,8 @@ where
     pub io: Io,
     sram: Vec<u8>,
     flash: Vec<u16>,
+    /// Decoded instruction cache, one entry per flash word, filled on first execution.
+    decoded: Vec<Option<Instruction>>,
 
     pc: u32,
     sp: u16,
@@ -34,12 +54,57 @@ where
     rampz: u8,
     eind: u8,
 
+    spm: SpmController,
+    state: CpuState,
+    /// Pending interrupts are not served before the next instruction (after SEI and RETI).
+    interrupt_inhibit: bool,
+
+    /// Number of CPU cycles executed since the start.
+    cycles: u64,
+    /// A fault detected during the current instruction.
+    pending_fault: Cell<Option<CpuFaultKind>>,
+    /// A fault not yet taken by [Mcu::take_fault].
+    fault: Option<CpuFault>,
+
+    /// Data memory writes of the current instruction, collected only while tracing.
+    write_log: Option<Vec<(u16, u8)>>,
+    /// Interrupt vector executed by the current step.
+    served_interrupt: Option<u8>,
+
+    /// Breakpoint word addresses.
+    breakpoints: Vec<u32>,
+    watchpoints: Vec<debug::Watchpoint>,
+    /// First watchpoint hit since the last [Mcu::take_watch_hit].
+    watch_hit: Cell<Option<(u16, debug::WatchKind)>>,
+
+    /// First external memory address read during the current instruction before its bus cycle.
+    xmem_miss: Cell<Option<u16>>,
+    /// Extra cycles of external memory accesses during the current instruction.
+    xmem_cycles: Cell<u8>,
+    /// Cycles already waited for external memory reads of the instruction being retried.
+    xmem_stall: u8,
+
+    /// Channel to the host, intercepting accesses to its data addresses.
+    semihosting: Option<semihost::Semihosting>,
+    /// Execution counts, collected only if enabled.
+    coverage: Option<coverage::Coverage>,
+
+    /// EEPROM contents. EEPROM control registers are not emulated yet.
+    eeprom: Vec<u8>,
+
+    fuses: Fuses,
+    /// MCUCR bits other than IVSEL and IVCE, on models with a boot loader section.
+    mcucr: u8,
+    /// Interrupt vectors are moved to the boot loader section.
+    ivsel: bool,
+    /// Remaining CPU cycles of the timed IVCE sequence, interrupts are disabled meanwhile.
+    ivce_cycles: u8,
+    /// Effective CPU clock frequency in Hz, for peripherals timed in real time.
+    cpu_frequency: u32,
+
     model: PhantomData<M>,
 }
 
-const SRAM_SIZE: usize = 8192;
-const SRAM_END: u16 = 0x200 + SRAM_SIZE as u16 - 1;
-
 impl<M> Default for Mcu<M, IoController<M>>
 where
     M: McuModel + 'static,
@@ -61,172 +126,260 @@ where
         Mcu { 
             reg_file: RegisterFile::new(),
             io,
-            sram: vec![0; SRAM_SIZE],
+            sram: vec![0; M::sram_size()],
             flash: vec![0; M::flash_size()],
+            decoded: vec![None; M::flash_size()],
 
             pc: 0,
-            sp: 0,
+            sp: M::sram_end(),
             rampz: 0,
             eind: 0,
             sreg: StatusRegister(0),
 
+            spm: SpmController::new(M::flash_page_size()),
+            state: CpuState::Running,
+            interrupt_inhibit: false,
+
+            cycles: 0,
+            pending_fault: Cell::new(None),
+            fault: None,
+
+            write_log: None,
+            served_interrupt: None,
+
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+
+            xmem_miss: Cell::new(None),
+            xmem_cycles: Cell::new(0),
+            xmem_stall: 0,
+
+            semihosting: None,
+            coverage: None,
+
+            eeprom: vec![0xFF; M::eeprom_size()],
+
+            fuses: Fuses::new::<M>(),
+            mcucr: 0,
+            ivsel: false,
+            ivce_cycles: 0,
+            cpu_frequency: 16_000_000,
+
             model: PhantomData
         }
     }
 
     /// Executes one instruction at PC address and returns number of cycles.
+    /// 
+    /// A sleeping, halted or faulted CPU doesn't execute anything and just waits a single cycle.
     pub fn step(&mut self) -> u8 {
-        if self.io.has_interrupt() && self.sreg.i() {
-            if let Some(addr) = self.io.get_interrupt_address() {
-                return self.execute_interrupt(addr)
-            }
+        let pc = self.pc;
+        let covered = self.coverage_enabled();
+        if covered {
+            self.served_interrupt = None;
+        }
+        let cycles = if M::peripherals().xmem.is_some() {self.step_xmem()} else {self.step_cpu()};
+        if let Some(kind) = self.pending_fault.take() {
+            self.fault(kind, pc);
+        }
+        if covered {
+            self.record_coverage(pc);
         }
+        self.spm.tick(cycles);
+        self.ivce_cycles = self.ivce_cycles.saturating_sub(cycles);
+        self.cycles += cycles as u64;
+        cycles
+    }
 
-        let opcode: u16 = self.read_at_pc_offset(0);
-        self.execute(opcode)
+    /// Advances the CPU core with external memory accesses.
+    /// 
+    /// External memory data is only known after the bus cycle, so an instruction reading it
+    /// is rolled back and retried when the data has been sampled from the pins.
+    fn step_xmem(&mut self) -> u8 {
+        let regs = self.reg_file.regs;
+        let (pc, sp, sreg, rampz, eind) = (self.pc, self.sp, self.sreg, self.rampz, self.eind);
+        let (interrupt_inhibit, state, watch_hit) = (self.interrupt_inhibit, self.state, self.watch_hit.get());
+
+        let cycles = self.step_cpu();
+        let xmem_cycles = self.xmem_cycles.take();
+        if let Some(addr) = self.xmem_miss.take() {
+            self.reg_file.regs = regs;
+            (self.pc, self.sp, self.sreg, self.rampz, self.eind) = (pc, sp, sreg, rampz, eind);
+            (self.interrupt_inhibit, self.state) = (interrupt_inhibit, state);
+            self.watch_hit.set(watch_hit);
+            self.pending_fault.set(None);
+            self.served_interrupt = None;
+            let stall = self.io.xmem_start_read(addr).max(1);
+            self.xmem_stall = self.xmem_stall.saturating_add(stall);
+            return stall;
+        }
+        if xmem_cycles == 0 {
+            return cycles;
+        }
+        self.io.xmem_finish_instruction();
+        cycles.saturating_add(xmem_cycles).saturating_sub(std::mem::take(&mut self.xmem_stall)).max(1)
     }
 
-    /// Executes an opcode and returns number of cycles.
-    fn execute(&mut self, opcode: u16) -> u8 {
-        let head = (opcode >> 8) as u8;
-        match head {
-            0x00 => {
-                if opcode == 0x0000 {
-                    self.pc += 1; // NOP
-                    1
-                } else {
-                    panic!("Reserved")
-                }
-            }
-            0x01        => self.instr_movw(opcode),
-            0x02        => self.instr_muls(opcode),
-            0x03 => {
-                let bits = bit_field_combined(opcode, &[7..=7, 3..=3]);
-                match bits {
-                    0b00 => self.instr_mulsu(opcode),
-                    0b01 => self.instr_fmul(opcode),
-                    0b10 => self.instr_fmuls(opcode),
-                    0b11 => self.instr_fmulsu(opcode),
-                    _ => panic!("2-bit field impossible value"),
-                }
-            },
-            0x04..=0x07 => self.instr_cpc(opcode),
-            0x08..=0x0B => self.instr_sbc(opcode),
-            0x0C..=0x0F => self.instr_add(opcode),
-            0x10..=0x13 => self.instr_cpse(opcode),
-            0x14..=0x17 => self.instr_cp(opcode),
-            0x18..=0x1B => self.instr_sub(opcode),
-            0x1C..=0x1F => self.instr_adc(opcode),
-
-            0x20..=0x23 => self.instr_and(opcode),
-            0x24..=0x27 => self.instr_eor(opcode),
-            0x28..=0x2B => self.instr_or(opcode),
-            0x2C..=0x2F => self.instr_mov(opcode),
-
-            0x30..=0x3F => self.instr_cpi(opcode),
-            0x40..=0x4F => self.instr_sbci(opcode),
-            0x50..=0x5F => self.instr_subi(opcode),
-            0x60..=0x6F => self.instr_ori(opcode),
-            0x70..=0x7F => self.instr_andi(opcode),
-
-            0x80..=0x8F |
-            0xA0..=0xAF => if head.bit(1) {
-                self.instr_std(opcode)
-            } else {
-                self.instr_ldd(opcode)
-            },
+    /// Returns `true` while an instruction waits for external memory data to be retried.
+    #[inline]
+    pub fn xmem_stalled(&self) -> bool {
+        self.xmem_stall > 0
+    }
 
-            0x90 | 0x91 => {
-                let tail = opcode & 0x000F;
-                match tail {
-                    0x0 => self.instr_lds(opcode),
-                    0x1 | 0x2 | 0x9 | 0xA | 0xC..=0xE => self.instr_ld(opcode),
-                    0x4 | 0x5 => self.instr_lpm(opcode),
-                    0x6 | 0x7 => self.instr_elpm(opcode),
-                    0xF => self.instr_pop(opcode),
-                    0x3 | 0x8 | 0xB => panic!("Reserved"),
-                    _ => panic!("Impossible for 4-bit value"),
-                }
-            },
+    /// Advances the CPU core by one instruction (or one waiting cycle).
+    fn step_cpu(&mut self) -> u8 {
+        if self.spm.cpu_halted() {
+            return 1;
+        }
 
-            0x92 | 0x93 => {
-                let tail = opcode & 0x000F;
-                match tail {
-                    0x0 => self.instr_sts(opcode),
-                    0x1 | 0x2 | 0x9 | 0xA | 0xC..=0xE => self.instr_st(opcode),
-                    0xF => self.instr_push(opcode),
-                    0x3..=0x8 | 0xB => panic!("Reserved"),
-                    _ => panic!("Impossible for 4-bit value"),
-                }
-            },
+        if self.io.take_watchdog_reset() {
+            self.reset(ResetSource::Watchdog);
+            return 1;
+        }
 
-            0x94 | 0x95 => {
-                let tail = opcode & 0x000F;
-                match tail {
-                    0x0 => self.instr_com(opcode),
-                    0x1 => self.instr_neg(opcode),
-                    0x2 => self.instr_swap(opcode),
-                    0x3 => self.instr_inc(opcode),
-                    0x4 => panic!("Reserved"),
-                    0x5 => self.instr_asr(opcode),
-                    0x6 => self.instr_lsr(opcode),
-                    0x7 => self.instr_ror(opcode),
-
-                    0x8 => if head == 0x94 {
-                        if opcode.bit(7) {
-                            self.instr_bclr(opcode)
-                        } else {
-                            self.instr_bset(opcode)
-                        }
-                    } else {
-                        match opcode {
-                            0x9508 => self.instr_ret(opcode),
-                            0x9518 => self.instr_reti(opcode),
-                            0x9588 => todo!(),
-                            0x9598 => todo!(),
-                            0x95A8 => todo!(),
-                            0x95C8 => self.instr_lpm(opcode),
-                            0x95D8 => self.instr_elpm(opcode),
-                            0x95E8 => self.instr_spm(opcode),
-                            _ => panic!("Reserved"),
-                        }
-                    }
-                    0x9 => match opcode {
-                        0x9409 => self.instr_ijmp(opcode),
-                        0x9419 => self.instr_eijmp(opcode),
-                        0x9509 => self.instr_icall(opcode),
-                        0x9519 => self.instr_eicall(opcode),
-                        _ => panic!("Reserved"),
-                    }
-                    0xA => self.instr_dec(opcode),
-                    0xB => panic!("Reserved"),
-                    0xC | 0xD => self.instr_jmp(opcode),
-                    0xE | 0xF => self.instr_call(opcode),
-                    _ => panic!("Impossible for 4-bit value"),
+        match self.state {
+            CpuState::Running => {},
+            CpuState::Sleeping(_) => {
+                if self.sreg.i() && self.io.has_wake_up_interrupt() {
+                    return self.wake_up();
                 }
+                return 1;
             },
+            CpuState::Halted | CpuState::Faulted(_) => return 1,
+        }
+
+        let inhibit = std::mem::replace(&mut self.interrupt_inhibit, false);
+        // An instruction waiting for external memory is retried before serving interrupts
+        if self.sreg.i() && !inhibit && self.xmem_stall == 0 && self.ivce_cycles == 0 {
+            if let Some(vector) = self.pending_interrupt() {
+                return self.execute_interrupt(vector)
+            }
+        }
 
-            0x96        => self.instr_adiw(opcode),
-            0x97        => self.instr_sbiw(opcode),
-            0x98        => self.instr_cbi(opcode),
-            0x99        => self.instr_sbic(opcode),
-            0x9A        => self.instr_sbi(opcode),
-            0x9B        => self.instr_sbis(opcode),
-            0x9C..=0x9F => self.instr_mul(opcode),
-
-            0xB0..=0xB7 => self.instr_in(opcode),
-            0xB8..=0xBF => self.instr_out(opcode),
-
-            0xC0..=0xCF => self.instr_rjmp(opcode),
-            0xD0..=0xDF => self.instr_rcall(opcode),
-            0xE0..=0xEF => self.instr_ldi(opcode),
-
-            0xF0..=0xF3 => self.instr_brbs(opcode),
-            0xF4..=0xF7 => self.instr_brbc(opcode),
-            0xF8..=0xF9 => self.instr_bld(opcode),
-            0xFA..=0xFB => self.instr_bst(opcode),
-            0xFC..=0xFD => self.instr_sbrc(opcode),
-            0xFE..=0xFF => self.instr_sbrs(opcode),
+        if self.pc >= M::flash_size() as u32 {
+            self.report_fault(CpuFaultKind::PcOutOfFlash);
+            return 1;
+        }
+        let instr = self.fetch();
+        self.execute_instruction(instr)
+    }
+
+    /// Fetches a decoded instruction at PC address, using the decoded instruction cache.
+    fn fetch(&mut self) -> Instruction {
+        let pc = self.pc as usize;
+        if self.spm.rww_busy() && self.pc < M::nrww_start() {
+            // RWW section reads are invalid while it is being programmed, so don't cache them
+            return self.decode_at_pc();
+        }
+        if let Some(instr) = self.decoded[pc] {
+            return instr;
+        }
+
+        let instr = self.decode_at_pc();
+        if self.pending_fault.get().is_none() {
+            self.decoded[pc] = Some(instr);
+        }
+        instr
+    }
+
+    /// Decodes an instruction at PC address, reading the second word only for two-word instructions.
+    fn decode_at_pc(&self) -> Instruction {
+        let opcode = self.read_at_pc_offset(0);
+        let next = if is_two_word(opcode) {self.read_at_pc_offset(1)} else {0};
+        let instr = Instruction::decode(opcode, next);
+        // Instructions missing from the core are executed as illegal opcodes
+        if M::instruction_set().supports(&instr) {instr} else {Instruction::Reserved}
+    }
+
+    /// Executes a decoded instruction and returns number of cycles.
+    fn execute_instruction(&mut self, instr: Instruction) -> u8 {
+        use Instruction::*;
+
+        match instr {
+            Nop => {
+                self.pc += 1;
+                1
+            }
+            Movw { d, r } => self.instr_movw(d, r),
+            Muls { d, r } => self.instr_muls(d, r),
+            Mulsu { d, r } => self.instr_mulsu(d, r),
+            Fmul { d, r } => self.instr_fmul(d, r),
+            Fmuls { d, r } => self.instr_fmuls(d, r),
+            Fmulsu { d, r } => self.instr_fmulsu(d, r),
+            Cpc { d, r } => self.instr_cpc(d, r),
+            Sbc { d, r } => self.instr_sbc(d, r),
+            Add { d, r } => self.instr_add(d, r),
+            Cpse { d, r } => self.instr_cpse(d, r),
+            Cp { d, r } => self.instr_cp(d, r),
+            Sub { d, r } => self.instr_sub(d, r),
+            Adc { d, r } => self.instr_adc(d, r),
+            And { d, r } => self.instr_and(d, r),
+            Eor { d, r } => self.instr_eor(d, r),
+            Or { d, r } => self.instr_or(d, r),
+            Mov { d, r } => self.instr_mov(d, r),
+            Mul { d, r } => self.instr_mul(d, r),
+
+            Cpi { d, k } => self.instr_cpi(d, k),
+            Sbci { d, k } => self.instr_sbci(d, k),
+            Subi { d, k } => self.instr_subi(d, k),
+            Ori { d, k } => self.instr_ori(d, k),
+            Andi { d, k } => self.instr_andi(d, k),
+            Ldi { d, k } => self.instr_ldi(d, k),
+            Adiw { d, k } => self.instr_adiw(d, k),
+            Sbiw { d, k } => self.instr_sbiw(d, k),
+
+            Ldd { d, ptr, q } => self.instr_ldd(d, ptr, q),
+            Std { ptr, q, r } => self.instr_std(ptr, q, r),
+            Ld { d, ptr, mode } => self.instr_ld(d, ptr, mode),
+            St { ptr, mode, r } => self.instr_st(ptr, mode, r),
+            Lds { d, k } => self.instr_lds(d, k),
+            Sts { k, r } => self.instr_sts(k, r),
+            Lpm { d, post_increment } => self.instr_lpm(d, post_increment),
+            Elpm { d, post_increment } => self.instr_elpm(d, post_increment),
+            Spm => self.instr_spm(),
+            Pop { d } => self.instr_pop(d),
+            Push { r } => self.instr_push(r),
+            In { d, a } => self.instr_in(d, a),
+            Out { a, r } => self.instr_out(a, r),
+
+            Com { d } => self.instr_com(d),
+            Neg { d } => self.instr_neg(d),
+            Swap { d } => self.instr_swap(d),
+            Inc { d } => self.instr_inc(d),
+            Dec { d } => self.instr_dec(d),
+            Asr { d } => self.instr_asr(d),
+            Lsr { d } => self.instr_lsr(d),
+            Ror { d } => self.instr_ror(d),
+            Bset { s } => self.instr_bset(s),
+            Bclr { s } => self.instr_bclr(s),
+            Bld { d, b } => self.instr_bld(d, b),
+            Bst { d, b } => self.instr_bst(d, b),
+            Sbi { a, b } => self.instr_sbi(a, b),
+            Cbi { a, b } => self.instr_cbi(a, b),
+
+            Rjmp { k } => self.instr_rjmp(k),
+            Rcall { k } => self.instr_rcall(k),
+            Jmp { k } => self.instr_jmp(k),
+            Call { k } => self.instr_call(k),
+            Ijmp => self.instr_ijmp(),
+            Eijmp => self.instr_eijmp(),
+            Icall => self.instr_icall(),
+            Eicall => self.instr_eicall(),
+            Ret => self.instr_ret(),
+            Reti => self.instr_reti(),
+            Sbrc { r, b } => self.instr_sbrc(r, b),
+            Sbrs { r, b } => self.instr_sbrs(r, b),
+            Sbic { a, b } => self.instr_sbic(a, b),
+            Sbis { a, b } => self.instr_sbis(a, b),
+            Brbs { s, k } => self.instr_brbs(s, k),
+            Brbc { s, k } => self.instr_brbc(s, k),
+
+            Sleep => self.instr_sleep(),
+            Break => self.instr_break(),
+            Wdr => self.instr_wdr(),
+
+            Reserved => self.illegal_opcode(),
         }
     }
 
@@ -236,6 +389,93 @@ where
             self.write_flash(addr as u32, val);
         }
     }
+
+    /// Loads EEPROM from a slice, starting at address 0
+    pub fn load_eeprom(&mut self, data: &[u8]) {
+        let len = data.len().min(self.eeprom.len());
+        self.eeprom[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// Gets fuse and lock bytes.
+    #[inline]
+    pub fn fuses(&self) -> &Fuses {
+        &self.fuses
+    }
+
+    /// Sets the effective CPU clock frequency in Hz (16 MHz by default).
+    ///
+    /// The watchdog oscillator and flash page operations keep their real time at any CPU clock.
+    pub fn set_cpu_frequency(&mut self, frequency: u32) {
+        if frequency != self.cpu_frequency {
+            self.cpu_frequency = frequency;
+            self.spm.set_cpu_frequency(frequency);
+            self.io.set_cpu_frequency(frequency);
+        }
+    }
+
+    /// Programs fuse and lock bytes and resets the MCU to apply them.
+    pub fn set_fuses(&mut self, fuses: Fuses) {
+        self.fuses = fuses;
+        self.io.set_watchdog_always_on(fuses.wdton());
+        self.io.reset_clock_prescaler(fuses.ckdiv8());
+        self.reset(ResetSource::PowerOn);
+    }
+
+    /// Resets the MCU, restarting execution from the reset vector selected by BOOTRST fuse.
+    /// 
+    /// All peripherals return to their reset state and the source is recorded in MCUSR.
+    /// Register file and SRAM contents are preserved.
+    pub fn reset(&mut self, source: ResetSource) {
+        self.io.reset(source);
+        self.spm = SpmController::new(M::flash_page_size());
+        self.spm.set_cpu_frequency(self.cpu_frequency);
+        self.io.set_cpu_frequency(self.cpu_frequency);
+        self.xmem_miss.set(None);
+        self.xmem_cycles.set(0);
+        self.xmem_stall = 0;
+        self.pc = self.fuses.reset_vector();
+        self.mcucr = 0;
+        self.ivsel = false;
+        self.ivce_cycles = 0;
+        self.sp = M::sram_end();
+        self.rampz = 0;
+        self.eind = 0;
+        self.sreg = StatusRegister(0);
+        self.state = CpuState::Running;
+        self.interrupt_inhibit = false;
+        self.pending_fault.set(None);
+        self.io.set_sleep_mode(None);
+    }
+
+    /// Gets current CPU execution state.
+    #[inline]
+    pub fn state(&self) -> CpuState {
+        self.state
+    }
+
+    /// Gets number of CPU cycles executed since the start.
+    #[inline]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Takes a fault which has stopped the CPU, returning it only once.
+    #[inline]
+    pub fn take_fault(&mut self) -> Option<CpuFault> {
+        self.fault.take()
+    }
+
+    /// Takes data addresses of IO registers accessed since the last call which are not modelled.
+    pub fn take_unmodeled_accesses(&mut self) -> Vec<u16> {
+        self.io.take_unmodeled_accesses()
+    }
+
+    /// Resumes execution of a CPU halted by BREAK instruction.
+    pub fn resume(&mut self) {
+        if self.state == CpuState::Halted {
+            self.state = CpuState::Running;
+        }
+    }
 }
 
 /// An implementation for [VcdFiller].
@@ -260,10 +500,15 @@ where
         builder.add_signal("pc", 32, PinState::Low);
         builder.add_node("regs", &self.reg_file);
         builder.add_node("sreg", &self.sreg);
-        builder.add_node("timer1", self.io.timer1());
-        builder.add_node("timer3", self.io.timer3());
-        builder.add_node("timer4", self.io.timer4());
-        builder.add_node("timer5", self.io.timer5());
+        for (desc, timer) in M::peripherals().timers8.iter().zip(self.io.timers8()) {
+            builder.add_node(&format!("timer{}", desc.index), timer);
+        }
+        for (desc, timer) in M::peripherals().timers16.iter().zip(self.io.timers16()) {
+            builder.add_node(&format!("timer{}", desc.index), timer);
+        }
+        if let (Some(desc), Some(timer)) = (&M::peripherals().pll_timer, self.io.pll_timer().first()) {
+            builder.add_node(&format!("timer{}", desc.index), timer);
+        }
     }
 
     fn fill_module(&self, module: &mut VcdTreeModule) -> bool {
@@ -271,17 +516,24 @@ where
         r |= module.update_subsignal(1, self.pc);
         r |= module.update_child(2, &self.reg_file);
         r |= module.update_child(3, &self.sreg);
-        r |= module.update_child(4, self.io.timer1());
-        r |= module.update_child(5, self.io.timer3());
-        r |= module.update_child(6, self.io.timer4());
-        r |= module.update_child(7, self.io.timer5());
+        let timers8 = self.io.timers8();
+        for (i, timer) in timers8.iter().enumerate() {
+            r |= module.update_child(4 + i, timer);
+        }
+        let timers16 = self.io.timers16();
+        for (i, timer) in timers16.iter().enumerate() {
+            r |= module.update_child(4 + timers8.len() + i, timer);
+        }
+        if let Some(timer) = self.io.pll_timer().first() {
+            r |= module.update_child(4 + timers8.len() + timers16.len(), timer);
+        }
         r
     }
 }
 
 #[cfg(test)]
 mod test_helper {
-    use crate::components::avr::{mcu_model::McuModel, sreg::test_helper::assert_sreg, io_controller::IoControllerTrait};
+    use crate::components::avr::{mcu_model::McuModel, sreg::test_helper::assert_sreg, io_controller::IoControllerTrait, bit_helpers::is_two_word, instruction::Instruction};
 
     use super::Mcu;
 
@@ -293,7 +545,8 @@ mod test_helper {
         /// Helper test function, for executing an instruction and checking the correct [StatusRegister] change.
         pub fn execute_and_assert_sreg(&mut self, opcode: u16, sreg_mask: &'static str) {
             let sreg_initial = self.sreg;
-            self.execute(opcode);
+            let next = if is_two_word(opcode) {self.read_at_pc_offset(1)} else {0};
+            self.execute_instruction(Instruction::decode(opcode, next));
             assert_sreg(&self.sreg, &sreg_initial, sreg_mask);
         }
     }
@@ -1,7 +1,6 @@
 use bitfield::Bit;
 
 use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
-use crate::components::avr::bit_helpers::{get_rd_fields, get_d_field, get_k6, get_k8};
 
 use super::Mcu;
 
@@ -27,8 +26,7 @@ where
         self.sreg.set_h(rd3 && rr3 || rd3 && !r3 || !r3 && rr3);
     }
 
-    pub fn instr_add(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_add(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rr.wrapping_add(rd);
@@ -40,8 +38,7 @@ where
         1
     }
 
-    pub fn instr_adc(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_adc(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rr.wrapping_add(rd).wrapping_add(self.sreg.c() as u8);
@@ -53,9 +50,8 @@ where
         1
     }
 
-    pub fn instr_adiw(&mut self, opcode: u16) -> u8 {
-        let k = get_k6(opcode) as u16;
-        let d = get_d_field(opcode, 2);
+    pub fn instr_adiw(&mut self, d: u8, k: u8) -> u8 {
+        let k = k as u16;
         let rd = self.read_register_pair(d);
         let result = rd.wrapping_add(k);
 
@@ -90,8 +86,7 @@ where
         self.sreg.set_h(!rd3 && rr3 || rr3 && r3 || r3 && !rd3);
     }
 
-    pub fn instr_sub(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_sub(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(rr);
@@ -103,9 +98,7 @@ where
         1
     }
 
-    pub fn instr_subi(&mut self, opcode: u16) -> u8 {
-        let k = get_k8(opcode);
-        let d = get_d_field(opcode, 4);
+    pub fn instr_subi(&mut self, d: u8, k: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(k);
 
@@ -116,8 +109,7 @@ where
         1
     }
 
-    pub fn instr_sbc(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_sbc(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(rr).wrapping_sub(self.sreg.c() as u8);
@@ -129,9 +121,7 @@ where
         1
     }
 
-    pub fn instr_sbci(&mut self, opcode: u16) -> u8 {
-        let k = get_k8(opcode);
-        let d = get_d_field(opcode, 4);
+    pub fn instr_sbci(&mut self, d: u8, k: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(k).wrapping_sub(self.sreg.c() as u8);
 
@@ -142,9 +132,8 @@ where
         1
     }
 
-    pub fn instr_sbiw(&mut self, opcode: u16) -> u8 {
-        let k = get_k6(opcode) as u16;
-        let d = get_d_field(opcode, 2);
+    pub fn instr_sbiw(&mut self, d: u8, k: u8) -> u8 {
+        let k = k as u16;
         let rd = self.read_register_pair(d);
         let result = rd.wrapping_sub(k);
 
@@ -163,8 +152,7 @@ where
         2
     }
 
-    pub fn instr_inc(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_inc(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd.wrapping_add(1);
 
@@ -179,8 +167,7 @@ where
         1
     }
 
-    pub fn instr_dec(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_dec(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(1);
 
@@ -195,8 +182,7 @@ where
         1
     }
 
-    pub fn instr_cp(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_cp(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(rr);
@@ -207,9 +193,7 @@ where
         1
     }
 
-    pub fn instr_cpi(&mut self, opcode: u16) -> u8 {
-        let k = get_k8(opcode);
-        let d = get_d_field(opcode, 4);
+    pub fn instr_cpi(&mut self, d: u8, k: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(k);
 
@@ -219,8 +203,7 @@ where
         1
     }
 
-    pub fn instr_cpc(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_cpc(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd.wrapping_sub(rr).wrapping_sub(self.sreg.c() as u8);
@@ -1,7 +1,6 @@
 use bitfield::Bit;
 
 use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
-use crate::components::avr::bit_helpers::{get_d_field, get_io5, bit_field_combined};
 
 use super::{Mcu};
 
@@ -10,23 +9,19 @@ where
     M: McuModel + 'static,
     Io: IoControllerTrait,
 {
-    pub fn instr_sbi(&mut self, opcode: u16) -> u8 {
-        let io = get_io5(opcode);
-        let b = opcode & 0x0007;
-        let mut val = self.read_io(io);
+    pub fn instr_sbi(&mut self, a: u8, b: u8) -> u8 {
+        let mut val = self.read_io(a);
         val.set_bit(b as usize, true);
-        self.write_io(io, val);
+        self.write_io(a, val);
 
         self.pc += 1;
         2
     }
 
-    pub fn instr_cbi(&mut self, opcode: u16) -> u8 {
-        let io = get_io5(opcode);
-        let b = opcode & 0x0007;
-        let mut val = self.read_io(io);
+    pub fn instr_cbi(&mut self, a: u8, b: u8) -> u8 {
+        let mut val = self.read_io(a);
         val.set_bit(b as usize, false);
-        self.write_io(io, val);
+        self.write_io(a, val);
 
         self.pc += 1;
         2
@@ -40,8 +35,7 @@ where
         self.sreg.set_s(self.sreg.n() ^ self.sreg.v());
     }
 
-    pub fn instr_lsr(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_lsr(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd >> 1;
 
@@ -52,8 +46,7 @@ where
         1
     }
 
-    pub fn instr_ror(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_ror(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd >> 1 | if self.sreg.c() {0x80} else {0x00};
 
@@ -64,8 +57,7 @@ where
         1
     }
 
-    pub fn instr_asr(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_asr(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd >> 1 | if rd.bit(7) {0x80} else {0x00};
 
@@ -76,8 +68,7 @@ where
         1
     }
 
-    pub fn instr_swap(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_swap(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = (rd >> 4) | (rd << 4);
 
@@ -87,33 +78,31 @@ where
         1
     }
 
-    pub fn instr_bset(&mut self, opcode: u16) -> u8 {
-        let b = bit_field_combined(opcode, &[6..=4]);
-        self.sreg.set_bit(b as usize, true);
+    pub fn instr_bset(&mut self, s: u8) -> u8 {
+        self.sreg.set_bit(s as usize, true);
+        if s == 7 {
+            // SEI: the next instruction is executed before any pending interrupt
+            self.interrupt_inhibit = true;
+        }
         self.pc += 1;
         1
     }
 
-    pub fn instr_bclr(&mut self, opcode: u16) -> u8 {
-        let b = bit_field_combined(opcode, &[6..=4]);
-        self.sreg.set_bit(b as usize, false);
+    pub fn instr_bclr(&mut self, s: u8) -> u8 {
+        self.sreg.set_bit(s as usize, false);
         self.pc += 1;
         1
     }
 
-    pub fn instr_bst(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_bst(&mut self, d: u8, b: u8) -> u8 {
         let rd = self.read_register(d);
-        let b = opcode & 0x0007;
         self.sreg.set_t(rd.bit(b as usize));
         self.pc += 1;
         1
     }
 
-    pub fn instr_bld(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_bld(&mut self, d: u8, b: u8) -> u8 {
         let mut rd = self.read_register(d);
-        let b = opcode & 0x0007;
         rd.set_bit(b as usize, self.sreg.t());
         self.write_register(d, rd);
         self.pc += 1;
@@ -1,94 +1,92 @@
 use bitfield::Bit;
 
-use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
-use crate::components::avr::bit_helpers::{bit_field_combined, get_rd_fields, is_two_word, get_d_field, get_io5};
+use crate::components::avr::{mcu_model::McuModel, io_controller::{IoControllerTrait, InterruptSource}};
+use crate::components::avr::bit_helpers::is_two_word;
 
 use super::{Mcu};
 
-const Z_REG: u16 = 30;
+const Z_REG: u8 = 30;
 
 impl<M, Io> Mcu<M, Io>
 where
     M: McuModel + 'static,
     Io: IoControllerTrait,
 {
-    pub fn instr_rjmp(&mut self, opcode: u16) -> u8 {
-        let k = opcode & 0x0FFF;
-
-        if k.bit(11) {
-            let k = (k ^ 0x0FFF) + 1;
-            self.set_pc(self.pc - (k as u32) + 1);
-        } else {
-            self.set_pc(self.pc + (k as u32) + 1);
-        }
+    pub fn instr_rjmp(&mut self, k: i16) -> u8 {
+        self.set_pc(self.pc.wrapping_add_signed(k as i32 + 1));
         2
     }
 
-    pub fn instr_ijmp(&mut self, _opcode: u16) -> u8 {
+    pub fn instr_ijmp(&mut self) -> u8 {
         self.set_pc(self.read_register_pair(Z_REG) as u32);
         2
     }
 
-    pub fn instr_eijmp(&mut self, _opcode: u16) -> u8 {
+    pub fn instr_eijmp(&mut self) -> u8 {
         let z = self.read_register_pair(Z_REG);
         self.set_pc(self.eind_address(z));
         2
     }
 
-    pub fn instr_jmp(&mut self, opcode: u16) -> u8 {
-        let addr = 
-            (bit_field_combined(opcode, &[8..=4, 0..=0]) as u32) << 16 |
-            self.read_at_pc_offset(1) as u32;
-        self.set_pc(addr);
+    pub fn instr_jmp(&mut self, k: u32) -> u8 {
+        self.set_pc(k);
         3
     }
 
+    /// Pushes PC+1 to the stack, using [McuModel::pc_bytes] bytes.
     fn push_pc(&mut self) {
         self.pc += 1;
-        self.write_at_sp_offset(0, (self.pc) as u8);
-        self.write_at_sp_offset(-1, (self.pc >> 8) as u8);
-        self.write_at_sp_offset(-2, (self.pc >> 16) as u8);
+        for i in 0..M::pc_bytes() {
+            self.write_at_sp_offset(-(i as i16), (self.pc >> (8 * i)) as u8);
+        }
         self.pc -= 1;
-        self.sp -= 3;
+        self.sp -= M::pc_bytes() as u16;
     }
 
-    pub fn instr_rcall(&mut self, opcode: u16) -> u8 {
+    /// Returns number of extra cycles it takes to push PC in a call instruction.
+    #[inline]
+    fn call_cycles() -> u8 {
+        M::pc_bytes() - 1
+    }
+
+    pub fn instr_rcall(&mut self, k: i16) -> u8 {
         self.push_pc();
-        self.instr_rjmp(opcode) + 2
+        self.instr_rjmp(k) + Self::call_cycles()
     }
 
-    pub fn instr_icall(&mut self, opcode: u16) -> u8 {
+    pub fn instr_icall(&mut self) -> u8 {
         self.push_pc();
-        self.instr_ijmp(opcode) + 2
+        self.instr_ijmp() + Self::call_cycles()
     }
 
-    pub fn instr_eicall(&mut self, opcode: u16) -> u8 {
+    pub fn instr_eicall(&mut self) -> u8 {
         self.push_pc();
-        self.instr_eijmp(opcode) + 2
+        self.instr_eijmp() + Self::call_cycles()
     }
 
-    pub fn instr_call(&mut self, opcode: u16) -> u8 {
+    pub fn instr_call(&mut self, k: u32) -> u8 {
         self.pc += 1;
         self.push_pc();
         self.pc -= 1;
-        self.instr_jmp(opcode) + 2
+        self.instr_jmp(k) + Self::call_cycles()
     }
 
-    pub fn instr_ret(&mut self, _opcode: u16) -> u8 {
-        
-        let v1 = self.read_at_sp_offset(1) as u32;
-        let v2 = self.read_at_sp_offset(2) as u32;
-        let v3 = self.read_at_sp_offset(3) as u32;
-        self.sp += 3;
+    pub fn instr_ret(&mut self) -> u8 {
+        let mut pc = 0;
+        for i in 1..=M::pc_bytes() {
+            pc = pc << 8 | self.read_at_sp_offset(i as i16) as u32;
+        }
+        self.sp += M::pc_bytes() as u16;
         
-        self.set_pc(v1 << 16 | v2 << 8 | v3);
+        self.set_pc(pc);
 
-        5
+        M::pc_bytes() + 2
     }
 
-    pub fn instr_reti(&mut self, opcode: u16) -> u8 {
+    pub fn instr_reti(&mut self) -> u8 {
         self.sreg.set_i(true);
-        self.instr_ret(opcode)
+        self.interrupt_inhibit = true;
+        self.instr_ret()
     }
 
     fn skip_if(&mut self, cond: bool) -> u8 {
@@ -106,15 +104,10 @@ where
         }
     }
 
-    fn jump_if(&mut self, cond: bool, k: u16) -> u8 {
+    fn jump_if(&mut self, cond: bool, k: i8) -> u8 {
         self.pc += 1;
         if cond {
-            if k.bit(6) {
-                let k = (k ^ 0x007F) + 1; // Negation
-                self.set_pc(self.pc - (k as u32));
-            } else {
-                self.set_pc(self.pc + (k as u32));
-            }
+            self.set_pc(self.pc.wrapping_add_signed(k as i32));
             2
         } else {
             1
@@ -122,65 +115,69 @@ where
     }
 
 
-    pub fn instr_cpse(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_cpse(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
         self.skip_if(rr == rd)
     }
 
-    pub fn instr_sbrc(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-        let b = opcode & 0x0007;
-        let rd = self.read_register(d);
+    pub fn instr_sbrc(&mut self, r: u8, b: u8) -> u8 {
+        let rr = self.read_register(r);
 
-        self.skip_if(!rd.bit(b as usize))
+        self.skip_if(!rr.bit(b as usize))
     }
 
-    pub fn instr_sbrs(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-        let b = opcode & 0x0007;
-        let rd = self.read_register(d);
+    pub fn instr_sbrs(&mut self, r: u8, b: u8) -> u8 {
+        let rr = self.read_register(r);
 
-        self.skip_if(rd.bit(b as usize))
+        self.skip_if(rr.bit(b as usize))
     }
 
-    pub fn instr_sbic(&mut self, opcode: u16) -> u8 {
-        let io = get_io5(opcode);
-        let b = opcode & 0x0007;
-        let val = self.read_io(io);
+    pub fn instr_sbic(&mut self, a: u8, b: u8) -> u8 {
+        let val = self.read_io(a);
 
         self.skip_if(!val.bit(b as usize))
     }
 
-    pub fn instr_sbis(&mut self, opcode: u16) -> u8 {
-        let io = get_io5(opcode);
-        let b = opcode & 0x0007;
-        let val = self.read_io(io);
+    pub fn instr_sbis(&mut self, a: u8, b: u8) -> u8 {
+        let val = self.read_io(a);
 
         self.skip_if(val.bit(b as usize))
     }
 
-    pub fn instr_brbc(&mut self, opcode: u16) -> u8 {
-        let k = bit_field_combined(opcode, &[9..=3]);
-        let s = bit_field_combined(opcode, &[2..=0]) as usize;
+    pub fn instr_brbc(&mut self, s: u8, k: i8) -> u8 {
+        self.jump_if(!self.sreg.bit(s as usize), k)
+    }
 
-        self.jump_if(!self.sreg.bit(s), k)
+    pub fn instr_brbs(&mut self, s: u8, k: i8) -> u8 {
+        self.jump_if(self.sreg.bit(s as usize), k)
     }
 
-    pub fn instr_brbs(&mut self, opcode: u16) -> u8 {
-        let k = bit_field_combined(opcode, &[9..=3]);
-        let s = bit_field_combined(opcode, &[2..=0]) as usize;
+    /// Returns the highest priority pending interrupt vector (IO peripherals or SPM).
+    pub(super) fn pending_interrupt(&self) -> Option<u8> {
+        let spm = M::interrupt_vector(InterruptSource::SpmReady)
+            .filter(|_| self.spm.interrupt_pending());
+        self.io.pending_interrupt().into_iter().chain(spm).min()
+    }
 
-        self.jump_if(self.sreg.bit(s), k)
+    /// Returns number of cycles it takes to jump into an interrupt vector.
+    /// 
+    /// Pushing a 3-byte PC takes one cycle more.
+    #[inline]
+    pub(super) fn interrupt_response_cycles() -> u8 {
+        M::pc_bytes() + 2
     }
 
-    pub fn execute_interrupt(&mut self, addr: u16) -> u8 {
+    pub fn execute_interrupt(&mut self, vector: u8) -> u8 {
+        self.io.acknowledge_interrupt(vector);
+        self.served_interrupt = Some(vector);
+        self.sreg.set_i(false);
         self.pc -= 1;
         self.push_pc();
-        self.set_pc(addr as u32);
-        5
+        let base = if self.ivsel {self.fuses.boot_start().unwrap_or(0)} else {0};
+        self.set_pc(base + vector as u32 * M::vector_size());
+        Self::interrupt_response_cycles()
     }
 }
 
@@ -188,7 +185,7 @@ where
 mod tests {
     use mockall::predicate::eq;
 
-    use crate::components::avr::{mcu_model::Atmega2560, io_controller::MockIoControllerTrait, sreg::StatusRegister};
+    use crate::{components::avr::{mcu_model::{Atmega2560, Atmega328P}, io_controller::{MockIoControllerTrait, ResetSource}, sreg::StatusRegister, fuses::Fuses}, pins::PinState};
 
     use super::*;
 
@@ -406,6 +403,26 @@ mod tests {
         assert_eq!(mcu.pc, 0x1236);
     }
 
+    #[test]
+    fn call_ret_2byte_pc() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        assert_eq!(mcu.sp, 0x08FF);
+        mcu.pc = 0x1234;
+        assert_eq!(mcu.instr_call(0x0100), 4);
+        assert_eq!(mcu.pc, 0x0100);
+        assert_eq!(mcu.sp, 0x08FD);
+        assert_eq!(mcu.read(0x08FF), 0x36);
+        assert_eq!(mcu.read(0x08FE), 0x12);
+
+        assert_eq!(mcu.instr_rcall(0x10), 3);
+        assert_eq!(mcu.sp, 0x08FB);
+        assert_eq!(mcu.instr_ret(), 4);
+        assert_eq!(mcu.pc, 0x0101);
+        assert_eq!(mcu.instr_ret(), 4);
+        assert_eq!(mcu.pc, 0x1236);
+        assert_eq!(mcu.sp, 0x08FF);
+    }
+
     #[test]
     fn reti() {
         let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
@@ -618,4 +635,167 @@ mod tests {
             "--------");
         assert_eq!(mcu.pc, 0x120F);
     }
-}
\ No newline at end of file
+    fn interrupt_io(vector: u8) -> MockIoControllerTrait {
+        let mut io = MockIoControllerTrait::new();
+        io.expect_take_watchdog_reset()
+          .return_const(false);
+        io.expect_pending_interrupt()
+          .return_const(Some(vector));
+        io.expect_acknowledge_interrupt()
+          .with(eq(vector))
+          .return_const(());
+        io
+    }
+
+    #[test]
+    fn interrupt_entry() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::new(interrupt_io(17));
+        mcu.pc = 0x11234;
+        mcu.sp = 0x21FF;
+        mcu.sreg.set_i(true);
+
+        assert_eq!(mcu.step(), 5);
+        assert_eq!(mcu.pc, 0x0022);
+        assert!(!mcu.sreg.i());
+        assert_eq!(mcu.read(0x21FF), 0x34);
+        assert_eq!(mcu.read(0x21FE), 0x12);
+        assert_eq!(mcu.read(0x21FD), 0x01);
+
+        // Nested interrupts are disabled until RETI
+        assert_eq!(mcu.step(), 1);
+        assert_eq!(mcu.pc, 0x0023);
+    }
+
+    #[test]
+    fn interrupt_after_reti() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::new(interrupt_io(20));
+        mcu.pc = 0x1234;
+        mcu.sp = 0x21FF;
+        mcu.sreg.set_i(true);
+        mcu.write_flash(0x0028, 0x9518); // reti
+
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0028);
+        mcu.step();
+        assert_eq!(mcu.pc, 0x1234);
+        assert!(mcu.sreg.i());
+
+        // One instruction of the main program is executed before the next interrupt
+        mcu.step();
+        assert_eq!(mcu.pc, 0x1235);
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0028);
+    }
+
+    #[test]
+    fn interrupt_after_sei() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::new(interrupt_io(20));
+        mcu.pc = 0x1234;
+        mcu.sp = 0x21FF;
+        mcu.write_flash(0x1234, 0x9478); // sei
+
+        mcu.step();
+        assert_eq!(mcu.pc, 0x1235);
+        assert!(mcu.sreg.i());
+        mcu.step();
+        assert_eq!(mcu.pc, 0x1236);
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0028);
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1234;
+        mcu.sp = 0x21FF;
+        mcu.sreg.set_i(true);
+        mcu.write(0x6F, 0b0000_0010); // TIMSK1: output compare A
+        mcu.write(0x81, 0b0000_0001); // TCCR1B: clk/1
+        mcu.write_io(0x37, 0b1000_0000); // SPMCSR: SPM ready interrupt
+
+        mcu.io.clock_rising_edge();
+        assert_eq!(mcu.io.pending_interrupt(), Some(17));
+        assert_eq!(mcu.read_io(0x16) & 0x02, 0x02);
+
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0022);
+        assert_eq!(mcu.read_io(0x16) & 0x02, 0x00); // OCF1A cleared by hardware
+        assert_eq!(mcu.io.pending_interrupt(), None);
+
+        mcu.sreg.set_i(true);
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0050);
+    }
+
+    #[test]
+    fn boot_vectors() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        // BOOTSZ = 01, BOOTRST programmed
+        mcu.set_fuses(Fuses::from_sections::<Atmega328P>(&[0xFF, 0xDA], None));
+        assert_eq!(mcu.pc, 0x3C00);
+
+        // IVSEL can only be changed by the timed IVCE sequence
+        mcu.write_io(0x35, 0x02);
+        assert_eq!(mcu.read_io(0x35), 0x00);
+        mcu.write_io(0x35, 0x01);
+        mcu.write_io(0x35, 0x02);
+        assert_eq!(mcu.read_io(0x35), 0x02);
+
+        mcu.sp = 0x08FF;
+        mcu.execute_interrupt(11);
+        assert_eq!(mcu.pc, 0x3C16);
+
+        mcu.reset(ResetSource::External);
+        assert_eq!(mcu.pc, 0x3C00);
+        assert_eq!(mcu.read_io(0x35), 0x00);
+    }
+
+    #[test]
+    fn usart_udre_interrupt() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        mcu.pc = 0x0100;
+        mcu.sp = 0x08FF;
+        mcu.sreg.set_i(true);
+        mcu.write(0xC1, 0b0010_1000); // UCSR0B: UDRIE and TXEN
+
+        // UDRE stays set until the handler writes UDR0
+        mcu.io.clock_rising_edge();
+        assert_eq!(mcu.io.pending_interrupt(), Some(19));
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0026);
+        mcu.io.clock_rising_edge();
+        assert_eq!(mcu.io.pending_interrupt(), Some(19));
+        mcu.write(0xC6, 0x55); // UDR0
+        mcu.io.clock_rising_edge();
+        assert_eq!(mcu.io.pending_interrupt(), None);
+    }
+
+    #[test]
+    fn usart_rx_interrupt() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        mcu.pc = 0x0100;
+        mcu.sp = 0x08FF;
+        mcu.sreg.set_i(true);
+        mcu.write(0xC1, 0b1001_0000); // UCSR0B: RXCIE and RXEN
+
+        // A zero byte on RXD (PD0), 16 samples per bit
+        mcu.io.set_pin(15, PinState::Low);
+        for _ in 0..145 {
+            mcu.io.clock_rising_edge();
+        }
+        assert_eq!(mcu.io.pending_interrupt(), None);
+        mcu.io.set_pin(15, PinState::High);
+        for _ in 0..16 {
+            mcu.io.clock_rising_edge();
+        }
+        assert_eq!(mcu.io.pending_interrupt(), Some(18));
+        mcu.step();
+        assert_eq!(mcu.pc, 0x0024);
+
+        // RXC is cleared by reading UDR0
+        assert_eq!(mcu.read(0xC0) & 0x90, 0x80);
+        assert_eq!(mcu.read(0xC6), 0x00);
+        mcu.io.clock_rising_edge();
+        assert_eq!(mcu.io.pending_interrupt(), None);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, fault::{CpuFault, CpuFaultKind}};
+
+use super::{Mcu, CpuState};
+
+impl<M, Io> Mcu<M, Io>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    pub fn instr_sleep(&mut self) -> u8 {
+        if let Some(mode) = self.io.sleep_mode() {
+            self.state = CpuState::Sleeping(mode);
+            self.io.set_sleep_mode(Some(mode));
+        }
+        self.pc += 1;
+        1
+    }
+
+    pub fn instr_break(&mut self) -> u8 {
+        self.state = CpuState::Halted;
+        self.pc += 1;
+        1
+    }
+
+    pub fn instr_wdr(&mut self) -> u8 {
+        self.io.watchdog_reset();
+        self.pc += 1;
+        1
+    }
+
+    /// Wakes the CPU up from a sleep mode and returns number of cycles it takes.
+    /// 
+    /// Waking up increases the interrupt response time by the response time itself.
+    pub(super) fn wake_up(&mut self) -> u8 {
+        self.state = CpuState::Running;
+        self.io.set_sleep_mode(None);
+        Self::interrupt_response_cycles()
+    }
+
+    /// Reports a fault, stopping the CPU after the current instruction.
+    /// 
+    /// Only the first fault of an instruction is kept.
+    pub(super) fn report_fault(&self, kind: CpuFaultKind) {
+        if self.pending_fault.get().is_none() {
+            self.pending_fault.set(Some(kind));
+        }
+    }
+
+    pub(super) fn illegal_opcode(&mut self) -> u8 {
+        self.report_fault(CpuFaultKind::IllegalOpcode);
+        1
+    }
+
+    /// Stops the CPU because of a fault in the instruction at `pc`.
+    pub(super) fn fault(&mut self, kind: CpuFaultKind, pc: u32) {
+        let fault = CpuFault {
+            kind,
+            pc,
+            opcode: self.flash.get(pc as usize).copied().unwrap_or(0xFFFF),
+            cycle: self.cycles,
+        };
+        self.state = CpuState::Faulted(fault);
+        self.fault = Some(fault);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use crate::components::avr::{mcu_model::{Atmega2560, Attiny85, McuModel}, io_controller::{MockIoControllerTrait, SleepMode, ResetSource}};
+
+    use super::*;
+
+    #[test]
+    fn sleep() {
+        let mut io = MockIoControllerTrait::new();
+        io.expect_sleep_mode()
+          .times(1)
+          .return_const(Some(SleepMode::PowerDown));
+        io.expect_set_sleep_mode()
+          .with(eq(Some(SleepMode::PowerDown)))
+          .times(1)
+          .return_const(());
+
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::new(io);
+        mcu.pc = 0x1234;
+        mcu.execute_and_assert_sreg(
+            0x9588, // sleep
+            "--------");
+        assert_eq!(mcu.pc, 0x1235);
+        assert_eq!(mcu.state, CpuState::Sleeping(SleepMode::PowerDown));
+    }
+
+    #[test]
+    fn sleep_disabled() {
+        let mut io = MockIoControllerTrait::new();
+        io.expect_sleep_mode()
+          .times(1)
+          .return_const(None);
+
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::new(io);
+        mcu.pc = 0x1234;
+        mcu.execute_and_assert_sreg(
+            0x9588, // sleep
+            "--------");
+        assert_eq!(mcu.pc, 0x1235);
+        assert_eq!(mcu.state, CpuState::Running);
+    }
+
+    #[test]
+    fn sleep_wake_up() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1234;
+        mcu.sp = 0x21FF;
+        mcu.sreg.set_i(true);
+        mcu.write_flash(0x1234, 0x9588); // sleep
+        mcu.write(0x53, 0b0000_010_1); // SMCR: power-down
+        mcu.write(0x60, 0b0100_0000); // WDTCSR: interrupt mode
+
+        assert_eq!(mcu.step(), 1);
+        assert_eq!(mcu.state, CpuState::Sleeping(SleepMode::PowerDown));
+
+        while mcu.io.pending_interrupt().is_none() {
+            assert_eq!(mcu.step(), 1);
+            assert_eq!(mcu.pc, 0x1235);
+            mcu.io.clock_rising_edge();
+        }
+
+        assert_eq!(mcu.step(), 5);
+        assert_eq!(mcu.state, CpuState::Running);
+        assert_eq!(mcu.step(), 5);
+        assert_eq!(mcu.pc, 0x0018);
+        assert!(!mcu.sreg.i());
+        assert_eq!(mcu.read(0x21FF), 0x35);
+        assert_eq!(mcu.read(0x21FE), 0x12);
+    }
+
+    #[test]
+    fn r#break() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1234;
+        mcu.write_flash(0x1234, 0x9598); // break
+        assert_eq!(mcu.step(), 1);
+        assert_eq!(mcu.state, CpuState::Halted);
+        assert_eq!(mcu.pc, 0x1235);
+
+        mcu.step();
+        assert_eq!(mcu.pc, 0x1235);
+
+        mcu.resume();
+        assert_eq!(mcu.state, CpuState::Running);
+    }
+
+    #[test]
+    fn wdr() {
+        let mut io = MockIoControllerTrait::new();
+        io.expect_watchdog_reset()
+          .times(1)
+          .return_const(());
+
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::new(io);
+        mcu.pc = 0x1234;
+        mcu.execute_and_assert_sreg(
+            0x95A8, // wdr
+            "--------");
+        assert_eq!(mcu.pc, 0x1235);
+    }
+
+    #[test]
+    fn watchdog_system_reset() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1234;
+        mcu.sreg.set_t(true);
+        mcu.write_flash(0x1234, 0xCFFF); // rjmp .-1
+        mcu.write(0x60, 0b0000_1000); // WDTCSR: system reset mode
+
+        for _ in 0..300_000 {
+            mcu.io.clock_rising_edge();
+            mcu.step();
+            if mcu.pc != 0x1234 {
+                break;
+            }
+        }
+        assert_eq!(mcu.pc, 0);
+        assert!(!mcu.sreg.t());
+    }
+
+    #[test]
+    fn illegal_opcode() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1234;
+        mcu.write_flash(0x1234, 0x0000); // nop
+        mcu.write_flash(0x1235, 0x0001); // reserved
+
+        mcu.step();
+        assert_eq!(mcu.step(), 1);
+        let fault = CpuFault {
+            kind: CpuFaultKind::IllegalOpcode,
+            pc: 0x1235,
+            opcode: 0x0001,
+            cycle: 1,
+        };
+        assert_eq!(mcu.state, CpuState::Faulted(fault));
+        assert_eq!(mcu.take_fault(), Some(fault));
+        assert_eq!(mcu.take_fault(), None);
+
+        mcu.step();
+        assert_eq!(mcu.pc, 0x1235);
+        assert_eq!(mcu.cycles(), 3);
+
+        mcu.reset(ResetSource::External);
+        assert_eq!(mcu.state, CpuState::Running);
+    }
+
+    #[test]
+    fn missing_instructions() {
+        let mut mcu: Mcu<Attiny85, _> = Mcu::default();
+        mcu.write_flash(0x0000, 0x9C01); // mul r0, r1
+        mcu.step();
+        assert!(matches!(mcu.state, CpuState::Faulted(CpuFault {kind: CpuFaultKind::IllegalOpcode, pc: 0x0000, ..})));
+
+        let mut mcu: Mcu<Attiny85, _> = Mcu::default();
+        mcu.write_flash(0x0000, 0x940E); // call 0x0100
+        mcu.write_flash(0x0001, 0x0100);
+        mcu.step();
+        assert!(matches!(mcu.state, CpuState::Faulted(CpuFault {kind: CpuFaultKind::IllegalOpcode, opcode: 0x940E, ..})));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1234;
+        mcu.sp = 0x0201;
+        mcu.write_register(0, 0x12);
+        for i in 0..3 {
+            mcu.write_flash(0x1234 + i, 0x920F); // push r0
+        }
+
+        mcu.step();
+        mcu.step();
+        assert_eq!(mcu.state, CpuState::Running);
+        mcu.step();
+        assert!(matches!(mcu.state, CpuState::Faulted(CpuFault {kind: CpuFaultKind::StackOverflow, pc: 0x1236, ..})));
+        assert_eq!(mcu.read(0x3F), 0x00);
+    }
+
+    #[test]
+    fn pc_out_of_flash() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        let last = Atmega2560::flash_size() as u32 - 1;
+        mcu.pc = last;
+        mcu.step();
+        assert_eq!(mcu.state, CpuState::Running);
+        mcu.step();
+        assert!(matches!(mcu.state, CpuState::Faulted(CpuFault {kind: CpuFaultKind::PcOutOfFlash, pc, ..}) if pc == last + 1));
+    }
+}
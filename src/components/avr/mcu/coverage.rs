@@ -0,0 +1,359 @@
+use std::{collections::BTreeMap, fmt, fs::File, io::{self, BufWriter, Write}};
+
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, disasm::disassemble_at, instruction::Instruction, elf::{SymbolKind, SymbolTable}, dwarf::LineTable};
+
+use super::{Mcu, CpuState};
+
+/// An error while writing a coverage report.
+#[derive(Debug)]
+pub enum CoverageError {
+    Io(io::Error),
+    /// Coverage wasn't enabled before the run.
+    NotEnabled,
+    /// The firmware was not loaded from an ELF file with debug line info.
+    NoLineInfo,
+    /// The firmware's `.debug_line` section couldn't be parsed.
+    BadLineInfo(&'static str),
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageError::Io(e) => write!(f, "{}", e),
+            CoverageError::NotEnabled => write!(f, "coverage is not enabled"),
+            CoverageError::NoLineInfo => write!(f, "firmware has no debug line info"),
+            CoverageError::BadLineInfo(e) => write!(f, "invalid debug line info: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CoverageError {}
+
+impl From<io::Error> for CoverageError {
+    fn from(e: io::Error) -> Self {
+        CoverageError::Io(e)
+    }
+}
+
+/// Returns `true` for conditional branches and skips, which have taken and not taken counts.
+fn is_branch(instr: Instruction) -> bool {
+    use Instruction::*;
+    matches!(instr, Brbs {..} | Brbc {..} | Cpse {..} | Sbrc {..} | Sbrs {..} | Sbic {..} | Sbis {..})
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {100.0} else {part as f64 * 100.0 / total as f64}
+}
+
+/// Flash code coverage of a run: execution counts of instructions, and taken counts of branches.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    /// Execution count of the instruction at every flash word.
+    executions: Vec<u64>,
+    /// Number of executions which haven't continued with the next word, like taken branches and skips.
+    taken: Vec<u64>,
+}
+
+impl Coverage {
+    fn new(flash_size: usize) -> Coverage {
+        Coverage {
+            executions: vec![0; flash_size],
+            taken: vec![0; flash_size],
+        }
+    }
+
+    /// Gets the execution count of the instruction at a word address.
+    pub fn executions(&self, addr: u32) -> u64 {
+        self.executions.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    /// Gets taken and not taken counts of a conditional branch or skip at a word address.
+    pub fn branch(&self, addr: u32) -> (u64, u64) {
+        let taken = self.taken.get(addr as usize).copied().unwrap_or(0);
+        (taken, self.executions(addr) - taken)
+    }
+
+    /// Word addresses and decoded instructions of the program, from the start of flash to its last used word.
+    ///
+    /// Data in flash is decoded as instructions too, but executed words are never hidden inside them.
+    fn instructions<'a>(&'a self, flash: &'a [u16], start: u32, end: u32) -> impl Iterator<Item = (u32, Instruction, u32)> + 'a {
+        let mut addr = start;
+        std::iter::from_fn(move || {
+            if addr >= end {
+                return None;
+            }
+            let opcode = flash.get(addr as usize).copied().unwrap_or(0xFFFF);
+            let operand = flash.get(addr as usize + 1).copied().unwrap_or(0xFFFF);
+            let instr = Instruction::decode(opcode, operand);
+            let two_words = super::is_two_word(opcode) && self.executions(addr + 1) == 0;
+            let size = if two_words {2} else {1};
+            let result = (addr, instr, size);
+            addr += size;
+            Some(result)
+        })
+    }
+
+    /// Gets the end of the program: the word after the last non-erased or executed word.
+    fn program_end(&self, flash: &[u16]) -> u32 {
+        let loaded = flash.iter().rposition(|&w| w != 0x0000 && w != 0xFFFF).map_or(0, |i| i + 1);
+        let executed = self.executions.iter().rposition(|&c| c > 0).map_or(0, |i| i + 1);
+        loaded.max(executed) as u32
+    }
+
+    /// Writes an address-level report: every instruction with its execution count,
+    /// and taken/not taken counts for conditional branches and skips.
+    ///
+    /// Never executed instructions are marked with `#####`, like in gcov.
+    pub fn write_report<M: McuModel>(&self, flash: &[u16], symbols: Option<&SymbolTable>, w: &mut impl Write) -> io::Result<()> {
+        let end = self.program_end(flash);
+        let (mut total, mut executed, mut directions, mut taken_directions) = (0, 0, 0, 0);
+        for (addr, instr, _) in self.instructions(flash, 0, end) {
+            total += 1;
+            if self.executions(addr) > 0 {
+                executed += 1;
+            }
+            if is_branch(instr) {
+                let (taken, not_taken) = self.branch(addr);
+                directions += 2;
+                taken_directions += (taken > 0) as usize + (not_taken > 0) as usize;
+            }
+        }
+        writeln!(w, "# Instructions: {} of {} executed ({:.1}%)", executed, total, percent(executed, total))?;
+        writeln!(w, "# Branches: {} of {} directions taken ({:.1}%)",
+            taken_directions, directions, percent(taken_directions, directions))?;
+
+        for (addr, instr, size) in self.instructions(flash, 0, end) {
+            if let Some((symbol, 0)) = symbols.and_then(|s| s.function_at(addr << 1)) {
+                writeln!(w, "<{}>:", symbol.name)?;
+            }
+            let count = match self.executions(addr) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            let text = if size == 1 && instr_size(flash, addr) == 2 {
+                format!("{:5X}:  .word 0x{:04X}", addr << 1, flash[addr as usize])
+            } else {
+                disassemble_at::<M>(flash, addr).to_string()
+            };
+            if is_branch(instr) {
+                let (taken, not_taken) = self.branch(addr);
+                writeln!(w, "{:>10} {}    ; taken {}, not taken {}", count, text, taken, not_taken)?;
+            } else {
+                writeln!(w, "{:>10} {}", count, text)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes coverage of source lines as an lcov tracefile, for `genhtml` or CI coverage tools.
+    ///
+    /// A line count is the highest execution count of its instructions.
+    /// Every conditional branch or skip is a block with a taken (0) and a not taken (1) branch.
+    pub fn write_lcov(&self, flash: &[u16], lines: &LineTable, symbols: &SymbolTable, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "TN:")?;
+        for (file_index, file) in lines.files.iter().enumerate() {
+            let mut line_counts = BTreeMap::new();
+            let mut branches: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+            for range in lines.ranges.iter().filter(|r| r.file == file_index) {
+                for (addr, instr, _) in self.instructions(flash, range.start >> 1, range.end.div_ceil(2)) {
+                    let count = line_counts.entry(range.line).or_insert(0);
+                    *count = self.executions(addr).max(*count);
+                    if is_branch(instr) {
+                        branches.entry(range.line).or_default().push(addr);
+                    }
+                }
+            }
+            if line_counts.is_empty() {
+                continue;
+            }
+            writeln!(w, "SF:{}", file)?;
+
+            let functions: Vec<_> = symbols.iter()
+                .filter(|s| s.kind == SymbolKind::Function)
+                .filter_map(|s| match lines.find(s.addr) {
+                    Some((f, line)) if f == file => Some((line, s)),
+                    _ => None,
+                })
+                .collect();
+            for (line, s) in &functions {
+                writeln!(w, "FN:{},{}", line, s.name)?;
+            }
+            for (_, s) in &functions {
+                writeln!(w, "FNDA:{},{}", self.executions(s.addr >> 1), s.name)?;
+            }
+            let functions_hit = functions.iter().filter(|(_, s)| self.executions(s.addr >> 1) > 0).count();
+            writeln!(w, "FNF:{}", functions.len())?;
+            writeln!(w, "FNH:{}", functions_hit)?;
+
+            let (mut found, mut hit) = (0, 0);
+            for (line, addrs) in &branches {
+                for (block, &addr) in addrs.iter().enumerate() {
+                    let (taken, not_taken) = self.branch(addr);
+                    for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                        found += 1;
+                        hit += (count > 0) as usize;
+                        match self.executions(addr) {
+                            0 => writeln!(w, "BRDA:{},{},{},-", line, block, branch)?,
+                            _ => writeln!(w, "BRDA:{},{},{},{}", line, block, branch, count)?,
+                        }
+                    }
+                }
+            }
+            writeln!(w, "BRF:{}", found)?;
+            writeln!(w, "BRH:{}", hit)?;
+
+            for (line, count) in &line_counts {
+                writeln!(w, "DA:{},{}", line, count)?;
+            }
+            writeln!(w, "LF:{}", line_counts.len())?;
+            writeln!(w, "LH:{}", line_counts.values().filter(|&&c| c > 0).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Writes an address-level report into a file, see [Coverage::write_report].
+    pub fn save_report<M: McuModel>(&self, flash: &[u16], symbols: Option<&SymbolTable>, filename: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(filename)?);
+        self.write_report::<M>(flash, symbols, &mut w)?;
+        w.flush()
+    }
+
+    /// Writes an lcov tracefile into a file, see [Coverage::write_lcov].
+    pub fn save_lcov(&self, flash: &[u16], lines: &LineTable, symbols: &SymbolTable, filename: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(filename)?);
+        self.write_lcov(flash, lines, symbols, &mut w)?;
+        w.flush()
+    }
+}
+
+/// Gets the size of the instruction at a word address, in words.
+fn instr_size(flash: &[u16], addr: u32) -> u32 {
+    let opcode = flash.get(addr as usize).copied().unwrap_or(0xFFFF);
+    if super::is_two_word(opcode) {2} else {1}
+}
+
+impl<M, Io> Mcu<M, Io>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    /// Starts collecting flash code coverage, clearing the previous counts.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(M::flash_size()));
+    }
+
+    /// Gets the coverage collected since [Mcu::enable_coverage].
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Returns `true` if the next step should be recorded in the coverage.
+    #[inline]
+    pub(super) fn coverage_enabled(&self) -> bool {
+        self.coverage.is_some() && self.state == CpuState::Running && !self.spm.cpu_halted()
+    }
+
+    /// Records the instruction at word address `pc` executed by the last step, unless it has served an interrupt
+    /// or has to be retried after an external memory read.
+    #[inline]
+    pub(super) fn record_coverage(&mut self, pc: u32) {
+        if self.served_interrupt.is_some() || self.xmem_stall > 0 || matches!(self.state, CpuState::Faulted(_)) {
+            return;
+        }
+        let next = self.pc;
+        if let Some(coverage) = &mut self.coverage {
+            let Some(count) = coverage.executions.get_mut(pc as usize) else {
+                return;
+            };
+            *count += 1;
+            if next != pc + 1 {
+                coverage.taken[pc as usize] += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::{mcu_model::Atmega328P, elf::Symbol, dwarf::LineRange};
+
+    use super::*;
+
+    fn run() -> Mcu<Atmega328P, crate::components::avr::io_controller::IoController<Atmega328P>> {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        mcu.enable_coverage();
+        mcu.load_flash(&[
+            0xE003, // ldi r16, 3
+            0x950A, // dec r16
+            0xF7F1, // brne .-4
+            0xFF00, // sbrs r16, 0
+            0x0000, // nop
+            0xCFFF, // rjmp .-2
+        ]);
+        for _ in 0..11 {
+            mcu.step();
+        }
+        mcu
+    }
+
+    #[test]
+    fn counts() {
+        let mcu = run();
+        let coverage = mcu.coverage().unwrap();
+        assert_eq!((0..7).map(|a| coverage.executions(a)).collect::<Vec<_>>(), [1, 3, 3, 1, 1, 2, 0]);
+        assert_eq!(coverage.branch(2), (2, 1));
+        assert_eq!(coverage.branch(3), (0, 1));
+
+        let mut report = Vec::new();
+        coverage.write_report::<Atmega328P>(&mcu.flash, None, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "# Instructions: 6 of 6 executed (100.0%)");
+        assert_eq!(lines[1], "# Branches: 3 of 4 directions taken (75.0%)");
+        assert_eq!(lines[4], "         3     4:  F1 F7          brne 0x2    ; taken 2, not taken 1");
+        assert_eq!(lines.len(), 8);
+    }
+
+    #[test]
+    fn lcov() {
+        let mcu = run();
+        let lines = LineTable {
+            files: vec!["main.c".to_string()],
+            ranges: vec![
+                LineRange {start: 0, end: 2, file: 0, line: 3},
+                LineRange {start: 2, end: 6, file: 0, line: 4},
+                LineRange {start: 6, end: 10, file: 0, line: 5},
+                LineRange {start: 10, end: 12, file: 0, line: 7},
+                LineRange {start: 12, end: 14, file: 0, line: 8},
+            ],
+        };
+        let symbols = SymbolTable::new(vec![
+            Symbol {name: "main".to_string(), addr: 0, size: 12, kind: SymbolKind::Function},
+        ]);
+        let mut lcov = Vec::new();
+        mcu.coverage().unwrap().write_lcov(&mcu.flash, &lines, &symbols, &mut lcov).unwrap();
+        assert_eq!(String::from_utf8(lcov).unwrap(), "\
+TN:
+SF:main.c
+FN:3,main
+FNDA:1,main
+FNF:1
+FNH:1
+BRDA:4,0,0,2
+BRDA:4,0,1,1
+BRDA:5,0,0,0
+BRDA:5,0,1,1
+BRF:4
+BRH:3
+DA:3,1
+DA:4,3
+DA:5,1
+DA:7,2
+DA:8,0
+LF:5
+LH:4
+end_of_record
+");
+    }
+}
@@ -0,0 +1,267 @@
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, sreg::StatusRegister};
+
+use super::Mcu;
+
+/// Kind of data memory accesses a watchpoint reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    #[inline]
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+/// A watchpoint on a data memory range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// Data memory address.
+    pub addr: u16,
+    pub len: u16,
+    pub kind: WatchKind,
+    /// Only accesses reading or writing this value match, if set.
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    /// Creates a watchpoint on a single data memory byte.
+    pub fn data(addr: u16, kind: WatchKind) -> Watchpoint {
+        Watchpoint {addr, len: 1, kind, value: None}
+    }
+
+    /// Creates a watchpoint on an IO register, given by its IO address (like in `in` and `out`).
+    pub fn io(addr: u8, kind: WatchKind) -> Watchpoint {
+        Watchpoint::data(addr as u16 + 0x20, kind)
+    }
+
+    /// Makes the watchpoint match only accesses of a given value.
+    pub fn with_value(self, value: u8) -> Watchpoint {
+        Watchpoint {value: Some(value), ..self}
+    }
+}
+
+/// Debugger support: breakpoints, watchpoints and direct CPU state access.
+impl<M, Io> Mcu<M, Io>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    /// Adds a breakpoint at a word address.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.retain(|&x| x != addr);
+    }
+
+    /// Returns `true` if there is a breakpoint at PC.
+    #[inline]
+    pub fn at_breakpoint(&self) -> bool {
+        !self.breakpoints.is_empty() && self.breakpoints.contains(&self.pc)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.retain(|&x| x != watchpoint);
+    }
+
+    /// Removes all breakpoints and watchpoints.
+    pub fn clear_debug_points(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.watch_hit.set(None);
+    }
+
+    /// Takes the first watchpoint hit since the last call, with the accessed address.
+    #[inline]
+    pub fn take_watch_hit(&mut self) -> Option<(u16, WatchKind)> {
+        self.watch_hit.take()
+    }
+
+    /// Records a watchpoint hit if a data memory access of value `val` matches any watchpoint.
+    #[inline]
+    pub(super) fn check_watchpoints(&self, addr: u16, write: bool, val: u8) {
+        if self.watchpoints.is_empty() || self.watch_hit.get().is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().find(|w| {
+            w.kind.matches(write) && addr >= w.addr && addr - w.addr < w.len.max(1) &&
+                w.value.is_none_or(|value| value == val)
+        });
+        if let Some(w) = hit {
+            self.watch_hit.set(Some((addr, w.kind)));
+        }
+    }
+
+    /// Gets PC word address.
+    #[inline]
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    #[inline]
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    #[inline]
+    pub fn set_sp(&mut self, val: u16) {
+        self.sp = val;
+    }
+
+    #[inline]
+    pub fn sreg(&self) -> u8 {
+        self.sreg.0
+    }
+
+    #[inline]
+    pub fn set_sreg(&mut self, val: u8) {
+        self.sreg = StatusRegister(val);
+    }
+
+    /// Gets the whole flash memory, in words.
+    pub fn flash(&self) -> &[u16] {
+        &self.flash
+    }
+
+    /// Reads a flash byte without any side effects, `None` outside of flash.
+    pub fn peek_flash_byte(&self, addr: u32) -> Option<u8> {
+        let word = *self.flash.get(addr as usize >> 1)?;
+        Some(if addr & 1 == 0 {word as u8} else {(word >> 8) as u8})
+    }
+
+    /// Writes a flash byte, `None` outside of flash.
+    pub fn poke_flash_byte(&mut self, addr: u32, val: u8) -> Option<()> {
+        let word = *self.flash.get(addr as usize >> 1)?;
+        let word = if addr & 1 == 0 {
+            word & 0xFF00 | val as u16
+        } else {
+            word & 0x00FF | (val as u16) << 8
+        };
+        self.write_flash(addr >> 1, word);
+        Some(())
+    }
+
+    /// Reads a data memory byte without triggering watchpoints.
+    /// 
+    /// External memory reads as 0, as it can only be read by a bus cycle.
+    /// Semihosting DATA returns the next input byte without taking it.
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(val) = self.semihosting_peek(addr) {
+            return val;
+        }
+        let hit = self.watch_hit.get();
+        let (miss, xmem_cycles) = (self.xmem_miss.get(), self.xmem_cycles.get());
+        let val = self.read(addr);
+        self.watch_hit.set(hit);
+        self.xmem_miss.set(miss);
+        self.xmem_cycles.set(xmem_cycles);
+        val
+    }
+
+    /// Returns `true` if [Mcu::peek] at a data address can't avoid changing IO state, like reading UDR.
+    pub fn peek_has_side_effects(&self, addr: u16) -> bool {
+        if self.semihosting_peek(addr).is_some() {
+            return false;
+        }
+        match addr {
+            // SPMCSR, RAMPZ, EIND, SP and SREG are kept by the core, like MCUCR with boot sections
+            0x57 | 0x5B..=0x5F => false,
+            0x55 if M::fuse_layout().boot_sizes.is_some() => false,
+            _ => (0x20..M::sram_start()).contains(&addr) && self.io.read_has_side_effects(addr),
+        }
+    }
+
+    /// Writes a data memory byte without triggering watchpoints.
+    /// 
+    /// External memory is written by a bus cycle.
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        let hit = self.watch_hit.get();
+        let xmem_cycles = self.xmem_cycles.get();
+        self.write(addr, val);
+        self.watch_hit.set(hit);
+        self.xmem_cycles.set(xmem_cycles);
+    }
+
+    pub fn read_eeprom(&self, addr: u16) -> Option<u8> {
+        self.eeprom.get(addr as usize).copied()
+    }
+
+    pub fn write_eeprom(&mut self, addr: u16, val: u8) -> Option<()> {
+        *self.eeprom.get_mut(addr as usize)? = val;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::mcu_model::Atmega2560;
+
+    use super::*;
+
+    #[test]
+    fn watchpoints() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.add_watchpoint(Watchpoint {addr: 0x0300, len: 2, kind: WatchKind::Write, value: None});
+        mcu.add_watchpoint(Watchpoint::io(0x05, WatchKind::Read));
+
+        mcu.read(0x0300);
+        mcu.write(0x0302, 0x12);
+        assert_eq!(mcu.take_watch_hit(), None);
+        mcu.write(0x0301, 0x12);
+        assert_eq!(mcu.take_watch_hit(), Some((0x0301, WatchKind::Write)));
+
+        mcu.write_register(16, 0xFF);
+        mcu.execute_and_assert_sreg(0xB905, "--------"); // out PORTB, r16
+        assert_eq!(mcu.take_watch_hit(), None);
+        mcu.execute_and_assert_sreg(0xB105, "--------"); // in r16, PORTB
+        assert_eq!(mcu.take_watch_hit(), Some((0x0025, WatchKind::Read)));
+
+        assert_eq!(mcu.peek(0x0025), 0xFF);
+        mcu.poke(0x0300, 0x34);
+        assert_eq!(mcu.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn value_watchpoints() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.add_watchpoint(Watchpoint::data(0x0200, WatchKind::Access).with_value(0x42));
+        mcu.write(0x0200, 0x41);
+        mcu.read(0x0200);
+        assert_eq!(mcu.take_watch_hit(), None);
+        mcu.write(0x0200, 0x42);
+        assert_eq!(mcu.take_watch_hit(), Some((0x0200, WatchKind::Access)));
+        mcu.read(0x0200);
+        assert_eq!(mcu.take_watch_hit(), Some((0x0200, WatchKind::Access)));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.add_breakpoint(0x0002);
+        mcu.add_breakpoint(0x0002);
+        mcu.step();
+        assert!(!mcu.at_breakpoint());
+        mcu.step();
+        assert!(mcu.at_breakpoint());
+        mcu.remove_breakpoint(0x0002);
+        assert!(!mcu.at_breakpoint());
+    }
+}
@@ -1,44 +1,142 @@
-use std::{fs::File, io::{BufReader, BufRead}, str::FromStr};
+use std::{fmt, fs, io};
 
 use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
 
 use super::Mcu;
 
-struct HexLine {
-    size: u8,
-    addr: u16,
-    record_type: u8,
-    data: Vec<u8>,
+/// An error while loading a firmware image.
+#[derive(Debug)]
+pub enum HexError {
+    Io(io::Error),
+    /// Malformed record at a given line.
+    Syntax(usize),
+    /// Record checksum mismatch at a given line.
+    Checksum(usize),
+    /// Unknown record type at a given line.
+    UnsupportedRecord(usize, u8),
+    /// Data byte address outside of the flash memory.
+    OutOfFlash(u32),
 }
 
-impl FromStr for HexLine {
-    type Err = std::num::ParseIntError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let size = u8::from_str_radix(&s[1..3], 16)?;
-        let addr = u16::from_str_radix(&s[3..7], 16)?;
-        let record_type = u8::from_str_radix(&s[7..9], 16)?;
-        if size == 0 && addr == 0 && record_type == 1 {
-            Ok(HexLine {
-                size, addr, record_type,
-                data: Vec::new()
-            })
-        } else {
-            assert_eq!(record_type, 0);
-            let mut data = vec![0; size as usize];
-            let mut index = 9;
-            let mut checksum = size as u16 + (addr & 0xFF) + (addr >> 8) + record_type as u16;
-            for i in 0..size {
-                let x = u8::from_str_radix(&s[index..index+2], 16)?;
-                index += 2;
-                data[i as usize] = x;
-                checksum += x as u16;
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexError::Io(e) => write!(f, "{}", e),
+            HexError::Syntax(line) => write!(f, "line {}: malformed record", line),
+            HexError::Checksum(line) => write!(f, "line {}: checksum mismatch", line),
+            HexError::UnsupportedRecord(line, t) => write!(f, "line {}: unsupported record type {}", line, t),
+            HexError::OutOfFlash(addr) => write!(f, "byte address 0x{:X} is outside of flash memory", addr),
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+impl From<io::Error> for HexError {
+    fn from(e: io::Error) -> Self {
+        HexError::Io(e)
+    }
+}
+
+/// A firmware image: data chunks at flash byte addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HexImage {
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    /// Start address record, if present.
+    ///
+    /// AVR always starts from the reset vector, so it is not used for loading.
+    pub start: Option<u32>,
+}
+
+/// Parses a record of hex digit pairs, checking that the checksum makes the byte sum equal to `sum`.
+fn parse_record(s: &str, line: usize, sum: u8) -> Result<Vec<u8>, HexError> {
+    if !s.len().is_multiple_of(2) || s.len() < 2 {
+        return Err(HexError::Syntax(line));
+    }
+    let bytes = (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i+2).and_then(|x| u8::from_str_radix(x, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(HexError::Syntax(line))?;
+    if bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) != sum {
+        return Err(HexError::Checksum(line));
+    }
+    Ok(bytes)
+}
+
+/// Parses Intel HEX file contents.
+pub fn parse_intel_hex(text: &str) -> Result<HexImage, HexError> {
+    let mut image = HexImage::default();
+    let mut base = 0u32;
+    for (i, l) in text.lines().enumerate() {
+        let line = i + 1;
+        let l = l.trim();
+        let Some(record) = l.strip_prefix(':') else {
+            continue;
+        };
+        let bytes = parse_record(record, line, 0)?;
+        if bytes.len() < 5 || bytes[0] as usize != bytes.len() - 5 {
+            return Err(HexError::Syntax(line));
+        }
+        let addr = (bytes[1] as u32) << 8 | bytes[2] as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        let word = || -> Result<u32, HexError> {
+            match data {
+                &[a, b] => Ok((a as u32) << 8 | b as u32),
+                _ => Err(HexError::Syntax(line)),
+            }
+        };
+        let dword = || -> Result<u32, HexError> {
+            match data {
+                &[a, b, c, d] => Ok(u32::from_be_bytes([a, b, c, d])),
+                _ => Err(HexError::Syntax(line)),
             }
-            checksum += u16::from_str_radix(&s[index..index+2], 16)?;
-            assert_eq!(checksum & 0xFF, 0);
-            Ok(HexLine {size, addr, record_type, data})
+        };
+        match bytes[3] {
+            0x00 => image.chunks.push((base + addr, data.to_vec())),
+            0x01 => break,
+            0x02 => base = word()? << 4,
+            0x03 => image.start = Some(dword()?),
+            0x04 => base = word()? << 16,
+            0x05 => image.start = Some(dword()?),
+            t => return Err(HexError::UnsupportedRecord(line, t)),
+        }
+    }
+    Ok(image)
+}
+
+/// Parses Motorola S-record file contents.
+pub fn parse_srec(text: &str) -> Result<HexImage, HexError> {
+    let mut image = HexImage::default();
+    for (i, l) in text.lines().enumerate() {
+        let line = i + 1;
+        let l = l.trim();
+        let Some(record) = l.strip_prefix('S') else {
+            continue;
+        };
+        let mut chars = record.chars();
+        let t = chars.next().and_then(|c| c.to_digit(10)).ok_or(HexError::Syntax(line))? as u8;
+        let bytes = parse_record(chars.as_str(), line, 0xFF)?;
+        if bytes[0] as usize != bytes.len() - 1 {
+            return Err(HexError::Syntax(line));
+        }
+        let addr_size = match t {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(HexError::UnsupportedRecord(line, t)),
+        };
+        if bytes.len() < addr_size + 2 {
+            return Err(HexError::Syntax(line));
+        }
+        let addr = bytes[1..=addr_size].iter().fold(0u32, |acc, &x| acc << 8 | x as u32);
+        let data = &bytes[addr_size + 1..bytes.len() - 1];
+        match t {
+            1..=3 => image.chunks.push((addr, data.to_vec())),
+            7..=9 => image.start = Some(addr),
+            _ => {}
         }
     }
+    Ok(image)
 }
 
 impl<M, Io> Mcu<M, Io>
@@ -46,27 +144,109 @@ where
     M: McuModel + 'static,
     Io: IoControllerTrait,
 {
-    /// Reads flash from Intel .hex file
-    pub fn load_flash_hex(&mut self, filename: &str) {
-        let file = File::open(filename).unwrap();
-        let lines = BufReader::new(file).lines();
-        for line in lines {
-            let l = line.unwrap();
-            if l.starts_with(':') {
-                let data: HexLine = l.parse().unwrap();
-                match data.record_type {
-                    0 => {
-                        let mut i = 0;
-                        while i < data.size as usize {
-                            let x = data.data[i] as u16 | (data.data[i+1] as u16) << 8;
-                            self.write_flash((data.addr as u32 + i as u32) >> 1, x);
-                            i += 2;
-                        }
-                    }
-                    1 => break,
-                    _ => panic!("Invalid record type")
-                }
+    /// Writes a firmware image into flash memory.
+    pub fn load_image(&mut self, image: &HexImage) -> Result<(), HexError> {
+        for (addr, data) in image.chunks.iter() {
+            let end = addr.checked_add(data.len() as u32).filter(|&end| end <= M::flash_size() as u32 * 2);
+            if end.is_none() {
+                return Err(HexError::OutOfFlash(addr.saturating_add((data.len() as u32).saturating_sub(1))));
+            }
+            for (i, &x) in data.iter().enumerate() {
+                let byte_addr = addr + i as u32;
+                let word = self.flash[byte_addr as usize >> 1];
+                let word = if byte_addr & 1 == 0 {
+                    word & 0xFF00 | x as u16
+                } else {
+                    word & 0x00FF | (x as u16) << 8
+                };
+                self.write_flash(byte_addr >> 1, word);
             }
         }
+        Ok(())
+    }
+
+    /// Reads flash from Intel .hex file
+    pub fn load_flash_hex(&mut self, filename: &str) -> Result<(), HexError> {
+        let image = parse_intel_hex(&fs::read_to_string(filename)?)?;
+        self.load_image(&image)
+    }
+
+    /// Reads flash from Motorola S-record file
+    pub fn load_flash_srec(&mut self, filename: &str) -> Result<(), HexError> {
+        let image = parse_srec(&fs::read_to_string(filename)?)?;
+        self.load_image(&image)
+    }
+
+    /// Reads flash from a raw binary file, starting at address 0
+    pub fn load_flash_bin(&mut self, filename: &str) -> Result<(), HexError> {
+        let image = HexImage {
+            chunks: vec![(0, fs::read(filename)?)],
+            start: None,
+        };
+        self.load_image(&image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::mcu_model::Atmega2560;
+
+    use super::*;
+
+    #[test]
+    fn intel_hex() {
+        let image = parse_intel_hex("\
+            :040000000C947200EA\n\
+            :020000021000EC\n\
+            :020010000102EB\n\
+            :020000040001F9\n\
+            :02FFFE00FFCF33\n\
+            :0400000500000100F6\n\
+            :00000001FF\n\
+            :0200000001FF\n").unwrap();
+        assert_eq!(image.chunks, [
+            (0x00000, vec![0x0C, 0x94, 0x72, 0x00]),
+            (0x10010, vec![0x01, 0x02]),
+            (0x1FFFE, vec![0xFF, 0xCF]),
+        ]);
+        assert_eq!(image.start, Some(0x100));
+
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.load_image(&image).unwrap();
+        assert_eq!(mcu.flash[0x0000..0x0002], [0x940C, 0x0072]);
+        assert_eq!(mcu.flash[0x8008], 0x0201);
+        assert_eq!(mcu.flash[0xFFFF], 0xCFFF);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn intel_hex_errors() {
+        assert!(matches!(parse_intel_hex(":0400000"), Err(HexError::Syntax(1))));
+        assert!(matches!(parse_intel_hex("\n:0400000X0C947200EA"), Err(HexError::Syntax(2))));
+        assert!(matches!(parse_intel_hex(":040000000C947200EB"), Err(HexError::Checksum(1))));
+        assert!(matches!(parse_intel_hex(":0000000BF5"), Err(HexError::UnsupportedRecord(1, 0x0B))));
+
+        let image = parse_intel_hex(":020000040004F6\n:02000000FFCF30").unwrap();
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        assert!(matches!(mcu.load_image(&image), Err(HexError::OutOfFlash(0x40001))));
+        let image = parse_srec("S307FFFFFFFF0102F9").unwrap();
+        assert!(matches!(mcu.load_image(&image), Err(HexError::OutOfFlash(0xFFFFFFFF))));
+    }
+
+    #[test]
+    fn srec() {
+        let image = parse_srec("\
+            S00600004844521B\n\
+            S1070000FFCF0C948A\n\
+            S2060100120102E3\n\
+            S5030002FA\n\
+            S9030000FC\n").unwrap();
+        assert_eq!(image.chunks, [
+            (0x00000, vec![0xFF, 0xCF, 0x0C, 0x94]),
+            (0x10012, vec![0x01, 0x02]),
+        ]);
+        assert_eq!(image.start, Some(0));
+
+        assert!(matches!(parse_srec("S1070000FFCF0C948B"), Err(HexError::Checksum(1))));
+        assert!(matches!(parse_srec("S4030000FC"), Err(HexError::UnsupportedRecord(1, 4))));
+    }
+}
@@ -1,7 +1,6 @@
 use bitfield::Bit;
 
 use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
-use crate::components::avr::bit_helpers::{get_rd_fields, get_k8, get_d_field};
 
 use super::{Mcu};
 
@@ -17,8 +16,7 @@ where
         self.sreg.set_s(r.bit(7));
     }
 
-    pub fn instr_and(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_and(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd & rr;
@@ -30,9 +28,7 @@ where
         1
     }
 
-    pub fn instr_andi(&mut self, opcode: u16) -> u8 {
-        let k = get_k8(opcode);
-        let d = get_d_field(opcode, 4);
+    pub fn instr_andi(&mut self, d: u8, k: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd & k;
 
@@ -43,8 +39,7 @@ where
         1
     }
 
-    pub fn instr_or(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_or(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd | rr;
@@ -56,9 +51,7 @@ where
         1
     }
 
-    pub fn instr_ori(&mut self, opcode: u16) -> u8 {
-        let k = get_k8(opcode);
-        let d = get_d_field(opcode, 4);
+    pub fn instr_ori(&mut self, d: u8, k: u8) -> u8 {
         let rd = self.read_register(d);
         let result = rd | k;
 
@@ -69,8 +62,7 @@ where
         1
     }
 
-    pub fn instr_eor(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_eor(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
         let result = rd ^ rr;
@@ -82,8 +74,7 @@ where
         1
     }
 
-    pub fn instr_com(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_com(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = !rd;
 
@@ -96,8 +87,7 @@ where
         1
     }
 
-    pub fn instr_neg(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_neg(&mut self, d: u8) -> u8 {
         let rd = self.read_register(d);
         let result = 0x00u8.wrapping_sub(rd);
 
@@ -1,6 +1,6 @@
-use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, sreg::StatusRegister};
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, sreg::StatusRegister, fault::CpuFaultKind};
 
-use super::{Mcu, SRAM_END};
+use super::Mcu;
 
 impl<M, Io> Mcu<M, Io>
 where
@@ -11,28 +11,41 @@ where
         self.pc = val % M::flash_size() as u32;
     }
 
-    pub fn read_register(&self, i: u16) -> u8 {
+    pub fn read_register(&self, i: u8) -> u8 {
         assert!(i < 32);
         self.reg_file.regs[i as usize]
     }
 
-    pub fn write_register(&mut self, i: u16, val: u8) {
+    pub fn write_register(&mut self, i: u8, val: u8) {
         assert!(i < 32);
         self.reg_file.regs[i as usize] = val;
     }
 
-    pub fn read_register_pair(&self, i: u16) -> u16 {
+    pub fn read_register_pair(&self, i: u8) -> u16 {
         assert!(i < 32);
         self.reg_file.read_u16(i as usize)
     }
 
-    pub fn write_register_pair(&mut self, i: u16, val: u16) {
+    pub fn write_register_pair(&mut self, i: u8, val: u16) {
         assert!(i < 32);
         self.reg_file.write_u16(i as usize, val);
     }
 
     pub fn read_io(&self, i: u8) -> u8 {
+        let val = self.read_io_unwatched(i);
+        self.check_watchpoints(i as u16 + 0x20, false, val);
+        val
+    }
+
+    fn read_io_unwatched(&self, i: u8) -> u8 {
+        if let Some(val) = self.semihosting_read(i as u16 + 0x20) {
+            return val;
+        }
         match i {
+            0x37 => self.spm.read_spmcsr(),
+            0x35 if M::fuse_layout().boot_sizes.is_some() => {
+                self.mcucr | (self.ivsel as u8) << 1 | (self.ivce_cycles > 0) as u8
+            }
             0x00..=0x3A => self.io.read_internal_u8(i),
             0x3B => self.rampz,
             0x3C => self.eind,
@@ -42,49 +55,139 @@ where
                 let StatusRegister(x) = self.sreg;
                 x
             }
-            _ => panic!("Only 64 internal IO registers!")
+            _ => {
+                self.report_fault(CpuFaultKind::InvalidIoRegister(i));
+                0
+            }
         }
-        
     }
 
     pub fn write_io(&mut self, i: u8, val: u8) {
+        if let Some(log) = &mut self.write_log {
+            log.push((i as u16 + 0x20, val));
+        }
+        self.check_watchpoints(i as u16 + 0x20, true, val);
+        if self.semihosting_write(i as u16 + 0x20, val) {
+            return;
+        }
         match i {
+            0x37 => self.spm.write_spmcsr(val),
+            0x35 if M::fuse_layout().boot_sizes.is_some() => self.write_mcucr(val),
             0x00..=0x3A => self.io.write_internal_u8(i, val),
             0x3B => self.rampz = val & M::rampz_mask(),
             0x3C => self.eind = val & M::eind_mask(),
             0x3D => self.sp = self.sp & 0xFF00 | val as u16,
             0x3E => self.sp = self.sp & 0x00FF | (val as u16) << 8,
             0x3F => self.sreg = StatusRegister(val),
-            _ => panic!("Only 64 internal IO registers!")
+            _ => self.report_fault(CpuFaultKind::InvalidIoRegister(i)),
+        }
+    }
+
+    /// Writes MCUCR, moving interrupt vectors by the timed IVCE sequence.
+    fn write_mcucr(&mut self, val: u8) {
+        self.mcucr = val & 0xFC;
+        if val & 0x01 != 0 {
+            self.ivce_cycles = 4;
+        } else if self.ivce_cycles > 0 {
+            self.ivsel = val & 0x02 != 0;
+            self.ivce_cycles = 0;
         }
     }
 
     pub fn read_flash(&self, addr: u32) -> u16 {
-        self.flash[addr as usize]
+        if self.spm.rww_busy() && addr < M::nrww_start() {
+            // RWW section cannot be read while it is being programmed
+            return 0xFFFF;
+        }
+        match self.flash.get(addr as usize) {
+            Some(&val) => val,
+            None => {
+                self.report_fault(CpuFaultKind::PcOutOfFlash);
+                0xFFFF
+            }
+        }
     }
 
+    /// Writes a flash word, invalidating cached instructions that contain it.
     pub fn write_flash(&mut self, addr: u32, val: u16) {
-        self.flash[addr as usize] = val
+        let addr = addr as usize;
+        self.flash[addr] = val;
+        self.decoded[addr] = None;
+        if addr > 0 {
+            // The word may be the second word of a two-word instruction
+            self.decoded[addr - 1] = None;
+        }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
+        if (0x0020..=0x005F).contains(&addr) {
+            return self.read_io((addr - 0x20) as u8);
+        }
+        let val = self.read_unwatched(addr);
+        self.check_watchpoints(addr, false, val);
+        val
+    }
+
+    fn read_unwatched(&self, addr: u16) -> u8 {
+        if let Some(val) = self.semihosting_read(addr) {
+            return val;
+        }
         match addr {
-            0x0000..=0x001F => self.read_register(addr),
-            0x0020..=0x005F => self.read_io((addr - 0x20) as u8),
-            0x0060..=0x01FF => self.io.read_external_u8(addr),
-            0x0200..=SRAM_END => self.sram[addr as usize - 0x200],
+            0x0000..=0x001F => self.read_register(addr as u8),
+            _ if addr < M::sram_start() => self.io.read_external_u8(addr),
+            _ if addr <= M::sram_end() => self.sram[(addr - M::sram_start()) as usize],
+            _ if M::peripherals().xmem.is_some() => self.read_external(addr),
             _ => 0,
         }
     }
 
+    /// Reads external memory, remembering the address if its bus cycle hasn't been done yet.
+    fn read_external(&self, addr: u16) -> u8 {
+        if !self.io.xmem_enabled() {
+            return 0;
+        }
+        self.xmem_cycles.set(self.xmem_cycles.get() + self.io.xmem_access_cycles(addr));
+        match self.io.xmem_read_data(addr) {
+            Some(val) => val,
+            None => {
+                if self.xmem_miss.get().is_none() {
+                    self.xmem_miss.set(Some(addr));
+                }
+                0
+            }
+        }
+    }
+
+    /// Writes external memory, unless the instruction will be retried.
+    fn write_external(&mut self, addr: u16, val: u8) {
+        if !self.io.xmem_enabled() || self.xmem_miss.get().is_some() {
+            return;
+        }
+        self.xmem_cycles.set(self.xmem_cycles.get() + self.io.xmem_access_cycles(addr));
+        self.io.xmem_write(addr, val);
+    }
+
     pub fn write(&mut self, addr: u16, val: u8) {
+        if !(0x0020..=0x005F).contains(&addr) {
+            self.check_watchpoints(addr, true, val);
+        }
+        if self.semihosting_write(addr, val) {
+            // Nothing is stored, so it's not logged
+            return;
+        }
         match addr {
-            0x0000..=0x001F => self.write_register(addr, val),
+            0x0000..=0x001F => self.write_register(addr as u8, val),
             0x0020..=0x005F => self.write_io((addr - 0x20) as u8, val),
-            0x0060..=0x01FF => self.io.write_external_u8(addr, val),
-            0x0200..=SRAM_END => self.sram[addr as usize - 0x200] = val,
+            _ if addr < M::sram_start() => self.io.write_external_u8(addr, val),
+            _ if addr <= M::sram_end() => self.sram[(addr - M::sram_start()) as usize] = val,
+            _ if M::peripherals().xmem.is_some() => self.write_external(addr, val),
             _ => {},
         }
+        if addr >= 0x60 {
+            if let Some(log) = &mut self.write_log {
+                log.push((addr, val));
+            }
+        }
     }
 
     pub fn read_at_pc_offset(&self, x: u32) -> u16 {
@@ -95,7 +198,12 @@ where
         self.read(self.sp.wrapping_add(x as u16))
     }
     pub fn write_at_sp_offset(&mut self, x: i16, val: u8) {
-        self.write(self.sp.wrapping_add(x as u16), val)
+        let addr = self.sp.wrapping_add(x as u16);
+        if addr < M::sram_start() {
+            self.report_fault(CpuFaultKind::StackOverflow);
+            return;
+        }
+        self.write(addr, val)
     }
 
     pub fn rampz_address(&self, z: u16) -> u32 {
@@ -108,7 +216,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::components::avr::mcu_model::Atmega2560;
+    use crate::{components::{avr::{mcu_model::Atmega2560, io_controller::IoController}, sram::Sram}, component::Component, pins::PinId};
 
     use super::*;
 
@@ -169,4 +277,88 @@ mod tests {
         assert_eq!(mcu.rampz_address(z), 0x00125678_u32);
         assert_eq!(mcu.eind_address(z), 0x00345678_u32);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn flash_write_invalidates_cache() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.write_flash(0x0, 0xE012); // ldi r17, 0x02
+        mcu.write_flash(0x1, 0x940C); // jmp 0x000000
+        mcu.write_flash(0x2, 0x0000);
+
+        mcu.step();
+        mcu.step();
+        assert_eq!(mcu.read_register(17), 0x02);
+        assert_eq!(mcu.pc, 0x0000);
+
+        mcu.write_flash(0x0, 0xE013); // ldi r17, 0x03
+        mcu.write_flash(0x2, 0x0010); // jmp 0x000010
+        mcu.step();
+        mcu.step();
+        assert_eq!(mcu.read_register(17), 0x03);
+        assert_eq!(mcu.pc, 0x0010);
+    }
+
+    /// Runs the MCU connected to an external SRAM until PC reaches `pc`.
+    fn run_with_sram(mcu: &mut Mcu<Atmega2560, IoController<Atmega2560>>, sram: &mut Sram, pc: u32) {
+        let peripherals = Atmega2560::peripherals();
+        let wires: Vec<(PinId, PinId)> = (0..19)
+            .map(|pin| (pin, peripherals.pin_id(match pin {
+                0..=7 => ('A', pin as u8),
+                8..=15 => ('C', pin as u8 - 8),
+                16 => ('G', 2),
+                17 => ('G', 1),
+                _ => ('G', 0),
+            })))
+            .collect();
+        let mut ticks = 0;
+        for edge in 0..200 {
+            if edge % 2 == 0 {
+                mcu.io.clock_rising_edge();
+                if ticks == 0 {
+                    if mcu.pc == pc {
+                        return;
+                    }
+                    ticks = mcu.step();
+                }
+                ticks -= 1;
+                sram.clock_rising_edge();
+            } else {
+                mcu.io.clock_falling_edge();
+                sram.clock_falling_edge();
+            }
+            let mcu_changes = mcu.io.get_output_changes().to_vec();
+            let sram_changes = sram.get_output_changes().to_vec();
+            for (pin, state) in mcu_changes {
+                if let Some(&(sram_pin, _)) = wires.iter().find(|&&(_, mcu_pin)| mcu_pin == pin) {
+                    sram.set_pin(sram_pin, state);
+                }
+            }
+            for (pin, state) in sram_changes {
+                mcu.io.set_pin(wires[pin as usize].1, state);
+            }
+        }
+        panic!("PC hasn't reached 0x{:X}", pc);
+    }
+
+    #[test]
+    fn external_memory() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        let mut sram = Sram::new(0x8000);
+        sram.data_mut()[0x2346] = 0xC3;
+        mcu.load_flash(&[
+            0xE804,         // ldi r16, 0x84
+            0x9300, 0x0074, // sts XMCRA, r16 ; one wait state
+            0xE51A,         // ldi r17, 0x5A
+            0x9310, 0x2345, // sts 0x2345, r17
+            0x9120, 0x2345, // lds r18, 0x2345
+            0x9130, 0x2346, // lds r19, 0x2346
+        ]);
+
+        run_with_sram(&mut mcu, &mut sram, 10);
+        assert_eq!(sram.data()[0x2345], 0x5A);
+        assert_eq!(mcu.read_register(18), 0x5A);
+        assert_eq!(mcu.read_register(19), 0xC3);
+        // External accesses take two extra cycles
+        assert_eq!(mcu.cycles(), 1 + 2 + 1 + 4 + 4 + 4);
+    }
+}
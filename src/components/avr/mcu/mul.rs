@@ -1,6 +1,6 @@
 use bitfield::Bit;
 
-use crate::components::avr::{mcu_model::McuModel, bit_helpers::get_rd_fields, io_controller::IoControllerTrait};
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
 
 use super::{Mcu};
 
@@ -9,8 +9,7 @@ where
     M: McuModel + 'static,
     Io: IoControllerTrait,
 {
-    pub fn instr_mul(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_mul(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
@@ -24,8 +23,7 @@ where
         2
     }
 
-    pub fn instr_muls(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 4);
+    pub fn instr_muls(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
@@ -41,8 +39,7 @@ where
         2
     }
 
-    pub fn instr_mulsu(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 3);
+    pub fn instr_mulsu(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
@@ -58,8 +55,7 @@ where
         2
     }
 
-    pub fn instr_fmul(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 3);
+    pub fn instr_fmul(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
@@ -73,8 +69,7 @@ where
         2
     }
 
-    pub fn instr_fmuls(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 3);
+    pub fn instr_fmuls(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
@@ -90,8 +85,7 @@ where
         2
     }
 
-    pub fn instr_fmulsu(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 3);
+    pub fn instr_fmulsu(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
         let rd = self.read_register(d);
 
@@ -0,0 +1,218 @@
+use std::{cell::Cell, io::{self, Write}};
+
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
+
+use super::Mcu;
+
+/// Command printing NUM as an unsigned decimal number.
+pub const PRINT_UNSIGNED: u8 = 0x01;
+/// Command printing NUM as a signed decimal number.
+pub const PRINT_SIGNED: u8 = 0x02;
+/// Command printing NUM as a hexadecimal number.
+pub const PRINT_HEX: u8 = 0x03;
+/// Command ending the simulation with NUM as the exit code.
+pub const EXIT: u8 = 0x04;
+
+/// A channel for firmware to talk to the host, through 6 reserved data addresses.
+///
+/// Registers, relative to the base address:
+/// - `+0` DATA: writing prints a character, reading takes the next byte of the host input (0 if there is none).
+/// - `+1` COMMAND: writing runs a command with NUM as its argument,
+///   reading returns the number of unread input bytes (up to 255).
+/// - `+2..=+5` NUM: 32-bit little-endian command argument.
+///
+/// The base address has to be unused by the model, accesses to the registers don't reach the IO controller or SRAM.
+pub struct Semihosting {
+    base: u16,
+    console: Box<dyn Write + Send>,
+    input: Vec<u8>,
+    /// Next unread byte of `input`.
+    input_pos: Cell<usize>,
+    number: u32,
+    exit_code: Option<i32>,
+}
+
+impl Semihosting {
+    pub fn new(base: u16, console: Box<dyn Write + Send>) -> Semihosting {
+        assert!(base <= 0xFFFA, "Semihosting registers don't fit into the data address space");
+        Semihosting {
+            base,
+            console,
+            input: Vec::new(),
+            input_pos: Cell::new(0),
+            number: 0,
+            exit_code: None,
+        }
+    }
+
+    /// Creates a semihosting channel printing into the standard output.
+    pub fn stdout(base: u16) -> Semihosting {
+        Semihosting::new(base, Box::new(io::stdout()))
+    }
+
+    /// Appends data (like test vectors) to the input read by firmware.
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend_from_slice(data);
+    }
+
+    /// Returns the register offset of a data address, if it's a semihosting register.
+    #[inline]
+    fn offset(&self, addr: u16) -> Option<u16> {
+        addr.checked_sub(self.base).filter(|&offset| offset < 6)
+    }
+
+    fn read(&self, offset: u16) -> u8 {
+        let pos = self.input_pos.get();
+        match offset {
+            0 => match self.input.get(pos) {
+                Some(&val) => {
+                    self.input_pos.set(pos + 1);
+                    val
+                }
+                None => 0,
+            },
+            1 => (self.input.len() - pos).min(255) as u8,
+            _ => self.number.to_le_bytes()[offset as usize - 2],
+        }
+    }
+
+    /// Reads a register without taking an input byte.
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            0 => self.input.get(self.input_pos.get()).copied().unwrap_or(0),
+            _ => self.read(offset),
+        }
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        // Console errors can't be reported to firmware, so they are ignored
+        match offset {
+            0 => {
+                let _ = self.console.write_all(&[val]);
+                if val == b'\n' {
+                    let _ = self.console.flush();
+                }
+            }
+            1 => match val {
+                PRINT_UNSIGNED => {
+                    let _ = write!(self.console, "{}", self.number);
+                }
+                PRINT_SIGNED => {
+                    let _ = write!(self.console, "{}", self.number as i32);
+                }
+                PRINT_HEX => {
+                    let _ = write!(self.console, "0x{:X}", self.number);
+                }
+                EXIT => {
+                    let _ = self.console.flush();
+                    self.exit_code = Some(self.number as i32);
+                }
+                _ => {}
+            },
+            _ => {
+                let shift = (offset - 2) * 8;
+                self.number = self.number & !(0xFF << shift) | (val as u32) << shift;
+            }
+        }
+    }
+}
+
+impl<M, Io> Mcu<M, Io>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    /// Enables a semihosting channel, replacing the previous one.
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    /// Takes an exit code requested by firmware through semihosting, returning it only once.
+    pub fn take_exit_code(&mut self) -> Option<i32> {
+        self.semihosting.as_mut().and_then(|s| s.exit_code.take())
+    }
+
+    /// Reads a semihosting register at data address `addr`, if there is one.
+    #[inline]
+    pub(super) fn semihosting_read(&self, addr: u16) -> Option<u8> {
+        let semihosting = self.semihosting.as_ref()?;
+        semihosting.offset(addr).map(|offset| semihosting.read(offset))
+    }
+
+    /// Reads a semihosting register at data address `addr` without side effects, if there is one.
+    #[inline]
+    pub(super) fn semihosting_peek(&self, addr: u16) -> Option<u8> {
+        let semihosting = self.semihosting.as_ref()?;
+        semihosting.offset(addr).map(|offset| semihosting.peek(offset))
+    }
+
+    /// Writes a semihosting register at data address `addr`, returns `false` if there is none.
+    #[inline]
+    pub(super) fn semihosting_write(&mut self, addr: u16, val: u8) -> bool {
+        let Some(semihosting) = &mut self.semihosting else {
+            return false;
+        };
+        let Some(offset) = semihosting.offset(addr) else {
+            return false;
+        };
+        semihosting.write(offset, val);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::{avr::mcu_model::Atmega328P, test_helper::CaptureWriter};
+
+    use super::*;
+
+    #[test]
+    fn print_and_exit() {
+        let console = CaptureWriter::default();
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        let mut semihosting = Semihosting::new(0xF8, Box::new(console.clone()));
+        semihosting.push_input(&[0x12, 0x34]);
+        mcu.enable_semihosting(semihosting);
+        mcu.load_flash(&[
+            0xE40F, // ldi r16, 'O'
+            0x9300, 0x00F8, // sts DATA, r16
+            0xEF1E, // ldi r17, 0xFE
+            0x9310, 0x00FA, // sts NUM, r17
+            0xEF1F, // ldi r17, 0xFF
+            0x9310, 0x00FB, // sts NUM+1, r17
+            0x9310, 0x00FC, // sts NUM+2, r17
+            0x9310, 0x00FD, // sts NUM+3, r17
+            0xE022, // ldi r18, PRINT_SIGNED
+            0x9320, 0x00F9, // sts COMMAND, r18
+            0x9100, 0x00F9, // lds r16, COMMAND
+            0x9110, 0x00F8, // lds r17, DATA
+            0xE024, // ldi r18, EXIT
+            0x9320, 0x00F9, // sts COMMAND, r18
+        ]);
+        for _ in 0..13 {
+            mcu.step();
+        }
+        assert_eq!(mcu.take_exit_code(), None);
+        mcu.step();
+        assert_eq!(mcu.take_exit_code(), Some(-2));
+        assert_eq!(mcu.take_exit_code(), None);
+        assert_eq!(console.contents(), b"O-2");
+        assert_eq!(mcu.read_register(16), 2);
+        assert_eq!(mcu.read_register(17), 0x12);
+        assert_eq!(mcu.io.take_unmodeled_accesses(), []);
+    }
+
+    #[test]
+    fn peek_keeps_input() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        let mut semihosting = Semihosting::new(0xF8, Box::new(io::sink()));
+        semihosting.push_input(&[0x12, 0x34]);
+        mcu.enable_semihosting(semihosting);
+        assert_eq!(mcu.peek(0xF8), 0x12);
+        assert_eq!(mcu.peek(0xF8), 0x12);
+        assert_eq!(mcu.peek(0xF9), 2);
+        assert_eq!(mcu.read(0xF8), 0x12);
+        assert_eq!(mcu.peek(0xF8), 0x34);
+        assert_eq!(mcu.peek(0xF9), 1);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, sreg::StatusRegister, fault::{CpuFault, CpuFaultKind}, snapshot::{StateWriter, StateReader, SnapshotError}};
+
+use super::{Mcu, CpuState};
+
+fn write_fault_kind(w: &mut StateWriter, kind: CpuFaultKind) {
+    match kind {
+        CpuFaultKind::IllegalOpcode => w.write_u8(0),
+        CpuFaultKind::PcOutOfFlash => w.write_u8(1),
+        CpuFaultKind::StackOverflow => w.write_u8(2),
+        CpuFaultKind::InvalidIoRegister(i) => {
+            w.write_u8(3);
+            w.write_u8(i);
+        }
+        CpuFaultKind::LockBitViolation => w.write_u8(4),
+    }
+}
+
+fn read_fault_kind(r: &mut StateReader) -> Result<CpuFaultKind, SnapshotError> {
+    match r.read_u8()? {
+        0 => Ok(CpuFaultKind::IllegalOpcode),
+        1 => Ok(CpuFaultKind::PcOutOfFlash),
+        2 => Ok(CpuFaultKind::StackOverflow),
+        3 => Ok(CpuFaultKind::InvalidIoRegister(r.read_u8()?)),
+        4 => Ok(CpuFaultKind::LockBitViolation),
+        _ => Err(SnapshotError::Format("invalid CPU fault")),
+    }
+}
+
+impl<M, Io> Mcu<M, Io>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    /// Writes the complete MCU state (CPU core, memories and all peripherals) into a snapshot.
+    ///
+    /// Breakpoints and watchpoints are debugger settings and are not saved.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.reg_file.regs);
+        w.write_u32(self.sram.len() as u32);
+        w.write_bytes(&self.sram);
+        w.write_u32(self.flash.len() as u32);
+        for &x in self.flash.iter() {
+            w.write_u16(x);
+        }
+        w.write_u32(self.eeprom.len() as u32);
+        w.write_bytes(&self.eeprom);
+
+        w.write_u32(self.pc);
+        w.write_u16(self.sp);
+        w.write_u8(self.sreg.0);
+        w.write_u8(self.rampz);
+        w.write_u8(self.eind);
+
+        self.spm.save_state(w);
+        match self.state {
+            CpuState::Running => w.write_u8(0),
+            CpuState::Sleeping(_) => w.write_u8(1),
+            CpuState::Halted => w.write_u8(2),
+            CpuState::Faulted(fault) => {
+                w.write_u8(3);
+                write_fault_kind(w, fault.kind);
+                w.write_u32(fault.pc);
+                w.write_u16(fault.opcode);
+                w.write_u64(fault.cycle);
+            }
+        }
+        w.write_bool(self.interrupt_inhibit);
+        w.write_u8(self.xmem_stall);
+        w.write_bytes(&[self.fuses.low, self.fuses.high, self.fuses.extended, self.fuses.lock]);
+        w.write_u8(self.mcucr);
+        w.write_bool(self.ivsel);
+        w.write_u8(self.ivce_cycles);
+        w.write_u64(self.cycles);
+
+        self.io.save_state(w);
+    }
+
+    /// Restores the complete MCU state from a snapshot made by [Mcu::save_state].
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        r.read_bytes(&mut self.reg_file.regs)?;
+        if r.read_u32()? as usize != self.sram.len() {
+            return Err(SnapshotError::Format("SRAM size doesn't match the MCU model"));
+        }
+        r.read_bytes(&mut self.sram)?;
+        if r.read_u32()? as usize != self.flash.len() {
+            return Err(SnapshotError::Format("flash size doesn't match the MCU model"));
+        }
+        for x in self.flash.iter_mut() {
+            *x = r.read_u16()?;
+        }
+        self.decoded.fill(None);
+        if r.read_u32()? as usize != self.eeprom.len() {
+            return Err(SnapshotError::Format("EEPROM size doesn't match the MCU model"));
+        }
+        r.read_bytes(&mut self.eeprom)?;
+
+        self.set_pc(r.read_u32()?);
+        self.sp = r.read_u16()?;
+        self.sreg = StatusRegister(r.read_u8()?);
+        self.rampz = r.read_u8()? & M::rampz_mask();
+        self.eind = r.read_u8()? & M::eind_mask();
+
+        self.spm.load_state(r)?;
+        let state = r.read_u8()?;
+        let fault = if state == 3 {
+            Some(CpuFault {
+                kind: read_fault_kind(r)?,
+                pc: r.read_u32()?,
+                opcode: r.read_u16()?,
+                cycle: r.read_u64()?,
+            })
+        } else {
+            None
+        };
+        self.interrupt_inhibit = r.read_bool()?;
+        self.xmem_stall = r.read_u8()?;
+        let mut fuses = [0; 4];
+        r.read_bytes(&mut fuses)?;
+        [self.fuses.low, self.fuses.high, self.fuses.extended, self.fuses.lock] = fuses;
+        self.mcucr = r.read_u8()?;
+        self.ivsel = r.read_bool()?;
+        self.ivce_cycles = r.read_u8()?;
+        self.cycles = r.read_u64()?;
+
+        self.io.load_state(r)?;
+        // Sleep mode is restored together with the IO controller
+        self.state = match (state, fault) {
+            (0, _) => CpuState::Running,
+            (1, _) => CpuState::Sleeping(self.io.sleep_mode().ok_or(SnapshotError::Format("sleep is not enabled"))?),
+            (2, _) => CpuState::Halted,
+            (3, Some(fault)) => CpuState::Faulted(fault),
+            _ => return Err(SnapshotError::Format("invalid CPU state")),
+        };
+        self.pending_fault.set(None);
+        self.fault = None;
+        self.watch_hit.set(None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::mcu_model::Atmega2560;
+
+    use super::*;
+
+    fn save(mcu: &Mcu<Atmega2560, impl IoControllerTrait>) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        mcu.save_state(&mut w);
+        w.into_inner()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.load_flash(&[
+            0xE50A, // ldi r16, 0x5A
+            0xE012, // ldi r17, 0x02
+            0x930F, // push r16
+            0xB904, // out DDRB, r16
+            0xB905, // out PORTB, r16
+            0x9310, 0x0081, // sts TCCR1B, r17
+            0x9408, // sec
+            0xCFFF, // rjmp .-2
+        ]);
+        mcu.write_eeprom(0x10, 0x42);
+        for _ in 0..7 {
+            mcu.step();
+            mcu.io.clock_rising_edge();
+        }
+        let data = save(&mcu);
+
+        let mut restored: Mcu<Atmega2560, _> = Mcu::default();
+        let mut r = StateReader::new(data.clone());
+        restored.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(save(&restored), data);
+        assert_eq!(restored.pc(), 8);
+        assert_eq!(restored.read_eeprom(0x10), Some(0x42));
+        assert_eq!(restored.io.read_external_u8(0x81), 0x02);
+
+        for _ in 0..100 {
+            mcu.step();
+            mcu.io.clock_rising_edge();
+            restored.step();
+            restored.io.clock_rising_edge();
+        }
+        assert_eq!(save(&restored), save(&mcu));
+    }
+
+    #[test]
+    fn truncated() {
+        let mcu: Mcu<Atmega2560, _> = Mcu::default();
+        let data = save(&mcu);
+        let mut restored: Mcu<Atmega2560, _> = Mcu::default();
+        let result = restored.load_state(&mut StateReader::new(data[..data.len() - 1].to_vec()));
+        assert!(matches!(result, Err(SnapshotError::Format(_))));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt::Write as _, fs::File, io::{self, BufWriter, Write}, ops::Range};
+
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, disasm::disassemble_at};
+
+use super::{Mcu, CpuState};
+
+const SREG_FLAGS: &[u8; 8] = b"ITHSVNZC";
+
+/// Selects which executed instructions are written into a trace.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// Byte addresses of traced instructions, everything if `None`.
+    pub pc_range: Option<Range<u32>>,
+    /// CPU cycle window of traced instructions, everything if `None`.
+    pub cycles: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    #[inline]
+    fn accepts(&self, pc: u32, cycle: u64) -> bool {
+        self.pc_range.as_ref().is_none_or(|r| r.contains(&pc)) &&
+        self.cycles.as_ref().is_none_or(|r| r.contains(&cycle))
+    }
+}
+
+/// Instruction-level execution trace writer.
+///
+/// Every traced instruction is a single text line with the cycle, byte address, opcode and disassembly,
+/// followed by the changed registers, SREG, SP and memory writes (`[addr]=val`, data address space).
+///
+/// The first write error stops the trace without stopping the simulation, it is returned by [Tracer::finish].
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    filter: TraceFilter,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write + Send>, filter: TraceFilter) -> Tracer {
+        Tracer { writer, filter, error: None }
+    }
+
+    /// Creates a tracer writing into a file.
+    pub fn create(path: &str, filter: TraceFilter) -> io::Result<Tracer> {
+        let f = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(f)), filter))
+    }
+
+    /// Gets the error which has stopped the trace.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Flushes the trace, returning the first write error.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+fn format_sreg(sreg: u8) -> String {
+    SREG_FLAGS.iter().enumerate()
+        .map(|(i, &c)| if sreg & (0x80 >> i) != 0 {c as char} else {'-'})
+        .collect()
+}
+
+impl<M, Io> Mcu<M, Io>
+where
+    M: McuModel + 'static,
+    Io: IoControllerTrait,
+{
+    /// Executes one step like [Mcu::step], writing it into a trace if it executes an instruction or an interrupt.
+    pub fn step_traced(&mut self, tracer: &mut Tracer) -> u8 {
+        let pc = self.pc;
+        let cycle = self.cycles;
+        if self.state != CpuState::Running || self.spm.cpu_halted() || tracer.error.is_some() || !tracer.filter.accepts(pc << 1, cycle) {
+            return self.step();
+        }
+
+        let regs = self.reg_file.regs;
+        let sreg = self.sreg.0;
+        let sp = self.sp;
+        self.write_log = Some(Vec::new());
+        self.served_interrupt = None;
+
+        let cycles = self.step();
+
+        let writes = self.write_log.take().unwrap_or_default();
+        if self.xmem_stalled() {
+            // The instruction is logged when it's retried
+            return cycles;
+        }
+        let mut line = match self.served_interrupt.take() {
+            Some(vector) => format!("{:>10} {:5X}:  interrupt {}", cycle, pc << 1, vector),
+            None => format!("{:>10} {}", cycle, disassemble_at::<M>(&self.flash, pc)),
+        };
+        while line.len() < 64 {
+            line.push(' ');
+        }
+        for (i, (&old, &new)) in regs.iter().zip(self.reg_file.regs.iter()).enumerate() {
+            if old != new {
+                write!(line, " r{}={:02X}", i, new).unwrap();
+            }
+        }
+        if sreg != self.sreg.0 {
+            write!(line, " SREG={}", format_sreg(self.sreg.0)).unwrap();
+        }
+        if sp != self.sp {
+            write!(line, " SP={:04X}", self.sp).unwrap();
+        }
+        for (addr, val) in writes {
+            write!(line, " [{:04X}]={:02X}", addr, val).unwrap();
+        }
+        if let Err(e) = writeln!(tracer.writer, "{}", line.trim_end()) {
+            tracer.error = Some(e);
+        }
+
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::{avr::mcu_model::Atmega2560, test_helper::CaptureWriter};
+
+    use super::*;
+
+    fn run(filter: TraceFilter, steps: usize) -> Vec<String> {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.load_flash(&[
+            0xE50A, // ldi r16, 0x5A
+            0x930F, // push r16
+            0x9300, 0x0300, // sts 0x0300, r16
+            0x9408, // sec
+            0xCFFF, // rjmp .-2
+        ]);
+        let buffer = CaptureWriter::default();
+        let mut tracer = Tracer::new(Box::new(buffer.clone()), filter);
+        for _ in 0..steps {
+            mcu.step_traced(&mut tracer);
+        }
+        let output = String::from_utf8(buffer.contents()).unwrap();
+        output.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn changes() {
+        let lines = run(TraceFilter::default(), 6);
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("         0     0:  0A E5          ldi r16, 0x5A"));
+        assert!(lines[0].ends_with(" r16=5A"));
+        assert!(lines[1].ends_with(" SP=21FE [21FF]=5A"));
+        assert!(lines[2].starts_with("         3     4:  00 93 00 03    sts 0x0300, r16"));
+        assert!(lines[2].ends_with(" [0300]=5A"));
+        assert!(lines[3].ends_with(" SREG=-------C"));
+        assert!(lines[4].ends_with("rjmp 0xA"));
+    }
+
+    #[test]
+    fn filters() {
+        let lines = run(TraceFilter {pc_range: Some(0x2..0x8), cycles: None}, 6);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("push r16"));
+        assert!(lines[1].contains("sts 0x0300, r16"));
+
+        let lines = run(TraceFilter {pc_range: None, cycles: Some(5..9)}, 8);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("sec"));
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_error() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.load_flash(&[0x9408, 0xCFFF]); // sec, rjmp .-2
+        let mut tracer = Tracer::new(Box::new(FullDisk), TraceFilter::default());
+        for _ in 0..4 {
+            mcu.step_traced(&mut tracer);
+        }
+        assert_eq!(mcu.pc, 1);
+        assert_eq!(tracer.error().unwrap().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(tracer.finish().unwrap_err().kind(), io::ErrorKind::StorageFull);
+    }
+}
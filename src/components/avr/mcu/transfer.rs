@@ -1,21 +1,18 @@
 use bitfield::Bit;
 
-use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait};
-use crate::components::avr::bit_helpers::{get_rd_fields, bit_field_combined, get_k8, get_d_field, get_io6};
+use crate::components::avr::{mcu_model::McuModel, io_controller::IoControllerTrait, spm_controller::SpmCommand, fault::CpuFaultKind};
+use crate::components::avr::instruction::{Pointer, PointerMode};
 
 use super::{Mcu};
 
-const X_REG: u16 = 26;
-const Y_REG: u16 = 28;
-const Z_REG: u16 = 30;
+const Z_REG: u8 = 30;
 
 impl<M, Io> Mcu<M, Io>
 where
     M: McuModel + 'static,
     Io: IoControllerTrait,
 {
-    pub fn instr_mov(&mut self, opcode: u16) -> u8 {
-        let (r, d) = get_rd_fields(opcode, 5);
+    pub fn instr_mov(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register(r);
 
         self.write_register(d, rr);
@@ -24,11 +21,7 @@ where
         1
     }
 
-    pub fn instr_movw(&mut self, opcode: u16) -> u8 {
-        let r = 
-            bit_field_combined(opcode, &[3..=0]) << 1;
-        let d =
-            bit_field_combined(opcode, &[7..=4]) << 1;
+    pub fn instr_movw(&mut self, d: u8, r: u8) -> u8 {
         let rr = self.read_register_pair(r);
 
         self.write_register_pair(d, rr);
@@ -37,64 +30,29 @@ where
         1
     }
 
-    pub fn instr_ldi(&mut self, opcode: u16) -> u8 {
-        let k = get_k8(opcode);
-        let d = get_d_field(opcode, 4);
-
+    pub fn instr_ldi(&mut self, d: u8, k: u8) -> u8 {
         self.write_register(d, k);
         self.pc += 1;
 
         1
     }
 
-    pub fn instr_ld(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-        
-        match bit_field_combined(opcode, &[3..=0]) {
-            0b0001 => {
-                let addr = self.read_register_pair(Z_REG);
-                let val = self.read(addr);
-                self.write_register(d, val);
-                self.write_register_pair(Z_REG, addr + 1);
-            }
-            0b0010 => {
-                let addr = self.read_register_pair(Z_REG);
-                let val = self.read(addr - 1);
-                self.write_register(d, val);
-                self.write_register_pair(Z_REG, addr - 1);
-            }
-
-            0b1001 => {
-                let addr = self.read_register_pair(Y_REG);
-                let val = self.read(addr);
-                self.write_register(d, val);
-                self.write_register_pair(Y_REG, addr + 1);
-            }
-            0b1010 => {
-                let addr = self.read_register_pair(Y_REG);
-                let val = self.read(addr - 1);
-                self.write_register(d, val);
-                self.write_register_pair(Y_REG, addr - 1);
-            }
+    /// Returns the address of an indirect access and the new pointer register value.
+    fn pointer_access(&self, ptr: Pointer, mode: PointerMode) -> (u16, u16) {
+        let p = self.read_register_pair(ptr.register());
+        match mode {
+            PointerMode::Unchanged => (p, p),
+            PointerMode::PostIncrement => (p, p.wrapping_add(1)),
+            PointerMode::PreDecrement => (p.wrapping_sub(1), p.wrapping_sub(1)),
+        }
+    }
 
-            0b1100 => {
-                let addr = self.read_register_pair(X_REG);
-                let val = self.read(addr);
-                self.write_register(d, val);
-            }
-            0b1101 => {
-                let addr = self.read_register_pair(X_REG);
-                let val = self.read(addr);
-                self.write_register(d, val);
-                self.write_register_pair(X_REG, addr + 1);
-            }
-            0b1110 => {
-                let addr = self.read_register_pair(X_REG);
-                let val = self.read(addr-1);
-                self.write_register(d, val);
-                self.write_register_pair(X_REG, addr - 1);
-            }
-            _ => panic!("Invalid LD instruction")
+    pub fn instr_ld(&mut self, d: u8, ptr: Pointer, mode: PointerMode) -> u8 {
+        let (addr, new_ptr) = self.pointer_access(ptr, mode);
+        let val = self.read(addr);
+        self.write_register(d, val);
+        if mode != PointerMode::Unchanged {
+            self.write_register_pair(ptr.register(), new_ptr);
         }
 
         self.pc += 1;
@@ -102,18 +60,10 @@ where
         2
     }
 
-    pub fn instr_ldd(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-        let q =
-            bit_field_combined(opcode, &[13..=13, 11..=10, 2..=0]);
-
-        let addr_base = if opcode.bit(3) {
-            self.read_register_pair(Y_REG)
-        } else {
-            self.read_register_pair(Z_REG)
-        };
+    pub fn instr_ldd(&mut self, d: u8, ptr: Pointer, q: u8) -> u8 {
+        let addr_base = self.read_register_pair(ptr.register());
 
-        let addr = addr_base.wrapping_add(q);
+        let addr = addr_base.wrapping_add(q as u16);
         let val = self.read(addr);
         self.write_register(d, val);
         self.pc += 1;
@@ -121,62 +71,20 @@ where
         2
     }
 
-    pub fn instr_lds(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-
-        let addr = self.read_at_pc_offset(1);
-
-        let val = self.read(addr);
+    pub fn instr_lds(&mut self, d: u8, k: u16) -> u8 {
+        let val = self.read(k);
         self.write_register(d, val);
         self.pc += 2;
 
         2
     }
 
-    pub fn instr_st(&mut self, opcode: u16) -> u8 {
-        
-
-        let d = get_d_field(opcode, 5);
-        let val = self.read_register(d);
-        
-        match bit_field_combined(opcode, &[3..=0]) {
-            0b0001 => {
-                let addr = self.read_register_pair(Z_REG);
-                self.write(addr, val);
-                self.write_register_pair(Z_REG, addr+1);
-            }
-            0b0010 => {
-                let addr = self.read_register_pair(Z_REG);
-                self.write(addr - 1, val);
-                self.write_register_pair(Z_REG, addr-1);
-            }
-
-            0b1001 => {
-                let addr = self.read_register_pair(Y_REG);
-                self.write(addr, val);
-                self.write_register_pair(Y_REG, addr+1);
-            }
-            0b1010 => {
-                let addr = self.read_register_pair(Y_REG);
-                self.write(addr - 1, val);
-                self.write_register_pair(Y_REG, addr-1);
-            }
-
-            0b1100 => {
-                let addr = self.read_register_pair(X_REG);
-                self.write(addr, val);
-            }
-            0b1101 => {
-                let addr = self.read_register_pair(X_REG);
-                self.write(addr, val);
-                self.write_register_pair(X_REG, addr+1);
-            }
-            0b1110 => {
-                let addr = self.read_register_pair(X_REG);
-                self.write(addr - 1, val);
-                self.write_register_pair(X_REG, addr-1);
-            }
-            _ => panic!("Invalid LD instruction")
+    pub fn instr_st(&mut self, ptr: Pointer, mode: PointerMode, r: u8) -> u8 {
+        let val = self.read_register(r);
+        let (addr, new_ptr) = self.pointer_access(ptr, mode);
+        self.write(addr, val);
+        if mode != PointerMode::Unchanged {
+            self.write_register_pair(ptr.register(), new_ptr);
         }
 
         self.pc += 1;
@@ -184,65 +92,53 @@ where
         2
     }
 
-    pub fn instr_std(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-        let q =
-            bit_field_combined(opcode, &[13..=13, 11..=10, 2..=0]);
-
-        let addr_base = if opcode.bit(3) {
-            self.read_register_pair(Y_REG)
-        } else {
-            self.read_register_pair(Z_REG)
-        };
+    pub fn instr_std(&mut self, ptr: Pointer, q: u8, r: u8) -> u8 {
+        let addr_base = self.read_register_pair(ptr.register());
 
-        let addr = addr_base.wrapping_add(q);
-        let val = self.read_register(d);
+        let addr = addr_base.wrapping_add(q as u16);
+        let val = self.read_register(r);
         self.write(addr, val);
         self.pc += 1;
 
         2
     }
 
-    pub fn instr_sts(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-
-        let addr = self.read_at_pc_offset(1);
-
-        let val = self.read_register(d);
-        self.write(addr, val);
+    pub fn instr_sts(&mut self, k: u16, r: u8) -> u8 {
+        let val = self.read_register(r);
+        self.write(k, val);
         self.pc += 2;
 
         2
     }
 
-    pub fn instr_lpm(&mut self, opcode: u16) -> u8 {
-        let d = if opcode == 0x95C8 {0} else {get_d_field(opcode, 5)};
-
+    pub fn instr_lpm(&mut self, d: u8, post_increment: Option<bool>) -> u8 {
         let addr = self.read_register_pair(Z_REG);
+        let flash_addr = Self::program_memory_address(addr as u32);
+        self.check_lpm_lock(flash_addr);
 
-        let val = self.read_flash(addr as u32 >> 1);
+        let val = self.read_flash(flash_addr >> 1);
 
         let val = if addr.bit(0) {(val >> 8) as u8} else {val as u8};
         self.write_register(d, val);
-        if opcode & 0x000F == 0x5 {
-            self.write_register_pair(Z_REG, addr + 1);
+        if post_increment == Some(true) {
+            self.write_register_pair(Z_REG, addr.wrapping_add(1));
         }
         self.pc += 1;
 
         3
     }
 
-    pub fn instr_elpm(&mut self, opcode: u16) -> u8 {
-        let d = if opcode == 0x95D8 {0} else {get_d_field(opcode, 5)};
-
+    pub fn instr_elpm(&mut self, d: u8, post_increment: Option<bool>) -> u8 {
         let z = self.read_register_pair(Z_REG);
-        let addr =self.rampz_address(z);
+        let addr = self.rampz_address(z);
+        let flash_addr = Self::program_memory_address(addr);
+        self.check_lpm_lock(flash_addr);
 
-        let val = self.read_flash(addr as u32 >> 1);
+        let val = self.read_flash(flash_addr >> 1);
 
         let val = if addr.bit(0) {(val >> 8) as u8} else {val as u8};
         self.write_register(d, val);
-        if opcode & 0x000F == 0x7 {
+        if post_increment == Some(true) {
             self.write_register_pair(Z_REG, (addr + 1) as u16);
             self.rampz = ((addr + 1) >> 16) as u8;
         }
@@ -251,39 +147,85 @@ where
         3
     }
 
-    pub fn instr_spm(&mut self, _opcode: u16) -> u8 {
-        todo!()
+    /// Wraps an LPM or ELPM byte address to the flash size, address bits above it are ignored.
+    #[inline]
+    fn program_memory_address(addr: u32) -> u32 {
+        addr % (M::flash_size() as u32 * 2)
+    }
+
+    /// Reports a fault if lock bits don't allow LPM at PC to read byte address `addr`.
+    fn check_lpm_lock(&self, addr: u32) {
+        if !self.fuses.lpm_allowed(self.pc, addr) {
+            self.report_fault(CpuFaultKind::LockBitViolation);
+        }
+    }
+
+    pub fn instr_spm(&mut self) -> u8 {
+        let z = self.read_register_pair(Z_REG);
+        // Address bits above the flash size are ignored
+        let addr = (self.rampz_address(z) >> 1) % M::flash_size() as u32;
+        let page_start = addr & !(M::flash_page_size() as u32 - 1);
+
+        let command = if self.fuses.spm_executable(self.pc) {self.spm.command()} else {SpmCommand::None};
+        let command = match command {
+            SpmCommand::PageErase | SpmCommand::PageWrite if !self.fuses.spm_allowed(page_start) => {
+                self.report_fault(CpuFaultKind::LockBitViolation);
+                SpmCommand::None
+            }
+            command => command,
+        };
+        match command {
+            SpmCommand::None => {}
+            SpmCommand::FillBuffer => {
+                let val = self.read_register_pair(0);
+                self.spm.fill_buffer(addr, val);
+            }
+            SpmCommand::PageErase => {
+                for i in 0..M::flash_page_size() as u32 {
+                    self.write_flash(page_start + i, 0xFFFF);
+                }
+                self.spm.start_page_operation(page_start >= M::nrww_start());
+            }
+            SpmCommand::PageWrite => {
+                // Programming can only clear bits, an erase is needed to set them
+                let buffer = self.spm.take_buffer();
+                for (i, val) in buffer.into_iter().enumerate() {
+                    let word_addr = page_start + i as u32;
+                    let old = self.flash[word_addr as usize];
+                    self.write_flash(word_addr, old & val);
+                }
+                self.spm.start_page_operation(page_start >= M::nrww_start());
+            }
+            SpmCommand::RwwEnable => self.spm.enable_rww(),
+        }
+
+        self.pc += 1;
+        1
     }
 
-    pub fn instr_in(&mut self, opcode: u16) -> u8 {
-        let io = get_io6(opcode);
-        let d = get_d_field(opcode, 5);
-        let val = self.read_io(io);
+    pub fn instr_in(&mut self, d: u8, a: u8) -> u8 {
+        let val = self.read_io(a);
         self.write_register(d, val);
         self.pc += 1;
         1
     }
 
-    pub fn instr_out(&mut self, opcode: u16) -> u8 {
-        let io = get_io6(opcode);
-        let d = get_d_field(opcode, 5);
-        let val = self.read_register(d);
-        self.write_io(io, val);
+    pub fn instr_out(&mut self, a: u8, r: u8) -> u8 {
+        let val = self.read_register(r);
+        self.write_io(a, val);
         self.pc += 1;
         1
     }
 
-    pub fn instr_push(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
-        let val = self.read_register(d);
+    pub fn instr_push(&mut self, r: u8) -> u8 {
+        let val = self.read_register(r);
         self.write_at_sp_offset(0, val);
         self.sp -= 1;
         self.pc += 1;
         2
     }
 
-    pub fn instr_pop(&mut self, opcode: u16) -> u8 {
-        let d = get_d_field(opcode, 5);
+    pub fn instr_pop(&mut self, d: u8) -> u8 {
         self.sp += 1;
         let val = self.read_at_sp_offset(0);
         self.write_register(d, val);
@@ -294,12 +236,15 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::components::avr::{mcu_model::Atmega2560};
+    use crate::components::avr::{mcu_model::{Atmega2560, Atmega328P}, fuses::Fuses};
 
     use super::*;
     use crate::components::avr::io_controller::MockIoControllerTrait;
     use mockall::predicate::eq;
 
+    const X_REG: u8 = 26;
+    const Y_REG: u8 = 28;
+
     #[test]
     fn mov() {
         let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
@@ -576,6 +521,20 @@ mod tests {
         assert_eq!(mcu.read_register_pair(Z_REG), 0x2469);
     }
 
+    #[test]
+    fn lpm_wraps_address() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        mcu.write_flash(0x0123, 0x2023);
+        mcu.write_register_pair(Z_REG, 0x8247);
+
+        mcu.execute_and_assert_sreg(
+            0b1001_000_00001_0101, // lpm r1, Z+
+            "--------");
+        assert_eq!(mcu.read_register(1), 0x20);
+        assert_eq!(mcu.read_register_pair(Z_REG), 0x8248);
+        assert_eq!(mcu.pending_fault.get(), None);
+    }
+
     #[test]
     fn elpm() {
         let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
@@ -602,6 +561,170 @@ mod tests {
         assert_eq!(mcu.read_register_pair(Z_REG), 0x2469);
     }
 
+    #[test]
+    fn spm() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1F800;
+        mcu.rampz = 0x01;
+        mcu.write_flash(0x01234, 0x0F0F);
+        mcu.write_flash(0x01235, 0x0F0F);
+
+        // Fill two words of the page buffer
+        for (z, val) in [(0x2468, 0x1234), (0x246A, 0xFF00)] {
+            mcu.write_io(0x37, 0b0000_0001);
+            mcu.write_register_pair(Z_REG, z);
+            mcu.write_register_pair(0, val);
+            mcu.rampz = 0x00;
+            mcu.execute_and_assert_sreg(
+                0x95E8, // spm
+                "--------");
+            mcu.spm.tick(1);
+            assert_eq!(mcu.read_io(0x37), 0b0000_0000);
+        }
+
+        // Page write without an erase only clears bits
+        mcu.write_io(0x37, 0b0000_0101);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.read_io(0x37), 0b0100_0101);
+        assert!(!mcu.spm.cpu_halted());
+        assert_eq!(mcu.read_flash(0x01234), 0xFFFF);
+        assert_eq!(mcu.flash[0x01234], 0x0204);
+        assert_eq!(mcu.flash[0x01235], 0x0F00);
+        assert_eq!(mcu.flash[0x01236], 0x0000);
+
+        mcu.spm.tick(255);
+        mcu.write_io(0x37, 0b0001_0001);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert!(mcu.spm.rww_busy());
+
+        while mcu.read_io(0x37) & 0x01 != 0 {
+            mcu.spm.tick(255);
+        }
+        mcu.write_io(0x37, 0b0001_0001);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.read_io(0x37), 0b0000_0000);
+
+        // Page erase
+        mcu.write_io(0x37, 0b0000_0011);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.flash[0x01234], 0xFFFF);
+        assert_eq!(mcu.flash[0x01236], 0xFFFF);
+    }
+
+    #[test]
+    fn spm_nrww() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1F800;
+        mcu.write_flash(0x1F800, 0x95E8); // spm
+        mcu.write_flash(0x1F801, 0x0000); // nop
+        mcu.write_register_pair(Z_REG, 0xF000);
+        mcu.rampz = 0x03;
+
+        mcu.write_io(0x37, 0b0000_0011);
+        assert_eq!(mcu.step(), 1);
+        assert_eq!(mcu.pc, 0x1F801);
+        assert!(mcu.spm.cpu_halted());
+        assert!(!mcu.spm.rww_busy());
+        assert_eq!(mcu.read_flash(0x1F801), 0xFFFF);
+
+        let mut cycles = 1;
+        while mcu.spm.cpu_halted() {
+            assert_eq!(mcu.step(), 1);
+            cycles += 1;
+        }
+        assert_eq!(cycles, 64_000);
+        assert_eq!(mcu.pc, 0x1F801);
+        assert_eq!(mcu.read_io(0x37), 0b0000_0000);
+    }
+
+    #[test]
+    fn spm_outside_boot_section() {
+        let mut mcu: Mcu<Atmega2560, _> = Mcu::default();
+        mcu.pc = 0x1000;
+        mcu.write_flash(0x0800, 0x1234);
+        mcu.write_register_pair(Z_REG, 0x1000);
+
+        mcu.write_io(0x37, 0b0000_0011);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.flash[0x0800], 0x1234);
+        assert_eq!(mcu.pending_fault.get(), None);
+    }
+
+    #[test]
+    fn spm_wraps_address() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        mcu.pc = 0x3F00;
+        mcu.write_flash(0x0080, 0x1234);
+        mcu.write_flash(0x0081, 0x5678);
+
+        // Z = 0x8100 is word 0x4080, past the end of the 16K words flash
+        mcu.write_register_pair(Z_REG, 0x8100);
+        mcu.write_io(0x37, 0b0000_0011);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.flash[0x0080], 0xFFFF);
+        assert_eq!(mcu.flash[0x0081], 0xFFFF);
+
+        while mcu.spm.cpu_halted() || mcu.read_io(0x37) & 0x01 != 0 {
+            mcu.spm.tick(255);
+        }
+        mcu.write_register_pair(Z_REG, 0x8102);
+        mcu.write_register_pair(0, 0xAA55);
+        mcu.write_io(0x37, 0b0000_0001);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        mcu.spm.tick(1);
+        mcu.write_io(0x37, 0b0000_0101);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.flash[0x0080], 0xFFFF);
+        assert_eq!(mcu.flash[0x0081], 0xAA55);
+        assert_eq!(mcu.pending_fault.get(), None);
+    }
+
+    #[test]
+    fn lock_bits() {
+        let mut mcu: Mcu<Atmega328P, _> = Mcu::default();
+        // BLB12 and BLB01 programmed
+        mcu.set_fuses(Fuses::from_sections::<Atmega328P>(&[], Some(0xDB)));
+        mcu.write_flash(0x3F00, 0x2023);
+
+        // Application section can't read the boot loader section
+        mcu.write_register_pair(Z_REG, 0x7E00);
+        mcu.execute_and_assert_sreg(
+            0x95C8, // lpm
+            "--------");
+        assert_eq!(mcu.pending_fault.take(), Some(CpuFaultKind::LockBitViolation));
+
+        // Boot loader can read itself, but can't erase the application section
+        mcu.pc = 0x3F00;
+        mcu.execute_and_assert_sreg(
+            0x95C8, // lpm
+            "--------");
+        assert_eq!(mcu.read_register(0), 0x23);
+        assert_eq!(mcu.pending_fault.get(), None);
+
+        mcu.write_register_pair(Z_REG, 0x0100);
+        mcu.write_io(0x37, 0b0000_0011);
+        mcu.execute_and_assert_sreg(
+            0x95E8, // spm
+            "--------");
+        assert_eq!(mcu.pending_fault.take(), Some(CpuFaultKind::LockBitViolation));
+    }
+
     #[test]
     fn r#in() {
         let mut io = MockIoControllerTrait::new();
@@ -1,11 +1,396 @@
+use crate::pins::PinId;
+
+use super::{io_controller::{InterruptSource, SleepMode}, instruction::Instruction};
+
+/// AVR instruction set variant of the CPU core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionSet {
+    /// Up to 8 KiB flash, without hardware multiplier and JMP/CALL (ATtiny).
+    Avr25,
+    /// Up to 128 KiB flash, without ELPM and EIJMP/EICALL.
+    Avr5,
+    /// Up to 128 KiB flash, with ELPM.
+    Avr51,
+    /// More than 128 KiB flash, with a 3-byte PC and EIJMP/EICALL.
+    Avr6,
+}
+
+impl InstructionSet {
+    /// Returns `false` if the instruction is not implemented by this core.
+    pub(crate) fn supports(self, instr: &Instruction) -> bool {
+        use Instruction::*;
+        match instr {
+            Mul {..} | Muls {..} | Mulsu {..} | Fmul {..} | Fmuls {..} | Fmulsu {..} |
+            Jmp {..} | Call {..} => self != InstructionSet::Avr25,
+            Elpm {..} => matches!(self, InstructionSet::Avr51 | InstructionSet::Avr6),
+            Eijmp | Eicall => self == InstructionSet::Avr6,
+            _ => true,
+        }
+    }
+}
+
+/// A GPIO port with PINx, DDRx and PORTx registers at consecutive data addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct GpioPortDesc {
+    /// Port letter, used in pin names.
+    pub name: char,
+    /// Data address of PINx register.
+    pub pin_addr: u16,
+    pub pin_count: u8,
+}
+
+/// A 16-bit timer, with TCCRnA at `base` and the rest of its registers at the standard offsets.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer16Desc {
+    /// Timer number, as in TCCR1A.
+    pub index: u8,
+    /// Data address of TCCRnA register.
+    pub base: u16,
+    pub timsk: u16,
+    pub tifr: u16,
+    /// Output compare pins (OCnA, OCnB and OCnC if present), as a port letter and a bit.
+    pub oc_pins: &'static [(char, u8)],
+}
+
+/// An 8-bit timer with two output compare channels.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer8Desc {
+    /// Timer number, as in TCCR0A.
+    pub index: u8,
+    /// Data addresses of TCCRnA, TCCRnB, TCNTn, OCRnA and OCRnB registers.
+    pub regs: [u16; 5],
+    /// TIMSK and TIFR registers may be shared with other timers.
+    pub timsk: u16,
+    pub tifr: u16,
+    /// Bits of TOVn, OCFnA and OCFnB flags in TIFR (and their enable bits in TIMSK).
+    pub int_bits: [u8; 3],
+    /// OCnA and OCnB pins, as a port letter and a bit.
+    pub oc_pins: [(char, u8); 2],
+    /// Uses the Timer2 prescaler, with clk/32 and clk/128 steps.
+    pub async_prescaler: bool,
+}
+
+/// A USART, with UCSRnA at `base` and the rest of its registers at the standard offsets.
+#[derive(Debug, Clone, Copy)]
+pub struct UsartDesc {
+    pub index: u8,
+    /// Data address of UCSRnA register.
+    pub base: u16,
+    pub xck_pin: (char, u8),
+    pub tx_pin: (char, u8),
+    pub rx_pin: (char, u8),
+}
+
+/// High speed 8-bit Timer1 of ATtiny25/45/85, clocked from the PLL.
+#[derive(Debug, Clone, Copy)]
+pub struct PllTimerDesc {
+    pub index: u8,
+    /// Data addresses of TCCR1, GTCCR, TCNT1, OCR1A, OCR1B, OCR1C and PLLCSR registers.
+    pub regs: [u16; 7],
+    pub timsk: u16,
+    pub tifr: u16,
+    /// Bits of TOV1, OCF1A and OCF1B flags in TIFR (and their enable bits in TIMSK).
+    pub int_bits: [u8; 3],
+    /// OC1A and OC1B pins, as a port letter and a bit.
+    pub oc_pins: [(char, u8); 2],
+}
+
+/// Universal Serial Interface.
+#[derive(Debug, Clone, Copy)]
+pub struct UsiDesc {
+    /// Data addresses of USIBR, USIDR, USISR and USICR registers.
+    pub regs: [u16; 4],
+    /// DI/SDA, DO and USCK/SCL pins, as a port letter and a bit.
+    pub di_pin: (char, u8),
+    pub do_pin: (char, u8),
+    pub usck_pin: (char, u8),
+}
+
+/// External memory interface of ATmega640/1280/2560.
+#[derive(Debug, Clone, Copy)]
+pub struct XmemDesc {
+    /// Data addresses of XMCRA and XMCRB registers.
+    pub regs: [u16; 2],
+    /// Port letter of the multiplexed AD7:0 bus.
+    pub ad_port: char,
+    /// Port letter of A15:8 address lines.
+    pub high_port: char,
+    /// ALE, RD and WR pins, as a port letter and a bit.
+    pub ale_pin: (char, u8),
+    pub rd_pin: (char, u8),
+    pub wr_pin: (char, u8),
+}
+
+/// Sleep mode control bits (SMCR on ATmega, MCUCR on ATtiny).
+#[derive(Debug, Clone, Copy)]
+pub struct SleepDesc {
+    /// Data address of the register.
+    pub addr: u16,
+    /// Implemented bits of the register.
+    pub mask: u8,
+    /// Sleep Enable bit.
+    pub se_bit: u8,
+    /// Lowest bit of the Sleep Mode bits.
+    pub sm_shift: u8,
+    /// Sleep modes for each Sleep Mode bits value, `None` for reserved values.
+    pub modes: &'static [Option<SleepMode>],
+}
+
+/// A clock source selected by CKSEL3:0 fuse bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// External clock or crystal on XTAL pins, driven by the board clock.
+    External,
+    /// Internal oscillator of a fixed frequency in Hz.
+    Internal(u32),
+    Reserved,
+}
+
+/// Fuse byte layout of a model.
+///
+/// CKDIV8 and CKSEL3:0 are in the low fuse byte, and WDTON is bit 4 of the high fuse byte on all parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuseDesc {
+    /// Low, high and extended fuse bytes of a new MCU: external clock without division and no boot reset.
+    pub defaults: [u8; 3],
+    /// Clock sources for each CKSEL3:0 value.
+    pub clock_sources: [ClockSource; 16],
+    /// Boot loader section sizes in words for each BOOTSZ1:0 value (high fuse bits 2:1),
+    /// `None` if there is no boot loader section.
+    pub boot_sizes: Option<[u32; 4]>,
+    /// Fuse byte (0 is low) with BODLEVEL2:0 in its lowest bits.
+    pub bodlevel_byte: usize,
+    /// Fuse byte and bit of SELFPRGEN, if self-programming has to be enabled by a fuse.
+    pub self_programming: Option<(usize, u8)>,
+}
+
+/// IO peripherals of a model and their data addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct Peripherals {
+    /// GPIO ports, pins are numbered in the same order.
+    pub gpio: &'static [GpioPortDesc],
+    pub timers8: &'static [Timer8Desc],
+    pub timers16: &'static [Timer16Desc],
+    pub pll_timer: Option<PllTimerDesc>,
+    pub usarts: &'static [UsartDesc],
+    pub usi: Option<UsiDesc>,
+    pub xmem: Option<XmemDesc>,
+    /// Data address of WDTCSR register.
+    pub wdtcsr: u16,
+    /// Data address of CLKPR register.
+    pub clkpr: u16,
+    /// Data address of MCUSR register.
+    pub mcusr: u16,
+    /// Reset flags present in MCUSR, JTRF is only present on parts with JTAG.
+    pub reset_flags: u8,
+    pub sleep: SleepDesc,
+}
+
+impl Peripherals {
+    /// Total number of GPIO pins.
+    pub fn pin_count(&self) -> usize {
+        self.gpio.iter().map(|port| port.pin_count as usize).sum()
+    }
+
+    /// Id of the first pin of a GPIO port.
+    pub fn port_start(&self, port: usize) -> PinId {
+        self.gpio[..port].iter().map(|port| port.pin_count as PinId).sum()
+    }
+
+    /// Finds a GPIO port and a bit of a pin.
+    pub fn pin_port(&self, pin: PinId) -> Option<(usize, u8)> {
+        let mut start = 0;
+        for (i, port) in self.gpio.iter().enumerate() {
+            if pin < start + port.pin_count as PinId {
+                return Some((i, (pin - start) as u8));
+            }
+            start += port.pin_count as PinId;
+        }
+        None
+    }
+
+    /// Id of a pin, given as a port letter and a bit.
+    pub fn pin_id(&self, (name, bit): (char, u8)) -> PinId {
+        let port = self.gpio.iter().position(|port| port.name == name)
+            .expect("Pin port is not defined by the MCU model");
+        assert!(bit < self.gpio[port].pin_count, "Pin is not defined by the MCU model");
+        self.port_start(port) + bit as PinId
+    }
+
+    /// Name of a pin, like `PB5`.
+    pub fn pin_name(&self, pin: PinId) -> Option<String> {
+        self.pin_port(pin).map(|(port, bit)| format!("P{}{}", self.gpio[port].name, bit))
+    }
+}
+
+/// Description of an AVR part: memories, CPU core variant, interrupt vectors and peripherals.
 pub trait McuModel: Send {
+    /// Flash size in words.
     fn flash_size() -> usize;
     fn rampz_mask() -> u8;
     fn eind_mask() -> u8;
+    /// Flash page size in words.
+    fn flash_page_size() -> usize;
+    /// Word address of the first No-Read-While-Write flash section page.
+    fn nrww_start() -> u32;
+    /// EEPROM size in bytes.
+    fn eeprom_size() -> usize;
+    /// Data address of the first SRAM byte, everything below it is registers and IO.
+    fn sram_start() -> u16;
+    /// SRAM size in bytes.
+    fn sram_size() -> usize;
+    /// Data address of the last SRAM byte, the initial stack pointer.
+    fn sram_end() -> u16 {
+        (Self::sram_start() as usize + Self::sram_size() - 1) as u16
+    }
+    /// Size of PC in bytes (2 or 3), as pushed on stack by calls and interrupts.
+    fn pc_bytes() -> u8;
+    /// Instruction set of the CPU core, other instructions are illegal.
+    fn instruction_set() -> InstructionSet;
+    /// IO peripherals and their addresses.
+    fn peripherals() -> &'static Peripherals;
+    /// Fuse bytes layout and boot loader section sizes.
+    fn fuse_layout() -> &'static FuseDesc;
+    /// Interrupt vector table: vector number of an interrupt source, if the model has it.
+    /// 
+    /// Vector 0 is reset, lower numbers have higher priority.
+    fn interrupt_vector(source: InterruptSource) -> Option<u8>;
+    /// Size of a single interrupt vector in words.
+    fn vector_size() -> u32;
+    /// Name of an IO register (IO address space, 0x00 to 0x3F), if it exists.
+    fn io_register_name(addr: u8) -> Option<&'static str>;
+    /// Extended IO registers (data addresses from 0x60 to SRAM) with their names, sorted by address.
+    fn extended_io_registers() -> &'static [(u16, &'static str)] {
+        &[]
+    }
+    /// Name of a register at a data address, in IO or extended IO space, if it exists.
+    fn register_name(addr: u16) -> Option<&'static str> {
+        match addr {
+            0x20..=0x5F => Self::io_register_name((addr - 0x20) as u8),
+            _ => {
+                let registers = Self::extended_io_registers();
+                registers.binary_search_by_key(&addr, |&(a, _)| a).ok().map(|i| registers[i].1)
+            }
+        }
+    }
 }
 
+/// MCUSR reset flags: WDRF, BORF, EXTRF and PORF.
+const RESET_FLAGS: u8 = 0x0F;
+/// MCUSR reset flags of parts with JTAG, adding JTRF.
+const RESET_FLAGS_JTAG: u8 = 0x1F;
+
+/// SMCR layout of ATmega parts.
+const ATMEGA_SLEEP: SleepDesc = SleepDesc {
+    addr: 0x53,
+    mask: 0x0F,
+    se_bit: 0,
+    sm_shift: 1,
+    modes: &[
+        Some(SleepMode::Idle), Some(SleepMode::AdcNoiseReduction), Some(SleepMode::PowerDown), Some(SleepMode::PowerSave),
+        None, None, Some(SleepMode::Standby), Some(SleepMode::ExtendedStandby),
+    ],
+};
+
+/// Clock sources of ATmega parts: external clock, 8 MHz RC, 128 kHz and crystals.
+const ATMEGA_CLOCK_SOURCES: [ClockSource; 16] = [
+    ClockSource::External, ClockSource::Reserved, ClockSource::Internal(8_000_000), ClockSource::Internal(128_000),
+    ClockSource::External, ClockSource::External, ClockSource::External, ClockSource::External,
+    ClockSource::External, ClockSource::External, ClockSource::External, ClockSource::External,
+    ClockSource::External, ClockSource::External, ClockSource::External, ClockSource::External,
+];
+
 pub struct Atmega2560;
 
+static ATMEGA2560_PERIPHERALS: Peripherals = Peripherals {
+    gpio: &[
+        GpioPortDesc {name: 'A', pin_addr: 0x20, pin_count: 8},
+        GpioPortDesc {name: 'B', pin_addr: 0x23, pin_count: 8},
+        GpioPortDesc {name: 'C', pin_addr: 0x26, pin_count: 8},
+        GpioPortDesc {name: 'D', pin_addr: 0x29, pin_count: 8},
+        GpioPortDesc {name: 'E', pin_addr: 0x2C, pin_count: 8},
+        GpioPortDesc {name: 'F', pin_addr: 0x2F, pin_count: 8},
+        GpioPortDesc {name: 'G', pin_addr: 0x32, pin_count: 6},
+        GpioPortDesc {name: 'H', pin_addr: 0x100, pin_count: 8},
+        GpioPortDesc {name: 'J', pin_addr: 0x103, pin_count: 8},
+        GpioPortDesc {name: 'K', pin_addr: 0x106, pin_count: 8},
+        GpioPortDesc {name: 'L', pin_addr: 0x109, pin_count: 8},
+    ],
+    timers8: &[],
+    timers16: &[
+        Timer16Desc {index: 1, base: 0x80, timsk: 0x6F, tifr: 0x36, oc_pins: &[('B', 5), ('B', 6), ('B', 7)]},
+        Timer16Desc {index: 3, base: 0x90, timsk: 0x71, tifr: 0x38, oc_pins: &[('E', 3), ('E', 4), ('E', 5)]},
+        Timer16Desc {index: 4, base: 0xA0, timsk: 0x72, tifr: 0x39, oc_pins: &[('H', 3), ('H', 4), ('H', 5)]},
+        Timer16Desc {index: 5, base: 0x120, timsk: 0x73, tifr: 0x3A, oc_pins: &[('L', 3), ('L', 4), ('L', 5)]},
+    ],
+    pll_timer: None,
+    usarts: &[
+        UsartDesc {index: 0, base: 0xC0, xck_pin: ('E', 2), tx_pin: ('E', 1), rx_pin: ('E', 0)},
+    ],
+    usi: None,
+    xmem: Some(XmemDesc {
+        regs: [0x74, 0x75],
+        ad_port: 'A',
+        high_port: 'C',
+        ale_pin: ('G', 2),
+        rd_pin: ('G', 1),
+        wr_pin: ('G', 0),
+    }),
+    wdtcsr: 0x60,
+    clkpr: 0x61,
+    mcusr: 0x54,
+    reset_flags: RESET_FLAGS_JTAG,
+    sleep: ATMEGA_SLEEP,
+};
+
+/// ATmega2560 IO register names, empty for reserved addresses.
+const ATMEGA2560_IO_REGISTERS: [&str; 64] = [
+    "PINA", "DDRA", "PORTA", "PINB", "DDRB", "PORTB", "PINC", "DDRC",
+    "PORTC", "PIND", "DDRD", "PORTD", "PINE", "DDRE", "PORTE", "PINF",
+    "DDRF", "PORTF", "PING", "DDRG", "PORTG", "TIFR0", "TIFR1", "TIFR2",
+    "TIFR3", "TIFR4", "TIFR5", "PCIFR", "EIFR", "EIMSK", "GPIOR0", "EECR",
+    "EEDR", "EEARL", "EEARH", "GTCCR", "TCCR0A", "TCCR0B", "TCNT0", "OCR0A",
+    "OCR0B", "", "GPIOR1", "GPIOR2", "SPCR", "SPSR", "SPDR", "",
+    "ACSR", "OCDR", "", "SMCR", "MCUSR", "MCUCR", "", "SPMCSR",
+    "", "", "", "RAMPZ", "EIND", "SPL", "SPH", "SREG",
+];
+
+/// ATmega2560 extended IO register names, sorted by data address.
+const ATMEGA2560_EXT_IO_REGISTERS: &[(u16, &str)] = &[
+    (0x60, "WDTCSR"), (0x61, "CLKPR"), (0x64, "PRR0"), (0x65, "PRR1"),
+    (0x66, "OSCCAL"), (0x68, "PCICR"), (0x69, "EICRA"), (0x6A, "EICRB"),
+    (0x6B, "PCMSK0"), (0x6C, "PCMSK1"), (0x6D, "PCMSK2"), (0x6E, "TIMSK0"),
+    (0x6F, "TIMSK1"), (0x70, "TIMSK2"), (0x71, "TIMSK3"), (0x72, "TIMSK4"),
+    (0x73, "TIMSK5"), (0x74, "XMCRA"), (0x75, "XMCRB"), (0x78, "ADCL"),
+    (0x79, "ADCH"), (0x7A, "ADCSRA"), (0x7B, "ADCSRB"), (0x7C, "ADMUX"),
+    (0x7D, "DIDR2"), (0x7E, "DIDR0"), (0x7F, "DIDR1"), (0x80, "TCCR1A"),
+    (0x81, "TCCR1B"), (0x82, "TCCR1C"), (0x84, "TCNT1L"), (0x85, "TCNT1H"),
+    (0x86, "ICR1L"), (0x87, "ICR1H"), (0x88, "OCR1AL"), (0x89, "OCR1AH"),
+    (0x8A, "OCR1BL"), (0x8B, "OCR1BH"), (0x8C, "OCR1CL"), (0x8D, "OCR1CH"),
+    (0x90, "TCCR3A"), (0x91, "TCCR3B"), (0x92, "TCCR3C"), (0x94, "TCNT3L"),
+    (0x95, "TCNT3H"), (0x96, "ICR3L"), (0x97, "ICR3H"), (0x98, "OCR3AL"),
+    (0x99, "OCR3AH"), (0x9A, "OCR3BL"), (0x9B, "OCR3BH"), (0x9C, "OCR3CL"),
+    (0x9D, "OCR3CH"), (0xA0, "TCCR4A"), (0xA1, "TCCR4B"), (0xA2, "TCCR4C"),
+    (0xA4, "TCNT4L"), (0xA5, "TCNT4H"), (0xA6, "ICR4L"), (0xA7, "ICR4H"),
+    (0xA8, "OCR4AL"), (0xA9, "OCR4AH"), (0xAA, "OCR4BL"), (0xAB, "OCR4BH"),
+    (0xAC, "OCR4CL"), (0xAD, "OCR4CH"), (0xB0, "TCCR2A"), (0xB1, "TCCR2B"),
+    (0xB2, "TCNT2"), (0xB3, "OCR2A"), (0xB4, "OCR2B"), (0xB6, "ASSR"),
+    (0xB8, "TWBR"), (0xB9, "TWSR"), (0xBA, "TWAR"), (0xBB, "TWDR"),
+    (0xBC, "TWCR"), (0xBD, "TWAMR"), (0xC0, "UCSR0A"), (0xC1, "UCSR0B"),
+    (0xC2, "UCSR0C"), (0xC4, "UBRR0L"), (0xC5, "UBRR0H"), (0xC6, "UDR0"),
+    (0xC8, "UCSR1A"), (0xC9, "UCSR1B"), (0xCA, "UCSR1C"), (0xCC, "UBRR1L"),
+    (0xCD, "UBRR1H"), (0xCE, "UDR1"), (0xD0, "UCSR2A"), (0xD1, "UCSR2B"),
+    (0xD2, "UCSR2C"), (0xD4, "UBRR2L"), (0xD5, "UBRR2H"), (0xD6, "UDR2"),
+    (0x100, "PINH"), (0x101, "DDRH"), (0x102, "PORTH"), (0x103, "PINJ"),
+    (0x104, "DDRJ"), (0x105, "PORTJ"), (0x106, "PINK"), (0x107, "DDRK"),
+    (0x108, "PORTK"), (0x109, "PINL"), (0x10A, "DDRL"), (0x10B, "PORTL"),
+    (0x120, "TCCR5A"), (0x121, "TCCR5B"), (0x122, "TCCR5C"), (0x124, "TCNT5L"),
+    (0x125, "TCNT5H"), (0x126, "ICR5L"), (0x127, "ICR5H"), (0x128, "OCR5AL"),
+    (0x129, "OCR5AH"), (0x12A, "OCR5BL"), (0x12B, "OCR5BH"), (0x12C, "OCR5CL"),
+    (0x12D, "OCR5CH"), (0x130, "UCSR3A"), (0x131, "UCSR3B"), (0x132, "UCSR3C"),
+    (0x134, "UBRR3L"), (0x135, "UBRR3H"), (0x136, "UDR3"),
+];
+
 impl McuModel for Atmega2560 {
     fn flash_size() -> usize {
         128 * 1024
@@ -18,4 +403,381 @@ impl McuModel for Atmega2560 {
     fn eind_mask() -> u8 {
         0x01
     }
-}
\ No newline at end of file
+
+    fn flash_page_size() -> usize {
+        128
+    }
+
+    fn nrww_start() -> u32 {
+        0x1F000
+    }
+
+    fn eeprom_size() -> usize {
+        4096
+    }
+
+    fn sram_start() -> u16 {
+        0x200
+    }
+
+    fn sram_size() -> usize {
+        8192
+    }
+
+    fn pc_bytes() -> u8 {
+        3
+    }
+
+    fn instruction_set() -> InstructionSet {
+        InstructionSet::Avr6
+    }
+
+    fn peripherals() -> &'static Peripherals {
+        &ATMEGA2560_PERIPHERALS
+    }
+
+    fn fuse_layout() -> &'static FuseDesc {
+        &FuseDesc {
+            defaults: [0xFF, 0xD9, 0xFF],
+            clock_sources: ATMEGA_CLOCK_SOURCES,
+            boot_sizes: Some([4096, 2048, 1024, 512]),
+            bodlevel_byte: 2,
+            self_programming: None,
+        }
+    }
+
+    fn interrupt_vector(source: InterruptSource) -> Option<u8> {
+        let timer_base = |timer| match timer {
+            1 => Some(16),
+            3 => Some(31),
+            4 => Some(41),
+            5 => Some(46),
+            _ => None,
+        };
+        let usart_base = |usart| match usart {
+            0 => Some(25),
+            1 => Some(36),
+            2 => Some(51),
+            3 => Some(54),
+            _ => None,
+        };
+        match source {
+            InterruptSource::Watchdog => Some(12),
+            InterruptSource::TimerCapture(timer) => timer_base(timer),
+            InterruptSource::TimerCompare(timer, channel @ 0..=2) => timer_base(timer).map(|v| v + 1 + channel),
+            InterruptSource::TimerCompare(_, _) => None,
+            InterruptSource::TimerOverflow(timer) => timer_base(timer).map(|v| v + 4),
+            InterruptSource::SpmReady => Some(40),
+            InterruptSource::UsiStart | InterruptSource::UsiOverflow => None,
+            InterruptSource::UsartRx(usart) => usart_base(usart),
+            InterruptSource::UsartUdre(usart) => usart_base(usart).map(|v| v + 1),
+            InterruptSource::UsartTx(usart) => usart_base(usart).map(|v| v + 2),
+        }
+    }
+
+    fn vector_size() -> u32 {
+        2
+    }
+
+    fn io_register_name(addr: u8) -> Option<&'static str> {
+        ATMEGA2560_IO_REGISTERS.get(addr as usize)
+            .copied()
+            .filter(|name| !name.is_empty())
+    }
+
+    fn extended_io_registers() -> &'static [(u16, &'static str)] {
+        ATMEGA2560_EXT_IO_REGISTERS
+    }
+}
+
+pub struct Atmega328P;
+
+static ATMEGA328P_PERIPHERALS: Peripherals = Peripherals {
+    gpio: &[
+        GpioPortDesc {name: 'B', pin_addr: 0x23, pin_count: 8},
+        GpioPortDesc {name: 'C', pin_addr: 0x26, pin_count: 7},
+        GpioPortDesc {name: 'D', pin_addr: 0x29, pin_count: 8},
+    ],
+    timers8: &[
+        Timer8Desc {
+            index: 0, regs: [0x44, 0x45, 0x46, 0x47, 0x48], timsk: 0x6E, tifr: 0x35, int_bits: [0, 1, 2],
+            oc_pins: [('D', 6), ('D', 5)], async_prescaler: false,
+        },
+        Timer8Desc {
+            index: 2, regs: [0xB0, 0xB1, 0xB2, 0xB3, 0xB4], timsk: 0x70, tifr: 0x37, int_bits: [0, 1, 2],
+            oc_pins: [('B', 3), ('D', 3)], async_prescaler: true,
+        },
+    ],
+    timers16: &[
+        Timer16Desc {index: 1, base: 0x80, timsk: 0x6F, tifr: 0x36, oc_pins: &[('B', 1), ('B', 2)]},
+    ],
+    pll_timer: None,
+    usarts: &[
+        UsartDesc {index: 0, base: 0xC0, xck_pin: ('D', 4), tx_pin: ('D', 1), rx_pin: ('D', 0)},
+    ],
+    usi: None,
+    xmem: None,
+    wdtcsr: 0x60,
+    clkpr: 0x61,
+    mcusr: 0x54,
+    reset_flags: RESET_FLAGS,
+    sleep: ATMEGA_SLEEP,
+};
+
+/// ATmega328P IO register names, empty for reserved addresses.
+const ATMEGA328P_IO_REGISTERS: [&str; 64] = [
+    "", "", "", "PINB", "DDRB", "PORTB", "PINC", "DDRC",
+    "PORTC", "PIND", "DDRD", "PORTD", "", "", "", "",
+    "", "", "", "", "", "TIFR0", "TIFR1", "TIFR2",
+    "", "", "", "PCIFR", "EIFR", "EIMSK", "GPIOR0", "EECR",
+    "EEDR", "EEARL", "EEARH", "GTCCR", "TCCR0A", "TCCR0B", "TCNT0", "OCR0A",
+    "OCR0B", "", "GPIOR1", "GPIOR2", "SPCR", "SPSR", "SPDR", "",
+    "ACSR", "", "", "SMCR", "MCUSR", "MCUCR", "", "SPMCSR",
+    "", "", "", "", "", "SPL", "SPH", "SREG",
+];
+
+/// ATmega328P extended IO register names, sorted by data address.
+const ATMEGA328P_EXT_IO_REGISTERS: &[(u16, &str)] = &[
+    (0x60, "WDTCSR"), (0x61, "CLKPR"), (0x64, "PRR"), (0x66, "OSCCAL"),
+    (0x68, "PCICR"), (0x69, "EICRA"), (0x6B, "PCMSK0"), (0x6C, "PCMSK1"),
+    (0x6D, "PCMSK2"), (0x6E, "TIMSK0"), (0x6F, "TIMSK1"), (0x70, "TIMSK2"),
+    (0x78, "ADCL"), (0x79, "ADCH"), (0x7A, "ADCSRA"), (0x7B, "ADCSRB"),
+    (0x7C, "ADMUX"), (0x7E, "DIDR0"), (0x7F, "DIDR1"), (0x80, "TCCR1A"),
+    (0x81, "TCCR1B"), (0x82, "TCCR1C"), (0x84, "TCNT1L"), (0x85, "TCNT1H"),
+    (0x86, "ICR1L"), (0x87, "ICR1H"), (0x88, "OCR1AL"), (0x89, "OCR1AH"),
+    (0x8A, "OCR1BL"), (0x8B, "OCR1BH"), (0xB0, "TCCR2A"), (0xB1, "TCCR2B"),
+    (0xB2, "TCNT2"), (0xB3, "OCR2A"), (0xB4, "OCR2B"), (0xB6, "ASSR"),
+    (0xB8, "TWBR"), (0xB9, "TWSR"), (0xBA, "TWAR"), (0xBB, "TWDR"),
+    (0xBC, "TWCR"), (0xBD, "TWAMR"), (0xC0, "UCSR0A"), (0xC1, "UCSR0B"),
+    (0xC2, "UCSR0C"), (0xC4, "UBRR0L"), (0xC5, "UBRR0H"), (0xC6, "UDR0"),
+];
+
+impl McuModel for Atmega328P {
+    fn flash_size() -> usize {
+        16 * 1024
+    }
+
+    fn rampz_mask() -> u8 {
+        0x00
+    }
+
+    fn eind_mask() -> u8 {
+        0x00
+    }
+
+    fn flash_page_size() -> usize {
+        64
+    }
+
+    fn nrww_start() -> u32 {
+        0x3800
+    }
+
+    fn eeprom_size() -> usize {
+        1024
+    }
+
+    fn sram_start() -> u16 {
+        0x100
+    }
+
+    fn sram_size() -> usize {
+        2048
+    }
+
+    fn pc_bytes() -> u8 {
+        2
+    }
+
+    fn instruction_set() -> InstructionSet {
+        InstructionSet::Avr5
+    }
+
+    fn peripherals() -> &'static Peripherals {
+        &ATMEGA328P_PERIPHERALS
+    }
+
+    fn fuse_layout() -> &'static FuseDesc {
+        &FuseDesc {
+            defaults: [0xFF, 0xD9, 0xFF],
+            clock_sources: ATMEGA_CLOCK_SOURCES,
+            boot_sizes: Some([2048, 1024, 512, 256]),
+            bodlevel_byte: 2,
+            self_programming: None,
+        }
+    }
+
+    fn interrupt_vector(source: InterruptSource) -> Option<u8> {
+        let timer_base = |timer| match timer {
+            0 => Some(14),
+            1 => Some(11),
+            2 => Some(7),
+            _ => None,
+        };
+        match source {
+            InterruptSource::Watchdog => Some(6),
+            InterruptSource::TimerCapture(1) => Some(10),
+            InterruptSource::TimerCapture(_) => None,
+            InterruptSource::TimerCompare(timer, channel @ 0..=1) => timer_base(timer).map(|v| v + channel),
+            InterruptSource::TimerCompare(_, _) => None,
+            InterruptSource::TimerOverflow(timer) => timer_base(timer).map(|v| v + 2),
+            InterruptSource::SpmReady => Some(25),
+            InterruptSource::UsiStart | InterruptSource::UsiOverflow => None,
+            InterruptSource::UsartRx(0) => Some(18),
+            InterruptSource::UsartUdre(0) => Some(19),
+            InterruptSource::UsartTx(0) => Some(20),
+            InterruptSource::UsartRx(_) | InterruptSource::UsartUdre(_) | InterruptSource::UsartTx(_) => None,
+        }
+    }
+
+    fn vector_size() -> u32 {
+        2
+    }
+
+    fn io_register_name(addr: u8) -> Option<&'static str> {
+        ATMEGA328P_IO_REGISTERS.get(addr as usize)
+            .copied()
+            .filter(|name| !name.is_empty())
+    }
+
+    fn extended_io_registers() -> &'static [(u16, &'static str)] {
+        ATMEGA328P_EXT_IO_REGISTERS
+    }
+}
+
+pub struct Attiny85;
+
+static ATTINY85_PERIPHERALS: Peripherals = Peripherals {
+    gpio: &[
+        GpioPortDesc {name: 'B', pin_addr: 0x36, pin_count: 6},
+    ],
+    timers8: &[
+        Timer8Desc {
+            index: 0, regs: [0x4A, 0x53, 0x52, 0x49, 0x48], timsk: 0x59, tifr: 0x58, int_bits: [1, 4, 3],
+            oc_pins: [('B', 0), ('B', 1)], async_prescaler: false,
+        },
+    ],
+    timers16: &[],
+    pll_timer: Some(PllTimerDesc {
+        index: 1, regs: [0x50, 0x4C, 0x4F, 0x4E, 0x4B, 0x4D, 0x47], timsk: 0x59, tifr: 0x58, int_bits: [2, 6, 5],
+        oc_pins: [('B', 1), ('B', 4)],
+    }),
+    usarts: &[],
+    usi: Some(UsiDesc {
+        regs: [0x30, 0x2F, 0x2E, 0x2D],
+        di_pin: ('B', 0),
+        do_pin: ('B', 1),
+        usck_pin: ('B', 2),
+    }),
+    xmem: None,
+    wdtcsr: 0x41,
+    clkpr: 0x46,
+    mcusr: 0x54,
+    reset_flags: RESET_FLAGS,
+    sleep: SleepDesc {
+        addr: 0x55,
+        mask: 0xFF,
+        se_bit: 5,
+        sm_shift: 3,
+        modes: &[Some(SleepMode::Idle), Some(SleepMode::AdcNoiseReduction), Some(SleepMode::PowerDown), None],
+    },
+};
+
+/// ATtiny85 IO register names, empty for reserved addresses.
+const ATTINY85_IO_REGISTERS: [&str; 64] = [
+    "", "", "", "", "ADCL", "ADCH", "ADCSRA", "ADMUX",
+    "ACSR", "", "", "", "", "USICR", "USISR", "USIDR",
+    "USIBR", "GPIOR0", "GPIOR1", "GPIOR2", "DIDR0", "PCMSK", "PINB", "DDRB",
+    "PORTB", "", "", "", "EECR", "EEDR", "EEARL", "EEARH",
+    "PRR", "WDTCR", "DWDR", "DTPS1", "DT1B", "DT1A", "CLKPR", "PLLCSR",
+    "OCR0B", "OCR0A", "TCCR0A", "OCR1B", "GTCCR", "OCR1C", "OCR1A", "TCNT1",
+    "TCCR1", "OSCCAL", "TCNT0", "TCCR0B", "MCUSR", "MCUCR", "", "SPMCSR",
+    "TIFR", "TIMSK", "GIFR", "GIMSK", "", "SPL", "SPH", "SREG",
+];
+
+impl McuModel for Attiny85 {
+    fn flash_size() -> usize {
+        4 * 1024
+    }
+
+    fn rampz_mask() -> u8 {
+        0x00
+    }
+
+    fn eind_mask() -> u8 {
+        0x00
+    }
+
+    fn flash_page_size() -> usize {
+        32
+    }
+
+    fn nrww_start() -> u32 {
+        // No boot section, the CPU is halted while programming any page
+        0
+    }
+
+    fn eeprom_size() -> usize {
+        512
+    }
+
+    fn sram_start() -> u16 {
+        0x60
+    }
+
+    fn sram_size() -> usize {
+        512
+    }
+
+    fn pc_bytes() -> u8 {
+        2
+    }
+
+    fn instruction_set() -> InstructionSet {
+        InstructionSet::Avr25
+    }
+
+    fn peripherals() -> &'static Peripherals {
+        &ATTINY85_PERIPHERALS
+    }
+
+    fn fuse_layout() -> &'static FuseDesc {
+        &FuseDesc {
+            defaults: [0xFF, 0xDF, 0xFF],
+            clock_sources: [
+                ClockSource::External, ClockSource::Internal(16_000_000), ClockSource::Internal(8_000_000), ClockSource::Internal(6_400_000),
+                ClockSource::Internal(128_000), ClockSource::Reserved, ClockSource::External, ClockSource::Reserved,
+                ClockSource::External, ClockSource::External, ClockSource::External, ClockSource::External,
+                ClockSource::External, ClockSource::External, ClockSource::External, ClockSource::External,
+            ],
+            boot_sizes: None,
+            bodlevel_byte: 1,
+            self_programming: Some((2, 0)),
+        }
+    }
+
+    fn interrupt_vector(source: InterruptSource) -> Option<u8> {
+        match source {
+            InterruptSource::Watchdog => Some(12),
+            InterruptSource::TimerCompare(0, 0) => Some(10),
+            InterruptSource::TimerCompare(0, 1) => Some(11),
+            InterruptSource::TimerOverflow(0) => Some(5),
+            InterruptSource::TimerCompare(1, 0) => Some(3),
+            InterruptSource::TimerCompare(1, 1) => Some(9),
+            InterruptSource::TimerOverflow(1) => Some(4),
+            InterruptSource::UsiStart => Some(13),
+            InterruptSource::UsiOverflow => Some(14),
+            _ => None,
+        }
+    }
+
+    fn vector_size() -> u32 {
+        1
+    }
+
+    fn io_register_name(addr: u8) -> Option<&'static str> {
+        ATTINY85_IO_REGISTERS.get(addr as usize)
+            .copied()
+            .filter(|name| !name.is_empty())
+    }
+}
@@ -1,12 +1,17 @@
+use std::{fmt, fs, io, path::Path};
+
 use crate::vcd::{VcdFiller, VcdConfig, VcdTree};
 use crate::pins::{PinId, PinState};
-use crate::component::Component;
+use crate::component::{Component, ComponentEvent};
 
-use super::{mcu::Mcu, mcu_model::McuModel, io_controller::{IoControllerTrait, IoController}};
+use super::{mcu::{Mcu, CpuState, trace::Tracer, hex::HexError, semihost::Semihosting, debug::Watchpoint, coverage::{Coverage, CoverageError}}, elf::{ElfImage, ElfError}, gdb::GdbStub, snapshot::{StateWriter, StateReader, SnapshotError}, fuses::Fuses, mcu_model::McuModel, io_controller::{IoControllerTrait, IoController, ResetSource}};
 
 /// Top level AVR MCU component.
 /// 
-/// Accounts for correct timings.
+/// Accounts for correct timings. The board clock is the external clock of the MCU,
+/// the system clock is derived from it according to the clock fuses and CLKPR.
+///
+/// Pins are the GPIO pins of the model, followed by the active low RESET pin.
 pub struct McuTicker<M, Io>
 where
     M: McuModel + 'static,
@@ -14,6 +19,58 @@ where
 {
     mcu: Mcu<M, Io>,
     ticks: u8,
+    /// Frequency of the board clock in Hz.
+    board_frequency: f64,
+    /// Supply voltage in volts, checked against the brown-out detection level.
+    supply_voltage: f64,
+    /// Accumulated fraction of a system clock cycle.
+    clock_phase: f64,
+    /// The last rising edge of the board clock has clocked the MCU.
+    clock_running: bool,
+    /// The brown-out detector holds the MCU in reset.
+    brown_out: bool,
+    /// RESET pin is low, holding the MCU in reset.
+    reset_held: bool,
+    tracer: Option<Tracer>,
+    firmware: Option<ElfImage>,
+    gdb: Option<GdbStub>,
+    /// Breakpoint, watchpoint or BREAK hit to be reported, without an attached GDB.
+    debug_event: Option<ComponentEvent>,
+    /// Breakpoints at PC have been checked since the last executed instruction.
+    breakpoint_checked: bool,
+}
+
+/// An error while loading a firmware file of any supported format.
+#[derive(Debug)]
+pub enum FirmwareError {
+    Hex(HexError),
+    Elf(ElfError),
+    /// File extension is not one of .hex, .ihex, .srec, .s19, .s28, .s37, .bin or .elf.
+    UnknownFormat,
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareError::Hex(e) => write!(f, "{}", e),
+            FirmwareError::Elf(e) => write!(f, "{}", e),
+            FirmwareError::UnknownFormat => write!(f, "unknown firmware file format"),
+        }
+    }
+}
+
+impl std::error::Error for FirmwareError {}
+
+impl From<HexError> for FirmwareError {
+    fn from(e: HexError) -> Self {
+        FirmwareError::Hex(e)
+    }
+}
+
+impl From<ElfError> for FirmwareError {
+    fn from(e: ElfError) -> Self {
+        FirmwareError::Elf(e)
+    }
 }
 
 /// AVR MCU with a default IoController.
@@ -28,7 +85,18 @@ where
         let io = IoController::new();
         McuTicker {
             mcu: Mcu::new(io),
-            ticks: 1
+            ticks: 1,
+            board_frequency: 16e6,
+            supply_voltage: 5.0,
+            clock_phase: 0.0,
+            clock_running: false,
+            brown_out: false,
+            reset_held: false,
+            tracer: None,
+            firmware: None,
+            gdb: None,
+            debug_event: None,
+            breakpoint_checked: false,
         }
     }
 }
@@ -41,20 +109,380 @@ where
     // Advances MCU a single clock forward.
     pub fn tick(&mut self) {
         if self.ticks == 0 {
-            self.ticks = self.mcu.step();
+            // PC isn't checked when the last instruction finishes while the CPU isn't running (like after BREAK),
+            // so the cycle is spent stopping the board instead
+            if !self.breakpoint_checked && self.check_breakpoint() {
+                return;
+            }
+            if let Some(gdb) = &mut self.gdb {
+                gdb.before_step(&mut self.mcu);
+            }
+            let (pc, state) = (self.mcu.pc(), self.mcu.state());
+            self.ticks = match &mut self.tracer {
+                Some(tracer) => self.mcu.step_traced(tracer),
+                None => self.mcu.step(),
+            };
+            self.breakpoint_checked = false;
+            if let Some(gdb) = &mut self.gdb {
+                gdb.after_step(&mut self.mcu);
+            } else {
+                if let Some((addr, kind)) = self.mcu.take_watch_hit() {
+                    self.debug_event.get_or_insert(ComponentEvent::Watchpoint(addr, kind));
+                }
+                if state == CpuState::Running && self.mcu.state() == CpuState::Halted {
+                    self.debug_event.get_or_insert(ComponentEvent::Break(pc));
+                }
+            }
         }
         
         assert!(self.ticks > 0);
         self.ticks -= 1;
+        // The next instruction starts on the next tick, so the board stops before executing it
+        if self.ticks == 0 {
+            self.check_breakpoint();
+        }
+    }
+
+    /// Reports a breakpoint at PC if the CPU is running without an attached GDB, returns `true` on a hit.
+    fn check_breakpoint(&mut self) -> bool {
+        if self.gdb.is_some() || self.mcu.state() != CpuState::Running {
+            return false;
+        }
+        self.breakpoint_checked = true;
+        let hit = self.mcu.at_breakpoint();
+        if hit {
+            self.debug_event.get_or_insert(ComponentEvent::Breakpoint(self.mcu.pc()));
+        }
+        hit
+    }
+
+    /// Returns `true` if the system clock has a rising edge on the current board clock edge.
+    ///
+    /// The system clock can't be faster than the board clock, faster internal oscillators are slowed down to it.
+    fn advance_clock(&mut self) -> bool {
+        if self.brown_out || self.reset_held {
+            return false;
+        }
+        let Some(source) = self.mcu.fuses().clock_frequency(self.board_frequency) else {
+            return false;
+        };
+        let ratio = (source / self.board_frequency).min(1.0) / self.mcu.io.clock_division() as f64;
+        self.mcu.set_cpu_frequency((ratio * self.board_frequency).round() as u32);
+        if ratio >= 1.0 {
+            return true;
+        }
+        self.clock_phase += ratio;
+        if self.clock_phase < 1.0 - 1e-9 {
+            return false;
+        }
+        self.clock_phase -= 1.0;
+        true
+    }
+
+    /// Resets the MCU, recording the source in MCUSR.
+    ///
+    /// [ResetSource::PowerOn] emulates a power cycle, other sources keep MCUSR flags set by earlier resets.
+    pub fn reset(&mut self, source: ResetSource) {
+        self.mcu.reset(source);
+        self.ticks = 1;
+        self.clock_phase = 0.0;
+    }
+
+    /// Id of the RESET pin, following the GPIO pins.
+    #[inline]
+    fn reset_pin() -> PinId {
+        IoController::<M>::pin_count() as PinId
+    }
+
+    /// Gets fuse and lock bytes.
+    pub fn fuses(&self) -> &Fuses {
+        self.mcu.fuses()
+    }
+
+    /// Programs fuse and lock bytes and resets the MCU to apply them.
+    pub fn set_fuses(&mut self, fuses: Fuses) {
+        self.mcu.set_fuses(fuses);
+        self.ticks = 1;
+        self.clock_phase = 0.0;
+        self.update_brown_out();
+    }
+
+    /// Sets frequency of the board clock in Hz (16 MHz by default).
+    pub fn set_board_frequency(&mut self, frequency: f64) {
+        assert!(frequency > 0.0, "Board frequency must be positive");
+        self.board_frequency = frequency;
+    }
+
+    /// Sets supply voltage in volts (5 V by default).
+    ///
+    /// The MCU is held in reset while the voltage is below the level selected by BODLEVEL fuses.
+    pub fn set_supply_voltage(&mut self, voltage: f64) {
+        self.supply_voltage = voltage;
+        self.update_brown_out();
+    }
+
+    fn update_brown_out(&mut self) {
+        let brown_out = self.mcu.fuses().bod_level().is_some_and(|level| self.supply_voltage < level);
+        if brown_out && !self.brown_out {
+            self.reset(ResetSource::BrownOut);
+        }
+        self.brown_out = brown_out;
+    }
+
+    /// Gets the effective CPU frequency in Hz, zero if the MCU isn't running.
+    pub fn cpu_frequency(&self) -> f64 {
+        if self.brown_out {
+            return 0.0;
+        }
+        self.mcu.fuses().clock_frequency(self.board_frequency)
+            .map_or(0.0, |source| source.min(self.board_frequency) / self.mcu.io.clock_division() as f64)
     }
 
     /// Loads MCU flash memory from a slice.
     pub fn load_flash(&mut self, data: &[u16]) {
         self.mcu.load_flash(data);
     }
-    /// Loads MCU flash memory from a hex file.
-    pub fn load_flash_hex(&mut self, filename: &str) {
-        self.mcu.load_flash_hex(filename);
+    /// Loads MCU flash memory from an Intel hex file.
+    pub fn load_flash_hex(&mut self, filename: &str) -> Result<(), HexError> {
+        self.mcu.load_flash_hex(filename)
+    }
+    /// Loads MCU flash memory from a Motorola S-record file.
+    pub fn load_flash_srec(&mut self, filename: &str) -> Result<(), HexError> {
+        self.mcu.load_flash_srec(filename)
+    }
+    /// Loads MCU flash memory from a raw binary file.
+    pub fn load_flash_bin(&mut self, filename: &str) -> Result<(), HexError> {
+        self.mcu.load_flash_bin(filename)
+    }
+    /// Loads MCU flash memory from an ELF file, keeping its symbols and other sections.
+    pub fn load_elf(&mut self, filename: &str) -> Result<(), ElfError> {
+        let image = ElfImage::read(filename)?;
+        self.load_elf_image(image)
+    }
+
+    /// Loads firmware from a file, choosing the format by its extension.
+    pub fn load_firmware(&mut self, filename: &str) -> Result<(), FirmwareError> {
+        let extension = Path::new(filename).extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("elf") => self.load_elf(filename)?,
+            Some("hex" | "ihex") => self.load_flash_hex(filename)?,
+            Some("srec" | "s19" | "s28" | "s37") => self.load_flash_srec(filename)?,
+            Some("bin") => self.load_flash_bin(filename)?,
+            _ => return Err(FirmwareError::UnknownFormat),
+        }
+        Ok(())
+    }
+
+    /// Loads MCU flash, EEPROM and fuses (if the image has `.fuse` or `.lock` sections) from an ELF image.
+    pub fn load_elf_image(&mut self, image: ElfImage) -> Result<(), ElfError> {
+        if image.flash.len() > M::flash_size() {
+            return Err(ElfError::OutOfFlash(image.flash.len() as u32 * 2 - 1));
+        }
+        self.mcu.load_flash(&image.flash);
+        if let Some(eeprom) = &image.eeprom {
+            self.mcu.load_eeprom(eeprom);
+        }
+        if image.fuses.is_some() || image.lock.is_some() {
+            let fuses = image.fuses.as_deref().unwrap_or(&[]);
+            let lock = image.lock.as_ref().and_then(|lock| lock.first().copied());
+            self.set_fuses(Fuses::from_sections::<M>(fuses, lock));
+        }
+        self.firmware = Some(image);
+        Ok(())
+    }
+
+    /// Gets the firmware image loaded by [McuTicker::load_elf].
+    pub fn firmware(&self) -> Option<&ElfImage> {
+        self.firmware.as_ref()
+    }
+
+    /// Gets current CPU execution state (running, sleeping or halted by BREAK).
+    pub fn state(&self) -> CpuState {
+        self.mcu.state()
+    }
+
+    /// Returns `true` if the last instruction has taken all its cycles, so the next CPU cycle starts a new one.
+    pub fn instruction_finished(&self) -> bool {
+        self.ticks == 0
+    }
+
+    /// Gets number of CPU cycles executed since the start.
+    pub fn cycles(&self) -> u64 {
+        self.mcu.cycles()
+    }
+
+    /// Takes data addresses of IO registers accessed since the last call which are not modelled yet.
+    ///
+    /// [DeviceDescription::register_name](super::atdf::DeviceDescription::register_name) can name them.
+    pub fn take_unmodeled_accesses(&mut self) -> Vec<u16> {
+        self.mcu.take_unmodeled_accesses()
+    }
+
+    /// Resumes CPU halted by BREAK instruction.
+    pub fn resume(&mut self) {
+        self.mcu.resume();
+    }
+
+    /// Starts writing an instruction-level execution trace.
+    pub fn enable_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops writing the execution trace, flushing it.
+    ///
+    /// Returns the write error which has stopped the trace earlier, if there was one.
+    pub fn disable_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the complete MCU state into a snapshot.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_header();
+        self.mcu.save_state(&mut w);
+        w.write_u8(self.ticks);
+        w.write_u64(self.clock_phase.to_bits());
+        w.write_bool(self.clock_running);
+        w.write_bool(self.reset_held);
+        w.into_inner()
+    }
+
+    /// Restores the complete MCU state from a snapshot made by [McuTicker::snapshot].
+    ///
+    /// Output pins are updated on the next clock.
+    pub fn restore(&mut self, data: Vec<u8>) -> Result<(), SnapshotError> {
+        let mut r = StateReader::new(data);
+        r.read_header()?;
+        self.mcu.load_state(&mut r)?;
+        self.ticks = r.read_u8()?;
+        self.clock_phase = f64::from_bits(r.read_u64()?);
+        self.clock_running = r.read_bool()?;
+        self.reset_held = r.read_bool()?;
+        self.update_brown_out();
+        // PC of the next instruction has been checked for breakpoints when the last one finished
+        self.breakpoint_checked = self.ticks == 0 && self.mcu.state() == CpuState::Running;
+        r.finish()
+    }
+
+    /// Saves the complete MCU state into a snapshot file.
+    pub fn save_snapshot(&self, filename: &str) -> Result<(), SnapshotError> {
+        fs::write(filename, self.snapshot())?;
+        Ok(())
+    }
+
+    /// Restores the complete MCU state from a snapshot file.
+    pub fn load_snapshot(&mut self, filename: &str) -> Result<(), SnapshotError> {
+        self.restore(fs::read(filename)?)
+    }
+
+    /// Enables a semihosting channel for firmware to print to the host, read input and end the simulation.
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.mcu.enable_semihosting(semihosting);
+    }
+
+    /// Starts collecting flash code coverage, clearing the previous counts.
+    pub fn enable_coverage(&mut self) {
+        self.mcu.enable_coverage();
+    }
+
+    /// Gets the coverage collected since [McuTicker::enable_coverage].
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.mcu.coverage()
+    }
+
+    /// Writes an address-level coverage report, naming functions if the firmware was loaded from an ELF file.
+    pub fn save_coverage_report(&self, filename: &str) -> Result<(), CoverageError> {
+        let coverage = self.mcu.coverage().ok_or(CoverageError::NotEnabled)?;
+        let symbols = self.firmware.as_ref().map(|f| &f.symbols);
+        Ok(coverage.save_report::<M>(self.mcu.flash(), symbols, filename)?)
+    }
+
+    /// Writes source line coverage as an lcov tracefile, the firmware has to be loaded from an ELF file with debug info.
+    pub fn save_lcov(&self, filename: &str) -> Result<(), CoverageError> {
+        let coverage = self.mcu.coverage().ok_or(CoverageError::NotEnabled)?;
+        let firmware = self.firmware.as_ref().ok_or(CoverageError::NoLineInfo)?;
+        if let Some(e) = firmware.line_error {
+            return Err(CoverageError::BadLineInfo(e));
+        }
+        if firmware.lines.is_empty() {
+            return Err(CoverageError::NoLineInfo);
+        }
+        Ok(coverage.save_lcov(self.mcu.flash(), &firmware.lines, &firmware.symbols, filename)?)
+    }
+
+    /// Adds a breakpoint at a word address, stopping the board before the instruction there.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.mcu.add_breakpoint(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.mcu.remove_breakpoint(addr);
+    }
+
+    /// Adds a watchpoint, stopping the board after an instruction accessing the watched data.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.mcu.add_watchpoint(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.mcu.remove_watchpoint(watchpoint);
+    }
+
+    /// Removes all breakpoints and watchpoints.
+    pub fn clear_debug_points(&mut self) {
+        self.mcu.clear_debug_points();
+    }
+
+    /// Gets PC word address.
+    pub fn pc(&self) -> u32 {
+        self.mcu.pc()
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.mcu.sp()
+    }
+
+    pub fn sreg(&self) -> u8 {
+        self.mcu.sreg()
+    }
+
+    pub fn read_register(&self, i: u8) -> u8 {
+        self.mcu.read_register(i)
+    }
+
+    /// Gets the whole flash memory, in words.
+    pub fn flash(&self) -> &[u16] {
+        self.mcu.flash()
+    }
+
+    /// Reads a data memory byte without triggering watchpoints.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.mcu.peek(addr)
+    }
+
+    /// Returns `true` if [McuTicker::peek] at a data address can't avoid changing IO state, like reading UDR.
+    pub fn peek_has_side_effects(&self, addr: u16) -> bool {
+        self.mcu.peek_has_side_effects(addr)
+    }
+
+    /// Writes a data memory byte without triggering watchpoints.
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        self.mcu.poke(addr, val);
+    }
+
+    /// Attaches a GDB server. The CPU waits for GDB to connect on its next instruction.
+    pub fn attach_gdb(&mut self, gdb: GdbStub) {
+        self.gdb = Some(gdb);
+    }
+
+    /// Gets the attached GDB server.
+    pub fn gdb(&self) -> Option<&GdbStub> {
+        self.gdb.as_ref()
     }
 }
 
@@ -65,30 +493,56 @@ where
     Io: IoControllerTrait,
 {
     fn pin_count() -> usize {
-        IoController::<M>::pin_count()
+        IoController::<M>::pin_count() + 1
     }
 
     fn advance(&mut self, _time_ns: f64) -> Option<f64> {None}
 
     fn set_pin(&mut self, pin: PinId, state: PinState) {
-        self.mcu.io.set_pin(pin, state)
+        if pin != Self::reset_pin() {
+            self.mcu.io.set_pin(pin, state);
+            return;
+        }
+        // RESET has an internal pull-up
+        let low = matches!(state, PinState::Low | PinState::WeakLow);
+        if low && !self.reset_held {
+            self.reset(ResetSource::External);
+        }
+        self.reset_held = low;
     }
 
     fn get_output_changes(&mut self) -> &[(PinId, PinState)] {
+        if !self.clock_running {
+            return &[];
+        }
         self.mcu.io.get_output_changes()
     }
 
     fn pin_name(pin: PinId) -> String {
+        if pin == Self::reset_pin() {
+            return "RESET".to_string();
+        }
         Io::pin_name(pin)
     }
 
     fn clock_rising_edge(&mut self) {
-        self.mcu.io.clock_rising_edge();
-        self.tick();
+        self.clock_running = self.advance_clock();
+        if self.clock_running {
+            self.mcu.io.clock_rising_edge();
+            self.tick();
+        }
     }
 
     fn clock_falling_edge(&mut self) {
-        self.mcu.io.clock_falling_edge();
+        if self.clock_running {
+            self.mcu.io.clock_falling_edge();
+        }
+    }
+
+    fn take_event(&mut self) -> Option<ComponentEvent> {
+        self.mcu.take_fault().map(ComponentEvent::CpuFault)
+            .or_else(|| self.mcu.take_exit_code().map(ComponentEvent::Exit))
+            .or_else(|| self.debug_event.take())
     }
 }
 
@@ -105,4 +559,194 @@ where
     fn fill_vcd(&self, tree: &mut VcdTree) -> bool {
         self.mcu.fill_vcd(tree)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::components::avr::{mcu_model::{Atmega328P, Attiny85}, elf::test_helper::build_elf, mcu::debug::WatchKind};
+
+    use super::*;
+
+    fn run(mcu: &mut McuDefault<Atmega328P>, board_cycles: usize) {
+        for _ in 0..board_cycles {
+            mcu.clock_rising_edge();
+            mcu.clock_falling_edge();
+        }
+    }
+
+    #[test]
+    fn clock_prescaler() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[0xCFFF]); // rjmp .-2
+        // CKDIV8 programmed
+        mcu.set_fuses(Fuses::from_sections::<Atmega328P>(&[0x7F], None));
+        assert_eq!(mcu.cpu_frequency(), 2e6);
+        run(&mut mcu, 80);
+        assert_eq!(mcu.cycles(), 10);
+
+        mcu.mcu.io.write_external_u8(0x61, 0x80); // CLKPR: CLKPCE
+        mcu.mcu.io.write_external_u8(0x61, 0x00);
+        assert_eq!(mcu.cpu_frequency(), 16e6);
+        run(&mut mcu, 80);
+        assert_eq!(mcu.cycles(), 90);
+
+        // Internal 8 MHz oscillator
+        mcu.set_fuses(Fuses::from_sections::<Atmega328P>(&[0xE2], None));
+        assert_eq!(mcu.cpu_frequency(), 8e6);
+    }
+
+    #[test]
+    fn watchdog_real_time() {
+        // WDTON programmed, the 16 ms time-out is the same with a 16 MHz and a 2 MHz CPU clock
+        for low_fuse in [0xFF, 0x7F] {
+            let mut mcu = McuDefault::<Atmega328P>::new();
+            mcu.load_flash(&[0xCFFF]); // rjmp .-2
+            mcu.set_fuses(Fuses::from_sections::<Atmega328P>(&[low_fuse, 0xC9], None));
+            mcu.mcu.write_io(0x34, 0x00);
+            run(&mut mcu, 255_000);
+            assert_eq!(mcu.mcu.read_io(0x34), 0x00);
+            run(&mut mcu, 2_000);
+            assert_eq!(mcu.mcu.read_io(0x34), 0x08); // MCUSR: WDRF
+        }
+    }
+
+    #[test]
+    fn brown_out() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[0xCFFF]); // rjmp .-2
+        // BODLEVEL = 4.3 V
+        mcu.set_fuses(Fuses::from_sections::<Atmega328P>(&[0xFF, 0xD9, 0xFC], None));
+        mcu.set_supply_voltage(3.3);
+        run(&mut mcu, 10);
+        assert_eq!(mcu.cycles(), 0);
+        assert_eq!(mcu.cpu_frequency(), 0.0);
+
+        mcu.set_supply_voltage(5.0);
+        run(&mut mcu, 10);
+        assert_eq!(mcu.cycles(), 10);
+        assert_eq!(mcu.mcu.read_io(0x34), 0x05); // MCUSR: PORF and BORF
+    }
+
+    #[test]
+    fn reset_pin() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[0xCFFF]); // rjmp .-2
+        let reset = IoController::<Atmega328P>::pin_count() as PinId;
+        assert_eq!(McuDefault::<Atmega328P>::pin_name(reset), "RESET");
+        mcu.mcu.write_io(0x34, 0x00);
+
+        mcu.set_pin(reset, PinState::Low);
+        run(&mut mcu, 10);
+        assert_eq!(mcu.cycles(), 0);
+
+        mcu.set_pin(reset, PinState::Z);
+        run(&mut mcu, 10);
+        assert_eq!(mcu.cycles(), 10);
+        assert_eq!(mcu.mcu.read_io(0x34), 0x02); // MCUSR: EXTRF
+    }
+
+    #[test]
+    fn snapshot_timing() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[0x0000; 64]); // nop
+        for _ in 0..5 {
+            mcu.tick();
+        }
+        let mut restored = McuDefault::<Atmega328P>::new();
+        restored.restore(mcu.snapshot()).unwrap();
+        for _ in 0..5 {
+            mcu.tick();
+            restored.tick();
+        }
+        assert_eq!((restored.cycles(), restored.pc()), (mcu.cycles(), mcu.pc()));
+        assert_eq!(restored.snapshot(), mcu.snapshot());
+    }
+
+    #[test]
+    fn elf_fuses() {
+        let elf = build_elf(
+            &[0xFF, 0xCF],
+            &[(".text", &[]), (".fuse", &[0xFF, 0xDA]), (".lock", &[0xFC])],
+            &[]);
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_elf_image(ElfImage::parse(&elf).unwrap()).unwrap();
+        assert_eq!(mcu.fuses().reset_vector(), 0x3C00);
+        assert_eq!(mcu.fuses().lock, 0xFC);
+        assert_eq!(mcu.mcu.pc(), 0x3C00);
+    }
+
+    #[test]
+    fn elf_too_large() {
+        let elf = build_elf(&[0xFF; 8194], &[(".text", &[])], &[]);
+        let mut mcu = McuDefault::<Attiny85>::new();
+        let result = mcu.load_elf_image(ElfImage::parse(&elf).unwrap());
+        assert!(matches!(result, Err(ElfError::OutOfFlash(0x2001))));
+    }
+
+    #[test]
+    fn bad_line_info() {
+        let elf = build_elf(&[0xFF, 0xCF], &[(".text", &[]), (".debug_line", &[0xFF; 4])], &[]);
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_elf_image(ElfImage::parse(&elf).unwrap()).unwrap();
+        mcu.enable_coverage();
+        run(&mut mcu, 4);
+        // The parse error is only reported when line info is needed
+        let result = mcu.save_lcov("unused.info");
+        assert!(matches!(result, Err(CoverageError::BadLineInfo(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn semihosting_exit() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.enable_semihosting(Semihosting::new(0xF8, Box::new(std::io::sink())));
+        mcu.load_flash(&[
+            0xE003, // ldi r16, 3
+            0x9300, 0x00FA, // sts NUM, r16
+            0xE004, // ldi r16, EXIT
+            0x9300, 0x00F9, // sts COMMAND, r16
+            0xCFFF, // rjmp .-2
+        ]);
+        let mut event = None;
+        for _ in 0..20 {
+            mcu.clock_rising_edge();
+            mcu.clock_falling_edge();
+            event = event.or_else(|| mcu.take_event());
+        }
+        assert_eq!(event, Some(ComponentEvent::Exit(3)));
+    }
+
+    /// Runs until an event, returning it with the number of board cycles taken.
+    fn run_to_event(mcu: &mut McuDefault<Atmega328P>, max_cycles: usize) -> Option<(ComponentEvent, usize)> {
+        for i in 1..=max_cycles {
+            mcu.clock_rising_edge();
+            mcu.clock_falling_edge();
+            if let Some(event) = mcu.take_event() {
+                return Some((event, i));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn debug_points() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[
+            0xE005, // ldi r16, 5
+            0x9300, 0x0200, // sts 0x0200, r16
+            0xE011, // ldi r17, 1
+            0xCFFF, // rjmp .-2
+        ]);
+        mcu.add_breakpoint(3);
+        mcu.add_watchpoint(Watchpoint::data(0x0200, WatchKind::Write).with_value(5));
+        mcu.add_watchpoint(Watchpoint::data(0x0200, WatchKind::Write).with_value(6));
+
+        assert_eq!(run_to_event(&mut mcu, 10), Some((ComponentEvent::Watchpoint(0x0200, WatchKind::Write), 3)));
+        // The breakpoint stops the board right before `ldi r17, 1` is executed
+        assert_eq!(run_to_event(&mut mcu, 10), Some((ComponentEvent::Breakpoint(3), 1)));
+        assert_eq!(mcu.read_register(17), 0);
+        assert_eq!(mcu.peek(0x0200), 5);
+        // Resuming executes the instruction at the breakpoint
+        assert_eq!(run_to_event(&mut mcu, 10), None);
+        assert_eq!(mcu.read_register(17), 1);
+        assert_eq!(mcu.pc(), 4);
+    }
+}
@@ -0,0 +1,228 @@
+//! Binary snapshots of the complete MCU state.
+//!
+//! A snapshot is a signature followed by a flat little-endian dump of every stateful part of the MCU,
+//! in a fixed order. It is only meant to be loaded by the same version of Amber and the same MCU model.
+
+use std::{fmt, io};
+
+use crate::pins::PinState;
+
+/// Snapshot file signature.
+pub const MAGIC: &[u8; 8] = b"AMBERSNP";
+/// Snapshot format version, changed whenever the saved state changes.
+pub const VERSION: u8 = 8;
+
+/// An error while loading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// Malformed, truncated or incompatible snapshot.
+    Format(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Format(msg) => write!(f, "invalid snapshot: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Serializer of a snapshot.
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    /// Writes the snapshot signature and version.
+    pub fn write_header(&mut self) {
+        self.write_bytes(MAGIC);
+        self.write_u8(VERSION);
+    }
+
+    #[inline]
+    pub fn write_u8(&mut self, x: u8) {
+        self.data.push(x);
+    }
+
+    #[inline]
+    pub fn write_bool(&mut self, x: bool) {
+        self.data.push(x as u8);
+    }
+
+    #[inline]
+    pub fn write_u16(&mut self, x: u16) {
+        self.data.extend_from_slice(&x.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_u32(&mut self, x: u32) {
+        self.data.extend_from_slice(&x.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_u64(&mut self, x: u64) {
+        self.data.extend_from_slice(&x.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_bytes(&mut self, x: &[u8]) {
+        self.data.extend_from_slice(x);
+    }
+
+    pub fn write_pin_state(&mut self, x: PinState) {
+        self.write_u8(match x {
+            PinState::Z => 0,
+            PinState::Low => 1,
+            PinState::High => 2,
+            PinState::WeakLow => 3,
+            PinState::WeakHigh => 4,
+            PinState::Error => 5,
+        });
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Deserializer of a snapshot.
+#[derive(Debug)]
+pub struct StateReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl StateReader {
+    pub fn new(data: Vec<u8>) -> StateReader {
+        StateReader { data, pos: 0 }
+    }
+
+    /// Checks the snapshot signature and version.
+    pub fn read_header(&mut self) -> Result<(), SnapshotError> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::Format("not a snapshot"));
+        }
+        if self.read_u8()? != VERSION {
+            return Err(SnapshotError::Format("unsupported version"));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], SnapshotError> {
+        if self.data.len() - self.pos < n {
+            return Err(SnapshotError::Format("unexpected end of data"));
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    #[inline]
+    pub fn read_u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Format("invalid boolean")),
+        }
+    }
+
+    #[inline]
+    pub fn read_u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    #[inline]
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    #[inline]
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Fills a slice with the next bytes.
+    pub fn read_bytes(&mut self, x: &mut [u8]) -> Result<(), SnapshotError> {
+        x.copy_from_slice(self.take(x.len())?);
+        Ok(())
+    }
+
+    pub fn read_pin_state(&mut self) -> Result<PinState, SnapshotError> {
+        match self.read_u8()? {
+            0 => Ok(PinState::Z),
+            1 => Ok(PinState::Low),
+            2 => Ok(PinState::High),
+            3 => Ok(PinState::WeakLow),
+            4 => Ok(PinState::WeakHigh),
+            5 => Ok(PinState::Error),
+            _ => Err(SnapshotError::Format("invalid pin state")),
+        }
+    }
+
+    /// Checks that the whole snapshot was read.
+    pub fn finish(&self) -> Result<(), SnapshotError> {
+        if self.pos != self.data.len() {
+            return Err(SnapshotError::Format("unexpected data at the end"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut w = StateWriter::new();
+        w.write_header();
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u32(0x789ABCDE);
+        w.write_u64(0x0123_4567_89AB_CDEF);
+        w.write_pin_state(PinState::WeakHigh);
+        w.write_bytes(&[1, 2, 3]);
+        let data = w.into_inner();
+
+        let mut r = StateReader::new(data);
+        r.read_header().unwrap();
+        assert_eq!(r.read_u8().unwrap(), 0x12);
+        assert!(r.read_bool().unwrap());
+        assert_eq!(r.read_u16().unwrap(), 0x3456);
+        assert_eq!(r.read_u32().unwrap(), 0x789ABCDE);
+        assert_eq!(r.read_u64().unwrap(), 0x0123_4567_89AB_CDEF);
+        assert_eq!(r.read_pin_state().unwrap(), PinState::WeakHigh);
+        let mut bytes = [0; 3];
+        r.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        r.finish().unwrap();
+        assert!(matches!(r.read_u8(), Err(SnapshotError::Format(_))));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(StateReader::new(b"AMBERSNQ\x01".to_vec()).read_header(), Err(SnapshotError::Format("not a snapshot"))));
+        assert!(matches!(StateReader::new(b"AMBERSNP\xFF".to_vec()).read_header(), Err(SnapshotError::Format("unsupported version"))));
+        assert!(matches!(StateReader::new(vec![2]).read_bool(), Err(SnapshotError::Format("invalid boolean"))));
+        assert!(matches!(StateReader::new(vec![1, 2]).finish(), Err(SnapshotError::Format(_))));
+    }
+}
@@ -0,0 +1,255 @@
+use bitfield::Bit;
+
+use super::snapshot::{StateWriter, StateReader, SnapshotError};
+
+/// Number of CPU clock cycles SPMEN stays set after being written.
+const ENABLE_CYCLES: u8 = 4;
+
+/// Page erase/write time in microseconds, independent of the CPU clock.
+const PAGE_OPERATION_TIME_US: u64 = 4_000;
+
+/// An operation selected by SPMCSR for the next SPM instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpmCommand {
+    /// SPM is not enabled or busy, instruction does nothing.
+    None,
+    /// Store R1:R0 into the temporary page buffer.
+    FillBuffer,
+    /// Erase a flash page.
+    PageErase,
+    /// Write the temporary page buffer into a flash page.
+    PageWrite,
+    /// Re-enable the RWW section after an erase/write.
+    RwwEnable,
+}
+
+/// Store Program Memory controller, together with SPMCSR register.
+pub struct SpmController {
+    /// SPMIE, RWWSRE, BLBSET, PGWRT, PGERS and SPMEN bits, as written by software.
+    spmcsr: u8,
+    /// Remaining CPU clock cycles of the SPMEN timed sequence.
+    enable_cycles: u8,
+    /// Remaining CPU clock cycles of the current page operation.
+    busy_cycles: u32,
+    /// CPU is halted until the current page operation ends (NRWW section write).
+    cpu_halted: bool,
+    /// Effective CPU clock frequency in Hz.
+    cpu_frequency: u32,
+    /// RWW section is busy and cannot be read (RWWSB).
+    rww_busy: bool,
+
+    /// Temporary page buffer.
+    page_buffer: Vec<u16>,
+}
+
+impl SpmController {
+    pub fn new(page_size: usize) -> SpmController {
+        SpmController {
+            spmcsr: 0,
+            enable_cycles: 0,
+            busy_cycles: 0,
+            cpu_halted: false,
+            cpu_frequency: 16_000_000,
+            rww_busy: false,
+            page_buffer: vec![0xFFFF; page_size],
+        }
+    }
+
+    /// Advances page operations and SPMEN timeout by several CPU clock cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        if self.busy_cycles > 0 {
+            self.busy_cycles = self.busy_cycles.saturating_sub(cycles as u32);
+            if self.busy_cycles == 0 {
+                self.cpu_halted = false;
+                self.spmcsr &= 0x80;
+            }
+        } else if self.enable_cycles > 0 {
+            self.enable_cycles = self.enable_cycles.saturating_sub(cycles);
+            if self.enable_cycles == 0 {
+                self.spmcsr &= 0x80;
+            }
+        }
+    }
+
+    /// Sets the effective CPU clock frequency in Hz, the controller is ticked with.
+    pub fn set_cpu_frequency(&mut self, frequency: u32) {
+        self.cpu_frequency = frequency;
+    }
+
+    /// Gets the page erase/write time in CPU clock cycles.
+    fn page_operation_cycles(&self) -> u32 {
+        (self.cpu_frequency as u64 * PAGE_OPERATION_TIME_US / 1_000_000).max(1) as u32
+    }
+
+    /// Returns the operation the next SPM instruction would perform.
+    pub fn command(&self) -> SpmCommand {
+        if self.busy_cycles > 0 || self.enable_cycles == 0 {
+            return SpmCommand::None;
+        }
+        match self.spmcsr & 0x1F {
+            0b00001 => SpmCommand::FillBuffer,
+            0b00011 => SpmCommand::PageErase,
+            0b00101 => SpmCommand::PageWrite,
+            0b10001 => SpmCommand::RwwEnable,
+            _ => SpmCommand::None,
+        }
+    }
+
+    /// Stores a word into the temporary page buffer.
+    pub fn fill_buffer(&mut self, addr: u32, val: u16) {
+        let index = addr as usize % self.page_buffer.len();
+        self.page_buffer[index] = val;
+        self.finish_command();
+    }
+
+    /// Takes the temporary page buffer contents, erasing the buffer.
+    pub fn take_buffer(&mut self) -> Vec<u16> {
+        let erased = vec![0xFFFF; self.page_buffer.len()];
+        std::mem::replace(&mut self.page_buffer, erased)
+    }
+
+    /// Starts a page erase or page write operation.
+    ///
+    /// Writing into NRWW section halts the CPU, writing into RWW section makes it unreadable.
+    pub fn start_page_operation(&mut self, in_nrww: bool) {
+        self.busy_cycles = self.page_operation_cycles();
+        self.enable_cycles = 0;
+        if in_nrww {
+            self.cpu_halted = true;
+        } else {
+            self.rww_busy = true;
+        }
+    }
+
+    /// Re-enables RWW section, aborting any temporary page buffer loading.
+    pub fn enable_rww(&mut self) {
+        self.rww_busy = false;
+        self.page_buffer.fill(0xFFFF);
+        self.finish_command();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.spmcsr);
+        w.write_u8(self.enable_cycles);
+        w.write_u32(self.busy_cycles);
+        w.write_bool(self.cpu_halted);
+        w.write_bool(self.rww_busy);
+        for &x in self.page_buffer.iter() {
+            w.write_u16(x);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SnapshotError> {
+        self.spmcsr = r.read_u8()?;
+        self.enable_cycles = r.read_u8()?;
+        self.busy_cycles = r.read_u32()?;
+        self.cpu_halted = r.read_bool()?;
+        self.rww_busy = r.read_bool()?;
+        for x in self.page_buffer.iter_mut() {
+            *x = r.read_u16()?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn finish_command(&mut self) {
+        self.enable_cycles = 0;
+        self.spmcsr &= 0x80;
+    }
+
+    /// Returns `true` if CPU is halted by an NRWW section page operation.
+    #[inline]
+    pub fn cpu_halted(&self) -> bool {
+        self.cpu_halted
+    }
+
+    /// Returns `true` if RWW section is busy (RWWSB bit).
+    #[inline]
+    pub fn rww_busy(&self) -> bool {
+        self.rww_busy
+    }
+
+    /// Returns `true` if SPM ready interrupt is requested (SPMIE is set and SPMEN is cleared).
+    #[inline]
+    pub fn interrupt_pending(&self) -> bool {
+        self.spmcsr & 0x81 == 0x80
+    }
+
+    #[inline]
+    pub fn read_spmcsr(&self) -> u8 {
+        self.spmcsr | (self.rww_busy as u8) << 6
+    }
+
+    pub fn write_spmcsr(&mut self, val: u8) {
+        if self.busy_cycles > 0 {
+            self.spmcsr.set_bit(7, val.bit(7));
+            return;
+        }
+        self.spmcsr = val & 0x9F;
+        self.enable_cycles = if val.bit(0) {ENABLE_CYCLES} else {0};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spmen_timeout() {
+        let mut spm = SpmController::new(128);
+        assert_eq!(spm.command(), SpmCommand::None);
+
+        spm.write_spmcsr(0b0000_0011);
+        assert_eq!(spm.command(), SpmCommand::PageErase);
+        assert_eq!(spm.read_spmcsr(), 0b0000_0011);
+        spm.tick(3);
+        assert_eq!(spm.command(), SpmCommand::PageErase);
+        spm.tick(1);
+        assert_eq!(spm.command(), SpmCommand::None);
+        assert_eq!(spm.read_spmcsr(), 0b0000_0000);
+    }
+
+    #[test]
+    fn page_operation() {
+        let mut spm = SpmController::new(128);
+        spm.write_spmcsr(0b1000_0101);
+        assert_eq!(spm.command(), SpmCommand::PageWrite);
+        spm.start_page_operation(false);
+        assert!(spm.rww_busy());
+        assert!(!spm.cpu_halted());
+        assert_eq!(spm.command(), SpmCommand::None);
+        assert_eq!(spm.read_spmcsr(), 0b1100_0101);
+
+        spm.tick(4);
+        spm.write_spmcsr(0b1001_0001);
+        assert_eq!(spm.command(), SpmCommand::None);
+        spm.tick(255);
+        while spm.read_spmcsr() & 0x01 != 0 {
+            spm.tick(255);
+        }
+        assert_eq!(spm.read_spmcsr(), 0b1100_0000);
+
+        assert!(spm.interrupt_pending());
+        spm.write_spmcsr(0b0001_0001);
+        assert!(!spm.interrupt_pending());
+        assert_eq!(spm.command(), SpmCommand::RwwEnable);
+        spm.enable_rww();
+        assert!(!spm.rww_busy());
+        assert_eq!(spm.read_spmcsr(), 0b0000_0000);
+    }
+
+    #[test]
+    fn page_buffer() {
+        let mut spm = SpmController::new(128);
+        spm.write_spmcsr(0b0000_0001);
+        spm.fill_buffer(0x1F005, 0x1234);
+        assert_eq!(spm.read_spmcsr(), 0b0000_0000);
+        spm.fill_buffer(0x1F006, 0x5678);
+
+        let buffer = spm.take_buffer();
+        assert_eq!(buffer[5], 0x1234);
+        assert_eq!(buffer[6], 0x5678);
+        assert_eq!(buffer[7], 0xFFFF);
+        assert_eq!(spm.take_buffer()[5], 0xFFFF);
+    }
+}
@@ -0,0 +1,160 @@
+//! External parallel SRAM with an address latch, as connected to the AVR external memory interface.
+
+use bitfield::Bit;
+
+use crate::{pins::{PinState, PinId, PinVec}, component::Component, vcd::{fillers::VcdFiller, VcdModuleBuilder, VcdTreeModule}};
+
+/// Parallel SRAM (like 62256) behind a transparent address latch (like 74HC573).
+///
+/// The latch follows AD7:0 while ALE is high. Data is driven on AD7:0 while RD is low,
+/// and written on the rising edge of WR.
+/// Pins are AD0-AD7, A8-A15, ALE, RD and WR, the address wraps around the memory size.
+pub struct Sram {
+    data: Vec<u8>,
+
+    ad: u8,
+    high: u8,
+    latch: u8,
+    ale: PinState,
+    rd: PinState,
+    wr: PinState,
+
+    /// Data currently driven on AD7:0.
+    driven: Option<u8>,
+    /// Last data read or written, for VCD.
+    last_data: Option<u8>,
+    output_changes: Vec<(PinId, PinState)>,
+}
+
+fn is_high(state: PinState) -> bool {
+    matches!(state, PinState::High | PinState::WeakHigh)
+}
+
+impl Sram {
+    /// Creates an SRAM of `size` bytes, filled with zeros.
+    pub fn new(size: usize) -> Sram {
+        assert!(size > 0 && size <= 0x10000, "SRAM size must be up to 64 KiB");
+        Sram {
+            data: vec![0; size],
+            ad: 0,
+            high: 0,
+            latch: 0,
+            ale: PinState::Z,
+            rd: PinState::Z,
+            wr: PinState::Z,
+            driven: None,
+            last_data: None,
+            output_changes: Vec::with_capacity(8),
+        }
+    }
+
+    /// Gets the memory contents.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Gets the memory contents for modification, for example to preload it.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    #[inline]
+    fn address(&self) -> usize {
+        ((self.high as usize) << 8 | self.latch as usize) % self.data.len()
+    }
+
+    /// Drives AD7:0 while RD is strobed and releases it otherwise.
+    fn update_outputs(&mut self) {
+        self.output_changes.clear();
+        let reading = !is_high(self.rd) && is_high(self.wr);
+        let driven = reading.then(|| self.data[self.address()]);
+        if driven != self.driven {
+            for i in 0..8 {
+                let state = driven.map_or(PinState::Z, |val| PinState::from_bool(val.bit(i)));
+                self.output_changes.push((i as PinId, state));
+            }
+            if driven.is_some() {
+                self.last_data = driven;
+            }
+            self.driven = driven;
+        }
+    }
+}
+
+impl Component for Sram {
+    fn pin_count() -> usize {
+        19
+    }
+
+    fn advance(&mut self, _time_ns: f64) -> Option<f64> {
+        self.update_outputs();
+        None
+    }
+
+    fn set_pin(&mut self, pin: PinId, state: PinState) {
+        match pin {
+            0..=7 => self.ad.set_bit(pin as usize, is_high(state)),
+            8..=15 => self.high.set_bit(pin as usize - 8, is_high(state)),
+            16 => self.ale = state,
+            17 => self.rd = state,
+            18 => {
+                if !is_high(self.wr) && is_high(state) {
+                    let addr = self.address();
+                    self.data[addr] = self.ad;
+                    self.last_data = Some(self.ad);
+                }
+                self.wr = state;
+            }
+            _ => panic!("Invalid pin id"),
+        }
+        if is_high(self.ale) {
+            self.latch = self.ad;
+        }
+    }
+
+    fn get_output_changes(&mut self) -> &[(PinId, PinState)] {
+        &self.output_changes
+    }
+
+    fn pin_name(pin_id: PinId) -> String {
+        match pin_id {
+            0..=7 => format!("AD{}", pin_id),
+            8..=15 => format!("A{}", pin_id),
+            16 => "ALE".to_string(),
+            17 => "RD".to_string(),
+            18 => "WR".to_string(),
+            _ => panic!("Invalid pin id")
+        }
+    }
+
+    fn clock_rising_edge(&mut self) {
+        self.update_outputs();
+    }
+
+    fn clock_falling_edge(&mut self) {
+        self.update_outputs();
+    }
+}
+
+impl VcdFiller for Sram {
+    const IS_SIGNAL: bool = false;
+
+    fn init_vcd_module(&self, builder: &mut VcdModuleBuilder) {
+        builder.add_signal("ale", 1, PinState::Z);
+        builder.add_signal("rd", 1, PinState::Z);
+        builder.add_signal("wr", 1, PinState::Z);
+        builder.add_signal("address", 16, PinState::Z);
+        builder.add_signal("data", 8, PinState::Z);
+    }
+
+    fn fill_module(&self, module: &mut VcdTreeModule) -> bool {
+        let mut r = module.update_subsignal(0, self.ale);
+        r |= module.update_subsignal(1, self.rd);
+        r |= module.update_subsignal(2, self.wr);
+        r |= module.update_subsignal(3, PinVec::init_logical(16, self.address() as u32));
+        if let Some(data) = self.last_data {
+            r |= module.update_subsignal(4, PinVec::init_logical(8, data as u32));
+        }
+        r
+    }
+}
@@ -1,7 +1,15 @@
+//! UART transceiver, which can connect a simulated serial port to the host.
+
+use std::{io::{self, Read, Write}, sync::mpsc::{self, Receiver, TryRecvError}, thread};
+
 use bitfield::Bit;
 
 use crate::{pins::{PinState, PinId, PinVec}, component::Component, vcd::{fillers::VcdFiller, VcdModuleBuilder, VcdTreeModule}};
 
+/// UART transceiver with `CHAR_SIZE` data bits and 1 stop bit.
+///
+/// Received characters can be written into an output, like the standard output,
+/// and bytes from an input channel are transmitted on the TX pin.
 pub struct Uart<const CHAR_SIZE: u8> {
     rx_pin_next: PinState,
     rx_pin: PinState,
@@ -20,6 +28,35 @@ pub struct Uart<const CHAR_SIZE: u8> {
     rx_frame_error: bool,
 
     parity: Option<bool>,
+
+    tx_start_time: f64,
+    /// Bit of the frame being transmitted, -1 if idle.
+    tx_pos: i8,
+    tx_data: u16,
+
+    /// Bytes to transmit, polled once per bit period while idle.
+    input: Option<Receiver<u8>>,
+    /// Sink for received characters.
+    output: Option<Box<dyn Write + Send>>,
+    output_changes: Vec<(PinId, PinState)>,
+}
+
+/// Spawns a thread reading the standard input, returning a channel of its bytes.
+///
+/// The channel is disconnected at the end of the input.
+pub fn stdin_channel() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new().name("stdin".to_string()).spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            let Ok(byte) = byte else {
+                break;
+            };
+            if tx.send(byte).is_err() {
+                break;
+            }
+        }
+    }).expect("Couldn't spawn stdin thread");
+    rx
 }
 
 impl<const CHAR_SIZE: u8> Uart<CHAR_SIZE> {
@@ -28,7 +65,7 @@ impl<const CHAR_SIZE: u8> Uart<CHAR_SIZE> {
             rx_pin_next: PinState::WeakHigh,
             rx_pin: PinState::WeakHigh,
             rx_falling_edge: false,
-            tx_pin: PinState::High,
+            tx_pin: PinState::Z,
 
             clk_period: 1e9 / baud_rate,
 
@@ -42,6 +79,108 @@ impl<const CHAR_SIZE: u8> Uart<CHAR_SIZE> {
             rx_running_parity: false,
             rx_parity_error: false,
             rx_frame_error: false,
+
+            tx_start_time: 0.0,
+            tx_pos: -1,
+            tx_data: 0,
+
+            input: None,
+            output: None,
+            output_changes: Vec::with_capacity(1),
+        }
+    }
+
+    /// Writes every received character into `output`, ignoring write errors.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = Some(output);
+    }
+
+    /// Transmits bytes from `input` until it's disconnected.
+    pub fn set_input(&mut self, input: Receiver<u8>) {
+        self.input = Some(input);
+    }
+
+    /// Number of bits in a frame, including start and stop bits.
+    #[inline]
+    fn frame_bits(&self) -> i8 {
+        CHAR_SIZE as i8 + 2 + self.parity.is_some() as i8
+    }
+
+    /// State of the TX pin during bit `pos` of the frame.
+    fn tx_bit(&self, pos: i8) -> PinState {
+        if pos == 0 {
+            PinState::Low
+        } else if pos <= CHAR_SIZE as i8 {
+            PinState::from_bool(self.tx_data.bit(pos as usize - 1))
+        } else if let (Some(parity), true) = (self.parity, pos == CHAR_SIZE as i8 + 1) {
+            PinState::from_bool(parity ^ (self.tx_data.count_ones() % 2 == 1))
+        } else {
+            PinState::High
+        }
+    }
+
+    fn drive_tx(&mut self, state: PinState) {
+        if self.tx_pin != state {
+            self.tx_pin = state;
+            self.output_changes.push((0, state));
+        }
+    }
+
+    /// Advances the transmitter, returning when it has to be advanced next.
+    fn advance_transmitter(&mut self, time_ns: f64) -> Option<f64> {
+        loop {
+            if self.tx_pos == -1 {
+                self.drive_tx(PinState::High);
+                let input = self.input.as_ref()?;
+                match input.try_recv() {
+                    Ok(byte) => {
+                        self.tx_data = byte as u16;
+                        self.tx_start_time = time_ns;
+                        self.tx_pos = 0;
+                    }
+                    Err(TryRecvError::Empty) => return Some(time_ns + self.clk_period),
+                    Err(TryRecvError::Disconnected) => {
+                        self.input = None;
+                        return None;
+                    }
+                }
+            }
+            let pos = ((time_ns - self.tx_start_time) / self.clk_period) as i8;
+            if pos >= self.frame_bits() {
+                self.tx_pos = -1;
+                continue;
+            }
+            self.tx_pos = pos;
+            self.drive_tx(self.tx_bit(pos));
+            return Some(self.tx_start_time + (pos + 1) as f64 * self.clk_period);
+        }
+    }
+
+    /// Advances the receiver, returning when it has to be advanced next.
+    fn advance_receiver(&mut self, time_ns: f64) -> Option<f64> {
+        if self.rx_pos == -1 {
+            if self.rx_falling_edge {
+                self.rx_start_time = time_ns;
+                self.rx_pos = 0;
+                self.rx_parity_error = false;
+                self.rx_frame_error = false;
+                self.rx_pin = self.rx_pin_next;
+                // Middle of the stop bit
+                Some(time_ns + self.clk_period * (self.frame_bits() as f64 - 0.5))
+            } else {
+                self.rx_pin = self.rx_pin_next;
+                None
+            }
+        } else {
+            let bit = self.rx_pin == PinState::High;
+            let mut next_bit_time = self.rx_start_time +
+                (0.5 + self.rx_pos as f64) * self.clk_period;
+            while self.rx_pos != -1 && time_ns >= next_bit_time {
+                self.handle_bit(bit);
+                next_bit_time += self.clk_period;
+            }
+            self.rx_pin = self.rx_pin_next;
+            None
         }
     }
 
@@ -75,6 +214,10 @@ impl<const CHAR_SIZE: u8> Uart<CHAR_SIZE> {
             } else {
                 // println!("{:02X}", self.rx_data);
                 self.rx_data_ready = Some(self.rx_data);
+                if let Some(output) = &mut self.output {
+                    let _ = output.write_all(&[self.rx_data as u8]);
+                    let _ = output.flush();
+                }
             }
             self.rx_pos = -1;
         }
@@ -87,28 +230,12 @@ impl<const CHAR_SIZE: u8> Component for Uart<CHAR_SIZE> {
     }
 
     fn advance(&mut self, time_ns: f64) -> Option<f64> {
-        if self.rx_pos == -1 {
-            if self.rx_falling_edge {
-                self.rx_start_time = time_ns;
-                self.rx_pos = 0;
-                self.rx_parity_error = false;
-                self.rx_frame_error = false;
-                self.rx_pin = self.rx_pin_next;
-                Some(time_ns + self.clk_period * (CHAR_SIZE as f64 + 2.5))
-            } else {
-                self.rx_pin = self.rx_pin_next;
-                None
-            }
-        } else {
-            let bit = self.rx_pin == PinState::High;
-            let mut next_bit_time = self.rx_start_time +
-                (0.5 + self.rx_pos as f64) * self.clk_period;
-            while time_ns >= next_bit_time {
-                self.handle_bit(bit);
-                next_bit_time += self.clk_period;
-            }
-            self.rx_pin = self.rx_pin_next;
-            None
+        self.output_changes.clear();
+        let rx_time = self.advance_receiver(time_ns);
+        let tx_time = self.advance_transmitter(time_ns);
+        match (rx_time, tx_time) {
+            (Some(rx), Some(tx)) => Some(rx.min(tx)),
+            (rx, tx) => rx.or(tx),
         }
     }
 
@@ -128,7 +255,7 @@ impl<const CHAR_SIZE: u8> Component for Uart<CHAR_SIZE> {
     }
 
     fn get_output_changes(&mut self) -> &[(PinId, PinState)] {
-        &[]
+        &self.output_changes
     }
 
     fn pin_name(pin_id: PinId) -> String {
@@ -165,4 +292,49 @@ impl<const CHAR_SIZE: u8> VcdFiller for Uart<CHAR_SIZE> {
         };
         r
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::{board::Board, components::{avr::{mcu_model::{Atmega328P, McuModel}, mcu_ticker::McuDefault}, test_helper::CaptureWriter}, vcd::VcdConfig};
+
+    use super::*;
+
+    #[test]
+    fn mcu_echo() {
+        let mut mcu = McuDefault::<Atmega328P>::new();
+        mcu.load_flash(&[
+            0xE001, // ldi r16, 1
+            0x9300, 0x00C4, // sts UBRR0L, r16
+            0xE108, // ldi r16, RXEN0 | TXEN0
+            0x9300, 0x00C1, // sts UCSR0B, r16
+            0x9110, 0x00C0, // loop: lds r17, UCSR0A
+            0xFF17, // sbrs r17, RXC0
+            0xCFFC, // rjmp loop
+            0x9100, 0x00C6, // lds r16, UDR0
+            0x9503, // inc r16
+            0x9300, 0x00C6, // sts UDR0, r16
+            0xCFF6, // rjmp loop
+        ]);
+        let console = CaptureWriter::default();
+        let (tx, rx) = mpsc::channel();
+        for &byte in b"HAL" {
+            tx.send(byte).unwrap();
+        }
+        drop(tx);
+        let mut terminal = Uart::<8>::new(500e3, None);
+        terminal.set_input(rx);
+        terminal.set_output(Box::new(console.clone()));
+
+        let mut board = Board::without_vcd(16e6);
+        let mcu = board.add_component_clocked(mcu, "mcu", &VcdConfig::Disable);
+        let terminal = board.add_component_threaded(terminal, "uart", &VcdConfig::Disable);
+        let peripherals = Atmega328P::peripherals();
+        let usart = &peripherals.usarts[0];
+        let pin_name = |pin| peripherals.pin_name(peripherals.pin_id(pin)).unwrap();
+        board.add_wire(&[mcu.pin(&pin_name(usart.tx_pin)), terminal.pin("RX")]);
+        board.add_wire(&[mcu.pin(&pin_name(usart.rx_pin)), terminal.pin("TX")]);
+
+        board.simulate(2000);
+        assert_eq!(console.contents(), b"IBM");
+    }
+}
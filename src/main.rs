@@ -1,41 +1,291 @@
-use amber::{board::Board, components::{avr::Atmega2560, led::Led, uart::Uart}, vcd::config::{VcdConfig}, vcd_config};
+//! Headless firmware runner.
+//!
+//! Runs a firmware file on a single MCU, with its first USART connected to the standard input and output,
+//! or a board from a description file.
+//! The exit status tells how the simulation has ended, so firmware tests can be run from scripts.
+//! With `--debug`, an interactive debugger is started instead.
 
-#[macro_use]
-extern crate timeit;
+use std::{io, process};
 
-fn main() {
-    let mut board = Board::new("out.vcd", 16e6);
-    let mut mcu = Atmega2560::new();
-    mcu.load_flash_hex("hex/uart_test.hex");
-
-    let mcu = board.add_component_clocked(
-        mcu, "mcu", 
-        
-        &vcd_config!{
-            // clk
-            // regs
-            // pc
-        });
-
-    let uart = Uart::<8>::new(9600.0, Some(false));
-    let uart = board.add_component_threaded(
-        uart, "uart",
-        &VcdConfig::Enable);
-
-    let xck_led = Led::new();
-    let xck_led = board.add_component_threaded(
-        xck_led, "xck_led", 
-        &VcdConfig::Enable);
-
-    let tx_led = Led::new();
-    let tx_led = board.add_component_threaded(
-        tx_led, "tx_led", 
-        &VcdConfig::Enable);
-
-    board.add_wire(&[mcu.pin("PE2"), xck_led.pin("LED")]);
-    board.add_wire(&[mcu.pin("PE1"), tx_led.pin("LED"), uart.pin("RX")]);
-
-    timeit!({
-        board.simulate(5 * 16000000);
+use amber::{
+    board::{Board, StopReason, description::Description},
+    component::ComponentEvent,
+    components::{avr::{mcu_model::{self, McuModel}, mcu_ticker::McuDefault, debugger::{Debugger, UartMonitor}, Semihosting}, uart::{self, Uart}},
+    vcd::config::VcdConfig,
+};
+
+/// Exit status for invalid arguments or firmware which couldn't be loaded.
+const EXIT_USAGE: i32 = 2;
+/// Exit status for a CPU fault.
+const EXIT_FAULT: i32 = 3;
+/// Exit status for a CPU halted by BREAK.
+const EXIT_BREAK: i32 = 4;
+/// Exit status for reaching the cycle limit, the same one `timeout` uses.
+const EXIT_TIMEOUT: i32 = 124;
+
+const USAGE: &str = "\
+Usage: amber [OPTIONS] <FIRMWARE>
+       amber [--time <SECONDS> | --cycles <N>] --board <FILE>
+
+Runs firmware (.hex, .elf, .srec or .bin) with USART0 connected to stdin/stdout,
+or a board description file.
+
+Options:
+  --mcu <MODEL>       atmega2560 (default), atmega328p or attiny85
+  --freq <HZ>         Board clock frequency [default: 16e6]
+  --time <SECONDS>    Stop after this much simulated time [default: 1]
+  --cycles <N>        Stop after N clock cycles, instead of --time
+  --baud <RATE>       USART baud rate [default: 9600]
+  --vcd <PATH>        Write all signals into a VCD file
+  --semihost <ADDR>   Enable semihosting registers at a data address (like 0x1FF0)
+  --board <FILE>      Run a board description instead of a single MCU
+  --debug             Start an interactive debugger, commands run for at most --time or --cycles
+  --coverage <PATH>   Write an address-level flash coverage report
+  --lcov <PATH>       Write source line coverage as an lcov tracefile (needs an ELF file with debug info)
+  -h, --help          Print this message
+
+Exit status:
+  firmware exit code  Semihosting EXIT command
+  2                   Invalid arguments, firmware or board description, or coverage not written
+  3                   CPU fault
+  4                   CPU halted by BREAK
+  124                 Cycle limit reached";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Model {
+    Atmega2560,
+    Atmega328P,
+    Attiny85,
+}
+
+#[derive(Debug)]
+struct Options {
+    firmware: String,
+    board: Option<String>,
+    model: Model,
+    freq: f64,
+    time: f64,
+    cycles: Option<u64>,
+    baud: f64,
+    vcd: Option<String>,
+    semihost: Option<u16>,
+    debug: bool,
+    coverage: Option<String>,
+    lcov: Option<String>,
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid {} value: {}", name, value))
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid --semihost address: {}", value))
+}
+
+/// Parses command line arguments, returns `Ok(None)` if help was requested.
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        firmware: String::new(),
+        board: None,
+        model: Model::Atmega2560,
+        freq: 16e6,
+        time: 1.0,
+        cycles: None,
+        baud: 9600.0,
+        vcd: None,
+        semihost: None,
+        debug: false,
+        coverage: None,
+        lcov: None,
+    };
+    let mut firmware = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        if !arg.starts_with("--") {
+            if firmware.replace(arg).is_some() {
+                return Err("Only one firmware file can be given".to_string());
+            }
+            continue;
+        }
+        if arg == "--debug" {
+            options.debug = true;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+        match arg.as_str() {
+            "--mcu" => {
+                options.model = match value.to_ascii_lowercase().as_str() {
+                    "atmega2560" => Model::Atmega2560,
+                    "atmega328p" => Model::Atmega328P,
+                    "attiny85" => Model::Attiny85,
+                    _ => return Err(format!("Unknown MCU model: {}", value)),
+                }
+            }
+            "--freq" => options.freq = parse_number(&arg, &value)?,
+            "--time" => options.time = parse_number(&arg, &value)?,
+            "--cycles" => options.cycles = Some(parse_number(&arg, &value)?),
+            "--baud" => options.baud = parse_number(&arg, &value)?,
+            "--vcd" => options.vcd = Some(value),
+            "--semihost" => options.semihost = Some(parse_address(&value)?),
+            "--board" => options.board = Some(value),
+            "--coverage" => options.coverage = Some(value),
+            "--lcov" => options.lcov = Some(value),
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    match (firmware, &options.board) {
+        (Some(_), Some(_)) => return Err("A firmware file can't be given together with --board".to_string()),
+        (Some(firmware), None) => options.firmware = firmware,
+        (None, None) => return Err("No firmware file given".to_string()),
+        (None, Some(_)) if options.debug || options.coverage.is_some() || options.lcov.is_some() => {
+            return Err("--debug, --coverage and --lcov can't be used together with --board".to_string())
+        }
+        (None, Some(_)) => {}
+    }
+    if !(options.freq > 0.0) || !(options.baud > 0.0) || !(options.time >= 0.0) {
+        return Err("Frequency, baud rate and time must be positive".to_string());
+    }
+    Ok(Some(options))
+}
+
+/// Runs the simulation, returning the exit status.
+fn run<M: McuModel + 'static>(options: &Options) -> i32 {
+    let mut mcu = McuDefault::<M>::new();
+    if let Err(e) = mcu.load_firmware(&options.firmware) {
+        eprintln!("Couldn't load {}: {}", options.firmware, e);
+        return EXIT_USAGE;
+    }
+    mcu.set_board_frequency(options.freq);
+    if options.coverage.is_some() || options.lcov.is_some() {
+        mcu.enable_coverage();
+    }
+    if let Some(addr) = options.semihost {
+        mcu.enable_semihosting(Semihosting::stdout(addr));
+    }
+
+    let (mut board, vcd_config) = match &options.vcd {
+        Some(path) => (Board::new(path, options.freq), VcdConfig::Enable),
+        None => (Board::without_vcd(options.freq), VcdConfig::Disable),
+    };
+    let mcu = board.add_component_clocked(mcu, "mcu", &vcd_config);
+
+    let peripherals = M::peripherals();
+    let mut monitor = None;
+    if let Some(usart) = peripherals.usarts.first() {
+        let pin_name = |pin| peripherals.pin_name(peripherals.pin_id(pin)).unwrap();
+        let mut terminal = Uart::<8>::new(options.baud, None);
+        // The debugger reads commands from the standard input, UART input is sent by a command
+        if options.debug {
+            monitor = Some(UartMonitor::connect(&mut terminal));
+        } else {
+            terminal.set_output(Box::new(io::stdout()));
+            terminal.set_input(uart::stdin_channel());
+        }
+        let terminal = board.add_component_threaded(terminal, "uart", &vcd_config);
+        board.add_wire(&[mcu.pin(&pin_name(usart.tx_pin)), terminal.pin("RX")]);
+        board.add_wire(&[mcu.pin(&pin_name(usart.rx_pin)), terminal.pin("TX")]);
+    }
+
+    let status = if options.debug {
+        let mut debugger = Debugger::<M>::new(&mut board, mcu.id());
+        debugger.set_cycle_limit(cycle_limit(options, options.freq));
+        if let Some(monitor) = monitor {
+            debugger.set_uart_monitor(monitor);
+        }
+        match debugger.run(io::stdin().lock(), io::stdout()) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Debugger error: {}", e);
+                EXIT_USAGE
+            }
+        }
+    } else {
+        simulate(&mut board, options, options.freq)
+    };
+
+    let mcu = board.component_mut::<McuDefault<M>>(mcu.id()).unwrap();
+    if let Some(path) = &options.coverage {
+        if let Err(e) = mcu.save_coverage_report(path) {
+            eprintln!("Couldn't write {}: {}", path, e);
+            return EXIT_USAGE;
+        }
+    }
+    if let Some(path) = &options.lcov {
+        if let Err(e) = mcu.save_lcov(path) {
+            eprintln!("Couldn't write {}: {}", path, e);
+            return EXIT_USAGE;
+        }
+    }
+    status
+}
+
+/// Runs a board from a description file, returning the exit status.
+fn run_board(options: &Options, path: &str) -> i32 {
+    let built = Description::load(path).and_then(|description| {
+        description.build().map(|(board, _)| (board, description.frequency()))
     });
+    match built {
+        Ok((mut board, freq)) => simulate(&mut board, options, freq),
+        Err(e) => {
+            eprintln!("Couldn't load {}: {}", path, e);
+            EXIT_USAGE
+        }
+    }
+}
+
+/// Gets the number of board cycles to simulate from `--time` or `--cycles`.
+fn cycle_limit(options: &Options, freq: f64) -> u64 {
+    options.cycles.unwrap_or((options.time * freq).round() as u64)
+}
+
+/// Runs the simulation until the time or cycle limit, returning the exit status.
+fn simulate(board: &mut Board, options: &Options, freq: f64) -> i32 {
+    let cycles = cycle_limit(options, freq);
+    match board.simulate(cycles) {
+        StopReason::Finished => {
+            eprintln!("Cycle limit reached after {} cycles", cycles);
+            EXIT_TIMEOUT
+        }
+        StopReason::Event(_, event, time_ns) => {
+            eprintln!("Simulation stopped at {} ns: {}", time_ns.round(), event);
+            match event {
+                ComponentEvent::Exit(code) => code,
+                ComponentEvent::CpuFault(_) => EXIT_FAULT,
+                ComponentEvent::Break(_) => EXIT_BREAK,
+                ComponentEvent::Breakpoint(_) | ComponentEvent::Watchpoint(..) => {
+                    unreachable!("The runner doesn't set breakpoints or watchpoints")
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    if let Some(path) = &options.board {
+        process::exit(run_board(&options, path));
+    }
+    let status = match options.model {
+        Model::Atmega2560 => run::<mcu_model::Atmega2560>(&options),
+        Model::Atmega328P => run::<mcu_model::Atmega328P>(&options),
+        Model::Attiny85 => run::<mcu_model::Attiny85>(&options),
+    };
+    process::exit(status);
 }
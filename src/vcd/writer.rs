@@ -10,8 +10,8 @@ use super::{VcdTree, VcdForest, VcdTreeModule, VcdTreeSignal, MutexVcdTree, VcdT
 
 /// Writes VCD signals into a .vcd file.
 pub struct VcdWriter {
-    /// File writer
-    f: BufWriter<File>,
+    /// File writer, `None` if VCD output is disabled
+    f: Option<BufWriter<File>>,
     /// Next VCD short identifier
     wire_id: Vec<u8>,
     /// Current state of VCD signals
@@ -28,7 +28,17 @@ impl VcdWriter {
     pub fn new(path: &str) -> VcdWriter {
         let f = File::create(path).expect("Couldn't create file");
         VcdWriter { 
-            f: BufWriter::new(f),
+            f: Some(BufWriter::new(f)),
+            wire_id: vec![33],
+            forest: VcdForest::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Creates a [VcdWriter] which doesn't write anything.
+    pub fn disabled() -> VcdWriter {
+        VcdWriter {
+            f: None,
             wire_id: vec![33],
             forest: VcdForest::new(),
             changes: Vec::new(),
@@ -195,16 +205,19 @@ impl VcdWriter {
 
     /// Writes .vcd file file header, together with $scope and $dumpvars sections.
     pub fn write_header(&mut self) {
-        write!(&mut self.f, "\
+        let Some(f) = &mut self.f else {
+            return;
+        };
+        write!(f, "\
             $version Generated by Amber $end\n\
             $date Wed Jun 7 18:38:32 2023 $end\n\
             $timescale 1ns $end\n\
             ").expect("Couldn't write header");
-        Self::write_scope_forest(&mut self.f, &mut self.wire_id, &mut self.forest);
-        write!(&mut self.f, "$enddefinitions $end\n$dumpvars\n")
+        Self::write_scope_forest(f, &mut self.wire_id, &mut self.forest);
+        write!(f, "$enddefinitions $end\n$dumpvars\n")
             .expect("Couldn't write header");
-        Self::write_data_forest(&mut self.f, &mut self.forest, &self.changes, true);
-        write!(&mut self.f, "$end\n").expect("Couldn't write header");
+        Self::write_data_forest(f, &mut self.forest, &self.changes, true);
+        write!(f, "$end\n").expect("Couldn't write header");
     }
 
     #[inline]
@@ -228,8 +241,10 @@ impl VcdWriter {
     /// Writes a single step into a .vcd file.
     pub fn write_step(&mut self, time_ns: f64) {
         if self.has_changed() {
-            write!(&mut self.f, "#{}\n", time_ns.round() as u64).expect("Couldn't write timestep");
-            Self::write_data_forest(&mut self.f, &mut self.forest, &self.changes, false);
+            if let Some(f) = &mut self.f {
+                write!(f, "#{}\n", time_ns.round() as u64).expect("Couldn't write timestep");
+                Self::write_data_forest(f, &mut self.forest, &self.changes, false);
+            }
             self.reset_changes();
         }
     }